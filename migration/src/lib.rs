@@ -7,6 +7,18 @@ mod m20230917_000001_create_account_table;
 mod m20230917_000002_create_author;
 mod m20230917_000003_create_music_table;
 mod m20230917_000004_create_progress_table;
+mod m20231020_000005_add_soft_delete;
+mod m20231022_000006_music_unique_per_author;
+mod m20231023_000007_add_timestamps;
+mod m20231024_000008_add_book_metadata;
+mod m20231025_000009_add_name_pinyin;
+mod m20231026_000010_add_tags_and_language;
+mod m20231027_000011_add_subsonic_password;
+mod m20231028_000012_add_feed_token;
+mod m20231029_000013_add_download_permission;
+mod m20231030_000014_add_chapter_duration;
+mod m20231031_000015_create_listening_session;
+mod m20231101_000016_add_progress_state;
 pub struct Migrator;
 
 #[async_trait::async_trait]
@@ -18,6 +30,18 @@ impl MigratorTrait for Migrator {
             Box::new(m20230917_000002_create_author::Migration),
             Box::new(m20230917_000003_create_music_table::Migration),
             Box::new(m20230917_000004_create_progress_table::Migration),
+            Box::new(m20231020_000005_add_soft_delete::Migration),
+            Box::new(m20231022_000006_music_unique_per_author::Migration),
+            Box::new(m20231023_000007_add_timestamps::Migration),
+            Box::new(m20231024_000008_add_book_metadata::Migration),
+            Box::new(m20231025_000009_add_name_pinyin::Migration),
+            Box::new(m20231026_000010_add_tags_and_language::Migration),
+            Box::new(m20231027_000011_add_subsonic_password::Migration),
+            Box::new(m20231028_000012_add_feed_token::Migration),
+            Box::new(m20231029_000013_add_download_permission::Migration),
+            Box::new(m20231030_000014_add_chapter_duration::Migration),
+            Box::new(m20231031_000015_create_listening_session::Migration),
+            Box::new(m20231101_000016_add_progress_state::Migration),
         ]
     }
 }
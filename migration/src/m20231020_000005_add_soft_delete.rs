@@ -0,0 +1,72 @@
+// m20231020_000005_add_soft_delete.rs
+
+use sea_orm_migration::prelude::*;
+
+use crate::{
+    m20230917_000001_create_account_table::Account, m20230917_000002_create_author::Author,
+    m20230917_000003_create_music_table::Music,
+};
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20231020_000005_add_soft_delete" // Make sure this matches with the file name
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    // Define how to apply this migration: add a nullable `deleted_at` to the trashable tables.
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Account::Table)
+                    .add_column(ColumnDef::new(SoftDelete::DeletedAt).date_time().null())
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Author::Table)
+                    .add_column(ColumnDef::new(SoftDelete::DeletedAt).date_time().null())
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Music::Table)
+                    .add_column(ColumnDef::new(SoftDelete::DeletedAt).date_time().null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    // Define how to rollback this migration: drop the `deleted_at` columns.
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for table in [
+            Account::Table.into_iden(),
+            Author::Table.into_iden(),
+            Music::Table.into_iden(),
+        ] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(table)
+                        .drop_column(SoftDelete::DeletedAt)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+// For ease of access
+#[derive(Iden)]
+pub enum SoftDelete {
+    DeletedAt,
+}
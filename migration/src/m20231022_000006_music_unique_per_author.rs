@@ -0,0 +1,92 @@
+// m20231022_000006_music_unique_per_author.rs
+
+use sea_orm_migration::prelude::*;
+
+use crate::m20230917_000003_create_music_table::Music;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20231022_000006_music_unique_per_author" // Make sure this matches with the file name
+    }
+}
+
+/// the index created by `unique_key()` on `music.name`, named after the column
+const NAME_INDEX: &str = "name";
+const AUTHOR_NAME_INDEX: &str = "idx_music_author_name";
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    // Define how to apply this migration: scope the book name uniqueness to the author, and add
+    // the audio info used to find duplicates.
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_index(
+                Index::drop()
+                    .name(NAME_INDEX)
+                    .table(Music::Table)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name(AUTHOR_NAME_INDEX)
+                    .table(Music::Table)
+                    .col(Music::AuthorId)
+                    .col(Music::Name)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Music::Table)
+                    .add_column(ColumnDef::new(AudioInfo::Duration).double().null())
+                    .add_column(ColumnDef::new(AudioInfo::Fingerprint).text().null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    // Define how to rollback this migration: fails if two authors already have a book with the
+    // same name.
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Music::Table)
+                    .drop_column(AudioInfo::Duration)
+                    .drop_column(AudioInfo::Fingerprint)
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name(NAME_INDEX)
+                    .table(Music::Table)
+                    .col(Music::Name)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .drop_index(
+                Index::drop()
+                    .name(AUTHOR_NAME_INDEX)
+                    .table(Music::Table)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+// For ease of access
+#[derive(Iden)]
+pub enum AudioInfo {
+    Duration,
+    Fingerprint,
+}
@@ -0,0 +1,85 @@
+// m20231023_000007_add_timestamps.rs
+
+use sea_orm_migration::prelude::*;
+
+use crate::{
+    m20230917_000002_create_author::Author, m20230917_000003_create_music_table::Music,
+    m20230917_000004_create_progress_table::Progress,
+};
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20231023_000007_add_timestamps" // Make sure this matches with the file name
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    // Define how to apply this migration: add `created_at`/`updated_at` to books and authors, and
+    // `last_played_at` to progress. The existing rows get the migration time.
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for table in [Music::Table.into_iden(), Author::Table.into_iden()] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(table)
+                        .add_column(
+                            ColumnDef::new(Timestamps::CreatedAt)
+                                .date_time()
+                                .not_null()
+                                .default(Expr::current_timestamp()),
+                        )
+                        .add_column(
+                            ColumnDef::new(Timestamps::UpdatedAt)
+                                .date_time()
+                                .not_null()
+                                .default(Expr::current_timestamp())
+                                .extra("ON UPDATE CURRENT_TIMESTAMP".to_string()),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+        }
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Progress::Table)
+                    .add_column(ColumnDef::new(Timestamps::LastPlayedAt).date_time().null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    // Define how to rollback this migration: drop the timestamp columns.
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for table in [Music::Table.into_iden(), Author::Table.into_iden()] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(table)
+                        .drop_column(Timestamps::CreatedAt)
+                        .drop_column(Timestamps::UpdatedAt)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Progress::Table)
+                    .drop_column(Timestamps::LastPlayedAt)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+// For ease of access
+#[derive(Iden)]
+pub enum Timestamps {
+    CreatedAt,
+    UpdatedAt,
+    LastPlayedAt,
+}
@@ -0,0 +1,94 @@
+// m20231024_000008_add_book_metadata.rs
+
+use sea_orm_migration::prelude::*;
+
+use crate::m20230917_000003_create_music_table::Music;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20231024_000008_add_book_metadata" // Make sure this matches with the file name
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    // Define how to apply this migration: add the searchable book metadata and the Chapter table.
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Music::Table)
+                    .add_column(ColumnDef::new(BookMetadata::Narrator).string().null())
+                    .add_column(ColumnDef::new(BookMetadata::Series).string().null())
+                    .add_column(ColumnDef::new(BookMetadata::Description).text().null())
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_table(
+                Table::create()
+                    .table(Chapter::Table)
+                    .col(
+                        ColumnDef::new(Chapter::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(Chapter::MusicId).integer().not_null())
+                    .col(ColumnDef::new(Chapter::ChapterNo).integer().not_null())
+                    .col(ColumnDef::new(Chapter::Title).string().not_null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-Chapter-MusicId")
+                            .from(Chapter::Table, Chapter::MusicId)
+                            .to(Music::Table, Music::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .index(
+                        Index::create()
+                            .name("idx_chapter_music_no")
+                            .col(Chapter::MusicId)
+                            .col(Chapter::ChapterNo),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    // Define how to rollback this migration: drop the Chapter table and the metadata columns.
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(Chapter::Table).to_owned())
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Music::Table)
+                    .drop_column(BookMetadata::Narrator)
+                    .drop_column(BookMetadata::Series)
+                    .drop_column(BookMetadata::Description)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+// For ease of access
+#[derive(Iden)]
+pub enum BookMetadata {
+    Narrator,
+    Series,
+    Description,
+}
+
+#[derive(Iden)]
+pub enum Chapter {
+    Table,
+    Id,
+    MusicId,
+    ChapterNo,
+    Title,
+}
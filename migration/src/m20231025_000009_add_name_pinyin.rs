@@ -0,0 +1,56 @@
+// m20231025_000009_add_name_pinyin.rs
+
+use sea_orm_migration::prelude::*;
+
+use crate::{m20230917_000002_create_author::Author, m20230917_000003_create_music_table::Music};
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20231025_000009_add_name_pinyin" // Make sure this matches with the file name
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    // Define how to apply this migration: add the pinyin of the book and author names. The server
+    // fills them in for the existing rows when it starts.
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for table in [Music::Table.into_iden(), Author::Table.into_iden()] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(table)
+                        .add_column(ColumnDef::new(NamePinyin::NamePinyin).string().null())
+                        .add_column(ColumnDef::new(NamePinyin::NameInitials).string().null())
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    // Define how to rollback this migration: drop the pinyin columns.
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        for table in [Music::Table.into_iden(), Author::Table.into_iden()] {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(table)
+                        .drop_column(NamePinyin::NamePinyin)
+                        .drop_column(NamePinyin::NameInitials)
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+// For ease of access
+#[derive(Iden)]
+pub enum NamePinyin {
+    NamePinyin,
+    NameInitials,
+}
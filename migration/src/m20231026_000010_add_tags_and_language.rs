@@ -0,0 +1,110 @@
+// m20231026_000010_add_tags_and_language.rs
+
+use sea_orm_migration::prelude::*;
+
+use crate::m20230917_000003_create_music_table::Music;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20231026_000010_add_tags_and_language" // Make sure this matches with the file name
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    // Define how to apply this migration: add the language of a book, the Tag table and the
+    // MusicTag table linking books to their tags.
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Music::Table)
+                    .add_column(ColumnDef::new(Language::Language).string().null())
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_table(
+                Table::create()
+                    .table(Tag::Table)
+                    .col(
+                        ColumnDef::new(Tag::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(Tag::Name).string().not_null().unique_key())
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_table(
+                Table::create()
+                    .table(MusicTag::Table)
+                    .col(ColumnDef::new(MusicTag::MusicId).integer().not_null())
+                    .col(ColumnDef::new(MusicTag::TagId).integer().not_null())
+                    .primary_key(
+                        Index::create()
+                            .col(MusicTag::MusicId)
+                            .col(MusicTag::TagId),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-MusicTag-MusicId")
+                            .from(MusicTag::Table, MusicTag::MusicId)
+                            .to(Music::Table, Music::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-MusicTag-TagId")
+                            .from(MusicTag::Table, MusicTag::TagId)
+                            .to(Tag::Table, Tag::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    // Define how to rollback this migration: drop the tag tables and the language column.
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(MusicTag::Table).to_owned())
+            .await?;
+        manager
+            .drop_table(Table::drop().table(Tag::Table).to_owned())
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Music::Table)
+                    .drop_column(Language::Language)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+// For ease of access
+#[derive(Iden)]
+pub enum Language {
+    Language,
+}
+
+#[derive(Iden)]
+pub enum Tag {
+    Table,
+    Id,
+    Name,
+}
+
+#[derive(Iden)]
+pub enum MusicTag {
+    Table,
+    MusicId,
+    TagId,
+}
@@ -0,0 +1,51 @@
+// m20231027_000011_add_subsonic_password.rs
+
+use sea_orm_migration::prelude::*;
+
+use crate::m20230917_000001_create_account_table::Account;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20231027_000011_add_subsonic_password" // Make sure this matches with the file name
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    // Define how to apply this migration: add the password subsonic clients log in with. The
+    // token scheme of subsonic needs it in clear, so it's separate from the account password.
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Account::Table)
+                    .add_column(
+                        ColumnDef::new(SubsonicPassword::SubsonicPassword)
+                            .string()
+                            .null(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    // Define how to rollback this migration: drop the subsonic password.
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Account::Table)
+                    .drop_column(SubsonicPassword::SubsonicPassword)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+// For ease of access
+#[derive(Iden)]
+pub enum SubsonicPassword {
+    SubsonicPassword,
+}
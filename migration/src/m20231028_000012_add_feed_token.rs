@@ -0,0 +1,52 @@
+// m20231028_000012_add_feed_token.rs
+
+use sea_orm_migration::prelude::*;
+
+use crate::m20230917_000001_create_account_table::Account;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20231028_000012_add_feed_token" // Make sure this matches with the file name
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    // Define how to apply this migration: add the token in the urls of the podcast feeds of an
+    // account, podcast clients can't log in with the cookie.
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Account::Table)
+                    .add_column(
+                        ColumnDef::new(FeedToken::FeedToken)
+                            .string()
+                            .null()
+                            .unique_key(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    // Define how to rollback this migration: drop the feed token.
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Account::Table)
+                    .drop_column(FeedToken::FeedToken)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+// For ease of access
+#[derive(Iden)]
+pub enum FeedToken {
+    FeedToken,
+}
@@ -0,0 +1,47 @@
+// m20231030_000014_add_chapter_duration.rs
+
+use sea_orm_migration::prelude::*;
+
+use crate::m20231024_000008_add_book_metadata::Chapter;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20231030_000014_add_chapter_duration" // Make sure this matches with the file name
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    // Define how to apply this migration: add the duration of each chapter in seconds, null until
+    // it's computed.
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Chapter::Table)
+                    .add_column(ColumnDef::new(ChapterDuration::Duration).double().null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    // Define how to rollback this migration: drop the chapter duration.
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Chapter::Table)
+                    .drop_column(ChapterDuration::Duration)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+// For ease of access
+#[derive(Iden)]
+pub enum ChapterDuration {
+    Duration,
+}
@@ -0,0 +1,121 @@
+// m20231031_000015_create_listening_session.rs
+
+use sea_orm_migration::prelude::*;
+
+use crate::{
+    m20230917_000001_create_account_table::Account, m20230917_000003_create_music_table::Music,
+};
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20231031_000015_create_listening_session" // Make sure this matches with the file name
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    // Define how to apply this migration: create the ListeningSession table, a log of who listened
+    // to what, from where to where and when.
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(ListeningSession::Table)
+                    .col(
+                        ColumnDef::new(ListeningSession::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(ListeningSession::AccountId)
+                            .integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(ListeningSession::MusicId)
+                            .integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(ListeningSession::StartChapterNo)
+                            .integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(ListeningSession::StartPosition)
+                            .double()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(ListeningSession::EndChapterNo)
+                            .integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(ListeningSession::EndPosition)
+                            .double()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(ListeningSession::StartedAt)
+                            .date_time()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(ListeningSession::EndedAt)
+                            .date_time()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(ListeningSession::Device).string().null())
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-ListeningSession-AccountId")
+                            .from(ListeningSession::Table, ListeningSession::AccountId)
+                            .to(Account::Table, Account::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .foreign_key(
+                        ForeignKey::create()
+                            .name("fk-ListeningSession-MusicId")
+                            .from(ListeningSession::Table, ListeningSession::MusicId)
+                            .to(Music::Table, Music::Id)
+                            .on_delete(ForeignKeyAction::Cascade),
+                    )
+                    .index(
+                        Index::create()
+                            .name("idx_listening_session_account_ended")
+                            .col(ListeningSession::AccountId)
+                            .col(ListeningSession::EndedAt),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    // Define how to rollback this migration: drop the ListeningSession table.
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(ListeningSession::Table).to_owned())
+            .await
+    }
+}
+
+// For ease of access
+#[derive(Iden)]
+pub enum ListeningSession {
+    Table,
+    Id,
+    AccountId,
+    MusicId,
+    StartChapterNo,
+    StartPosition,
+    EndChapterNo,
+    EndPosition,
+    StartedAt,
+    EndedAt,
+    Device,
+}
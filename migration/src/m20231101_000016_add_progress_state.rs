@@ -0,0 +1,119 @@
+// m20231101_000016_add_progress_state.rs
+
+use sea_orm_migration::prelude::*;
+
+use crate::{
+    m20230917_000003_create_music_table::Music, m20230917_000004_create_progress_table::Progress,
+    m20231023_000007_add_timestamps::Timestamps, m20231024_000008_add_book_metadata::Chapter,
+    m20231030_000014_add_chapter_duration::ChapterDuration,
+};
+
+/// a progress closer than this to the end of the book is finished, in seconds, like the server
+const FINISH_MARGIN: f64 = 30.;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20231101_000016_add_progress_state" // Make sure this matches with the file name
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    // Define how to apply this migration: add the listening state of a progress, when it was last
+    // finished, how many times it was finished and listened again. The progress within
+    // FINISH_MARGIN of the end of the last chapter, whose duration is known, counts as finished
+    // once, on its last play. Any other progress stays in progress.
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Progress::Table)
+                    .add_column(
+                        ColumnDef::new(ProgressState::State)
+                            .string_len(16)
+                            .not_null()
+                            .default("in_progress"),
+                    )
+                    .add_column(ColumnDef::new(ProgressState::FinishedAt).date_time().null())
+                    .add_column(
+                        ColumnDef::new(ProgressState::FinishCount)
+                            .integer()
+                            .not_null()
+                            .default(0),
+                    )
+                    .add_column(
+                        ColumnDef::new(ProgressState::RelistenCount)
+                            .integer()
+                            .not_null()
+                            .default(0),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        let finished = Query::select()
+            .column((Chapter::Table, Chapter::Id))
+            .from(Chapter::Table)
+            .inner_join(
+                Music::Table,
+                Expr::col((Music::Table, Music::Id)).equals((Chapter::Table, Chapter::MusicId)),
+            )
+            .and_where(
+                Expr::col((Chapter::Table, Chapter::MusicId))
+                    .equals((Progress::Table, Progress::MusicId)),
+            )
+            .and_where(
+                Expr::col((Chapter::Table, Chapter::ChapterNo))
+                    .equals((Progress::Table, Progress::ChapterNo)),
+            )
+            .and_where(
+                Expr::col((Chapter::Table, Chapter::ChapterNo))
+                    .equals((Music::Table, Music::Chapters)),
+            )
+            .and_where(Expr::col((Chapter::Table, ChapterDuration::Duration)).is_not_null())
+            .and_where(
+                Expr::col((Progress::Table, Progress::Progress))
+                    .gte(Expr::col((Chapter::Table, ChapterDuration::Duration)).sub(FINISH_MARGIN)),
+            )
+            .to_owned();
+        manager
+            .exec_stmt(
+                Query::update()
+                    .table(Progress::Table)
+                    .value(ProgressState::State, "finished")
+                    .value(
+                        ProgressState::FinishedAt,
+                        Expr::col((Progress::Table, Timestamps::LastPlayedAt)),
+                    )
+                    .value(ProgressState::FinishCount, 1)
+                    .and_where(Expr::exists(finished))
+                    .to_owned(),
+            )
+            .await
+    }
+
+    // Define how to rollback this migration: drop the listening state.
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Progress::Table)
+                    .drop_column(ProgressState::State)
+                    .drop_column(ProgressState::FinishedAt)
+                    .drop_column(ProgressState::FinishCount)
+                    .drop_column(ProgressState::RelistenCount)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+// For ease of access
+#[derive(Iden)]
+pub enum ProgressState {
+    State,
+    FinishedAt,
+    FinishCount,
+    RelistenCount,
+}
@@ -0,0 +1,619 @@
+//! the versioned json api, mounted at `/api/v1`
+//!
+//! every list is paginated with `page` (from 0) and `page_size`, every error is an
+//! [`AppError`](crate::error::AppError) and every route but the login and the openapi document
+//! needs the session cookie set by `/api/v1/login`. the description of the routes is served at
+//! `/api/v1/openapi.json`.
+//!
+//! the older `/music`, `/progress` and `/account` routes are kept as deprecated aliases.
+
+use std::path::PathBuf;
+
+use axum::{
+    extract::{Path, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+    Json, Router,
+};
+use hyper::{header, HeaderMap, Request, StatusCode};
+use tower_cookies::Cookies;
+use tracing::debug;
+
+use crate::{
+    auth::{self, AccountResponse, Credentials, UserInfo},
+    entities::{sea_orm_active_enums::ProgressState, *},
+    error::{AppError, AppJson, AppQuery, AppResult},
+    middleware::LoginInfo,
+    music::{
+        download, filter::BookFilter, publication, AuthorResponse, BookResponse, Listing, Paged,
+        SearchHit,
+    },
+    progress::{
+        history::{self, HistoryEntry},
+        stats::{self, ListeningStats, ServerStats},
+        BookWithProgress, ProgressResponse,
+    },
+    tools,
+    transcode::{hls, Codec, Target},
+    AppStat,
+};
+
+pub(crate) mod openapi;
+
+pub(crate) fn route(state: AppStat) -> Router<AppStat> {
+    let user = Router::new()
+        .route("/me", get(get_session))
+        .route("/me/subsonic-password", post(reset_subsonic_password))
+        .route("/me/feed-token", post(reset_feed_token))
+        .route("/me/history", get(get_history))
+        .route("/me/stats", get(get_stats))
+        .route("/books", get(list_books))
+        .route("/books/:id", get(get_book))
+        .route("/books/:id/feed", get(get_feed))
+        .route("/books/:id/manifest.json", get(get_manifest))
+        .route("/books/:id/chapters/:no/stream", get(stream_chapter))
+        .route("/books/:id/hls/index.m3u8", get(hls_playlist))
+        .route("/books/:id/hls/:segment", get(hls_segment))
+        .route("/books/:id/download.zip", get(download_zip))
+        .route("/books/:id/download.m4b", get(download_m4b))
+        .route("/books/:id/progress", get(get_progress).put(put_progress))
+        .route("/books/:id/state", put(put_state))
+        .route("/authors", get(list_authors))
+        .route("/authors/:id", get(get_author))
+        .route("/search", get(search))
+        .route("/opds", get(opds_root))
+        .route("/opds/books", get(opds_books))
+        .route("/opds/authors", get(opds_authors))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            crate::middleware::user_auth::user_auth,
+        ));
+    let admin = Router::new()
+        .route("/accounts", get(list_accounts).post(create_account))
+        .route("/accounts/:id/download", put(set_download_permission))
+        .route("/stats", get(get_server_stats))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state,
+            crate::middleware::admin_auth::admin_auth,
+        ));
+    Router::new()
+        .merge(user)
+        .merge(admin)
+        .route("/login", post(login))
+        .route("/logout", post(logout))
+        .route("/openapi.json", get(openapi_document))
+        .route_layer(axum::middleware::from_fn(
+            crate::middleware::log_system::log_sys,
+        ))
+}
+
+/// mark the response of a route replaced by `/api/v1` as deprecated
+pub(crate) async fn deprecated<B>(request: Request<B>, next: Next<B>) -> Response {
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.insert("Deprecation", header::HeaderValue::from_static("true"));
+    headers.insert(
+        header::LINK,
+        header::HeaderValue::from_static("</api/v1/openapi.json>; rel=\"successor-version\""),
+    );
+    response
+}
+
+async fn openapi_document() -> Json<serde_json::Value> {
+    Json(openapi::document())
+}
+
+/// one page of a list, with the position of the page
+#[derive(Debug, serde::Serialize)]
+struct Page<T> {
+    page: u64,
+    page_size: u64,
+    #[serde(flatten)]
+    paged: Paged<T>,
+}
+
+impl<T> Page<T> {
+    fn new(listing: &Listing, paged: Paged<T>) -> Self {
+        Self {
+            page: listing.page,
+            page_size: listing.page_size,
+            paged,
+        }
+    }
+}
+
+/// the listing with the page size in the allowed range
+fn clamp(mut listing: Listing) -> Listing {
+    listing.page_size = listing.clamped_page_size();
+    listing
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct NameQuery {
+    /// matched against the name, its pinyin and its initials
+    q: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct SearchQuery {
+    q: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct LoginBody {
+    username: String,
+    password: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Session {
+    user_id: i32,
+    user_name: String,
+    role_level: i32,
+}
+
+impl From<LoginInfo> for Session {
+    fn from(login: LoginInfo) -> Self {
+        Self {
+            user_id: login.user_id,
+            user_name: login.user_name,
+            role_level: login.role_level,
+        }
+    }
+}
+
+async fn login(
+    State(state): State<AppStat>,
+    cookies: Cookies,
+    AppJson(body): AppJson<LoginBody>,
+) -> AppResult<Json<Session>> {
+    debug!("login: {}", body.username);
+    match auth::check_credentials(&state, &body.username, &body.password).await? {
+        Credentials::Valid(user) => {
+            let login = auth::start_session(&state, &cookies, user).await?;
+            Ok(Json(login.into()))
+        }
+        // don't tell which accounts exist
+        Credentials::WrongPassword | Credentials::NoSuchUser => {
+            Err(AppError::unauthorized("wrong username or password"))
+        }
+    }
+}
+
+async fn get_session(login: LoginInfo) -> Json<Session> {
+    Json(login.into())
+}
+
+/// the password for subsonic clients, shown once
+async fn reset_subsonic_password(
+    State(state): State<AppStat>,
+    login: LoginInfo,
+) -> AppResult<Json<serde_json::Value>> {
+    let password = auth::reset_subsonic_password(&state, login.user_id).await?;
+    Ok(Json(serde_json::json!({ "password": password })))
+}
+
+/// a new token for the podcast feeds, the feeds subscribed before stop working
+async fn reset_feed_token(
+    State(state): State<AppStat>,
+    login: LoginInfo,
+) -> AppResult<Json<serde_json::Value>> {
+    let token = crate::feed::reset_feed_token(&state.connections.db, login.user_id).await?;
+    Ok(Json(serde_json::json!({ "token": token })))
+}
+
+async fn logout(State(state): State<AppStat>, cookies: Cookies) -> AppResult<StatusCode> {
+    auth::end_session(&state, &cookies).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_accounts(State(state): State<AppStat>) -> AppResult<Json<Vec<AccountResponse>>> {
+    Ok(Json(auth::list_accounts(&state).await?))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct DownloadPermission {
+    allowed: bool,
+}
+
+async fn set_download_permission(
+    State(state): State<AppStat>,
+    Path(id): Path<i32>,
+    AppJson(body): AppJson<DownloadPermission>,
+) -> AppResult<StatusCode> {
+    auth::set_download_permission(&state, id, body.allowed).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn create_account(
+    State(state): State<AppStat>,
+    AppJson(user_info): AppJson<UserInfo>,
+) -> AppResult<Response> {
+    if user_info.username.is_empty() || user_info.password.is_empty() {
+        return Err(AppError::invalid_input(
+            "username and password can't be empty",
+        ));
+    }
+    let id = auth::insert_account(&state, user_info).await?;
+    Ok((
+        StatusCode::CREATED,
+        Json(serde_json::json!({ "user_id": id })),
+    )
+        .into_response())
+}
+
+async fn list_books(
+    State(state): State<AppStat>,
+    login: LoginInfo,
+    AppQuery(name): AppQuery<NameQuery>,
+    AppQuery(filter): AppQuery<BookFilter>,
+    AppQuery(listing): AppQuery<Listing>,
+) -> AppResult<Json<Page<BookWithProgress>>> {
+    let listing = clamp(listing);
+    let books =
+        crate::music::find_books(&state, login.user_id, name.q.as_deref(), &filter, &listing)
+            .await?;
+    let items =
+        crate::progress::books_with_progress(&state.connections.db, login.user_id, books.items)
+            .await?;
+    let books = Paged {
+        items,
+        total_items: books.total_items,
+        total_pages: books.total_pages,
+        facets: books.facets,
+    };
+    Ok(Json(Page::new(&listing, books)))
+}
+
+async fn get_book(
+    State(state): State<AppStat>,
+    Path(id): Path<i32>,
+) -> AppResult<Json<BookResponse>> {
+    Ok(Json(crate::music::find_book(&state, id).await?.into()))
+}
+
+/// the url of the podcast feed of a book for the current user
+async fn get_feed(
+    State(state): State<AppStat>,
+    login: LoginInfo,
+    Path(id): Path<i32>,
+    headers: HeaderMap,
+) -> AppResult<Json<serde_json::Value>> {
+    auth::check_download_permission(&state, login.user_id).await?;
+    let book = crate::music::find_book(&state, id).await?;
+    let token = crate::feed::feed_token(&state.connections.db, login.user_id).await?;
+    let url = format!(
+        "{}{}",
+        crate::feed::base_url(&headers),
+        crate::feed::feed_path(&token, book.id)
+    );
+    Ok(Json(serde_json::json!({ "url": url })))
+}
+
+/// the readium manifest of a book, for the audiobook readers
+async fn get_manifest(State(state): State<AppStat>, Path(id): Path<i32>) -> AppResult<Response> {
+    let manifest = publication::book_manifest(&state, id, &publication::manifest_path(id)).await?;
+    Ok((
+        [(header::CONTENT_TYPE, publication::MANIFEST_TYPE)],
+        Json(manifest),
+    )
+        .into_response())
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct StreamQuery {
+    #[serde(default)]
+    codec: Codec,
+    /// kbps
+    bitrate: Option<u32>,
+    /// seconds into the chapter
+    start: Option<f64>,
+}
+
+/// a chapter transcoded by ffmpeg, for a slow connection
+async fn stream_chapter(
+    State(state): State<AppStat>,
+    Path((id, chapter_no)): Path<(i32, i32)>,
+    AppQuery(query): AppQuery<StreamQuery>,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    let book = crate::music::find_book(&state, id).await?;
+    let source = chapter_files(&state, &book)?
+        .into_iter()
+        .find(|(no, _)| *no == chapter_no)
+        .map(|(_, path)| path)
+        .ok_or_else(|| AppError::not_found(format!("chapter {} not found", chapter_no)))?;
+    let target = Target::new(query.codec, query.bitrate, query.start);
+    state.transcoder.respond(source, target, headers).await
+}
+
+fn chapter_files(state: &AppStat, book: &music::Model) -> AppResult<Vec<(i32, PathBuf)>> {
+    tools::chapter_files(state.book_dir.join(&book.file_folder)).map_err(AppError::internal)
+}
+
+/// the chapter files of a book with their durations, every duration is needed to lay out the
+/// segments
+async fn timed_chapters(
+    state: &AppStat,
+    book: &music::Model,
+) -> AppResult<Vec<(publication::ChapterFile, f64)>> {
+    publication::chapter_files(state, book)
+        .await?
+        .into_iter()
+        .map(|file| {
+            let duration = file.track.duration.ok_or_else(|| {
+                AppError::internal(format!(
+                    "the duration of chapter {} is unknown",
+                    file.chapter_no
+                ))
+            })?;
+            Ok((file, duration))
+        })
+        .collect()
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct HlsQuery {
+    /// kbps
+    bitrate: Option<u32>,
+}
+
+/// the whole book as one hls playlist
+async fn hls_playlist(
+    State(state): State<AppStat>,
+    Path(id): Path<i32>,
+    AppQuery(query): AppQuery<HlsQuery>,
+) -> AppResult<Response> {
+    let book = crate::music::find_book(&state, id).await?;
+    let chapters = timed_chapters(&state, &book)
+        .await?
+        .into_iter()
+        .map(|(file, duration)| hls::PlaylistChapter {
+            chapter_no: file.chapter_no,
+            title: file.track.title,
+            duration,
+        })
+        .collect::<Vec<_>>();
+    Ok((
+        [(header::CONTENT_TYPE, hls::PLAYLIST_TYPE)],
+        hls::playlist(&chapters, query.bitrate),
+    )
+        .into_response())
+}
+
+/// a segment of the hls playlist, transcoded on first use
+async fn hls_segment(
+    State(state): State<AppStat>,
+    Path((id, name)): Path<(i32, String)>,
+    AppQuery(query): AppQuery<HlsQuery>,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    let book = crate::music::find_book(&state, id).await?;
+    let chapters = timed_chapters(&state, &book).await?;
+    let durations = chapters
+        .iter()
+        .map(|(file, duration)| (file.chapter_no, *duration))
+        .collect::<Vec<_>>();
+    let not_found = || AppError::not_found(format!("segment {} not found", name));
+    let segment = hls::find_segment(&durations, &name, query.bitrate).ok_or_else(not_found)?;
+    let source = chapters
+        .iter()
+        .find(|(file, _)| file.chapter_no == segment.chapter_no)
+        .map(|(file, _)| &file.path)
+        .ok_or_else(not_found)?;
+    let path = state.transcoder.segment(source, &segment).await?;
+    let mut response = crate::serve_file(path, headers).await;
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static(hls::SEGMENT_TYPE),
+    );
+    Ok(response)
+}
+
+/// the chapters, a playlist, the cover and the manifest of a book in a zip
+async fn download_zip(
+    State(state): State<AppStat>,
+    login: LoginInfo,
+    Path(id): Path<i32>,
+) -> AppResult<Response> {
+    auth::check_download_permission(&state, login.user_id).await?;
+    download::book_zip(&state, id).await
+}
+
+/// the chapters of a book merged into an m4b with chapter markers
+async fn download_m4b(
+    State(state): State<AppStat>,
+    login: LoginInfo,
+    Path(id): Path<i32>,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    auth::check_download_permission(&state, login.user_id).await?;
+    download::book_m4b(&state, id, headers).await
+}
+
+fn opds(feed: serde_json::Value) -> Response {
+    ([(header::CONTENT_TYPE, publication::OPDS_TYPE)], Json(feed)).into_response()
+}
+
+async fn opds_root() -> Response {
+    opds(publication::opds_root())
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct OpdsQuery {
+    /// the search template of opds fills `query`
+    query: Option<String>,
+}
+
+async fn opds_books(
+    State(state): State<AppStat>,
+    login: LoginInfo,
+    AppQuery(query): AppQuery<OpdsQuery>,
+    AppQuery(filter): AppQuery<BookFilter>,
+    AppQuery(listing): AppQuery<Listing>,
+) -> AppResult<Response> {
+    let listing = clamp(listing);
+    let query = query.query.as_deref();
+    let books = crate::music::find_books(&state, login.user_id, query, &filter, &listing).await?;
+    let feed = publication::opds_books(&state, query, &filter, &listing, books).await?;
+    Ok(opds(feed))
+}
+
+async fn opds_authors(
+    State(state): State<AppStat>,
+    login: LoginInfo,
+    AppQuery(listing): AppQuery<Listing>,
+) -> AppResult<Response> {
+    let listing = clamp(listing);
+    let authors = crate::music::find_authors(&state, login.user_id, None, &listing).await?;
+    Ok(opds(publication::opds_authors(&listing, authors)))
+}
+
+async fn list_authors(
+    State(state): State<AppStat>,
+    login: LoginInfo,
+    AppQuery(name): AppQuery<NameQuery>,
+    AppQuery(listing): AppQuery<Listing>,
+) -> AppResult<Json<Page<AuthorResponse>>> {
+    let listing = clamp(listing);
+    let authors =
+        crate::music::find_authors(&state, login.user_id, name.q.as_deref(), &listing).await?;
+    Ok(Json(Page::new(&listing, authors.map(Into::into))))
+}
+
+async fn get_author(
+    State(state): State<AppStat>,
+    Path(id): Path<i32>,
+) -> AppResult<Json<AuthorResponse>> {
+    Ok(Json(crate::music::find_author(&state, id).await?.into()))
+}
+
+async fn search(
+    State(state): State<AppStat>,
+    login: LoginInfo,
+    AppQuery(query): AppQuery<SearchQuery>,
+    AppQuery(filter): AppQuery<BookFilter>,
+    AppQuery(listing): AppQuery<Listing>,
+) -> AppResult<Json<Page<SearchHit>>> {
+    let listing = clamp(listing);
+    let hits =
+        crate::music::full_text_search(&state, login.user_id, &query.q, &filter, &listing).await?;
+    Ok(Json(Page::new(&listing, hits)))
+}
+
+async fn get_progress(
+    State(state): State<AppStat>,
+    login: LoginInfo,
+    Path(id): Path<i32>,
+) -> AppResult<Json<ProgressResponse>> {
+    let book = crate::music::find_book(&state, id).await?;
+    let db = &state.connections.db;
+    let progress = crate::progress::find_progress(db, login.user_id, id).await?;
+    Ok(Json(
+        crate::progress::with_book_progress(db, &book, progress).await?,
+    ))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct HistoryQuery {
+    /// only the sessions on this book
+    book_id: Option<i32>,
+}
+
+/// the listening sessions of the current user, the latest first
+async fn get_history(
+    State(state): State<AppStat>,
+    login: LoginInfo,
+    AppQuery(query): AppQuery<HistoryQuery>,
+    AppQuery(listing): AppQuery<Listing>,
+) -> AppResult<Json<Page<HistoryEntry>>> {
+    let listing = clamp(listing);
+    let sessions = history::history(
+        &state.connections.db,
+        login.user_id,
+        query.book_id,
+        listing.page,
+        listing.page_size,
+    )
+    .await?;
+    Ok(Json(Page::new(&listing, sessions)))
+}
+
+/// the listening stats of the current user
+async fn get_stats(
+    State(state): State<AppStat>,
+    login: LoginInfo,
+) -> AppResult<Json<ListeningStats>> {
+    Ok(Json(
+        stats::user_stats(&state.connections.db, login.user_id).await?,
+    ))
+}
+
+/// the listening stats of the whole server
+async fn get_server_stats(State(state): State<AppStat>) -> AppResult<Json<ServerStats>> {
+    Ok(Json(stats::server_stats(&state.connections.db).await?))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ProgressBody {
+    chapter_no: i32,
+    /// seconds into the chapter
+    progress: f64,
+    /// the name of the device in the listening history, the user agent when missing
+    device: Option<String>,
+}
+
+async fn put_progress(
+    State(state): State<AppStat>,
+    login: LoginInfo,
+    Path(id): Path<i32>,
+    headers: HeaderMap,
+    AppJson(body): AppJson<ProgressBody>,
+) -> AppResult<Json<ProgressResponse>> {
+    let book = crate::music::find_book(&state, id).await?;
+    if body.chapter_no < 0
+        || body.chapter_no > book.chapters
+        || !body.progress.is_finite()
+        || body.progress < 0.
+    {
+        return Err(AppError::invalid_input(format!(
+            "the chapter must be between 0 and {} and the progress positive",
+            book.chapters
+        )));
+    }
+    let progress = crate::progress::set_position(
+        &state.connections.db,
+        login.user_id,
+        &book,
+        body.chapter_no,
+        body.progress,
+        body.device
+            .as_deref()
+            .or_else(|| history::user_agent(&headers)),
+    )
+    .await?;
+    Ok(Json(
+        crate::progress::with_book_progress(&state.connections.db, &book, progress).await?,
+    ))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct StateBody {
+    state: ProgressState,
+}
+
+/// mark a book finished, abandoned, wanted or back in progress for the current user
+async fn put_state(
+    State(state): State<AppStat>,
+    login: LoginInfo,
+    Path(id): Path<i32>,
+    AppJson(body): AppJson<StateBody>,
+) -> AppResult<Json<ProgressResponse>> {
+    let book = crate::music::find_book(&state, id).await?;
+    let progress =
+        crate::progress::set_state(&state.connections.db, login.user_id, id, body.state).await?;
+    Ok(Json(
+        crate::progress::with_book_progress(&state.connections.db, &book, progress).await?,
+    ))
+}
@@ -0,0 +1,1106 @@
+//! the openapi 3 description of `/api/v1`, built from the table of [`OPERATIONS`]
+//!
+//! a route added to [`super::route`] needs its line in the table, the tests check the table
+//! lists the routes of the router and every schema the table refers to is defined.
+
+use serde_json::{json, Map, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Public,
+    User,
+    /// a user allowed to download whole books
+    Download,
+    Admin,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Location {
+    Path,
+    Query,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Param {
+    name: &'static str,
+    location: Location,
+    /// the json schema type
+    kind: &'static str,
+    required: bool,
+    description: &'static str,
+}
+
+const fn path(name: &'static str, description: &'static str) -> Param {
+    Param {
+        name,
+        location: Location::Path,
+        kind: "integer",
+        required: true,
+        description,
+    }
+}
+
+const fn query(name: &'static str, kind: &'static str, description: &'static str) -> Param {
+    Param {
+        name,
+        location: Location::Query,
+        kind,
+        required: false,
+        description,
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Body {
+    Empty,
+    /// a component schema
+    Schema(&'static str),
+    /// an array of a component schema
+    List(&'static str),
+    /// a [`super::Page`] of a component schema
+    Page(&'static str),
+    /// a file of this media type
+    File(&'static str),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Operation {
+    method: &'static str,
+    path: &'static str,
+    tag: &'static str,
+    summary: &'static str,
+    access: Access,
+    params: &'static [&'static [Param]],
+    request: Body,
+    status: u16,
+    response: Body,
+}
+
+const LISTING: &[Param] = &[
+    query("page", "integer", "the page, from 0"),
+    query("page_size", "integer", "1 to 100, 20 by default"),
+    query(
+        "sort_by",
+        "string",
+        "Id, Name, DateAdded, DateUpdated, Author, Duration or RecentlyPlayed",
+    ),
+    query("order", "string", "Asc or Desc"),
+];
+
+const NAME: &[Param] = &[query(
+    "q",
+    "string",
+    "matched against the name, its pinyin and its initials",
+)];
+
+const FILTER: &[Param] = &[
+    query("author_id", "integer", "only the books of this author"),
+    query("narrator", "string", "only the books read by this narrator"),
+    query("tag", "string", "only the books with this tag"),
+    query("language", "string", "only the books in this language"),
+    query("series", "string", "only the books of this series"),
+    query("min_duration", "number", "the shortest duration in seconds"),
+    query("max_duration", "number", "the longest duration in seconds"),
+    query("added_after", "string", "a date like 2023-10-26"),
+    query(
+        "state",
+        "string",
+        "not_started, in_progress, finished, abandoned or want_to_listen for the current user",
+    ),
+    query("facets", "boolean", "also count the books by facet"),
+];
+
+const SEARCH: &[Param] = &[Param {
+    name: "q",
+    location: Location::Query,
+    kind: "string",
+    required: true,
+    description: "the words to search in the titles, names, descriptions and chapter titles",
+}];
+
+const OPDS_QUERY: &[Param] = &[query(
+    "query",
+    "string",
+    "matched against the name, its pinyin and its initials",
+)];
+
+const HISTORY: &[Param] = &[
+    query("book_id", "integer", "only the sessions on this book"),
+    query("page", "integer", "the page, from 0"),
+    query("page_size", "integer", "1 to 100, 20 by default"),
+];
+
+const BOOK_ID: &[Param] = &[path("id", "the book id")];
+const CHAPTER_NO: &[Param] = &[path("no", "the chapter number, from 1")];
+
+const SEGMENT: &[Param] = &[Param {
+    name: "segment",
+    location: Location::Path,
+    kind: "string",
+    required: true,
+    description: "like 3-12.ts, as listed in the playlist",
+}];
+
+const HLS: &[Param] = &[query("bitrate", "integer", "16 to 320 kbps, 64 by default")];
+
+const STREAM: &[Param] = &[
+    query("codec", "string", "opus, aac or mp3, opus by default"),
+    query("bitrate", "integer", "16 to 320 kbps, 64 by default"),
+    query(
+        "start",
+        "number",
+        "the seconds into the chapter to start at, not cached",
+    ),
+];
+const AUTHOR_ID: &[Param] = &[path("id", "the author id")];
+const ACCOUNT_ID: &[Param] = &[path("id", "the account id")];
+
+const OPERATIONS: &[Operation] = &[
+    Operation {
+        method: "post",
+        path: "/login",
+        tag: "session",
+        summary: "log in and set the session cookie",
+        access: Access::Public,
+        params: &[],
+        request: Body::Schema("Credentials"),
+        status: 200,
+        response: Body::Schema("Session"),
+    },
+    Operation {
+        method: "post",
+        path: "/logout",
+        tag: "session",
+        summary: "end the session",
+        access: Access::Public,
+        params: &[],
+        request: Body::Empty,
+        status: 204,
+        response: Body::Empty,
+    },
+    Operation {
+        method: "get",
+        path: "/openapi.json",
+        tag: "session",
+        summary: "this document",
+        access: Access::Public,
+        params: &[],
+        request: Body::Empty,
+        status: 200,
+        response: Body::File("application/json"),
+    },
+    Operation {
+        method: "get",
+        path: "/me",
+        tag: "session",
+        summary: "the logged in user",
+        access: Access::User,
+        params: &[],
+        request: Body::Empty,
+        status: 200,
+        response: Body::Schema("Session"),
+    },
+    Operation {
+        method: "post",
+        path: "/me/subsonic-password",
+        tag: "session",
+        summary: "replace the password of the subsonic clients at /rest, returned once",
+        access: Access::User,
+        params: &[],
+        request: Body::Empty,
+        status: 200,
+        response: Body::Schema("SubsonicPassword"),
+    },
+    Operation {
+        method: "post",
+        path: "/me/feed-token",
+        tag: "session",
+        summary: "replace the token of the podcast feeds, the feeds subscribed before stop working",
+        access: Access::User,
+        params: &[],
+        request: Body::Empty,
+        status: 200,
+        response: Body::Schema("FeedToken"),
+    },
+    Operation {
+        method: "get",
+        path: "/me/history",
+        tag: "session",
+        summary: "the listening sessions of the current user, the latest first",
+        access: Access::User,
+        params: &[HISTORY],
+        request: Body::Empty,
+        status: 200,
+        response: Body::Page("HistoryEntry"),
+    },
+    Operation {
+        method: "get",
+        path: "/me/stats",
+        tag: "session",
+        summary: "the listening stats of the current user",
+        access: Access::User,
+        params: &[],
+        request: Body::Empty,
+        status: 200,
+        response: Body::Schema("ListeningStats"),
+    },
+    Operation {
+        method: "get",
+        path: "/accounts",
+        tag: "accounts",
+        summary: "list the accounts",
+        access: Access::Admin,
+        params: &[],
+        request: Body::Empty,
+        status: 200,
+        response: Body::List("Account"),
+    },
+    Operation {
+        method: "post",
+        path: "/accounts",
+        tag: "accounts",
+        summary: "create an account",
+        access: Access::Admin,
+        params: &[],
+        request: Body::Schema("NewAccount"),
+        status: 201,
+        response: Body::Schema("CreatedAccount"),
+    },
+    Operation {
+        method: "put",
+        path: "/accounts/{id}/download",
+        tag: "accounts",
+        summary: "allow or forbid the account to download whole books",
+        access: Access::Admin,
+        params: &[ACCOUNT_ID],
+        request: Body::Schema("DownloadPermission"),
+        status: 204,
+        response: Body::Empty,
+    },
+    Operation {
+        method: "get",
+        path: "/stats",
+        tag: "accounts",
+        summary: "the listening stats of the whole server, with the active listeners and the most played books",
+        access: Access::Admin,
+        params: &[],
+        request: Body::Empty,
+        status: 200,
+        response: Body::Schema("ServerStats"),
+    },
+    Operation {
+        method: "get",
+        path: "/books",
+        tag: "books",
+        summary: "list the books, with how far the user is in each",
+        access: Access::User,
+        params: &[NAME, LISTING, FILTER],
+        request: Body::Empty,
+        status: 200,
+        response: Body::Page("BookWithProgress"),
+    },
+    Operation {
+        method: "get",
+        path: "/books/{id}",
+        tag: "books",
+        summary: "get a book",
+        access: Access::User,
+        params: &[BOOK_ID],
+        request: Body::Empty,
+        status: 200,
+        response: Body::Schema("Book"),
+    },
+    Operation {
+        method: "get",
+        path: "/books/{id}/feed",
+        tag: "books",
+        summary: "the url of the private podcast feed of a book",
+        access: Access::Download,
+        params: &[BOOK_ID],
+        request: Body::Empty,
+        status: 200,
+        response: Body::Schema("Feed"),
+    },
+    Operation {
+        method: "get",
+        path: "/books/{id}/manifest.json",
+        tag: "publications",
+        summary: "the readium web publication manifest of a book, audiobook profile",
+        access: Access::User,
+        params: &[BOOK_ID],
+        request: Body::Empty,
+        status: 200,
+        response: Body::Schema("Manifest"),
+    },
+    Operation {
+        method: "get",
+        path: "/books/{id}/chapters/{no}/stream",
+        tag: "books",
+        summary: "a chapter transcoded by ffmpeg, cached on the server when it starts at 0",
+        access: Access::User,
+        params: &[BOOK_ID, CHAPTER_NO, STREAM],
+        request: Body::Empty,
+        status: 200,
+        response: Body::File("audio/*"),
+    },
+    Operation {
+        method: "get",
+        path: "/books/{id}/hls/index.m3u8",
+        tag: "books",
+        summary: "the whole book as one hls playlist, the titles of the segments mark the chapters",
+        access: Access::User,
+        params: &[BOOK_ID, HLS],
+        request: Body::Empty,
+        status: 200,
+        response: Body::File(crate::transcode::hls::PLAYLIST_TYPE),
+    },
+    Operation {
+        method: "get",
+        path: "/books/{id}/hls/{segment}",
+        tag: "books",
+        summary: "a segment of the hls playlist, transcoded and cached when first asked for",
+        access: Access::User,
+        params: &[BOOK_ID, SEGMENT, HLS],
+        request: Body::Empty,
+        status: 200,
+        response: Body::File(crate::transcode::hls::SEGMENT_TYPE),
+    },
+    Operation {
+        method: "get",
+        path: "/books/{id}/download.zip",
+        tag: "books",
+        summary: "the chapters, a playlist, the cover and a readium manifest in a zip",
+        access: Access::Download,
+        params: &[BOOK_ID],
+        request: Body::Empty,
+        status: 200,
+        response: Body::File("application/zip"),
+    },
+    Operation {
+        method: "get",
+        path: "/books/{id}/download.m4b",
+        tag: "books",
+        summary: "the chapters merged into an m4b with chapter markers, made on first download",
+        access: Access::Download,
+        params: &[BOOK_ID],
+        request: Body::Empty,
+        status: 200,
+        response: Body::File("audio/mp4"),
+    },
+    Operation {
+        method: "get",
+        path: "/books/{id}/progress",
+        tag: "progress",
+        summary: "the progress of the current user in a book",
+        access: Access::User,
+        params: &[BOOK_ID],
+        request: Body::Empty,
+        status: 200,
+        response: Body::Schema("Progress"),
+    },
+    Operation {
+        method: "put",
+        path: "/books/{id}/progress",
+        tag: "progress",
+        summary: "save the progress of the current user in a book",
+        access: Access::User,
+        params: &[BOOK_ID],
+        request: Body::Schema("ProgressUpdate"),
+        status: 200,
+        response: Body::Schema("Progress"),
+    },
+    Operation {
+        method: "put",
+        path: "/books/{id}/state",
+        tag: "progress",
+        summary: "mark a book finished, abandoned, wanted or back in progress, a finished book in progress again starts over",
+        access: Access::User,
+        params: &[BOOK_ID],
+        request: Body::Schema("StateUpdate"),
+        status: 200,
+        response: Body::Schema("Progress"),
+    },
+    Operation {
+        method: "get",
+        path: "/authors",
+        tag: "authors",
+        summary: "list the authors",
+        access: Access::User,
+        params: &[NAME, LISTING],
+        request: Body::Empty,
+        status: 200,
+        response: Body::Page("Author"),
+    },
+    Operation {
+        method: "get",
+        path: "/authors/{id}",
+        tag: "authors",
+        summary: "get an author",
+        access: Access::User,
+        params: &[AUTHOR_ID],
+        request: Body::Empty,
+        status: 200,
+        response: Body::Schema("Author"),
+    },
+    Operation {
+        method: "get",
+        path: "/search",
+        tag: "books",
+        summary: "full-text search of the books, ranked by relevance",
+        access: Access::User,
+        params: &[SEARCH, LISTING, FILTER],
+        request: Body::Empty,
+        status: 200,
+        response: Body::Page("SearchHit"),
+    },
+    Operation {
+        method: "get",
+        path: "/opds",
+        tag: "publications",
+        summary: "the entry point of the opds 2.0 catalog",
+        access: Access::User,
+        params: &[],
+        request: Body::Empty,
+        status: 200,
+        response: Body::Schema("OpdsFeed"),
+    },
+    Operation {
+        method: "get",
+        path: "/opds/books",
+        tag: "publications",
+        summary: "the books as an opds 2.0 feed of publications",
+        access: Access::User,
+        params: &[OPDS_QUERY, LISTING, FILTER],
+        request: Body::Empty,
+        status: 200,
+        response: Body::Schema("OpdsFeed"),
+    },
+    Operation {
+        method: "get",
+        path: "/opds/authors",
+        tag: "publications",
+        summary: "the authors as an opds 2.0 navigation feed",
+        access: Access::User,
+        params: &[LISTING],
+        request: Body::Empty,
+        status: 200,
+        response: Body::Schema("OpdsFeed"),
+    },
+];
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+fn body_schema(body: Body) -> Option<Value> {
+    match body {
+        Body::Empty => None,
+        Body::Schema(name) => Some(schema_ref(name)),
+        Body::List(name) => Some(json!({ "type": "array", "items": schema_ref(name) })),
+        Body::Page(name) => Some(json!({
+            "allOf": [
+                schema_ref("Page"),
+                {
+                    "type": "object",
+                    "properties": { "items": { "type": "array", "items": schema_ref(name) } }
+                }
+            ]
+        })),
+        Body::File(_) => Some(json!({ "type": "string", "format": "binary" })),
+    }
+}
+
+fn json_content(schema: Value) -> Value {
+    json!({ "application/json": { "schema": schema } })
+}
+
+fn error_response(description: &str) -> Value {
+    json!({ "description": description, "content": json_content(schema_ref("Error")) })
+}
+
+fn operation(op: &Operation) -> Value {
+    let params = op
+        .params
+        .iter()
+        .flat_map(|params| params.iter())
+        .map(|param| {
+            json!({
+                "name": param.name,
+                "in": match param.location {
+                    Location::Path => "path",
+                    Location::Query => "query",
+                },
+                "required": param.required,
+                "description": param.description,
+                "schema": { "type": param.kind },
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut success = json!({ "description": "success" });
+    if let Some(schema) = body_schema(op.response) {
+        let media_type = match op.response {
+            Body::Schema("Manifest") => crate::music::publication::MANIFEST_TYPE,
+            Body::Schema("OpdsFeed") => crate::music::publication::OPDS_TYPE,
+            Body::File(media_type) => media_type,
+            _ => "application/json",
+        };
+        success["content"] = json!({ media_type: { "schema": schema } });
+    }
+    let mut responses = Map::new();
+    responses.insert(op.status.to_string(), success);
+    if !op.params.is_empty() || !matches!(op.request, Body::Empty) {
+        responses.insert("422".into(), error_response("invalid parameters"));
+    }
+    if op
+        .params
+        .iter()
+        .any(|params| params.iter().any(|p| p.name == "id"))
+    {
+        responses.insert("404".into(), error_response("not found or in the trash"));
+    }
+    match op.access {
+        Access::Public if op.path == "/login" => {
+            responses.insert("401".into(), error_response("wrong username or password"));
+        }
+        Access::Public => {}
+        Access::User => {
+            responses.insert("401".into(), error_response("not logged in"));
+        }
+        Access::Download => {
+            responses.insert("401".into(), error_response("not logged in"));
+            responses.insert("403".into(), error_response("downloads are disabled"));
+        }
+        Access::Admin => {
+            responses.insert("401".into(), error_response("not logged in"));
+            responses.insert("403".into(), error_response("not an admin"));
+        }
+    }
+    if op.path == "/accounts" && op.method == "post" {
+        responses.insert("409".into(), error_response("the name is taken"));
+    }
+    responses.insert(
+        "503".into(),
+        error_response("the database or the session store is unavailable"),
+    );
+
+    let mut value = json!({
+        "tags": [op.tag],
+        "summary": op.summary,
+        "parameters": params,
+        "responses": responses,
+    });
+    if op.access == Access::Public {
+        value["security"] = json!([]);
+    }
+    if let Some(schema) = body_schema(op.request) {
+        value["requestBody"] = json!({ "required": true, "content": json_content(schema) });
+    }
+    value
+}
+
+fn nullable(kind: &str) -> Value {
+    json!({ "type": [kind, "null"] })
+}
+
+fn schemas() -> Value {
+    let datetime = json!({ "type": "string", "format": "date-time" });
+    let nullable_datetime = json!({ "type": ["string", "null"], "format": "date-time" });
+    let value_count = json!({
+        "type": "object",
+        "properties": { "value": { "type": "string" }, "count": { "type": "integer" } }
+    });
+    json!({
+        "Error": {
+            "type": "object",
+            "required": ["code", "message"],
+            "properties": {
+                "code": {
+                    "type": "string",
+                    "enum": [
+                        "not_found", "conflict", "invalid_input", "unauthorized", "forbidden",
+                        "unavailable", "internal"
+                    ]
+                },
+                "message": { "type": "string" }
+            }
+        },
+        "Page": {
+            "type": "object",
+            "required": ["page", "page_size", "items", "total_items", "total_pages"],
+            "properties": {
+                "page": { "type": "integer" },
+                "page_size": { "type": "integer" },
+                "items": { "type": "array" },
+                "total_items": { "type": "integer" },
+                "total_pages": { "type": "integer" },
+                "facets": schema_ref("Facets")
+            }
+        },
+        "Facets": {
+            "type": "object",
+            "properties": {
+                "authors": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "id": { "type": "integer" },
+                            "name": { "type": "string" },
+                            "count": { "type": "integer" }
+                        }
+                    }
+                },
+                "narrators": { "type": "array", "items": value_count },
+                "tags": { "type": "array", "items": value_count },
+                "languages": { "type": "array", "items": value_count },
+                "series": { "type": "array", "items": value_count },
+                "states": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "state": {
+                                "type": "string",
+                                "enum": ["not_started", "in_progress", "finished", "abandoned", "want_to_listen"]
+                            },
+                            "count": { "type": "integer" }
+                        }
+                    }
+                }
+            }
+        },
+        "Book": {
+            "type": "object",
+            "properties": {
+                "id": { "type": "integer" },
+                "author_id": { "type": "integer" },
+                "name": { "type": "string" },
+                "chapters": { "type": "integer" },
+                "duration": nullable("number"),
+                "narrator": nullable("string"),
+                "series": nullable("string"),
+                "description": nullable("string"),
+                "language": nullable("string"),
+                "name_pinyin": nullable("string"),
+                "name_initials": nullable("string"),
+                "created_at": datetime,
+                "updated_at": datetime
+            }
+        },
+        "BookProgress": {
+            "type": "object",
+            "description": "the place in the whole book, in seconds",
+            "properties": {
+                "total_duration": { "type": "number" },
+                "elapsed": { "type": "number" },
+                "remaining": { "type": "number" },
+                "percent": { "type": "number", "description": "from 0 to 100" }
+            }
+        },
+        "BookWithProgress": {
+            "allOf": [
+                schema_ref("Book"),
+                {
+                    "type": "object",
+                    "properties": {
+                        "progress": {
+                            "description": "null until the durations of the chapters are known",
+                            "oneOf": [schema_ref("BookProgress"), { "type": "null" }]
+                        }
+                    }
+                }
+            ]
+        },
+        "Author": {
+            "type": "object",
+            "properties": {
+                "id": { "type": "integer" },
+                "name": { "type": "string" },
+                "avatar": { "type": "string", "description": "empty when there is none" },
+                "description": { "type": "string" },
+                "name_pinyin": nullable("string"),
+                "name_initials": nullable("string"),
+                "created_at": datetime,
+                "updated_at": datetime
+            }
+        },
+        "SearchHit": {
+            "type": "object",
+            "properties": {
+                "book": schema_ref("Book"),
+                "author": { "oneOf": [schema_ref("Author"), { "type": "null" }] },
+                "score": { "type": "number" },
+                "highlights": {
+                    "type": "object",
+                    "description": "html fragments by field, the matches are wrapped in <b>",
+                    "additionalProperties": { "type": "array", "items": { "type": "string" } }
+                },
+                "progress": { "oneOf": [schema_ref("BookProgress"), { "type": "null" }] }
+            }
+        },
+        "Progress": {
+            "type": "object",
+            "description": "the fields of BookProgress are left out until the durations of the chapters are known",
+            "properties": {
+                "id": { "type": "integer", "description": "0 for a book not played yet, at chapter 0" },
+                "account_id": { "type": "integer" },
+                "music_id": { "type": "integer" },
+                "chapter_no": { "type": "integer" },
+                "progress": { "type": "number", "description": "seconds into the chapter" },
+                "last_played_at": nullable_datetime,
+                "state": schema_ref("ProgressState"),
+                "finished_at": nullable_datetime,
+                "finish_count": { "type": "integer" },
+                "relisten_count": { "type": "integer", "description": "times the book was started over once finished" },
+                "total_duration": { "type": "number" },
+                "elapsed": { "type": "number" },
+                "remaining": { "type": "number" },
+                "percent": { "type": "number", "description": "from 0 to 100" }
+            }
+        },
+        "ProgressState": {
+            "type": "string",
+            "description": "reaching the last 30 seconds finishes a book, playing an abandoned or wanted book resumes it",
+            "enum": ["in_progress", "finished", "abandoned", "want_to_listen"]
+        },
+        "StateUpdate": {
+            "type": "object",
+            "required": ["state"],
+            "properties": { "state": schema_ref("ProgressState") }
+        },
+        "ProgressUpdate": {
+            "type": "object",
+            "required": ["chapter_no", "progress"],
+            "properties": {
+                "chapter_no": { "type": "integer" },
+                "progress": { "type": "number", "description": "seconds into the chapter" },
+                "device": {
+                    "type": "string",
+                    "description": "the device in the listening history, the user agent by default"
+                }
+            }
+        },
+        "HistoryEntry": {
+            "type": "object",
+            "description": "updates from the same device less than 10 minutes apart make one session",
+            "properties": {
+                "id": { "type": "integer" },
+                "account_id": { "type": "integer" },
+                "music_id": { "type": "integer" },
+                "start_chapter_no": { "type": "integer" },
+                "start_position": { "type": "number", "description": "seconds into the chapter" },
+                "end_chapter_no": { "type": "integer" },
+                "end_position": { "type": "number", "description": "seconds into the chapter" },
+                "started_at": datetime,
+                "ended_at": datetime,
+                "device": nullable("string"),
+                "book": { "oneOf": [schema_ref("Book"), { "type": "null" }] }
+            }
+        },
+        "Credentials": {
+            "type": "object",
+            "required": ["username", "password"],
+            "properties": {
+                "username": { "type": "string" },
+                "password": { "type": "string" }
+            }
+        },
+        "Session": {
+            "type": "object",
+            "properties": {
+                "user_id": { "type": "integer" },
+                "user_name": { "type": "string" },
+                "role_level": { "type": "integer", "description": "0 for an admin" },
+                "can_download": { "type": "boolean", "description": "may take books offline as a zip, an m4b or a podcast feed, the streams are not restricted" }
+            }
+        },
+        "Account": {
+            "type": "object",
+            "properties": {
+                "user_id": { "type": "integer" },
+                "username": { "type": "string" },
+                "role_level": { "type": "integer", "description": "0 for an admin" }
+            }
+        },
+        "NewAccount": {
+            "type": "object",
+            "required": ["username", "password", "role_level"],
+            "properties": {
+                "username": { "type": "string" },
+                "password": { "type": "string" },
+                "role_level": { "type": "string", "enum": ["Admin", "User"] }
+            }
+        },
+        "SubsonicPassword": {
+            "type": "object",
+            "properties": { "password": { "type": "string" } }
+        },
+        "FeedToken": {
+            "type": "object",
+            "properties": { "token": { "type": "string" } }
+        },
+        "Feed": {
+            "type": "object",
+            "properties": {
+                "url": {
+                    "type": "string",
+                    "description": "an rss 2.0 feed, authenticated by the token in the url"
+                }
+            }
+        },
+        "Manifest": {
+            "type": "object",
+            "description": "a readium web publication manifest, see https://readium.org/webpub-manifest/profiles/audiobook"
+        },
+        "OpdsFeed": {
+            "type": "object",
+            "description": "an opds 2.0 feed, see https://drafts.opds.io/opds-2.0"
+        },
+        "CreatedAccount": {
+            "type": "object",
+            "properties": { "user_id": { "type": "integer" } }
+        },
+        "DownloadPermission": {
+            "type": "object",
+            "required": ["allowed"],
+            "properties": { "allowed": { "type": "boolean" } }
+        }
+    })
+}
+
+/// the schemas of the listening stats, apart to keep `json!` within its recursion limit
+fn stats_schemas() -> Value {
+    let datetime = json!({ "type": "string", "format": "date-time" });
+    json!({
+        "PeriodTime": {
+            "type": "object",
+            "properties": {
+                "start": { "type": "string", "format": "date", "description": "the first day" },
+                "seconds": { "type": "number" }
+            }
+        },
+        "ListeningStats": {
+            "type": "object",
+            "description": "the time of a session runs from its first to its last progress update",
+            "properties": {
+                "total_seconds": { "type": "number" },
+                "days": { "type": "array", "items": schema_ref("PeriodTime"), "description": "the last 30 days, the oldest first" },
+                "weeks": { "type": "array", "items": schema_ref("PeriodTime"), "description": "the last 12 weeks, from monday" },
+                "months": { "type": "array", "items": schema_ref("PeriodTime"), "description": "the last 12 months" },
+                "current_streak": { "type": "integer", "description": "days in a row up to today or yesterday" },
+                "longest_streak": { "type": "integer" },
+                "books_finished": { "type": "integer" },
+                "top_authors": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "id": { "type": "integer" },
+                            "name": { "type": "string" },
+                            "seconds": { "type": "number" }
+                        }
+                    }
+                },
+                "average_speed": {
+                    "type": ["number", "null"],
+                    "description": "book seconds covered per second listened in the last 30 days, null until chapter durations are known"
+                }
+            }
+        },
+        "ServerStats": {
+            "allOf": [
+                schema_ref("ListeningStats"),
+                {
+                    "type": "object",
+                    "properties": {
+                        "active_listeners": {
+                            "type": "array",
+                            "description": "the users with a session in the last 7 days",
+                            "items": {
+                                "type": "object",
+                                "properties": {
+                                    "id": { "type": "integer" },
+                                    "name": { "type": "string" },
+                                    "seconds": { "type": "number" },
+                                    "last_played_at": datetime
+                                }
+                            }
+                        },
+                        "most_played": {
+                            "type": "array",
+                            "items": {
+                                "type": "object",
+                                "properties": {
+                                    "id": { "type": "integer" },
+                                    "name": { "type": "string" },
+                                    "seconds": { "type": "number" },
+                                    "listeners": { "type": "integer" }
+                                }
+                            }
+                        }
+                    }
+                }
+            ]
+        }
+    })
+}
+
+/// the openapi document of `/api/v1`
+pub(crate) fn document() -> Value {
+    let mut paths = Map::new();
+    for op in OPERATIONS {
+        let entry = paths
+            .entry(op.path.to_string())
+            .or_insert_with(|| json!({}));
+        entry[op.method] = operation(op);
+    }
+    let mut schemas = schemas();
+    if let (Some(schemas), Value::Object(stats)) = (schemas.as_object_mut(), stats_schemas()) {
+        schemas.extend(stats);
+    }
+    json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": env!("CARGO_PKG_NAME"),
+            "description": env!("CARGO_PKG_DESCRIPTION"),
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "servers": [{ "url": "/api/v1" }],
+        "security": [{ "session": [] }],
+        "paths": paths,
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": {
+                "session": {
+                    "type": "apiKey",
+                    "in": "cookie",
+                    "name": crate::consts::USR_COOKIE_KEY,
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use serde_json::Value;
+
+    use super::{document, OPERATIONS};
+
+    /// the method and path of every route of [`super::super::route`], read from its source as
+    /// axum can't list the routes of a router
+    fn router_routes() -> BTreeSet<(String, String)> {
+        let source = include_str!("mod.rs");
+        let start = source.find("pub(crate) fn route(").unwrap();
+        let end = start + source[start..].find("\n}\n").unwrap();
+        let mut routes = BTreeSet::new();
+        for call in source[start..end].split(".route(\"").skip(1) {
+            let (path, methods) = call.split_once('"').unwrap();
+            // `:id` in axum is `{id}` in openapi
+            let path = path
+                .split('/')
+                .map(|part| match part.strip_prefix(':') {
+                    Some(name) => format!("{{{}}}", name),
+                    None => part.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join("/");
+            let methods = methods.split_once("\n").map_or(methods, |(line, _)| line);
+            for method in ["get", "post", "put", "delete", "patch"] {
+                let called = methods.starts_with(&format!(", {}(", method))
+                    || methods.contains(&format!(").{}(", method));
+                if called {
+                    routes.insert((method.to_string(), path.clone()));
+                }
+            }
+        }
+        routes
+    }
+
+    #[test]
+    fn test_operations_match_router() {
+        let routes = router_routes();
+        assert!(routes.contains(&("put".to_string(), "/books/{id}/progress".to_string())));
+        let operations = OPERATIONS
+            .iter()
+            .map(|op| (op.method.to_string(), op.path.to_string()))
+            .collect::<BTreeSet<_>>();
+        assert_eq!(
+            operations.len(),
+            OPERATIONS.len(),
+            "an operation is listed twice"
+        );
+        assert_eq!(
+            routes.difference(&operations).collect::<Vec<_>>(),
+            Vec::<&(String, String)>::new(),
+            "routes missing from OPERATIONS"
+        );
+        assert_eq!(
+            operations.difference(&routes).collect::<Vec<_>>(),
+            Vec::<&(String, String)>::new(),
+            "operations without a route"
+        );
+    }
+
+    #[test]
+    fn test_internal_fields_hidden() {
+        let document = document();
+        let book = &document["components"]["schemas"]["Book"]["properties"];
+        for field in ["file_folder", "fingerprint", "deleted_at"] {
+            assert!(book.get(field).is_none(), "{}", field);
+        }
+        let json = serde_json::to_value(crate::music::BookResponse::from(crate::fixtures::book(
+            1, "book",
+        )))
+        .unwrap();
+        let fields = json.as_object().unwrap().keys().collect::<BTreeSet<_>>();
+        let documented = book.as_object().unwrap().keys().collect::<BTreeSet<_>>();
+        assert_eq!(fields, documented);
+    }
+
+    fn refs(value: &Value, out: &mut Vec<String>) {
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map {
+                    match (key.as_str(), value) {
+                        ("$ref", Value::String(target)) => out.push(target.clone()),
+                        _ => refs(value, out),
+                    }
+                }
+            }
+            Value::Array(values) => values.iter().for_each(|value| refs(value, out)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn test_document() {
+        let document = document();
+        let mut targets = vec![];
+        refs(&document, &mut targets);
+        assert!(!targets.is_empty());
+        for target in targets {
+            let name = target.trim_start_matches("#/components/schemas/");
+            assert!(
+                document["components"]["schemas"][name].is_object(),
+                "missing schema {}",
+                name
+            );
+        }
+        let books = &document["paths"]["/books"]["get"];
+        assert!(books["responses"]["200"].is_object());
+        assert!(books["parameters"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|param| param["name"] == "narrator"));
+        let progress = &document["paths"]["/books/{id}/progress"];
+        assert!(progress["get"].is_object() && progress["put"].is_object());
+        assert_eq!(
+            document["paths"]["/login"]["post"]["security"],
+            serde_json::json!([])
+        );
+    }
+}
@@ -2,29 +2,33 @@ use axum::{extract::State, response::IntoResponse, routing::post, Json};
 use cookie::time::Duration;
 use cookie::Cookie;
 
-use hyper::{header::LOCATION, HeaderMap, StatusCode};
+use hyper::{
+    header::{HeaderValue, LOCATION},
+    HeaderMap, StatusCode,
+};
 use redis::AsyncCommands;
-use sea_orm::{ActiveValue, ColumnTrait, EntityTrait, QueryFilter};
+use sea_orm::{sea_query::Expr, ActiveValue, ColumnTrait, EntityTrait, QueryFilter};
 use tower::ServiceBuilder;
 use tower_cookies::Cookies;
 use tracing::debug;
 
 use crate::consts::USR_COOKIE_KEY;
+use crate::error::{AppError, AppJson, AppResult};
 use crate::middleware::LoginInfo;
 use crate::{entities, AppStat};
 use entities::prelude::*;
 use entities::*;
 
-#[derive(Debug, serde::Deserialize)]
-enum RoleLevel {
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+pub(crate) enum RoleLevel {
     Admin,
     User,
 }
 #[derive(Debug, serde::Deserialize)]
 pub struct UserInfo {
-    username: String,
-    password: String,
-    role_level: RoleLevel,
+    pub(crate) username: String,
+    pub(crate) password: String,
+    pub(crate) role_level: RoleLevel,
 }
 
 pub(crate) fn route(state: super::AppStat) -> axum::Router<super::AppStat> {
@@ -49,11 +53,8 @@ pub struct User {
     id: u32,
     username: String,
 }
-async fn create_account(
-    State(state): State<AppStat>,
-    Json(user_info): Json<UserInfo>,
-) -> impl IntoResponse {
-    debug!("create_account: {:?}", user_info);
+/// insert the account, a taken name is a conflict
+pub(crate) async fn insert_account(state: &AppStat, user_info: UserInfo) -> AppResult<i32> {
     let passwd_md5 = format!("{:x}", md5::compute(&user_info.password));
     let new_account = account::ActiveModel {
         name: ActiveValue::Set(user_info.username),
@@ -64,44 +65,101 @@ async fn create_account(
         }),
         ..Default::default()
     };
-    let account_result = Account::insert(new_account)
+    let result = Account::insert(new_account)
         .exec(&state.connections.db)
-        .await;
-    match account_result {
-        Ok(_) => {
-            let mut headers = HeaderMap::new();
-            headers.insert(LOCATION, "/".parse().unwrap());
-            // status 200
-            (StatusCode::OK, headers, "create account succeed")
-        }
-        Err(_) => {
-            let mut headers = HeaderMap::new();
-            headers.insert(LOCATION, "/".parse().unwrap());
-            // status InternalServerError
-            (StatusCode::CONFLICT, headers, "create account failed")
-        }
+        .await?;
+    Ok(result.last_insert_id)
+}
+
+/// replace the subsonic password of the account with a random one, returned in clear
+pub(crate) async fn reset_subsonic_password(state: &AppStat, user_id: i32) -> AppResult<String> {
+    let password = hex::encode(rand::random::<[u8; 8]>());
+    Account::update_many()
+        .col_expr(
+            account::Column::SubsonicPassword,
+            Expr::value(password.clone()),
+        )
+        .filter(account::Column::Id.eq(user_id))
+        .exec(&state.connections.db)
+        .await?;
+    Ok(password)
+}
+
+/// allow or forbid the account to download whole books, a missing account is not found
+pub(crate) async fn set_download_permission(
+    state: &AppStat,
+    user_id: i32,
+    allowed: bool,
+) -> AppResult<()> {
+    let result = Account::update_many()
+        .col_expr(account::Column::CanDownload, Expr::value(allowed))
+        .filter(account::Column::Id.eq(user_id))
+        .filter(account::Column::DeletedAt.is_null())
+        .exec(&state.connections.db)
+        .await?;
+    if result.rows_affected == 0 {
+        return Err(AppError::not_found(format!(
+            "account {} not found",
+            user_id
+        )));
     }
+    Ok(())
+}
+
+/// fail unless the account may take books offline, the streams of the players are not checked
+pub(crate) async fn check_download_permission(state: &AppStat, user_id: i32) -> AppResult<()> {
+    let allowed = Account::find_by_id(user_id)
+        .filter(account::Column::DeletedAt.is_null())
+        .one(&state.connections.db)
+        .await?
+        .is_some_and(|user| user.can_download);
+    if allowed {
+        Ok(())
+    } else {
+        Err(AppError::forbidden(
+            "downloads are disabled for this account",
+        ))
+    }
+}
+
+async fn create_account(
+    State(state): State<AppStat>,
+    AppJson(user_info): AppJson<UserInfo>,
+) -> AppResult<impl IntoResponse> {
+    debug!("create_account: {:?}", user_info.username);
+    insert_account(&state, user_info).await?;
+    let mut headers = HeaderMap::new();
+    headers.insert(LOCATION, HeaderValue::from_static("/"));
+    Ok((StatusCode::OK, headers, "create account succeed"))
 }
 
 #[derive(Debug, serde::Serialize)]
-struct AccountResponse {
+pub(crate) struct AccountResponse {
     username: String,
     user_id: i32,
     role_level: i32,
+    can_download: bool,
+}
+
+/// the accounts that are not in the trash
+pub(crate) async fn list_accounts(state: &AppStat) -> AppResult<Vec<AccountResponse>> {
+    let users = Account::find()
+        .filter(account::Column::DeletedAt.is_null())
+        .all(&state.connections.db)
+        .await?;
+    Ok(users
+        .into_iter()
+        .map(|u| AccountResponse {
+            username: u.name,
+            user_id: u.id,
+            role_level: u.role_level,
+            can_download: u.can_download,
+        })
+        .collect())
 }
 
-async fn get_account(State(state): State<AppStat>) -> Json<Vec<AccountResponse>> {
-    let users = Account::find().all(&state.connections.db).await.unwrap();
-    Json(
-        users
-            .into_iter()
-            .map(|u| AccountResponse {
-                username: u.name,
-                user_id: u.id,
-                role_level: u.role_level,
-            })
-            .collect::<Vec<_>>(),
-    )
+async fn get_account(State(state): State<AppStat>) -> AppResult<Json<Vec<AccountResponse>>> {
+    Ok(Json(list_accounts(&state).await?))
 }
 #[derive(Debug, serde::Deserialize)]
 struct UserLoginInfo {
@@ -113,88 +171,108 @@ struct LoginResult {
     code: i32,
     message: String,
 }
-async fn login(
-    State(state): State<AppStat>,
-    cookies: Cookies,
-    Json(user_info): Json<UserLoginInfo>,
-) -> impl IntoResponse {
-    debug!("login: {:?}", user_info);
+pub(crate) enum Credentials {
+    Valid(account::Model),
+    WrongPassword,
+    NoSuchUser,
+}
+
+/// check `password` against the account named `username`, trashed accounts can't log in
+pub(crate) async fn check_credentials(
+    state: &AppStat,
+    username: &str,
+    password: &str,
+) -> AppResult<Credentials> {
     let user = Account::find()
-        .filter(account::Column::Name.eq(&user_info.username))
+        .filter(account::Column::Name.eq(username))
+        .filter(account::Column::DeletedAt.is_null())
         .one(&state.connections.db)
-        .await
-        .unwrap();
+        .await?;
     debug!("user: {:?}", user);
-    match user {
-        Some(user) => {
-            let passwd_md5 = format!("{:x}", md5::compute(user_info.password));
-            if passwd_md5 == user.password {
-                // login succeed
-                // generate redis passkey from random 16 Bytes
-                let passkey = rand::random::<[u8; 16]>();
-                let passkey_str = hex::encode(passkey);
-                // save redis
-                let mut redis_conn = state.connections.redis.lock().await;
-                let redis_value = LoginInfo {
-                    user_id: user.id,
-                    role_level: user.role_level,
-                    user_name: user.name,
-                };
-                let _: () = redis_conn
-                    .set_ex(&passkey_str, redis_value, 3600 * 24 * 7)
-                    .await
-                    .unwrap();
-
-                // set cookie
-                let mut cookie = cookie::Cookie::new(crate::consts::USR_COOKIE_KEY, passkey_str);
-                cookie.set_max_age(Duration::days(7));
-                cookie.set_path("/");
-                cookies.add(cookie);
-
-                //redirect to /
-                debug!("login success");
-                Json(LoginResult {
-                    code: 0,
-                    message: "login success".to_string(),
-                })
-            } else {
-                // wrong password
-                debug!(
-                    "wrong password, required md5: {}, input: {}",
-                    user.password, passwd_md5
-                );
-                let mut headers = HeaderMap::new();
-                headers.insert(LOCATION, "/".parse().unwrap());
-                Json(LoginResult {
-                    code: 1,
-                    message: "wrong password".to_string(),
-                })
-            }
-        }
-        None => {
-            // no such user
-            let mut headers = HeaderMap::new();
-            headers.insert(LOCATION, "/".parse().unwrap());
-            Json(LoginResult {
-                code: 2,
-                message: "no such user".to_string(),
-            })
-        }
+    let Some(user) = user else {
+        return Ok(Credentials::NoSuchUser);
+    };
+    let passwd_md5 = format!("{:x}", md5::compute(password));
+    if passwd_md5 == user.password {
+        Ok(Credentials::Valid(user))
+    } else {
+        debug!(
+            "wrong password, required md5: {}, input: {}",
+            user.password, passwd_md5
+        );
+        Ok(Credentials::WrongPassword)
     }
 }
 
-async fn logout(State(state): State<AppStat>, cookies: Cookies) -> impl IntoResponse {
-    debug!("logout");
-    let passkey = cookies.get(USR_COOKIE_KEY);
-    if let Some(passkey) = passkey {
+/// store a new passkey of `user` in redis and set it as the session cookie
+pub(crate) async fn start_session(
+    state: &AppStat,
+    cookies: &Cookies,
+    user: account::Model,
+) -> AppResult<LoginInfo> {
+    // generate redis passkey from random 16 Bytes
+    let passkey = rand::random::<[u8; 16]>();
+    let passkey_str = hex::encode(passkey);
+    let login_info = LoginInfo {
+        user_id: user.id,
+        role_level: user.role_level,
+        user_name: user.name,
+    };
+    let mut redis_conn = state.connections.redis.lock().await;
+    let _: () = redis_conn
+        .set_ex(&passkey_str, login_info.clone(), 3600 * 24 * 7)
+        .await?;
+
+    let mut cookie = cookie::Cookie::new(crate::consts::USR_COOKIE_KEY, passkey_str);
+    cookie.set_max_age(Duration::days(7));
+    cookie.set_path("/");
+    cookies.add(cookie);
+    Ok(login_info)
+}
+
+/// forget the passkey of the session cookie and remove the cookie
+pub(crate) async fn end_session(state: &AppStat, cookies: &Cookies) -> AppResult<()> {
+    if let Some(passkey) = cookies.get(USR_COOKIE_KEY) {
         debug!("deleting passkey: {}", passkey.value());
         let mut redis_conn = state.connections.redis.lock().await;
-        let _: () = redis_conn.del(passkey.value()).await.unwrap();
+        let _: () = redis_conn.del(passkey.value()).await?;
     }
-    // delete cookie
     let cookie = Cookie::build(USR_COOKIE_KEY, "").path("/").finish();
     cookies.remove(cookie);
+    Ok(())
+}
+
+async fn login(
+    State(state): State<AppStat>,
+    cookies: Cookies,
+    AppJson(user_info): AppJson<UserLoginInfo>,
+) -> AppResult<Json<LoginResult>> {
+    debug!("login: {:?}", user_info.username);
+    let result = match check_credentials(&state, &user_info.username, &user_info.password).await? {
+        Credentials::Valid(user) => {
+            start_session(&state, &cookies, user).await?;
+            debug!("login success");
+            LoginResult {
+                code: 0,
+                message: "login success".to_string(),
+            }
+        }
+        Credentials::WrongPassword => LoginResult {
+            code: 1,
+            message: "wrong password".to_string(),
+        },
+        Credentials::NoSuchUser => LoginResult {
+            code: 2,
+            message: "no such user".to_string(),
+        },
+    };
+    Ok(Json(result))
+}
+
+async fn logout(State(state): State<AppStat>, cookies: Cookies) -> AppResult<impl IntoResponse> {
+    debug!("logout");
+    end_session(&state, &cookies).await?;
     let mut headers = HeaderMap::new();
-    headers.insert(LOCATION, "/".parse().unwrap());
-    (headers, "logout success")
+    headers.insert(LOCATION, HeaderValue::from_static("/"));
+    Ok((headers, "logout success"))
 }
@@ -1,5 +1,8 @@
+use std::path::Path;
+
 use audiobook_server::entities::{prelude::*, *};
-use audiobook_server::{init_log, init_mysql};
+use audiobook_server::tools::trash::{self, TrashKind};
+use audiobook_server::{init_log, init_mysql, init_redis};
 use clap::{Args, Parser, Subcommand, ValueEnum};
 use sea_orm::{ActiveModelTrait, ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter};
 
@@ -7,7 +10,7 @@ use sea_orm::{ActiveModelTrait, ColumnTrait, EntityTrait, IntoActiveModel, Query
 async fn main() {
     init_log();
 
-    let Cli { db, subcmd } = Cli::parse();
+    let Cli { db, redis, subcmd } = Cli::parse();
     let db = init_mysql(&db).await;
     match subcmd {
         SubCommand::Create(create_args) => {
@@ -32,15 +35,29 @@ async fn main() {
             .unwrap();
         }
         SubCommand::Del(del_args) => {
-            let DelArgs { user_name } = del_args;
+            let DelArgs { user_name, purge } = del_args;
             let account = Account::find()
                 .filter(account::Column::Name.eq(&user_name))
                 .one(&db)
                 .await
                 .unwrap();
             if let Some(account) = account {
-                let account = account.into_active_model();
-                account.delete(&db).await.unwrap();
+                if account.deleted_at.is_none() {
+                    trash::trash(&db, TrashKind::Account, account.id)
+                        .await
+                        .unwrap();
+                }
+                // the account is logged out everywhere, trashed or purged
+                let mut redis = init_redis(&redis).await;
+                trash::revoke_sessions(&mut redis, account.id)
+                    .await
+                    .unwrap();
+                if purge {
+                    // accounts have no files, the book dir is never touched
+                    trash::purge(&db, Path::new(""), TrashKind::Account, account.id)
+                        .await
+                        .unwrap();
+                }
             }
         }
         SubCommand::Update(update_args) => {
@@ -112,6 +129,10 @@ async fn main() {
 
 #[derive(Debug, Parser)]
 pub struct Cli {
+    /// the redis url,start at "redis://", to log out deleted accounts
+    #[clap(short, long, env = "REDIS_URL", default_value = "redis://localhost/0")]
+    redis: String,
+
     /// the database url,start at "mysql://"
     #[clap(
         short,
@@ -149,6 +170,9 @@ struct CreateArgs {
 #[derive(Debug, Args, Clone)]
 struct DelArgs {
     user_name: String,
+    /// delete the account and its progress for good instead of moving it to the trash
+    #[clap(long)]
+    purge: bool,
 }
 
 #[derive(Debug, Args, Clone)]
@@ -47,7 +47,7 @@ pub struct Cli {
     /// the name of the author of the book to be created
     #[clap(short, long)]
     author_name: String,
-    /// the source dir of the book to be find
+    /// the source dir of the book to be find, or a .zip/.tar/.tar.gz archive
     #[clap(short, long)]
     source_dir: String,
 }
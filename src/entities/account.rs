@@ -12,6 +12,17 @@ pub struct Model {
     pub name: String,
     pub password: String,
     pub role_level: i32,
+    pub deleted_at: Option<DateTime>,
+    /// the password of subsonic clients, in clear for the token scheme
+    #[serde(skip_serializing)]
+    pub subsonic_password: Option<String>,
+    /// the token in the urls of the podcast feeds
+    #[sea_orm(unique)]
+    #[serde(skip_serializing)]
+    pub feed_token: Option<String>,
+    /// whether the account may take books offline, as a zip, an m4b or a podcast feed. playing a
+    /// book streams its files either way, so this is no copy protection.
+    pub can_download: bool,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -12,6 +12,11 @@ pub struct Model {
     #[sea_orm(unique)]
     pub name: String,
     pub description: String,
+    pub deleted_at: Option<DateTime>,
+    pub created_at: DateTime,
+    pub updated_at: DateTime,
+    pub name_pinyin: Option<String>,
+    pub name_initials: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -4,5 +4,10 @@ pub mod prelude;
 
 pub mod account;
 pub mod author;
+pub mod chapter;
+pub mod listening_session;
 pub mod music;
+pub mod music_tag;
 pub mod progress;
+pub mod sea_orm_active_enums;
+pub mod tag;
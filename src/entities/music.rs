@@ -3,16 +3,29 @@
 use sea_orm::entity::prelude::*;
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Eq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
 #[sea_orm(table_name = "music")]
 pub struct Model {
     #[sea_orm(primary_key)]
     pub id: i32,
     pub author_id: i32,
-    #[sea_orm(unique)]
     pub name: String,
     pub chapters: i32,
     pub file_folder: String,
+    pub deleted_at: Option<DateTime>,
+    #[sea_orm(column_type = "Double", nullable)]
+    pub duration: Option<f64>,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub fingerprint: Option<String>,
+    pub created_at: DateTime,
+    pub updated_at: DateTime,
+    pub narrator: Option<String>,
+    pub series: Option<String>,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub description: Option<String>,
+    pub name_pinyin: Option<String>,
+    pub name_initials: Option<String>,
+    pub language: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -25,6 +38,10 @@ pub enum Relation {
         on_delete = "Restrict"
     )]
     Author,
+    #[sea_orm(has_many = "super::chapter::Entity")]
+    Chapter,
+    #[sea_orm(has_many = "super::music_tag::Entity")]
+    MusicTag,
     #[sea_orm(has_many = "super::progress::Entity")]
     Progress,
 }
@@ -35,6 +52,28 @@ impl Related<super::author::Entity> for Entity {
     }
 }
 
+impl Related<super::chapter::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Chapter.def()
+    }
+}
+
+impl Related<super::music_tag::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::MusicTag.def()
+    }
+}
+
+impl Related<super::tag::Entity> for Entity {
+    fn to() -> RelationDef {
+        super::music_tag::Relation::Tag.def()
+    }
+
+    fn via() -> Option<RelationDef> {
+        Some(super::music_tag::Relation::Music.def().rev())
+    }
+}
+
 impl Related<super::progress::Entity> for Entity {
     fn to() -> RelationDef {
         Relation::Progress.def()
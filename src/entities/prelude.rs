@@ -2,5 +2,9 @@
 
 pub use super::account::Entity as Account;
 pub use super::author::Entity as Author;
+pub use super::chapter::Entity as Chapter;
+pub use super::listening_session::Entity as ListeningSession;
 pub use super::music::Entity as Music;
+pub use super::music_tag::Entity as MusicTag;
 pub use super::progress::Entity as Progress;
+pub use super::tag::Entity as Tag;
@@ -3,6 +3,8 @@
 use sea_orm::entity::prelude::*;
 use serde::{Deserialize, Serialize};
 
+use super::sea_orm_active_enums::ProgressState;
+
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
 #[sea_orm(table_name = "progress")]
 pub struct Model {
@@ -13,6 +15,15 @@ pub struct Model {
     pub chapter_no: i32,
     #[sea_orm(column_type = "Double")]
     pub progress: f64,
+    pub last_played_at: Option<DateTime>,
+    #[serde(default)]
+    pub state: ProgressState,
+    #[serde(default)]
+    pub finished_at: Option<DateTime>,
+    #[serde(default)]
+    pub finish_count: i32,
+    #[serde(default)]
+    pub relisten_count: i32,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -0,0 +1,255 @@
+//! the json error returned by the api handlers
+//!
+//! the body is `{"code": "not_found", "message": "book 3 not found"}`, clients should match on
+//! the `code`, the message is for humans and may change.
+
+use axum::{
+    async_trait,
+    extract::{
+        rejection::{FormRejection, JsonRejection, QueryRejection},
+        FromRequest, FromRequestParts,
+    },
+    http::request::Parts,
+    response::{IntoResponse, Response},
+};
+use hyper::{Request, StatusCode};
+use sea_orm::{DbErr, SqlErr};
+use tracing::error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ErrorCode {
+    /// the book, author or account doesn't exist or is in the trash
+    NotFound,
+    /// the request collides with an existing row, like a duplicated name
+    Conflict,
+    /// the parameters are missing or malformed
+    InvalidInput,
+    /// not logged in
+    Unauthorized,
+    /// logged in without the rights to do it
+    Forbidden,
+    /// the database or redis can't be reached, retrying later may succeed
+    Unavailable,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::Conflict => StatusCode::CONFLICT,
+            ErrorCode::InvalidInput => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub(crate) struct AppError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+pub(crate) type AppResult<T> = Result<T, AppError>;
+
+impl AppError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::NotFound, message)
+    }
+
+    pub fn invalid_input(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::InvalidInput, message)
+    }
+
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Unauthorized, message)
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Forbidden, message)
+    }
+
+    /// log `err` and hide it from the client
+    pub fn internal(err: impl std::fmt::Display) -> Self {
+        error!("internal error: {}", err);
+        Self::new(ErrorCode::Internal, "internal error")
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        (self.code.status(), axum::Json(self)).into_response()
+    }
+}
+
+/// whether an io error is anywhere in the chain of `err`, a lost connection to the database
+/// surfaces as an io error inside a query error
+fn caused_by_io(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if err.is::<std::io::Error>() {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+impl From<DbErr> for AppError {
+    fn from(err: DbErr) -> Self {
+        match err.sql_err() {
+            Some(SqlErr::UniqueConstraintViolation(_)) => {
+                return Self::new(ErrorCode::Conflict, "already exists")
+            }
+            Some(SqlErr::ForeignKeyConstraintViolation(_)) => {
+                return Self::new(
+                    ErrorCode::Conflict,
+                    "still referenced or missing a reference",
+                )
+            }
+            _ => {}
+        }
+        match err {
+            DbErr::RecordNotFound(message) => Self::not_found(message),
+            DbErr::ConnectionAcquire(_) | DbErr::Conn(_) => {
+                error!("database unavailable: {}", err);
+                Self::new(ErrorCode::Unavailable, "the database is unavailable")
+            }
+            err if caused_by_io(&err) => {
+                error!("database unavailable: {}", err);
+                Self::new(ErrorCode::Unavailable, "the database is unavailable")
+            }
+            err => Self::internal(err),
+        }
+    }
+}
+
+impl From<redis::RedisError> for AppError {
+    fn from(err: redis::RedisError) -> Self {
+        error!("redis unavailable: {}", err);
+        Self::new(ErrorCode::Unavailable, "the session store is unavailable")
+    }
+}
+
+impl From<tera::Error> for AppError {
+    fn from(err: tera::Error) -> Self {
+        Self::internal(format!("render error: {:?}", err))
+    }
+}
+
+impl From<FormRejection> for AppError {
+    fn from(rejection: FormRejection) -> Self {
+        Self::invalid_input(rejection.body_text())
+    }
+}
+
+impl From<JsonRejection> for AppError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self::invalid_input(rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for AppError {
+    fn from(rejection: QueryRejection) -> Self {
+        Self::invalid_input(rejection.body_text())
+    }
+}
+
+/// [`axum::Form`] rejecting malformed parameters with an [`AppError`]
+pub(crate) struct AppForm<T>(pub T);
+
+/// [`axum::Json`] rejecting a malformed body with an [`AppError`]
+pub(crate) struct AppJson<T>(pub T);
+
+/// [`axum::extract::Query`] rejecting malformed parameters with an [`AppError`]
+pub(crate) struct AppQuery<T>(pub T);
+
+#[async_trait]
+impl<T, S, B> FromRequest<S, B> for AppForm<T>
+where
+    axum::Form<T>: FromRequest<S, B, Rejection = FormRejection>,
+    S: Send + Sync,
+    B: Send + 'static,
+{
+    type Rejection = AppError;
+
+    async fn from_request(request: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::Form(value) = axum::Form::from_request(request, state).await?;
+        Ok(Self(value))
+    }
+}
+
+#[async_trait]
+impl<T, S, B> FromRequest<S, B> for AppJson<T>
+where
+    axum::Json<T>: FromRequest<S, B, Rejection = JsonRejection>,
+    S: Send + Sync,
+    B: Send + 'static,
+{
+    type Rejection = AppError;
+
+    async fn from_request(request: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::Json(value) = axum::Json::from_request(request, state).await?;
+        Ok(Self(value))
+    }
+}
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for AppQuery<T>
+where
+    axum::extract::Query<T>: FromRequestParts<S, Rejection = QueryRejection>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::extract::Query(value) =
+            axum::extract::Query::from_request_parts(parts, state).await?;
+        Ok(Self(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::StatusCode;
+    use sea_orm::{ConnAcquireErr, DbErr};
+
+    use super::{AppError, ErrorCode};
+
+    #[test]
+    fn test_db_error_codes() {
+        let err = AppError::from(DbErr::ConnectionAcquire(ConnAcquireErr::Timeout));
+        assert_eq!(err.code, ErrorCode::Unavailable);
+        assert_eq!(err.code.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let err = AppError::from(DbErr::RecordNotFound("book 3".to_string()));
+        assert_eq!(err.code.status(), StatusCode::NOT_FOUND);
+        let err = AppError::from(DbErr::Custom("boom".to_string()));
+        assert_eq!(err.code, ErrorCode::Internal);
+        // the details of internal errors stay in the log
+        assert_eq!(err.message, "internal error");
+    }
+
+    #[test]
+    fn test_error_body() {
+        let err = AppError::not_found("book 3 not found");
+        assert_eq!(
+            serde_json::to_string(&err).unwrap(),
+            r#"{"code":"not_found","message":"book 3 not found"}"#
+        );
+        assert_eq!(
+            AppError::invalid_input("bad").code.status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+    }
+}
@@ -0,0 +1,334 @@
+//! private podcast feeds, one rss 2.0 feed per book and user with the chapters as episodes
+//!
+//! podcast clients can't log in, so the feed, its episodes and its cover are authenticated by
+//! the feed token of the user in the url. resetting the token at `/api/v1/me/feed-token`
+//! revokes every url given out before. podcast clients download the episodes, so the feeds are
+//! only served to the accounts allowed to download.
+
+use std::path::{Path as FsPath, PathBuf};
+
+use axum::{
+    extract::{Path, State},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use chrono::TimeZone;
+use hyper::{header, HeaderMap};
+use sea_orm::{sea_query::Expr, ColumnTrait, DatabaseConnection, DbErr, EntityTrait, QueryFilter};
+
+use crate::entities::{prelude::*, *};
+use crate::error::{AppError, AppResult};
+use crate::tools::{self, escape_xml};
+use crate::AppStat;
+
+pub(crate) fn route() -> Router<AppStat> {
+    Router::new()
+        .route("/:token/:feed", get(feed))
+        .route("/:token/:book/:file", get(episode))
+        .route_layer(axum::middleware::from_fn(
+            super::middleware::log_system::log_sys,
+        ))
+}
+
+fn new_token() -> String {
+    hex::encode(rand::random::<[u8; 16]>())
+}
+
+/// the feed token of the user, created on first use
+pub(crate) async fn feed_token(db: &DatabaseConnection, user_id: i32) -> Result<String, DbErr> {
+    let token = Account::find_by_id(user_id)
+        .one(db)
+        .await?
+        .and_then(|user| user.feed_token);
+    match token {
+        Some(token) => Ok(token),
+        None => reset_feed_token(db, user_id).await,
+    }
+}
+
+/// replace the feed token of the user, the feeds subscribed with the old one stop working
+pub(crate) async fn reset_feed_token(
+    db: &DatabaseConnection,
+    user_id: i32,
+) -> Result<String, DbErr> {
+    let token = new_token();
+    Account::update_many()
+        .col_expr(account::Column::FeedToken, Expr::value(token.clone()))
+        .filter(account::Column::Id.eq(user_id))
+        .exec(db)
+        .await?;
+    Ok(token)
+}
+
+/// the origin the client reached the server at, behind a proxy setting `X-Forwarded-Proto`
+pub(crate) fn base_url(headers: &HeaderMap) -> String {
+    let value = |name| {
+        headers
+            .get(name)
+            .and_then(|value: &header::HeaderValue| value.to_str().ok())
+    };
+    format!(
+        "{}://{}",
+        value("x-forwarded-proto").unwrap_or("http"),
+        value("host").unwrap_or("localhost")
+    )
+}
+
+/// the feed of a book, relative to the origin
+pub(crate) fn feed_path(token: &str, book_id: i32) -> String {
+    format!("/feed/{}/{}.rss", token, book_id)
+}
+
+/// the book of a feed url, an unknown token looks like a missing feed
+async fn feed_book(db: &DatabaseConnection, token: &str, book_id: i32) -> AppResult<music::Model> {
+    let not_found = || AppError::not_found("feed not found");
+    let account = Account::find()
+        .filter(account::Column::FeedToken.eq(token))
+        .filter(account::Column::DeletedAt.is_null())
+        .one(db)
+        .await?
+        .ok_or_else(not_found)?;
+    if !account.can_download {
+        return Err(AppError::forbidden(
+            "downloads are disabled for this account",
+        ));
+    }
+    Music::find_by_id(book_id)
+        .filter(music::Column::DeletedAt.is_null())
+        .one(db)
+        .await?
+        .ok_or_else(not_found)
+}
+
+/// the cover of the book, or the avatar of its author
+fn cover(
+    book_dir: &FsPath,
+    book: &music::Model,
+    author: Option<&author::Model>,
+) -> Option<PathBuf> {
+    tools::book_cover(book_dir.join(&book.file_folder)).or_else(|| {
+        author
+            .filter(|author| !author.avatar.is_empty())
+            .map(|author| book_dir.join(&author.avatar))
+    })
+}
+
+#[derive(Debug)]
+struct Episode {
+    chapter_no: i32,
+    title: String,
+    /// the file name in the url, like `0001.mp3`
+    file_name: String,
+    length: u64,
+    mime: String,
+}
+
+/// the rss document, `base` is the origin and `token` the feed token
+fn render_feed(
+    base: &str,
+    token: &str,
+    book: &music::Model,
+    author: &str,
+    has_cover: bool,
+    episodes: &[Episode],
+) -> String {
+    let mut rss = String::from(concat!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+        r#"<rss version="2.0" xmlns:itunes="http://www.itunes.com/dtds/podcast-1.0.dtd">"#,
+        "<channel>"
+    ));
+    let description = book.description.as_deref().unwrap_or(&book.name);
+    rss.push_str(&format!(
+        "<title>{}</title><link>{}/webui/book_detail?id={}</link><description>{}</description>",
+        escape_xml(&book.name),
+        escape_xml(base),
+        book.id,
+        escape_xml(description)
+    ));
+    if let Some(language) = &book.language {
+        rss.push_str(&format!("<language>{}</language>", escape_xml(language)));
+    }
+    rss.push_str(&format!(
+        "<itunes:author>{}</itunes:author><itunes:summary>{}</itunes:summary>",
+        escape_xml(author),
+        escape_xml(description)
+    ));
+    // the chapters are listened in order, not newest first
+    rss.push_str("<itunes:type>serial</itunes:type><itunes:block>Yes</itunes:block>");
+    if has_cover {
+        rss.push_str(&format!(
+            r#"<itunes:image href="{}/feed/{}/{}/cover"/>"#,
+            escape_xml(base),
+            token,
+            book.id
+        ));
+    }
+    for episode in episodes {
+        // the dates only order the episodes, a minute per chapter from the date of the book
+        let published = chrono::Utc.from_utc_datetime(
+            &(book.created_at + chrono::Duration::minutes(episode.chapter_no.into())),
+        );
+        rss.push_str(&format!(
+            concat!(
+                "<item><title>{title}</title>",
+                r#"<guid isPermaLink="false">book-{book}-chapter-{no}</guid>"#,
+                r#"<enclosure url="{base}/feed/{token}/{book}/{file}" length="{length}" type="{mime}"/>"#,
+                "<pubDate>{date}</pubDate>",
+                "<itunes:episode>{no}</itunes:episode>",
+                "<itunes:episodeType>full</itunes:episodeType></item>"
+            ),
+            title = escape_xml(&episode.title),
+            book = book.id,
+            no = episode.chapter_no,
+            base = escape_xml(base),
+            token = token,
+            file = escape_xml(&episode.file_name),
+            length = episode.length,
+            mime = escape_xml(&episode.mime),
+            date = published.to_rfc2822(),
+        ));
+    }
+    rss.push_str("</channel></rss>");
+    rss
+}
+
+async fn feed(
+    State(state): State<AppStat>,
+    Path((token, feed)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    let book_id = feed
+        .strip_suffix(".rss")
+        .and_then(|id| id.parse::<i32>().ok())
+        .ok_or_else(|| AppError::not_found("feed not found"))?;
+    let db = &state.connections.db;
+    let book = feed_book(db, &token, book_id).await?;
+    let author = Author::find_by_id(book.author_id).one(db).await?;
+    let titles = Chapter::find()
+        .filter(chapter::Column::MusicId.eq(book.id))
+        .all(db)
+        .await?;
+    let files =
+        tools::chapter_files(state.book_dir.join(&book.file_folder)).map_err(AppError::internal)?;
+    let episodes = files
+        .into_iter()
+        .map(|(chapter_no, file)| Episode {
+            chapter_no,
+            title: titles
+                .iter()
+                .find(|chapter| chapter.chapter_no == chapter_no)
+                .map_or_else(|| format!("Chapter {}", chapter_no), |c| c.title.clone()),
+            file_name: file
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            length: std::fs::metadata(&file).map_or(0, |metadata| metadata.len()),
+            mime: mime_guess::from_path(&file)
+                .first_or_octet_stream()
+                .to_string(),
+        })
+        .collect::<Vec<_>>();
+    let has_cover = cover(&state.book_dir, &book, author.as_ref()).is_some();
+    let rss = render_feed(
+        &base_url(&headers),
+        &token,
+        &book,
+        author.as_ref().map_or("", |author| author.name.as_str()),
+        has_cover,
+        &episodes,
+    );
+    Ok((
+        [(header::CONTENT_TYPE, "application/rss+xml; charset=utf-8")],
+        rss,
+    )
+        .into_response())
+}
+
+/// a chapter file like `0001.mp3`, or the `cover`
+async fn episode(
+    State(state): State<AppStat>,
+    Path((token, book_id, file)): Path<(String, i32, String)>,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    let db = &state.connections.db;
+    let book = feed_book(db, &token, book_id).await?;
+    let not_found = || AppError::not_found(format!("{} not found", file));
+    let path = if file == "cover" {
+        let author = Author::find_by_id(book.author_id).one(db).await?;
+        cover(&state.book_dir, &book, author.as_ref()).ok_or_else(not_found)?
+    } else {
+        let chapter_no = file
+            .split_once('.')
+            .and_then(|(stem, _)| stem.parse::<i32>().ok())
+            .ok_or_else(not_found)?;
+        tools::chapter_files(state.book_dir.join(&book.file_folder))
+            .map_err(AppError::internal)?
+            .into_iter()
+            .find(|(no, _)| *no == chapter_no)
+            .map(|(_, path)| path)
+            .ok_or_else(not_found)?
+    };
+    Ok(crate::serve_file(path, headers).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use sea_orm::{DatabaseBackend, MockDatabase};
+
+    use crate::entities::{account, music};
+    use crate::error::ErrorCode;
+    use crate::fixtures;
+
+    use super::{feed_book, render_feed, Episode};
+
+    #[tokio::test]
+    async fn test_feed_needs_download() {
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([[account::Model {
+                can_download: false,
+                ..fixtures::account(1, "user")
+            }]])
+            .into_connection();
+        let error = feed_book(&db, "token", 1).await.unwrap_err();
+        assert_eq!(error.code, ErrorCode::Forbidden);
+
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([[fixtures::account(1, "user")]])
+            .append_query_results([[fixtures::book(1, "book")]])
+            .into_connection();
+        assert_eq!(feed_book(&db, "token", 1).await.unwrap().id, 1);
+    }
+
+    #[test]
+    fn test_render_feed() {
+        let book = music::Model {
+            file_folder: "author/book".to_string(),
+            language: Some("en".to_string()),
+            ..fixtures::book(3, "Tom & Jerry")
+        };
+        let episodes = [Episode {
+            chapter_no: 1,
+            title: "<one>".to_string(),
+            file_name: "0001.mp3".to_string(),
+            length: 42,
+            mime: "audio/mpeg".to_string(),
+        }];
+        let rss = render_feed(
+            "https://books.example",
+            "abc",
+            &book,
+            "author",
+            true,
+            &episodes,
+        );
+        assert!(rss.contains("<title>Tom &amp; Jerry</title>"));
+        assert!(rss.contains("<language>en</language>"));
+        assert!(rss.contains(r#"<itunes:image href="https://books.example/feed/abc/3/cover"/>"#));
+        assert!(rss.contains("<title>&lt;one&gt;</title>"));
+        assert!(rss.contains(
+            r#"<enclosure url="https://books.example/feed/abc/3/0001.mp3" length="42" type="audio/mpeg"/>"#
+        ));
+        assert!(rss.contains("<pubDate>Thu, 1 Jan 1970 00:01:00 +0000</pubDate>"));
+    }
+}
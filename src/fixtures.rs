@@ -0,0 +1,119 @@
+//! models and audio files for the tests, the models with every optional field empty
+//!
+//! a test only spells out the fields it cares about, like
+//! `music::Model { chapters: 3, ..book(1, "book") }`, so a new column is added here only.
+
+use std::path::Path;
+
+use crate::entities::{sea_orm_active_enums::ProgressState, *};
+
+/// a user account, `id` 0 is the admin
+pub(crate) fn account(id: i32, name: &str) -> account::Model {
+    account::Model {
+        id,
+        name: name.to_string(),
+        password: String::new(),
+        role_level: if id == 0 { 0 } else { 1 },
+        deleted_at: None,
+        subsonic_password: None,
+        feed_token: None,
+        can_download: true,
+    }
+}
+
+/// an author without avatar nor description
+pub(crate) fn author(id: i32, name: &str) -> author::Model {
+    author::Model {
+        id,
+        avatar: String::new(),
+        name: name.to_string(),
+        description: String::new(),
+        deleted_at: None,
+        created_at: Default::default(),
+        updated_at: Default::default(),
+        name_pinyin: None,
+        name_initials: None,
+    }
+}
+
+/// a book of author 1 with one chapter, in the folder `author/<name>`
+pub(crate) fn book(id: i32, name: &str) -> music::Model {
+    music::Model {
+        id,
+        author_id: 1,
+        name: name.to_string(),
+        chapters: 1,
+        file_folder: format!("author/{}", name),
+        deleted_at: None,
+        duration: None,
+        fingerprint: None,
+        created_at: Default::default(),
+        updated_at: Default::default(),
+        narrator: None,
+        series: None,
+        description: None,
+        name_pinyin: None,
+        name_initials: None,
+        language: None,
+    }
+}
+
+/// chapter `chapter_no` of `music_id`, titled after its number and of unknown duration
+pub(crate) fn chapter(music_id: i32, chapter_no: i32) -> chapter::Model {
+    chapter::Model {
+        id: chapter_no,
+        music_id,
+        chapter_no,
+        title: format!("Chapter {}", chapter_no),
+        duration: None,
+    }
+}
+
+/// the progress of account 1 in `music_id`, in progress at `position` seconds into `chapter_no`
+pub(crate) fn progress(music_id: i32, chapter_no: i32, position: f64) -> progress::Model {
+    progress::Model {
+        id: 1,
+        account_id: 1,
+        music_id,
+        chapter_no,
+        progress: position,
+        last_played_at: None,
+        state: ProgressState::InProgress,
+        finished_at: None,
+        finish_count: 0,
+        relisten_count: 0,
+    }
+}
+
+/// the sample rate of [`write_wav`]
+pub(crate) const SAMPLE_RATE: u32 = 8000;
+
+/// write a mono 16 bit wav, a tone whose loudness changes every 50 ms following `seed`
+pub(crate) fn write_wav(path: &Path, seconds: u32, seed: u32, gain: f32) {
+    let samples = (0..SAMPLE_RATE * seconds)
+        .map(|i| {
+            let frame = i / (SAMPLE_RATE / 20);
+            let loudness = ((frame ^ seed).wrapping_mul(2654435761) >> 24) as f32;
+            let tone = (i as f32 * 440. * std::f32::consts::TAU / SAMPLE_RATE as f32).sin();
+            (tone * loudness * 100. * gain) as i16
+        })
+        .collect::<Vec<_>>();
+    let data_len = samples.len() as u32 * 2;
+    let mut wav = vec![];
+    wav.extend(b"RIFF");
+    wav.extend((36 + data_len).to_le_bytes());
+    wav.extend(b"WAVEfmt ");
+    wav.extend(16u32.to_le_bytes());
+    wav.extend(1u16.to_le_bytes());
+    wav.extend(1u16.to_le_bytes());
+    wav.extend(SAMPLE_RATE.to_le_bytes());
+    wav.extend((SAMPLE_RATE * 2).to_le_bytes());
+    wav.extend(2u16.to_le_bytes());
+    wav.extend(16u16.to_le_bytes());
+    wav.extend(b"data");
+    wav.extend(data_len.to_le_bytes());
+    for sample in samples {
+        wav.extend(sample.to_le_bytes());
+    }
+    std::fs::write(path, wav).unwrap();
+}
@@ -2,7 +2,7 @@ use axum::headers::IfModifiedSince;
 use axum::response::Response;
 use axum::TypedHeader;
 use axum::{response::IntoResponse, routing::get, Router};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use dotenv::dotenv;
 #[cfg(not(target_os = "linux"))]
 use hyper::server::{accept::Accept, conn::AddrIncoming};
@@ -33,17 +33,25 @@ use tower_http::{
     cors::{Any, CorsLayer},
     services::ServeDir,
 };
-use tracing::{debug, info};
+use tracing::{debug, error, info, warn};
 
+mod api;
 mod auth;
 pub mod consts;
 mod database;
 pub mod entities;
+mod error;
+mod feed;
+#[cfg(test)]
+mod fixtures;
 mod management;
 mod middleware;
 mod music;
 pub(crate) mod progress;
+mod search;
+mod subsonic;
 pub mod tools;
+mod transcode;
 mod webui;
 
 lazy_static! {
@@ -65,6 +73,12 @@ pub(crate) struct AppStats {
     pub tera: Tera,
     pub connections: AppConnections,
     pub book_dir: PathBuf,
+    pub max_upload_size: u64,
+    /// canonicalized folders the book manager is allowed to import from
+    pub import_roots: Vec<PathBuf>,
+    pub search: search::SearchIndex,
+    pub transcoder: transcode::Transcoder,
+    pub uploads: management::UploadLocks,
 }
 pub(crate) struct AppConnections {
     pub db: DatabaseConnection,
@@ -99,6 +113,100 @@ pub struct Cli {
     /// the path store all books
     #[clap(short, long, env = "BOOKS", default_value = "./books")]
     book_dir: String,
+
+    /// the max size in bytes of a single uploaded file
+    #[clap(long, env = "MAX_UPLOAD_SIZE", default_value = "4294967296")]
+    max_upload_size: u64,
+
+    /// the folders the book manager can import books from, separated by ','
+    #[clap(
+        long,
+        env = "IMPORT_ROOTS",
+        default_value = "./import",
+        value_delimiter = ','
+    )]
+    import_roots: Vec<PathBuf>,
+
+    /// the days a deleted book, author or account stays in the trash before it's purged
+    #[clap(long, env = "TRASH_RETENTION_DAYS", default_value = "30")]
+    trash_retention_days: i64,
+
+    /// the ffmpeg used to transcode chapters
+    #[clap(long, env = "FFMPEG", default_value = "ffmpeg")]
+    ffmpeg: PathBuf,
+
+    /// the max size in bytes of the transcoded chapters kept on disk
+    #[clap(long, env = "TRANSCODE_CACHE_SIZE", default_value = "2147483648")]
+    transcode_cache_size: u64,
+
+    /// the max ffmpeg processes running at once, the other transcodes wait for one to end
+    #[clap(long, env = "MAX_TRANSCODES", default_value = "4")]
+    max_transcodes: usize,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// check the catalog against the files in the book dir, then exit
+    Check {
+        /// move books without folder to the trash and fix the chapter counts
+        #[clap(long)]
+        repair: bool,
+        /// print the report as json
+        #[clap(long)]
+        json: bool,
+    },
+}
+
+async fn run_check(db: &str, book_dir: &str, repair: bool, json: bool) -> eyre::Result<()> {
+    let db = init_mysql(db).await;
+    let report = tools::check::check_library(&db, std::path::Path::new(book_dir), repair).await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        for issue in &report.issues {
+            println!("{:?}", issue);
+        }
+        for repair in &report.repaired {
+            println!("repaired: {:?}", repair);
+        }
+        println!(
+            "checked {} books, {} issues, {} repaired",
+            report.books_checked,
+            report.issues.len(),
+            report.repaired.len()
+        );
+    }
+    Ok(())
+}
+
+/// purge the expired trash every hour
+async fn purge_trash_task(stat: AppStat, retention: chrono::Duration) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(3600));
+    loop {
+        interval.tick().await;
+        match tools::trash::purge_expired(&stat.connections.db, &stat.book_dir, retention).await {
+            Ok(0) => {}
+            Ok(purged) => info!("purged {} expired items from trash", purged),
+            Err(e) => error!("fail to purge trash: {}", e),
+        }
+    }
+}
+
+/// fill the missing audio info every hour, so the progress of edited and older books gets its
+/// durations
+async fn audio_info_task(stat: AppStat) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(3600));
+    loop {
+        interval.tick().await;
+        if let Err(e) =
+            tools::duplicates::refresh_audio_info(&stat.connections.db, &stat.book_dir).await
+        {
+            error!("fail to compute the audio info: {}", e);
+        }
+    }
 }
 
 pub fn init_log() {
@@ -150,10 +258,13 @@ fn setup_tera() -> Tera {
     let newplayer = include_str!("../templates/newplayer.tera");
     let manager = include_str!("../templates/manager.tera");
     let book_manager = include_str!("../templates/book_manager.tera");
+    let author_manager = include_str!("../templates/author_manager.tera");
     let account_manager = include_str!("../templates/account_manager.tera");
     let manager_base = include_str!("../templates/manager_base.tera");
     let user_op = include_str!("../templates/user_op.tera");
     let simple = include_str!("../templates/simple.tera");
+    let trash = include_str!("../templates/trash.tera");
+    let stats = include_str!("../templates/stats.tera");
     tera.add_raw_templates([
         ("index.tera", index),
         ("login.tera", login),
@@ -167,10 +278,13 @@ fn setup_tera() -> Tera {
         ("newplayer.tera", newplayer),
         ("manager.tera", manager),
         ("book_manager.tera", book_manager),
+        ("author_manager.tera", author_manager),
         ("account_manager.tera", account_manager),
         ("manager_base.tera", manager_base),
         ("user_op.tera", user_op),
         ("simple.tera", simple),
+        ("trash.tera", trash),
+        ("stats.tera", stats),
     ])
     .unwrap();
     tera
@@ -181,18 +295,50 @@ pub async fn app_main() -> eyre::Result<()> {
     init_log();
     let cli = Cli::parse();
     debug!("cli:{:?}", cli);
+    if let Some(Command::Check { repair, json }) = cli.command {
+        return run_check(&cli.db, &cli.book_dir, repair, json).await;
+    }
 
     info!("redis url:{}", cli.redis);
     info!("database url:{}", cli.db);
     info!("starting server,connecting to database and redis");
 
+    let import_roots = cli
+        .import_roots
+        .iter()
+        .filter_map(|root| match root.canonicalize() {
+            Ok(root) => Some(root),
+            Err(e) => {
+                warn!("skip import root {:?}: {}", root, e);
+                None
+            }
+        })
+        .collect();
+    info!("import roots:{:?}", import_roots);
+
     info!("database connected");
     let (db, redis) = init_db(&cli.db, &cli.redis).await;
     let stat: AppStat = Arc::new(AppStats {
         tera: setup_tera(),
         connections: AppConnections::new(db, redis),
         book_dir: PathBuf::from(cli.book_dir.clone()),
+        max_upload_size: cli.max_upload_size,
+        import_roots,
+        search: search::SearchIndex::new()?,
+        transcoder: transcode::Transcoder::new(
+            cli.ffmpeg.clone(),
+            PathBuf::from(&cli.book_dir).join(transcode::CACHE_DIR),
+            cli.transcode_cache_size,
+            cli.max_transcodes,
+        ),
+        uploads: Default::default(),
     });
+    tokio::spawn(search::rebuild_task(stat.clone()));
+    tokio::spawn(purge_trash_task(
+        stat.clone(),
+        chrono::Duration::days(cli.trash_retention_days),
+    ));
+    tokio::spawn(audio_info_task(stat.clone()));
     let fetch_book_router = Router::new()
         .nest_service("/fetchbook", ServeDir::new(cli.book_dir))
         .route_layer(axum::middleware::from_fn_with_state(
@@ -201,10 +347,22 @@ pub async fn app_main() -> eyre::Result<()> {
         ));
 
     let app = Router::new()
-        .nest("/account", auth::route(stat.clone()))
-        .nest("/music", music::route(stat.clone()))
-        .nest("/progress", progress::route(stat.clone()))
+        .nest("/api/v1", api::route(stat.clone()))
+        .nest(
+            "/account",
+            auth::route(stat.clone()).layer(axum::middleware::from_fn(api::deprecated)),
+        )
+        .nest(
+            "/music",
+            music::route(stat.clone()).layer(axum::middleware::from_fn(api::deprecated)),
+        )
+        .nest(
+            "/progress",
+            progress::route(stat.clone()).layer(axum::middleware::from_fn(api::deprecated)),
+        )
         .nest("/webui", webui::route(stat.clone()))
+        .nest("/rest", subsonic::route())
+        .nest("/feed", feed::route())
         .nest("/management", management::route(stat.clone()))
         .merge(fetch_book_router)
         .route_layer(CookieManagerLayer::new()) // above route need login auth, so need cookie service
@@ -260,7 +418,13 @@ pub async fn app_main() -> eyre::Result<()> {
         )
         .route_layer(
             CorsLayer::new()
-                .allow_methods([Method::GET, Method::POST])
+                .allow_methods([
+                    Method::GET,
+                    Method::POST,
+                    Method::PUT,
+                    Method::HEAD,
+                    Method::PATCH,
+                ])
                 .allow_origin(Any),
         )
         .with_state(stat);
@@ -291,6 +455,16 @@ pub async fn app_main() -> eyre::Result<()> {
     Ok(())
 }
 
+/// serve the file with the range and cache headers of the request
+pub(crate) async fn serve_file(path: PathBuf, headers: hyper::HeaderMap) -> Response {
+    let mut request = hyper::Request::new(axum::body::Body::empty());
+    *request.headers_mut() = headers;
+    match tower::ServiceExt::oneshot(tower_http::services::ServeFile::new(path), request).await {
+        Ok(response) => response.map(axum::body::boxed),
+        Err(never) => match never {},
+    }
+}
+
 fn cached_response(
     if_last_modified: Option<TypedHeader<IfModifiedSince>>,
     text_type: TypedHeader<axum::headers::ContentType>,
@@ -393,6 +567,11 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_setup_tera() {
+        super::setup_tera();
+    }
+
     #[test]
     fn test_build_time() {
         let now: DateTime<Utc> = Utc::now();
@@ -0,0 +1,122 @@
+//! admin endpoints to edit authors
+
+use axum::{
+    extract::{Multipart, State},
+    response::{IntoResponse, Response},
+    Form, Json,
+};
+use hyper::StatusCode;
+use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+use tracing::error;
+
+use super::manage_response;
+use crate::{
+    entities::{prelude::*, *},
+    search,
+    tools::authors as author_tools,
+    AppStat,
+};
+
+fn edit_response(result: eyre::Result<author::Model>) -> Response {
+    match result {
+        Ok(_) => manage_response(StatusCode::OK, "success"),
+        Err(e) => {
+            error!("fail to edit author: {}", e);
+            manage_response(StatusCode::BAD_REQUEST, format!("failed: {}", e))
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct RenamePara {
+    id: i32,
+    name: String,
+}
+
+pub(super) async fn rename_author(
+    State(state): State<AppStat>,
+    Form(para): Form<RenamePara>,
+) -> Response {
+    let result =
+        author_tools::rename_author(&state.connections.db, &state.book_dir, para.id, para.name)
+            .await;
+    if let Ok(author) = &result {
+        search::refresh_author(&state, author.id);
+    }
+    edit_response(result)
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct DescriptionPara {
+    id: i32,
+    description: String,
+}
+
+pub(super) async fn set_description(
+    State(state): State<AppStat>,
+    Form(para): Form<DescriptionPara>,
+) -> Response {
+    let result =
+        author_tools::set_author_description(&state.connections.db, para.id, para.description)
+            .await;
+    edit_response(result)
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct MergePara {
+    from: i32,
+    into: i32,
+}
+
+pub(super) async fn merge_authors(
+    State(state): State<AppStat>,
+    Form(para): Form<MergePara>,
+) -> Response {
+    let result =
+        author_tools::merge_authors(&state.connections.db, &state.book_dir, para.from, para.into)
+            .await;
+    if let Ok(author) = &result {
+        search::refresh_author(&state, author.id);
+    }
+    edit_response(result)
+}
+
+/// receive the `id` and `file` fields, the image is resized before it's saved
+pub(super) async fn set_avatar(State(state): State<AppStat>, mut multipart: Multipart) -> Response {
+    let result = async {
+        let mut id = None;
+        let mut image = None;
+        while let Some(field) = multipart.next_field().await? {
+            match field.name() {
+                Some("id") => id = Some(field.text().await?.parse::<i32>()?),
+                Some("file") => image = Some(field.bytes().await?.to_vec()),
+                _ => {}
+            }
+        }
+        let (Some(id), Some(image)) = (id, image) else {
+            eyre::bail!("id and file are required");
+        };
+        author_tools::set_author_avatar(&state.connections.db, &state.book_dir, id, image).await
+    }
+    .await;
+    edit_response(result)
+}
+
+/// the groups of authors that look like the same person
+pub(super) async fn duplicates(State(state): State<AppStat>) -> Response {
+    let authors = Author::find()
+        .filter(author::Column::DeletedAt.is_null())
+        .all(&state.connections.db)
+        .await;
+    match authors {
+        Ok(authors) => (
+            StatusCode::OK,
+            Json(author_tools::duplicate_authors(authors)),
+        )
+            .into_response(),
+        Err(e) => {
+            error!("fail to list authors: {}", e);
+            manage_response(StatusCode::INTERNAL_SERVER_ERROR, "database error")
+        }
+    }
+}
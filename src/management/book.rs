@@ -0,0 +1,239 @@
+//! admin endpoints to edit a book after it's imported
+
+use axum::{
+    extract::{Multipart, State},
+    response::{IntoResponse, Response},
+    Form, Json,
+};
+use hyper::StatusCode;
+use tokio::io::AsyncWriteExt;
+use tracing::error;
+
+use super::manage_response;
+use crate::{
+    search,
+    tools::{self, duplicates, edit, StagingDir},
+    AppStat,
+};
+
+fn edit_response(result: eyre::Result<()>) -> Response {
+    match result {
+        Ok(()) => manage_response(StatusCode::OK, "success"),
+        Err(e) => {
+            error!("fail to edit book: {}", e);
+            manage_response(StatusCode::BAD_REQUEST, format!("failed: {}", e))
+        }
+    }
+}
+
+/// like [`edit_response`], the search index is refreshed when the book changed
+fn refresh_response(state: &AppStat, id: i32, result: eyre::Result<()>) -> Response {
+    if result.is_ok() {
+        search::refresh_books(state, vec![id]);
+    }
+    edit_response(result)
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct RenamePara {
+    id: i32,
+    name: String,
+}
+
+pub(super) async fn rename_book(
+    State(state): State<AppStat>,
+    Form(para): Form<RenamePara>,
+) -> Response {
+    let result = edit::rename_book(&state.connections.db, &state.book_dir, para.id, para.name)
+        .await
+        .map(|_| ());
+    refresh_response(&state, para.id, result)
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct ChangeAuthorPara {
+    id: i32,
+    author: String,
+}
+
+pub(super) async fn change_author(
+    State(state): State<AppStat>,
+    Form(para): Form<ChangeAuthorPara>,
+) -> Response {
+    let result =
+        edit::change_book_author(&state.connections.db, &state.book_dir, para.id, para.author)
+            .await
+            .map(|_| ());
+    refresh_response(&state, para.id, result)
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct MetadataPara {
+    id: i32,
+    #[serde(default)]
+    narrator: String,
+    #[serde(default)]
+    series: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    language: String,
+}
+
+pub(super) async fn set_metadata(
+    State(state): State<AppStat>,
+    Form(para): Form<MetadataPara>,
+) -> Response {
+    let metadata = edit::BookMetadata {
+        narrator: para.narrator,
+        series: para.series,
+        description: para.description,
+        language: para.language,
+    };
+    let result = edit::set_book_metadata(&state.connections.db, para.id, metadata)
+        .await
+        .map(|_| ());
+    refresh_response(&state, para.id, result)
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct TagsPara {
+    id: i32,
+    /// the tag names, separated by ','
+    #[serde(default)]
+    tags: String,
+}
+
+pub(super) async fn set_tags(State(state): State<AppStat>, Form(para): Form<TagsPara>) -> Response {
+    let tags = para.tags.split(',').map(str::to_string).collect();
+    let result = edit::set_book_tags(&state.connections.db, para.id, tags)
+        .await
+        .map(|_| ());
+    edit_response(result)
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct DeletePara {
+    id: i32,
+    /// delete the folder too, otherwise only the book is removed from the library
+    #[serde(default)]
+    delete_files: bool,
+}
+
+pub(super) async fn delete_book(
+    State(state): State<AppStat>,
+    Form(para): Form<DeletePara>,
+) -> Response {
+    let result = edit::delete_book(
+        &state.connections.db,
+        &state.book_dir,
+        para.id,
+        para.delete_files,
+    )
+    .await;
+    refresh_response(&state, para.id, result)
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct ReorderPara {
+    id: i32,
+    /// the old chapter numbers in their new order, separated by ','
+    order: String,
+}
+
+pub(super) async fn reorder_chapters(
+    State(state): State<AppStat>,
+    Form(para): Form<ReorderPara>,
+) -> Response {
+    let order = para
+        .order
+        .split(',')
+        .map(|no| no.trim().parse::<i32>())
+        .collect::<Result<Vec<_>, _>>();
+    let Ok(order) = order else {
+        return manage_response(StatusCode::BAD_REQUEST, "invalid order");
+    };
+    let result =
+        edit::reorder_chapters(&state.connections.db, &state.book_dir, para.id, &order).await;
+    refresh_response(&state, para.id, result)
+}
+
+/// receive the `id`, `chapter_no` and `file` fields and replace the chapter file
+pub(super) async fn replace_chapter(
+    State(state): State<AppStat>,
+    mut multipart: Multipart,
+) -> Response {
+    let mut book_id = None;
+    let result = async {
+        // the new file is written next to the books, so it can be renamed into place
+        let staging = StagingDir::new(&state.book_dir)?;
+        let mut id = None;
+        let mut chapter_no = None;
+        let mut new_file = None;
+        let mut title = None;
+        while let Some(mut field) = multipart.next_field().await? {
+            match field.name() {
+                Some("id") => id = Some(field.text().await?.parse::<i32>()?),
+                Some("chapter_no") => chapter_no = Some(field.text().await?.parse::<i32>()?),
+                Some("file") => {
+                    let file_name = field.file_name().unwrap_or_default().to_string();
+                    if !tools::is_audio_file(&file_name) {
+                        eyre::bail!("not an audio file: {}", file_name);
+                    }
+                    // the title when the file has no title tag, like on import
+                    title = std::path::Path::new(&file_name)
+                        .file_stem()
+                        .map(|stem| stem.to_string_lossy().into_owned());
+                    let ext = std::path::Path::new(&file_name)
+                        .extension()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                        .into_owned();
+                    let path = staging.path().join(format!("chapter.{}", ext));
+                    let mut file = tokio::fs::File::create(&path).await?;
+                    let mut size = 0;
+                    while let Some(chunk) = field.chunk().await? {
+                        size += chunk.len() as u64;
+                        if size > state.max_upload_size {
+                            eyre::bail!("upload too large");
+                        }
+                        file.write_all(&chunk).await?;
+                    }
+                    file.flush().await?;
+                    new_file = Some(path);
+                }
+                _ => {}
+            }
+        }
+        let (Some(id), Some(chapter_no), Some(new_file)) = (id, chapter_no, new_file) else {
+            eyre::bail!("id, chapter_no and file are required");
+        };
+        book_id = Some(id);
+        edit::replace_chapter(
+            &state.connections.db,
+            &state.book_dir,
+            id,
+            chapter_no,
+            &new_file,
+            title,
+        )
+        .await
+    }
+    .await;
+    match book_id {
+        // the chapter titles are in the search index
+        Some(id) => refresh_response(&state, id, result),
+        None => edit_response(result),
+    }
+}
+
+/// the groups of books that look like the same book
+pub(super) async fn duplicates(State(state): State<AppStat>) -> Response {
+    match duplicates::duplicate_report(&state.connections.db).await {
+        Ok(groups) => (StatusCode::OK, Json(groups)).into_response(),
+        Err(e) => {
+            error!("fail to find duplicate books: {}", e);
+            manage_response(StatusCode::INTERNAL_SERVER_ERROR, format!("failed: {}", e))
+        }
+    }
+}
@@ -0,0 +1,36 @@
+//! admin endpoints for the library integrity checker
+
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    Json,
+};
+use hyper::StatusCode;
+use tracing::error;
+
+use super::manage_response;
+use crate::{search, tools::check, AppStat};
+
+async fn check_response(state: &AppStat, repair: bool) -> Response {
+    match check::check_library(&state.connections.db, &state.book_dir, repair).await {
+        Ok(report) => {
+            if !report.repaired.is_empty() {
+                search::refresh_all(state);
+            }
+            (StatusCode::OK, Json(report)).into_response()
+        }
+        Err(e) => {
+            error!("fail to check library: {}", e);
+            manage_response(StatusCode::INTERNAL_SERVER_ERROR, format!("failed: {}", e))
+        }
+    }
+}
+
+/// report the issues without touching anything
+pub(super) async fn check_library(State(state): State<AppStat>) -> Response {
+    check_response(&state, false).await
+}
+
+pub(super) async fn repair_library(State(state): State<AppStat>) -> Response {
+    check_response(&state, true).await
+}
@@ -1,20 +1,78 @@
-use std::path::Path;
+use std::{
+    path::{Component, Path, PathBuf},
+    time::UNIX_EPOCH,
+};
 
 use axum::{
-    extract::State,
-    response::IntoResponse,
-    routing::{get, post},
+    extract::{DefaultBodyLimit, State},
+    response::{IntoResponse, Response},
+    routing::{get, head, post},
     Form, Json, Router,
 };
 use hyper::{header::LOCATION, StatusCode};
 use tower::ServiceBuilder;
+use tracing::error;
+
+use crate::{search, tools, tools::archive::ArchiveKind, AppStat};
+
+mod author;
+mod book;
+mod check;
+mod trash;
+mod upload;
+
+pub(crate) use upload::UploadLocks;
 
-use crate::{tools, AppStat};
+/// the max size of an uploaded avatar before it's resized
+const AVATAR_UPLOAD_LIMIT: usize = 20 * 1024 * 1024;
 
 pub(crate) fn route(state: AppStat) -> Router<AppStat> {
     Router::new()
         .route("/listfile", get(listfile))
         .route("/selectpath", post(selectpath))
+        .route(
+            "/upload",
+            post(upload::upload_multipart).layer(DefaultBodyLimit::max(
+                state.max_upload_size.try_into().unwrap_or(usize::MAX),
+            )),
+        )
+        .route("/upload/import", post(upload::upload_import))
+        .route("/book/rename", post(book::rename_book))
+        .route("/book/author", post(book::change_author))
+        .route("/book/delete", post(book::delete_book))
+        .route("/book/reorder", post(book::reorder_chapters))
+        .route("/book/metadata", post(book::set_metadata))
+        .route("/book/tags", post(book::set_tags))
+        .route("/search/reindex", post(reindex))
+        .route("/book/duplicates", get(book::duplicates))
+        .route(
+            "/book/replace",
+            post(book::replace_chapter).layer(DefaultBodyLimit::max(
+                state.max_upload_size.try_into().unwrap_or(usize::MAX),
+            )),
+        )
+        .route(
+            "/tus",
+            post(upload::tus_create).options(upload::tus_options),
+        )
+        .route(
+            "/tus/:group/:id",
+            head(upload::tus_head).patch(upload::tus_patch),
+        )
+        .route("/author/rename", post(author::rename_author))
+        .route("/author/description", post(author::set_description))
+        .route("/author/merge", post(author::merge_authors))
+        .route("/author/duplicates", get(author::duplicates))
+        .route("/check", get(check::check_library))
+        .route("/check/repair", post(check::repair_library))
+        .route("/trash", get(trash::list_trash))
+        .route("/trash/move", post(trash::move_to_trash))
+        .route("/trash/restore", post(trash::restore))
+        .route("/trash/purge", post(trash::purge))
+        .route(
+            "/author/avatar",
+            post(author::set_avatar).layer(DefaultBodyLimit::max(AVATAR_UPLOAD_LIMIT)),
+        )
         .route_layer(
             ServiceBuilder::new()
                 .layer(axum::middleware::from_fn_with_state(
@@ -26,6 +84,25 @@ pub(crate) fn route(state: AppStat) -> Router<AppStat> {
                 )),
         )
 }
+#[derive(Debug, serde::Serialize, Clone)]
+pub(crate) struct ManageResult {
+    code: i32,
+    msg: String,
+}
+
+/// a json [`ManageResult`], the code is 0 for a success status and -1 otherwise
+fn manage_response(status: StatusCode, msg: impl Into<String>) -> Response {
+    let code = if status.is_success() { 0 } else { -1 };
+    (
+        status,
+        Json(ManageResult {
+            code,
+            msg: msg.into(),
+        }),
+    )
+        .into_response()
+}
+
 #[derive(Debug, serde::Serialize, Clone)]
 enum FileType {
     Dir,
@@ -35,83 +112,123 @@ enum FileType {
 struct File {
     file_type: FileType,
     file_name: String,
+    /// the path relative to the import root
+    path: String,
+    size: u64,
+    /// seconds since unix epoch
+    modified: Option<u64>,
+    is_audio: bool,
+    is_archive: bool,
 }
 #[derive(Debug, serde::Serialize, Clone)]
 struct FileList {
     code: i32,
     msg: String,
+    root: usize,
+    path: String,
     file_list: Vec<File>,
 }
 #[derive(Debug, serde::Deserialize)]
 struct ListFilePara {
+    root: usize,
+    #[serde(default)]
     path: String,
 }
-async fn listfile(para: Form<ListFilePara>) -> impl IntoResponse {
+
+/// resolve `relative` inside the import root `root`, rejecting `..`, absolute paths and symlinks
+/// pointing outside of the root
+fn resolve_import_path(roots: &[PathBuf], root: usize, relative: &str) -> eyre::Result<PathBuf> {
+    let root = roots
+        .get(root)
+        .ok_or_else(|| eyre::eyre!("no such import root: {}", root))?;
+    let relative = Path::new(relative);
+    for component in relative.components() {
+        match component {
+            Component::Normal(_) | Component::CurDir => {}
+            _ => eyre::bail!("invalid path: {:?}", relative),
+        }
+    }
+    let path = root.join(relative).canonicalize()?;
+    if !path.starts_with(root) {
+        eyre::bail!("path {:?} is outside of the import root", relative);
+    }
+    Ok(path)
+}
+
+fn relative_path(roots: &[PathBuf], root: usize, path: &Path) -> String {
+    path.strip_prefix(&roots[root])
+        .unwrap_or(path)
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn list_dir(roots: &[PathBuf], root: usize, dir: &Path) -> std::io::Result<Vec<File>> {
     let mut file_list = Vec::new();
-    let files = std::fs::read_dir(&para.path).map_err(|e| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(FileList {
-                code: -1,
-                msg: format!("read_dir error: {}", e),
-                file_list: vec![],
-            }),
-        )
-    })?;
-    for file in files {
-        let file = file.map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(FileList {
-                    code: -1,
-                    msg: format!("read_dir error: {}", e),
-                    file_list: vec![],
-                }),
-            )
-        })?;
-        let file_type = if file
-            .file_type()
-            .map_err(|e| {
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    Json(FileList {
-                        code: -1,
-                        msg: format!("read_dir error: {}", e),
-                        file_list: vec![],
-                    }),
-                )
-            })?
-            .is_dir()
-        {
+    for file in std::fs::read_dir(dir)? {
+        let file = file?;
+        let path = file.path();
+        // hide symlinks escaping the import root
+        let Ok(real_path) = path.canonicalize() else {
+            continue;
+        };
+        if !real_path.starts_with(&roots[root]) {
+            continue;
+        }
+        let metadata = real_path.metadata()?;
+        let file_type = if metadata.is_dir() {
             FileType::Dir
         } else {
             FileType::File
         };
-        let file_name = file.file_name().into_string().map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(FileList {
-                    code: -1,
-                    msg: format!("read_dir error: {:?}", e),
-                    file_list: vec![],
-                }),
-            )
-        })?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs());
         file_list.push(File {
+            is_audio: metadata.is_file() && tools::is_audio_file(&path),
+            is_archive: metadata.is_file() && ArchiveKind::detect(&path).is_some(),
             file_type,
-            file_name,
+            file_name: file.file_name().to_string_lossy().into_owned(),
+            path: relative_path(roots, root, &path),
+            size: metadata.len(),
+            modified,
         });
     }
+    file_list.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+    Ok(file_list)
+}
+
+async fn listfile(State(state): State<AppStat>, para: Form<ListFilePara>) -> impl IntoResponse {
+    let error = |msg: String| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(FileList {
+                code: -1,
+                msg,
+                root: para.root,
+                path: para.path.clone(),
+                file_list: vec![],
+            }),
+        )
+    };
+    let dir = resolve_import_path(&state.import_roots, para.root, &para.path)
+        .map_err(|e| error(format!("invalid path: {}", e)))?;
+    let file_list = list_dir(&state.import_roots, para.root, &dir)
+        .map_err(|e| error(format!("read_dir error: {}", e)))?;
 
     Ok::<_, (StatusCode, Json<FileList>)>(Json(FileList {
         code: 0,
         msg: "success".to_string(),
+        root: para.root,
+        path: relative_path(&state.import_roots, para.root, &dir),
         file_list,
     }))
 }
 
 #[derive(Debug, serde::Deserialize)]
 struct SelectPathPara {
+    root: usize,
     path: String,
     name: String,
     author: String,
@@ -121,16 +238,26 @@ async fn selectpath(
     State(state): State<AppStat>,
     Form(para): Form<SelectPathPara>,
 ) -> impl IntoResponse {
+    let source = match resolve_import_path(&state.import_roots, para.root, &para.path) {
+        Ok(source) => source,
+        Err(e) => {
+            error!("invalid import path: {}", e);
+            return (StatusCode::BAD_REQUEST, [(LOCATION, "/")], "invalid path");
+        }
+    };
     let result = tools::create_new_book(
         para.author,
         para.name,
         &state.book_dir,
-        Path::new(&para.path),
+        &source,
         &state.connections.db,
     )
     .await;
     match result {
-        Ok(_) => (StatusCode::OK, [(LOCATION, "/")], "success"),
+        Ok(book_id) => {
+            search::refresh_books(&state, vec![book_id]);
+            (StatusCode::OK, [(LOCATION, "/")], "success")
+        }
         Err(_) => (
             StatusCode::INTERNAL_SERVER_ERROR,
             [(LOCATION, "/")],
@@ -138,3 +265,55 @@ async fn selectpath(
         ),
     }
 }
+
+/// rebuild the search index now instead of waiting for the periodic rebuild
+async fn reindex(State(state): State<AppStat>) -> Response {
+    match state.search.rebuild(&state.connections.db).await {
+        Ok(count) => manage_response(StatusCode::OK, format!("{} books indexed", count)),
+        Err(e) => {
+            error!("fail to rebuild the search index: {}", e);
+            manage_response(StatusCode::INTERNAL_SERVER_ERROR, format!("failed: {}", e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{list_dir, resolve_import_path};
+
+    #[test]
+    fn test_resolve_import_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir_all(root.join("book")).unwrap();
+        let roots = vec![root.canonicalize().unwrap()];
+        assert_eq!(
+            resolve_import_path(&roots, 0, "book").unwrap(),
+            roots[0].join("book")
+        );
+        assert_eq!(resolve_import_path(&roots, 0, "").unwrap(), roots[0]);
+        assert!(resolve_import_path(&roots, 0, "..").is_err());
+        assert!(resolve_import_path(&roots, 0, "book/../..").is_err());
+        assert!(resolve_import_path(&roots, 0, "/etc").is_err());
+        assert!(resolve_import_path(&roots, 1, "book").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_escape() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(dir.path().join("outside")).unwrap();
+        std::fs::write(root.join("01.mp3"), "1").unwrap();
+        std::os::unix::fs::symlink(dir.path().join("outside"), root.join("link")).unwrap();
+        let roots = vec![root.canonicalize().unwrap()];
+        assert!(resolve_import_path(&roots, 0, "link").is_err());
+
+        let files = list_dir(&roots, 0, &roots[0]).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, "01.mp3");
+        assert_eq!(files[0].size, 1);
+        assert!(files[0].is_audio);
+    }
+}
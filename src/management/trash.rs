@@ -0,0 +1,70 @@
+//! admin endpoints for the trash
+
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    Form, Json,
+};
+use hyper::StatusCode;
+use tracing::error;
+
+use super::manage_response;
+use crate::{
+    search,
+    tools::trash::{self, TrashKind},
+    AppStat,
+};
+
+fn trash_response(state: &AppStat, para: &TrashPara, result: eyre::Result<()>) -> Response {
+    if result.is_ok() && para.kind == TrashKind::Book {
+        search::refresh_books(state, vec![para.id]);
+    }
+    match result {
+        Ok(()) => manage_response(StatusCode::OK, "success"),
+        Err(e) => {
+            error!("trash operation failed: {}", e);
+            manage_response(StatusCode::BAD_REQUEST, format!("failed: {}", e))
+        }
+    }
+}
+
+pub(super) async fn list_trash(State(state): State<AppStat>) -> Response {
+    match trash::list_trash(&state.connections.db).await {
+        Ok(trash) => (StatusCode::OK, Json(trash)).into_response(),
+        Err(e) => {
+            error!("fail to list trash: {}", e);
+            manage_response(StatusCode::INTERNAL_SERVER_ERROR, "database error")
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct TrashPara {
+    kind: TrashKind,
+    id: i32,
+}
+
+pub(super) async fn move_to_trash(
+    State(state): State<AppStat>,
+    Form(para): Form<TrashPara>,
+) -> Response {
+    let mut result = trash::trash(&state.connections.db, para.kind, para.id).await;
+    if result.is_ok() && para.kind == TrashKind::Account {
+        let mut redis = state.connections.redis.lock().await;
+        result = trash::revoke_sessions(&mut redis, para.id)
+            .await
+            .map(|_| ())
+            .map_err(Into::into);
+    }
+    trash_response(&state, &para, result)
+}
+
+pub(super) async fn restore(State(state): State<AppStat>, Form(para): Form<TrashPara>) -> Response {
+    let result = trash::restore(&state.connections.db, para.kind, para.id).await;
+    trash_response(&state, &para, result)
+}
+
+pub(super) async fn purge(State(state): State<AppStat>, Form(para): Form<TrashPara>) -> Response {
+    let result = trash::purge(&state.connections.db, &state.book_dir, para.kind, para.id).await;
+    trash_response(&state, &para, result)
+}
@@ -0,0 +1,642 @@
+//! upload books over http, either in one multipart request or chunk by chunk with a tus-style protocol
+//!
+//! uploaded files and archives are stored in `book_dir/.uploads/{group}`, one group per book, and then imported
+//! with [`tools::create_new_book`].
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::Bytes,
+    extract::{BodyStream, Multipart, Path as UrlPath, State},
+    response::{IntoResponse, Response},
+    Form,
+};
+use base64::Engine;
+use futures::{Stream, StreamExt};
+use hyper::{header, HeaderMap, StatusCode};
+use tokio::{io::AsyncWriteExt, sync::OwnedMutexGuard};
+use tracing::{debug, error};
+
+use super::manage_response;
+use crate::{
+    search,
+    tools::{
+        self,
+        archive::{self, ArchiveKind, ExtractLimits},
+    },
+    AppStat,
+};
+
+const TUS_VERSION: &str = "1.0.0";
+/// the folder under the upload dir keeping the state of unfinished tus uploads
+const TUS_META_DIR: &str = ".tus";
+const PART_EXTENSION: &str = "part";
+
+#[derive(Debug)]
+pub(super) enum UploadError {
+    NotFound,
+    OffsetMismatch(u64),
+    TooLarge,
+    BadRequest(String),
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for UploadError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl IntoResponse for UploadError {
+    fn into_response(self) -> Response {
+        match self {
+            UploadError::NotFound => manage_response(StatusCode::NOT_FOUND, "upload not found"),
+            UploadError::OffsetMismatch(offset) => manage_response(
+                StatusCode::CONFLICT,
+                format!("offset mismatch, current offset: {}", offset),
+            ),
+            UploadError::TooLarge => {
+                manage_response(StatusCode::PAYLOAD_TOO_LARGE, "upload too large")
+            }
+            UploadError::BadRequest(msg) => manage_response(StatusCode::BAD_REQUEST, msg),
+            UploadError::Io(e) => {
+                error!("upload io error: {}", e);
+                manage_response(StatusCode::INTERNAL_SERVER_ERROR, "io error")
+            }
+        }
+    }
+}
+
+/// a group is the name of the folder holding the files of one book
+fn check_group(group: &str) -> Result<(), UploadError> {
+    let valid = !group.is_empty()
+        && group.len() <= 64
+        && group
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(UploadError::BadRequest(format!("invalid group: {}", group)))
+    }
+}
+
+/// only plain audio or archive file names are accepted, never a path
+fn check_file_name(file_name: &str) -> Result<(), UploadError> {
+    let path = Path::new(file_name);
+    let plain = !file_name.is_empty()
+        && !file_name.starts_with('.')
+        && !file_name.contains(['/', '\\'])
+        && path.file_name().map(|f| f == file_name).unwrap_or(false);
+    if plain && (tools::is_audio_file(path) || ArchiveKind::detect(path).is_some()) {
+        Ok(())
+    } else {
+        Err(UploadError::BadRequest(format!(
+            "invalid file name: {}",
+            file_name
+        )))
+    }
+}
+
+/// parse the `Upload-Metadata` header: comma separated `key base64(value)` pairs
+fn parse_metadata(metadata: &str) -> HashMap<String, String> {
+    metadata
+        .split(',')
+        .filter_map(|pair| {
+            let mut kv = pair.trim().splitn(2, ' ');
+            let key = kv.next()?;
+            if key.is_empty() {
+                return None;
+            }
+            let value = match kv.next() {
+                Some(value) => base64::engine::general_purpose::STANDARD
+                    .decode(value.trim())
+                    .ok()
+                    .and_then(|v| String::from_utf8(v).ok())?,
+                None => String::new(),
+            };
+            Some((key.to_string(), value))
+        })
+        .collect()
+}
+
+fn header_u64(headers: &HeaderMap, name: &str) -> Result<u64, UploadError> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| UploadError::BadRequest(format!("missing or invalid {}", name)))
+}
+
+/// the tus uploads being written, by id
+///
+/// a patch holds the lock of its upload from the offset check to its last byte, so two patches at
+/// the same offset can't both pass the check and interleave their bytes in the part file.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct UploadLocks(Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>);
+
+impl UploadLocks {
+    async fn lock(&self, id: &str) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.0.lock().unwrap();
+            // nobody holds nor waits for the locks only kept here
+            locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+            locks.entry(id.to_string()).or_default().clone()
+        };
+        lock.lock_owned().await
+    }
+}
+
+/// the state of a tus upload, stored as json in `.uploads/.tus/{group}/{id}.json`
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+struct TusUpload {
+    group: String,
+    file_name: String,
+    length: u64,
+}
+
+impl TusUpload {
+    fn meta_path(upload_root: &Path, group: &str, id: &str) -> PathBuf {
+        upload_root
+            .join(TUS_META_DIR)
+            .join(group)
+            .join(format!("{}.json", id))
+    }
+
+    fn file_path(&self, upload_root: &Path) -> PathBuf {
+        upload_root.join(&self.group).join(&self.file_name)
+    }
+
+    fn part_path(&self, upload_root: &Path) -> PathBuf {
+        upload_root
+            .join(&self.group)
+            .join(format!("{}.{}", self.file_name, PART_EXTENSION))
+    }
+
+    async fn create(&self, upload_root: &Path, id: &str) -> Result<(), UploadError> {
+        let meta_path = Self::meta_path(upload_root, &self.group, id);
+        tokio::fs::create_dir_all(meta_path.parent().unwrap()).await?;
+        tokio::fs::create_dir_all(upload_root.join(&self.group)).await?;
+        if self.file_path(upload_root).exists() || self.part_path(upload_root).exists() {
+            return Err(UploadError::BadRequest(format!(
+                "file {} already uploaded",
+                self.file_name
+            )));
+        }
+        tokio::fs::File::create(self.part_path(upload_root)).await?;
+        let meta = serde_json::to_vec(self).map_err(std::io::Error::from)?;
+        tokio::fs::write(meta_path, meta).await?;
+        if self.length == 0 {
+            tokio::fs::rename(self.part_path(upload_root), self.file_path(upload_root)).await?;
+        }
+        Ok(())
+    }
+
+    async fn load(upload_root: &Path, group: &str, id: &str) -> Result<Self, UploadError> {
+        check_group(group)?;
+        if !id.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(UploadError::NotFound);
+        }
+        let meta = tokio::fs::read(Self::meta_path(upload_root, group, id))
+            .await
+            .map_err(|_| UploadError::NotFound)?;
+        serde_json::from_slice(&meta).map_err(|_| UploadError::NotFound)
+    }
+
+    /// the number of bytes received so far
+    async fn offset(&self, upload_root: &Path) -> Result<u64, UploadError> {
+        if self.file_path(upload_root).exists() {
+            return Ok(self.length);
+        }
+        match tokio::fs::metadata(self.part_path(upload_root)).await {
+            Ok(meta) => Ok(meta.len()),
+            Err(_) => Err(UploadError::NotFound),
+        }
+    }
+
+    /// append the body at `offset`, the part file is renamed to the real name once complete
+    async fn append<S, E>(
+        &self,
+        locks: &UploadLocks,
+        upload_root: &Path,
+        id: &str,
+        offset: u64,
+        mut body: S,
+    ) -> Result<u64, UploadError>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: Display,
+    {
+        let _writing = locks.lock(id).await;
+        let current = self.offset(upload_root).await?;
+        if current != offset || current == self.length {
+            return Err(UploadError::OffsetMismatch(current));
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(self.part_path(upload_root))
+            .await?;
+        let mut written = current;
+        while let Some(chunk) = body.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    // keep what we got, the client resumes from the new offset
+                    debug!("upload interrupted: {}", e);
+                    break;
+                }
+            };
+            if written + chunk.len() as u64 > self.length {
+                file.flush().await?;
+                return Err(UploadError::TooLarge);
+            }
+            file.write_all(&chunk).await?;
+            written += chunk.len() as u64;
+        }
+        file.flush().await?;
+        if written == self.length {
+            tokio::fs::rename(self.part_path(upload_root), self.file_path(upload_root)).await?;
+        }
+        Ok(written)
+    }
+}
+
+fn upload_root(state: &AppStat) -> PathBuf {
+    state.book_dir.join(tools::UPLOAD_DIR)
+}
+
+fn tus_headers(state: &AppStat) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("Tus-Resumable", TUS_VERSION.parse().unwrap());
+    headers.insert("Tus-Version", TUS_VERSION.parse().unwrap());
+    headers.insert("Tus-Extension", "creation".parse().unwrap());
+    headers.insert("Tus-Max-Size", state.max_upload_size.into());
+    headers
+}
+
+pub(super) async fn tus_options(State(state): State<AppStat>) -> impl IntoResponse {
+    (StatusCode::NO_CONTENT, tus_headers(&state))
+}
+
+pub(super) async fn tus_create(
+    State(state): State<AppStat>,
+    headers: HeaderMap,
+) -> Result<Response, UploadError> {
+    let length = header_u64(&headers, "Upload-Length")?;
+    if length > state.max_upload_size {
+        return Err(UploadError::TooLarge);
+    }
+    let metadata = headers
+        .get("Upload-Metadata")
+        .and_then(|v| v.to_str().ok())
+        .map(parse_metadata)
+        .unwrap_or_default();
+    let (Some(group), Some(file_name)) = (metadata.get("group"), metadata.get("filename")) else {
+        return Err(UploadError::BadRequest(
+            "Upload-Metadata needs group and filename".to_string(),
+        ));
+    };
+    check_group(group)?;
+    check_file_name(file_name)?;
+    let upload = TusUpload {
+        group: group.clone(),
+        file_name: file_name.clone(),
+        length,
+    };
+    let id = hex::encode(rand::random::<[u8; 16]>());
+    upload.create(&upload_root(&state), &id).await?;
+    debug!("tus upload created: {} {:?}", id, upload);
+
+    let mut headers = tus_headers(&state);
+    headers.insert(
+        header::LOCATION,
+        format!("/management/tus/{}/{}", group, id).parse().unwrap(),
+    );
+    Ok((StatusCode::CREATED, headers).into_response())
+}
+
+pub(super) async fn tus_head(
+    State(state): State<AppStat>,
+    UrlPath((group, id)): UrlPath<(String, String)>,
+) -> Result<Response, UploadError> {
+    let root = upload_root(&state);
+    let upload = TusUpload::load(&root, &group, &id).await?;
+    let offset = upload.offset(&root).await?;
+    let mut headers = tus_headers(&state);
+    headers.insert("Upload-Offset", offset.into());
+    headers.insert("Upload-Length", upload.length.into());
+    headers.insert(header::CACHE_CONTROL, "no-store".parse().unwrap());
+    Ok((StatusCode::OK, headers).into_response())
+}
+
+pub(super) async fn tus_patch(
+    State(state): State<AppStat>,
+    UrlPath((group, id)): UrlPath<(String, String)>,
+    headers: HeaderMap,
+    body: BodyStream,
+) -> Result<Response, UploadError> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok());
+    if content_type != Some("application/offset+octet-stream") {
+        return Ok(manage_response(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "content type should be application/offset+octet-stream",
+        ));
+    }
+    let offset = header_u64(&headers, "Upload-Offset")?;
+    let root = upload_root(&state);
+    let upload = TusUpload::load(&root, &group, &id).await?;
+    let offset = upload
+        .append(&state.uploads, &root, &id, offset, body)
+        .await?;
+    let mut headers = tus_headers(&state);
+    headers.insert("Upload-Offset", offset.into());
+    Ok((StatusCode::NO_CONTENT, headers).into_response())
+}
+
+/// extract every archive in `dir` into a sub folder named after the archive
+async fn extract_archives(dir: &Path) -> eyre::Result<()> {
+    let dir = dir.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let Some(stem) = archive::archive_stem(&path) else {
+                continue;
+            };
+            archive::extract_archive(&path, dir.join(stem), ExtractLimits::default())?;
+            std::fs::remove_file(&path)?;
+        }
+        Ok(())
+    })
+    .await?
+}
+
+/// import the files of `group` as a new book, the uploaded files are removed on success
+async fn import_group(state: &AppStat, group: &str, name: String, author: String) -> Response {
+    if let Err(e) = check_group(group) {
+        return e.into_response();
+    }
+    let root = upload_root(state);
+    let group_dir = root.join(group);
+    let pending = match std::fs::read_dir(&group_dir) {
+        Ok(entries) => entries.filter_map(Result::ok).any(|e| {
+            e.path()
+                .extension()
+                .map(|ext| ext == PART_EXTENSION)
+                .unwrap_or(false)
+        }),
+        Err(_) => return UploadError::NotFound.into_response(),
+    };
+    if pending {
+        return manage_response(StatusCode::CONFLICT, "some uploads are not finished");
+    }
+    if let Err(e) = extract_archives(&group_dir).await {
+        error!("fail to extract archives: {}", e);
+        return manage_response(StatusCode::BAD_REQUEST, format!("bad archive: {}", e));
+    }
+    let result = tools::create_new_book(
+        author,
+        name,
+        &state.book_dir,
+        &group_dir,
+        &state.connections.db,
+    )
+    .await;
+    match result {
+        Ok(book_id) => {
+            search::refresh_books(state, vec![book_id]);
+            // the book holds hard links to the uploaded files
+            let _ = std::fs::remove_dir_all(&group_dir);
+            let _ = std::fs::remove_dir_all(root.join(TUS_META_DIR).join(group));
+            manage_response(StatusCode::OK, "success")
+        }
+        Err(e) => {
+            error!("fail to import upload {}: {}", group, e);
+            manage_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("import failed: {}", e),
+            )
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub(super) struct ImportPara {
+    group: String,
+    name: String,
+    author: String,
+}
+
+pub(super) async fn upload_import(
+    State(state): State<AppStat>,
+    Form(para): Form<ImportPara>,
+) -> Response {
+    import_group(&state, &para.group, para.name, para.author).await
+}
+
+/// receive the `name`, `author` and `file` fields in one request and import the book right away
+pub(super) async fn upload_multipart(
+    State(state): State<AppStat>,
+    mut multipart: Multipart,
+) -> Result<Response, UploadError> {
+    let group = hex::encode(rand::random::<[u8; 16]>());
+    let group_dir = upload_root(&state).join(&group);
+    tokio::fs::create_dir_all(&group_dir).await?;
+    let mut name = None;
+    let mut author = None;
+    let result = async {
+        while let Some(mut field) = multipart
+            .next_field()
+            .await
+            .map_err(|e| UploadError::BadRequest(e.to_string()))?
+        {
+            match field.name() {
+                Some("name") => {
+                    name = Some(
+                        field
+                            .text()
+                            .await
+                            .map_err(|e| UploadError::BadRequest(e.to_string()))?,
+                    )
+                }
+                Some("author") => {
+                    author = Some(
+                        field
+                            .text()
+                            .await
+                            .map_err(|e| UploadError::BadRequest(e.to_string()))?,
+                    )
+                }
+                Some("file") => {
+                    let file_name = field.file_name().unwrap_or_default().to_string();
+                    check_file_name(&file_name)?;
+                    let mut file = tokio::fs::File::create(group_dir.join(&file_name)).await?;
+                    let mut size = 0;
+                    while let Some(chunk) = field
+                        .chunk()
+                        .await
+                        .map_err(|e| UploadError::BadRequest(e.to_string()))?
+                    {
+                        size += chunk.len() as u64;
+                        if size > state.max_upload_size {
+                            return Err(UploadError::TooLarge);
+                        }
+                        file.write_all(&chunk).await?;
+                    }
+                    file.flush().await?;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+    .await;
+    if let Err(e) = result {
+        let _ = std::fs::remove_dir_all(&group_dir);
+        return Err(e);
+    }
+    let (Some(name), Some(author)) = (name, author) else {
+        let _ = std::fs::remove_dir_all(&group_dir);
+        return Err(UploadError::BadRequest(
+            "name and author are required".to_string(),
+        ));
+    };
+    let response = import_group(&state, &group, name, author).await;
+    // a failed multipart upload can't be retried, so don't keep the files
+    let _ = std::fs::remove_dir_all(&group_dir);
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use axum::body::Bytes;
+    use base64::Engine;
+
+    use super::{check_file_name, parse_metadata, TusUpload, UploadError, UploadLocks};
+
+    fn body(chunks: &[&'static str]) -> impl futures::Stream<Item = Result<Bytes, Infallible>> {
+        futures::stream::iter(
+            chunks
+                .iter()
+                .map(|c| Ok(Bytes::from_static(c.as_bytes())))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[test]
+    fn test_parse_metadata() {
+        let b64 = |s: &str| base64::engine::general_purpose::STANDARD.encode(s);
+        let header = format!(
+            "filename {},group {},is_confidential",
+            b64("01.mp3"),
+            b64("g1")
+        );
+        let metadata = parse_metadata(&header);
+        assert_eq!(metadata.get("filename").unwrap(), "01.mp3");
+        assert_eq!(metadata.get("group").unwrap(), "g1");
+        assert_eq!(metadata.get("is_confidential").unwrap(), "");
+    }
+
+    #[test]
+    fn test_check_file_name() {
+        assert!(check_file_name("01.mp3").is_ok());
+        assert!(check_file_name("book.ZIP").is_ok());
+        assert!(check_file_name("book.tar.gz").is_ok());
+        assert!(check_file_name("../01.mp3").is_err());
+        assert!(check_file_name(".hidden.mp3").is_err());
+        assert!(check_file_name("notes.txt").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tus_upload() {
+        let root = tempfile::tempdir().unwrap();
+        let upload = TusUpload {
+            group: "g1".to_string(),
+            file_name: "01.mp3".to_string(),
+            length: 10,
+        };
+        upload.create(root.path(), "ab").await.unwrap();
+        assert_eq!(
+            TusUpload::load(root.path(), "g1", "ab").await.unwrap(),
+            upload
+        );
+        let locks = UploadLocks::default();
+
+        let offset = upload
+            .append(&locks, root.path(), "ab", 0, body(&["01234"]))
+            .await
+            .unwrap();
+        assert_eq!(offset, 5);
+        assert!(!upload.file_path(root.path()).exists());
+
+        // resuming from a wrong offset is rejected
+        let result = upload
+            .append(&locks, root.path(), "ab", 2, body(&["56789"]))
+            .await;
+        assert!(matches!(result, Err(UploadError::OffsetMismatch(5))));
+
+        let offset = upload
+            .append(&locks, root.path(), "ab", 5, body(&["567", "89"]))
+            .await
+            .unwrap();
+        assert_eq!(offset, 10);
+        assert_eq!(
+            std::fs::read_to_string(upload.file_path(root.path())).unwrap(),
+            "0123456789"
+        );
+        assert_eq!(upload.offset(root.path()).await.unwrap(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_tus_upload_too_large() {
+        let root = tempfile::tempdir().unwrap();
+        let upload = TusUpload {
+            group: "g1".to_string(),
+            file_name: "01.mp3".to_string(),
+            length: 4,
+        };
+        upload.create(root.path(), "ab").await.unwrap();
+        let result = upload
+            .append(
+                &UploadLocks::default(),
+                root.path(),
+                "ab",
+                0,
+                body(&["01234"]),
+            )
+            .await;
+        assert!(matches!(result, Err(UploadError::TooLarge)));
+        assert!(!upload.file_path(root.path()).exists());
+    }
+
+    #[tokio::test]
+    async fn test_tus_concurrent_append() {
+        let root = tempfile::tempdir().unwrap();
+        let upload = TusUpload {
+            group: "g1".to_string(),
+            file_name: "01.mp3".to_string(),
+            length: 10,
+        };
+        upload.create(root.path(), "ab").await.unwrap();
+        let locks = UploadLocks::default();
+        // both patches start at 0, the second one sees the bytes of the first
+        let (first, second) = tokio::join!(
+            upload.append(&locks, root.path(), "ab", 0, body(&["012", "34"])),
+            upload.append(&locks, root.path(), "ab", 0, body(&["abc", "de"])),
+        );
+        assert!(matches!(first, Ok(5)));
+        assert!(matches!(second, Err(UploadError::OffsetMismatch(5))));
+        assert_eq!(
+            std::fs::read_to_string(upload.part_path(root.path())).unwrap(),
+            "01234"
+        );
+    }
+}
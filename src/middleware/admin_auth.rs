@@ -1,9 +1,14 @@
-use axum::{body, extract::State, middleware::Next, response::Response};
+use axum::{
+    extract::State,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
 use hyper::Request;
 use tower_cookies::Cookies;
 use tracing::debug;
 
 use crate::{
+    error::AppError,
     middleware::{check_passkey, generate_response_util},
     AppStat,
 };
@@ -22,13 +27,7 @@ pub(crate) async fn admin_auth<B>(
             next.run(request).await
         } else {
             debug!("user is not admin");
-            // return 403
-
-            Response::builder()
-                .status(403)
-                .header("Location", "/")
-                .body(body::boxed("Only Admin User can operate user".to_string()))
-                .unwrap()
+            AppError::forbidden("Only Admin User can operate user").into_response()
         }
     })
     .await
@@ -1,16 +1,16 @@
 use axum::{
     async_trait,
     extract::FromRequestParts,
-    http::{self, request::Parts},
+    http::request::Parts,
     response::{IntoResponse, Response},
 };
 use futures::Future;
-use hyper::{Request, StatusCode};
+use hyper::Request;
 use redis::{AsyncCommands, FromRedisValue, ToRedisArgs};
 use tower_cookies::Cookies;
 use tracing::debug;
 
-use crate::AppStat;
+use crate::{error::AppError, AppStat};
 
 #[derive(Debug, Clone)]
 pub(crate) enum PasskeyCheckResult {
@@ -22,17 +22,16 @@ pub(crate) enum PasskeyCheckResult {
 #[async_trait]
 
 impl<S> FromRequestParts<S> for PasskeyCheckResult {
-    type Rejection = (http::StatusCode, &'static str);
+    type Rejection = AppError;
 
     async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
         parts
             .extensions
             .get::<PasskeyCheckResult>()
             .cloned()
-            .ok_or((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Can't extract PasskeyCheckResult. Is `webui_auth` enabled?",
-            ))
+            .ok_or_else(|| {
+                AppError::internal("Can't extract PasskeyCheckResult. Is `webui_auth` enabled?")
+            })
     }
 }
 
@@ -106,13 +105,12 @@ impl<S> FromRequestParts<S> for LoginInfo
 where
     S: Sync + Send,
 {
-    type Rejection = (http::StatusCode, &'static str);
+    type Rejection = AppError;
 
     async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
-        parts.extensions.get::<LoginInfo>().cloned().ok_or((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Can't extract LoginInfo. Is `user_auth` or `adim_auth` enabled?",
-        ))
+        parts.extensions.get::<LoginInfo>().cloned().ok_or_else(|| {
+            AppError::internal("Can't extract LoginInfo. Is `user_auth` or `adim_auth` enabled?")
+        })
     }
 }
 
@@ -128,8 +126,10 @@ where
     O: IntoResponse,
 {
     match check_result {
-        PasskeyCheckResult::NoCookie => (StatusCode::UNAUTHORIZED, "Not Login").into_response(),
-        PasskeyCheckResult::NoRedis => (StatusCode::UNAUTHORIZED, "Redis Error").into_response(),
+        PasskeyCheckResult::NoCookie => AppError::unauthorized("Not Login").into_response(),
+        PasskeyCheckResult::NoRedis => {
+            AppError::unauthorized("the session expired, please login again").into_response()
+        }
         PasskeyCheckResult::LogInSucceed((_passkey, login_info)) => {
             let role_level = login_info.role_level;
             request.extensions_mut().insert(login_info);
@@ -17,14 +17,11 @@ pub(crate) async fn webui_auth<B>(
     debug!("webui_auth");
     let cookies: &Cookies = request.extensions().get().unwrap();
     let check_result = check_passkey(cookies, &stats).await;
-    match &check_result {
-        // if login succeed, then extend the expire time
-        PasskeyCheckResult::LogInSucceed((key, _login_info)) => {
-            debug!("extend_login_expire_time");
-            tools::extend_login_expire_time(&stats, &key).await;
-        }
-        _ => {}
-    };
+    // if login succeed, then extend the expire time
+    if let PasskeyCheckResult::LogInSucceed((key, _login_info)) = &check_result {
+        debug!("extend_login_expire_time");
+        tools::extend_login_expire_time(&stats, key).await;
+    }
     request.extensions_mut().insert(check_result);
     next.run(request).await
 }
@@ -0,0 +1,331 @@
+//! a whole book to take offline, as a zip of its chapters or merged into one `.m4b`
+//!
+//! the zip is streamed while the chapters are read, with a `playlist.m3u`, the cover and a
+//! `manifest.json` linking the files inside the zip, which makes it a readium audiobook package.
+//! the m4b is merged by ffmpeg in the background on first download and kept in the transcode
+//! cache, a download that gave up before the end finds it there when it comes back.
+
+use std::path::{Path, PathBuf};
+
+use axum::{
+    body::{boxed, Body, Bytes},
+    response::{IntoResponse, Response},
+};
+use hyper::{header, HeaderMap};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use sea_orm::EntityTrait;
+use tokio::io::AsyncReadExt;
+use tracing::warn;
+
+use super::publication::{self, ChapterFile, Track};
+use crate::entities::{prelude::*, *};
+use crate::error::{AppError, AppResult};
+use crate::tools::zipstream::ZipStream;
+use crate::transcode::m4b::{MergeChapter, MergeTags};
+use crate::AppStat;
+
+/// the characters escaped in `filename*`, all but the attr-chars of rfc 5987 that are common in
+/// names
+const FILENAME: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'.')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'~');
+
+/// a file of the zip
+#[derive(Debug)]
+enum ZipItem {
+    /// made on the fly
+    Data(String, Vec<u8>),
+    /// read from the disk while it's sent
+    File(String, PathBuf),
+}
+
+/// the name of a file in the zip
+fn zip_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// an extended m3u of the chapters, relative to the zip
+fn playlist(tracks: &[Track]) -> String {
+    let mut m3u = String::from("#EXTM3U\n");
+    for track in tracks {
+        let title = track.title.replace(['\r', '\n'], " ");
+        let seconds = track
+            .duration
+            .map_or(-1, |duration| duration.round() as i64);
+        m3u.push_str(&format!("#EXTINF:{},{}\n{}\n", seconds, title, track.href));
+    }
+    m3u
+}
+
+/// the header making the browser save the response as `name`
+fn attachment(name: &str) -> String {
+    // a plain fallback for the clients without `filename*`
+    let plain = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        plain,
+        utf8_percent_encode(name, FILENAME)
+    )
+}
+
+/// the files of the zip of a book
+fn zip_items(
+    book: &music::Model,
+    author: Option<&author::Model>,
+    chapters: Vec<ChapterFile>,
+    cover: Option<PathBuf>,
+) -> AppResult<Vec<ZipItem>> {
+    let tracks = chapters
+        .iter()
+        .map(|chapter| Track {
+            href: zip_name(&chapter.path),
+            ..chapter.track.clone()
+        })
+        .collect::<Vec<_>>();
+    let cover_name = cover.as_ref().map(|cover| {
+        let extension = cover
+            .extension()
+            .map(|ext| ext.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        format!("cover.{}", extension)
+    });
+    let cover_link = cover
+        .as_ref()
+        .zip(cover_name.clone())
+        .map(|(cover, name)| publication::link_to_cover(cover, name));
+    let manifest = publication::manifest("manifest.json", book, author, &tracks, cover_link);
+    let mut items = vec![
+        ZipItem::Data(
+            "manifest.json".to_string(),
+            serde_json::to_vec_pretty(&manifest).map_err(AppError::internal)?,
+        ),
+        ZipItem::Data("playlist.m3u".to_string(), playlist(&tracks).into_bytes()),
+    ];
+    if let (Some(cover), Some(name)) = (cover, cover_name) {
+        items.push(ZipItem::File(name, cover));
+    }
+    items.extend(
+        chapters
+            .into_iter()
+            .map(|chapter| ZipItem::File(zip_name(&chapter.path), chapter.path)),
+    );
+    Ok(items)
+}
+
+/// write the zip of `items` to the body, `modified` is the date of the generated files
+async fn send_zip(
+    items: Vec<ZipItem>,
+    modified: chrono::NaiveDateTime,
+    sender: &mut hyper::body::Sender,
+) -> eyre::Result<()> {
+    let mut zip = ZipStream::new();
+    let mut buffer = vec![0; 64 * 1024];
+    for item in items {
+        match item {
+            ZipItem::Data(name, data) => {
+                sender
+                    .send_data(zip.entry(&name, modified, &data)?.into())
+                    .await?;
+            }
+            ZipItem::File(name, path) => {
+                let mut file = tokio::fs::File::open(&path).await?;
+                let file_modified = file.metadata().await?.modified()?;
+                let file_modified =
+                    chrono::DateTime::<chrono::Utc>::from(file_modified).naive_utc();
+                sender
+                    .send_data(zip.start_entry(&name, file_modified)?.into())
+                    .await?;
+                let mut hasher = crc32fast::Hasher::new();
+                let mut size = 0;
+                loop {
+                    let read = file.read(&mut buffer).await?;
+                    if read == 0 {
+                        break;
+                    }
+                    hasher.update(&buffer[..read]);
+                    size += read as u64;
+                    sender
+                        .send_data(Bytes::copy_from_slice(&buffer[..read]))
+                        .await?;
+                }
+                sender
+                    .send_data(zip.finish_entry(hasher.finalize(), size)?.into())
+                    .await?;
+            }
+        }
+    }
+    sender.send_data(zip.finish()?.into()).await?;
+    Ok(())
+}
+
+/// the zip of a live book, streamed
+pub(crate) async fn book_zip(state: &AppStat, book_id: i32) -> AppResult<Response> {
+    let book = super::find_book(state, book_id).await?;
+    let author = Author::find_by_id(book.author_id)
+        .one(&state.connections.db)
+        .await?;
+    let chapters = publication::chapter_files(state, &book).await?;
+    let cover = publication::cover_path(&state.book_dir, &book, author.as_ref())
+        .map(|cover| state.book_dir.join(cover))
+        .filter(|cover| cover.is_file());
+    let items = zip_items(&book, author.as_ref(), chapters, cover)?;
+    let (mut sender, body) = Body::channel();
+    let modified = book.updated_at;
+    tokio::spawn(async move {
+        if let Err(e) = send_zip(items, modified, &mut sender).await {
+            warn!("fail to send the zip of book {}: {}", book_id, e);
+            // a truncated zip should not look complete
+            sender.abort();
+        }
+    });
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                attachment(&format!("{}.zip", book.name)),
+            ),
+        ],
+        boxed(body),
+    )
+        .into_response())
+}
+
+/// the chapters of a live book merged into an m4b with chapter markers
+pub(crate) async fn book_m4b(
+    state: &AppStat,
+    book_id: i32,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    let book = super::find_book(state, book_id).await?;
+    let author = Author::find_by_id(book.author_id)
+        .one(&state.connections.db)
+        .await?;
+    let files = publication::chapter_files(state, &book).await?;
+    let chapters = files
+        .iter()
+        .map(|file| {
+            let duration = file.track.duration.ok_or_else(|| {
+                AppError::internal(format!("the duration of {:?} is unknown", file.path))
+            })?;
+            Ok(MergeChapter {
+                path: &file.path,
+                title: &file.track.title,
+                duration,
+            })
+        })
+        .collect::<AppResult<Vec<_>>>()?;
+    if chapters.is_empty() {
+        return Err(AppError::not_found(format!(
+            "book {} has no chapters",
+            book_id
+        )));
+    }
+    let tags = MergeTags {
+        title: &book.name,
+        author: author.as_ref().map_or("", |author| author.name.as_str()),
+        narrator: book.narrator.as_deref(),
+        description: book.description.as_deref(),
+    };
+    let merged = state.transcoder.merge(&tags, &chapters).await?;
+    let mut response = crate::serve_file(merged, headers).await;
+    let disposition = attachment(&format!("{}.m4b", book.name));
+    if let Ok(value) = header::HeaderValue::from_str(&disposition) {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_DISPOSITION, value);
+    }
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("audio/mp4"),
+    );
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use axum::body::Body;
+
+    use super::{attachment, playlist, send_zip, zip_items};
+    use crate::fixtures;
+    use crate::music::publication::{ChapterFile, Track};
+
+    #[test]
+    fn test_attachment() {
+        assert_eq!(
+            attachment("三体 \"1\".zip"),
+            "attachment; filename=\"__ _1_.zip\"; \
+             filename*=UTF-8''%E4%B8%89%E4%BD%93%20%221%22.zip"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_book_zip() {
+        let dir = tempfile::tempdir().unwrap();
+        let chapter = dir.path().join("0001.mp3");
+        std::fs::write(&chapter, b"chapter one").unwrap();
+        let cover = dir.path().join("folder.jpg");
+        std::fs::write(&cover, b"jpeg").unwrap();
+        let book = fixtures::book(3, "book");
+        let chapters = vec![ChapterFile {
+            chapter_no: 1,
+            path: chapter,
+            track: Track {
+                href: "/fetchbook/author/book/0001.mp3".to_string(),
+                mime: "audio/mpeg".to_string(),
+                title: "One".to_string(),
+                duration: Some(61.4),
+            },
+        }];
+        let items = zip_items(&book, None, chapters, Some(cover)).unwrap();
+        let (mut sender, body) = Body::channel();
+        let sent = tokio::spawn(async move {
+            send_zip(items, Default::default(), &mut sender)
+                .await
+                .unwrap()
+        });
+        let bytes = hyper::body::to_bytes(body).await.unwrap();
+        sent.await.unwrap();
+
+        let mut archive = zip::ZipArchive::new(Cursor::new(bytes.to_vec())).unwrap();
+        let mut names = archive.file_names().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(
+            names,
+            ["0001.mp3", "cover.jpg", "manifest.json", "playlist.m3u"]
+        );
+        let mut read = String::new();
+        archive
+            .by_name("0001.mp3")
+            .unwrap()
+            .read_to_string(&mut read)
+            .unwrap();
+        assert_eq!(read, "chapter one");
+        let manifest: serde_json::Value =
+            serde_json::from_reader(archive.by_name("manifest.json").unwrap()).unwrap();
+        assert_eq!(manifest["readingOrder"][0]["href"], "0001.mp3");
+        assert_eq!(manifest["resources"][0]["href"], "cover.jpg");
+        let tracks = [Track {
+            href: "0001.mp3".to_string(),
+            mime: String::new(),
+            title: "One".to_string(),
+            duration: Some(61.4),
+        }];
+        assert_eq!(playlist(&tracks), "#EXTM3U\n#EXTINF:61,One\n0001.mp3\n");
+    }
+}
@@ -0,0 +1,398 @@
+//! filters of the book list and the facet counts the webui renders as filter chips
+//!
+//! every facet is counted with the other filters applied but not its own, so picking a narrator
+//! still shows the other narrators to switch to.
+
+use chrono::NaiveDate;
+use sea_orm::{
+    sea_query::{Expr, Query},
+    ColumnTrait, ConnectionTrait, DbErr, JoinType, PaginatorTrait, QueryFilter, QuerySelect,
+    RelationTrait, Select,
+};
+
+use crate::entities::{prelude::*, *};
+
+/// a book the user has no progress on, or only wants to listen to, is not started, the other
+/// states are the state of the progress
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ListeningState {
+    NotStarted,
+    InProgress,
+    Finished,
+    Abandoned,
+    WantToListen,
+}
+
+impl ListeningState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ListeningState::NotStarted => "not_started",
+            ListeningState::InProgress => "in_progress",
+            ListeningState::Finished => "finished",
+            ListeningState::Abandoned => "abandoned",
+            ListeningState::WantToListen => "want_to_listen",
+        }
+    }
+}
+
+const LISTENING_STATES: [ListeningState; 5] = [
+    ListeningState::NotStarted,
+    ListeningState::InProgress,
+    ListeningState::Finished,
+    ListeningState::Abandoned,
+    ListeningState::WantToListen,
+];
+
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub(crate) struct BookFilter {
+    pub author_id: Option<i32>,
+    pub narrator: Option<String>,
+    pub tag: Option<String>,
+    pub language: Option<String>,
+    pub series: Option<String>,
+    /// seconds
+    pub min_duration: Option<f64>,
+    /// seconds
+    pub max_duration: Option<f64>,
+    pub added_after: Option<NaiveDate>,
+    /// for the current user
+    pub state: Option<ListeningState>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Facet {
+    Author,
+    Narrator,
+    Tag,
+    Language,
+    Series,
+    State,
+}
+
+/// an empty value in the query string, like `narrator=`, means no filter
+fn non_empty(value: &Option<String>) -> Option<&str> {
+    value.as_deref().filter(|value| !value.is_empty())
+}
+
+impl BookFilter {
+    pub fn is_empty(&self) -> bool {
+        self.author_id.is_none()
+            && non_empty(&self.narrator).is_none()
+            && non_empty(&self.tag).is_none()
+            && non_empty(&self.language).is_none()
+            && non_empty(&self.series).is_none()
+            && self.min_duration.is_none()
+            && self.max_duration.is_none()
+            && self.added_after.is_none()
+            && self.state.is_none()
+    }
+
+    /// the filter as query string pairs, the webui keeps them in the links of the filter chips
+    pub fn query_pairs(&self) -> Vec<(&'static str, String)> {
+        let mut pairs = vec![];
+        if let Some(author_id) = self.author_id {
+            pairs.push(("author_id", author_id.to_string()));
+        }
+        let texts = [
+            ("narrator", &self.narrator),
+            ("tag", &self.tag),
+            ("language", &self.language),
+            ("series", &self.series),
+        ];
+        for (key, value) in texts {
+            if let Some(value) = non_empty(value) {
+                pairs.push((key, value.to_string()));
+            }
+        }
+        if let Some(min) = self.min_duration {
+            pairs.push(("min_duration", min.to_string()));
+        }
+        if let Some(max) = self.max_duration {
+            pairs.push(("max_duration", max.to_string()));
+        }
+        if let Some(added_after) = self.added_after {
+            pairs.push(("added_after", added_after.to_string()));
+        }
+        if let Some(state) = self.state {
+            pairs.push(("state", state.as_str().to_string()));
+        }
+        pairs
+    }
+
+    /// restrict `query` to the books matching the filter
+    pub fn apply(&self, query: Select<Music>, user_id: i32) -> Select<Music> {
+        self.apply_except(query, user_id, None)
+    }
+
+    fn apply_except(
+        &self,
+        mut query: Select<Music>,
+        user_id: i32,
+        skip: Option<Facet>,
+    ) -> Select<Music> {
+        let applies = |facet| skip != Some(facet);
+        if let (Some(author_id), true) = (self.author_id, applies(Facet::Author)) {
+            query = query.filter(music::Column::AuthorId.eq(author_id));
+        }
+        if let (Some(narrator), true) = (non_empty(&self.narrator), applies(Facet::Narrator)) {
+            query = query.filter(music::Column::Narrator.eq(narrator));
+        }
+        if let (Some(language), true) = (non_empty(&self.language), applies(Facet::Language)) {
+            query = query.filter(music::Column::Language.eq(language));
+        }
+        if let (Some(series), true) = (non_empty(&self.series), applies(Facet::Series)) {
+            query = query.filter(music::Column::Series.eq(series));
+        }
+        if let (Some(tag), true) = (non_empty(&self.tag), applies(Facet::Tag)) {
+            query = query.filter(
+                music::Column::Id.in_subquery(
+                    Query::select()
+                        .column(music_tag::Column::MusicId)
+                        .from(MusicTag)
+                        .inner_join(
+                            Tag,
+                            Expr::col((Tag, tag::Column::Id))
+                                .equals((MusicTag, music_tag::Column::TagId)),
+                        )
+                        .and_where(Expr::col((Tag, tag::Column::Name)).eq(tag))
+                        .to_owned(),
+                ),
+            );
+        }
+        if let Some(min) = self.min_duration {
+            query = query.filter(music::Column::Duration.gte(min));
+        }
+        if let Some(max) = self.max_duration {
+            query = query.filter(music::Column::Duration.lte(max));
+        }
+        if let Some(added_after) = self.added_after {
+            query = query.filter(music::Column::CreatedAt.gte(added_after.and_hms_opt(0, 0, 0)));
+        }
+        if let (Some(state), true) = (self.state, applies(Facet::State)) {
+            query = query.filter(state_condition(state, user_id));
+        }
+        query
+    }
+}
+
+fn state_condition(state: ListeningState, user_id: i32) -> sea_orm::sea_query::SimpleExpr {
+    // progress has one row per user and book, the state is only the state of that row and not
+    // guessed from the chapters. An in progress row still at chapter 0 was only opened, like the
+    // rows older versions created when a book was viewed, so it is not started.
+    const OPENED: &str = "(`progress`.`state` = 'in_progress' AND `progress`.`chapter_no` = 0)";
+    let sql = match state {
+        ListeningState::NotStarted => format!(
+            "NOT EXISTS (SELECT 1 FROM `progress` WHERE `progress`.`music_id` = `music`.`id` \
+             AND `progress`.`account_id` = ? AND `progress`.`state` <> 'want_to_listen' \
+             AND NOT {})",
+            OPENED
+        ),
+        ListeningState::InProgress => format!(
+            "EXISTS (SELECT 1 FROM `progress` WHERE `progress`.`music_id` = `music`.`id` \
+             AND `progress`.`account_id` = ? AND `progress`.`state` = 'in_progress' \
+             AND NOT {})",
+            OPENED
+        ),
+        state => format!(
+            "EXISTS (SELECT 1 FROM `progress` WHERE `progress`.`music_id` = `music`.`id` \
+             AND `progress`.`account_id` = ? AND `progress`.`state` = '{}')",
+            state.as_str()
+        ),
+    };
+    Expr::cust_with_values(sql, [user_id])
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub(crate) struct AuthorCount {
+    pub id: i32,
+    pub name: String,
+    pub count: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub(crate) struct ValueCount {
+    pub value: String,
+    pub count: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub(crate) struct StateCount {
+    pub state: ListeningState,
+    pub count: u64,
+}
+
+/// the values of each facet with their number of books, the most common first
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub(crate) struct Facets {
+    pub authors: Vec<AuthorCount>,
+    pub narrators: Vec<ValueCount>,
+    pub tags: Vec<ValueCount>,
+    pub languages: Vec<ValueCount>,
+    pub series: Vec<ValueCount>,
+    pub states: Vec<StateCount>,
+}
+
+fn sort_counts(mut counts: Vec<ValueCount>) -> Vec<ValueCount> {
+    counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+    counts
+}
+
+/// count the values of `column` among the books of `query`, books without a value are skipped
+async fn count_values(
+    db: &impl ConnectionTrait,
+    query: Select<Music>,
+    column: music::Column,
+) -> Result<Vec<ValueCount>, DbErr> {
+    let counts: Vec<(String, i64)> = query
+        .select_only()
+        .column(column)
+        .column_as(music::Column::Id.count(), "count")
+        .filter(column.is_not_null())
+        .group_by(column)
+        .into_tuple()
+        .all(db)
+        .await?;
+    Ok(sort_counts(
+        counts
+            .into_iter()
+            .map(|(value, count)| ValueCount { value, count })
+            .collect(),
+    ))
+}
+
+/// the facet counts of the books of `base` matching `filter`
+pub(crate) async fn facets(
+    db: &impl ConnectionTrait,
+    base: Select<Music>,
+    filter: &BookFilter,
+    user_id: i32,
+) -> Result<Facets, DbErr> {
+    let without = |facet| filter.apply_except(base.clone(), user_id, Some(facet));
+
+    let authors: Vec<(i32, String, i64)> = without(Facet::Author)
+        .select_only()
+        .column(music::Column::AuthorId)
+        .column(author::Column::Name)
+        .column_as(music::Column::Id.count(), "count")
+        .join(JoinType::InnerJoin, music::Relation::Author.def())
+        .group_by(music::Column::AuthorId)
+        .group_by(author::Column::Name)
+        .into_tuple()
+        .all(db)
+        .await?;
+    let mut authors = authors
+        .into_iter()
+        .map(|(id, name, count)| AuthorCount { id, name, count })
+        .collect::<Vec<_>>();
+    authors.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+
+    let tags: Vec<(String, i64)> = without(Facet::Tag)
+        .select_only()
+        .column(tag::Column::Name)
+        .column_as(music::Column::Id.count(), "count")
+        .join(JoinType::InnerJoin, music::Relation::MusicTag.def())
+        .join(JoinType::InnerJoin, music_tag::Relation::Tag.def())
+        .group_by(tag::Column::Name)
+        .into_tuple()
+        .all(db)
+        .await?;
+    let tags = sort_counts(
+        tags.into_iter()
+            .map(|(value, count)| ValueCount { value, count })
+            .collect(),
+    );
+
+    let mut states = vec![];
+    for state in LISTENING_STATES {
+        let count = without(Facet::State)
+            .filter(state_condition(state, user_id))
+            .count(db)
+            .await?;
+        states.push(StateCount { state, count });
+    }
+
+    Ok(Facets {
+        authors,
+        narrators: count_values(db, without(Facet::Narrator), music::Column::Narrator).await?,
+        tags,
+        languages: count_values(db, without(Facet::Language), music::Column::Language).await?,
+        series: count_values(db, without(Facet::Series), music::Column::Series).await?,
+        states,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use sea_orm::{DbBackend, EntityTrait, QueryTrait};
+
+    use super::{BookFilter, Facet, ListeningState};
+    use crate::entities::prelude::*;
+
+    #[test]
+    fn test_apply_filter() {
+        let filter = BookFilter {
+            author_id: Some(2),
+            narrator: Some("".to_string()),
+            tag: Some("fantasy".to_string()),
+            min_duration: Some(3600.),
+            added_after: "2023-10-01".parse().ok(),
+            state: Some(ListeningState::InProgress),
+            ..Default::default()
+        };
+        assert!(!filter.is_empty());
+        assert_eq!(
+            filter.query_pairs(),
+            vec![
+                ("author_id", "2".to_string()),
+                ("tag", "fantasy".to_string()),
+                ("min_duration", "3600".to_string()),
+                ("added_after", "2023-10-01".to_string()),
+                ("state", "in_progress".to_string()),
+            ]
+        );
+        let sql = filter
+            .apply(Music::find(), 7)
+            .build(DbBackend::MySql)
+            .to_string();
+        assert!(sql.contains("`music`.`author_id` = 2"));
+        assert!(!sql.contains("`music`.`narrator` ="));
+        assert!(sql.contains("`tag`.`name` = 'fantasy'"));
+        assert!(sql.contains("`music`.`duration` >= 3600"));
+        assert!(sql.contains("`music`.`created_at` >= '2023-10-01 00:00:00'"));
+        assert!(sql.contains("`progress`.`account_id` = 7 AND `progress`.`state` = 'in_progress'"));
+
+        let sql = filter
+            .apply_except(Music::find(), 7, Some(Facet::Author))
+            .build(DbBackend::MySql)
+            .to_string();
+        assert!(!sql.contains("`author_id` = 2"));
+
+        let empty = BookFilter {
+            series: Some("".to_string()),
+            ..Default::default()
+        };
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn test_state_condition() {
+        let sql = |state| {
+            BookFilter {
+                state: Some(state),
+                ..Default::default()
+            }
+            .apply(Music::find(), 7)
+            .build(DbBackend::MySql)
+            .to_string()
+        };
+        // finished is the state of the progress, not a chapter past the last one
+        let finished = sql(ListeningState::Finished);
+        assert!(finished.contains("`progress`.`state` = 'finished'"));
+        assert!(!finished.contains("`progress`.`chapter_no`"));
+        // a book only opened is not started
+        let opened = "(`progress`.`state` = 'in_progress' AND `progress`.`chapter_no` = 0)";
+        assert!(sql(ListeningState::InProgress).contains(&format!("AND NOT {}", opened)));
+        assert!(sql(ListeningState::NotStarted).contains(&format!("AND NOT {}", opened)));
+    }
+}
@@ -1,11 +1,25 @@
+use std::collections::{BTreeMap, HashMap};
+
 use axum::extract::Path;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
 use axum::{extract::State, routing::get};
-use axum::{Form, Json};
-use sea_orm::{ColumnTrait, EntityTrait, PaginatorTrait, QueryFilter, QueryOrder};
+use hyper::header;
+use sea_orm::{
+    sea_query::Expr, ColumnTrait, Condition, EntityTrait, JoinType, Order, PaginatorTrait,
+    QueryFilter, QueryOrder, QuerySelect, RelationTrait, Select,
+};
 use tracing::debug;
 
 use crate::entities::{prelude::*, *};
-use crate::AppStat;
+use crate::error::{AppError, AppForm, AppQuery, AppResult};
+use crate::{middleware::LoginInfo, progress::BookProgress, tools::romanize, AppStat};
+
+use self::filter::{BookFilter, Facets};
+
+pub(crate) mod download;
+pub(crate) mod filter;
+pub(crate) mod publication;
 
 pub(crate) fn route(state: AppStat) -> axum::Router<AppStat> {
     axum::Router::new()
@@ -14,7 +28,9 @@ pub(crate) fn route(state: AppStat) -> axum::Router<AppStat> {
         .route("/getauthor/:author", get(get_author_by_id))
         .route("/searchauthor", get(get_authors_by_name))
         .route("/getbook/:book", get(getbook_by_id))
+        .route("/getbook/:book/manifest.json", get(getbook_manifest))
         .route("/searchbook", get(getbooks_by_name))
+        .route("/search", get(search))
         // .route("/getfile/:book/:no", get(getfile_by_id))
         .route_layer(
             tower::ServiceBuilder::new()
@@ -28,21 +44,130 @@ pub(crate) fn route(state: AppStat) -> axum::Router<AppStat> {
         )
 }
 
-#[derive(Debug, serde::Deserialize)]
-enum QueryAscDesc {
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize)]
+pub(crate) enum QueryAscDesc {
+    #[default]
     Asc,
     Desc,
 }
-#[derive(Debug, serde::Deserialize)]
-enum QueryBy {
+
+impl From<QueryAscDesc> for Order {
+    fn from(order: QueryAscDesc) -> Self {
+        match order {
+            QueryAscDesc::Asc => Order::Asc,
+            QueryAscDesc::Desc => Order::Desc,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize)]
+pub(crate) enum QueryBy {
+    #[default]
     Id,
     Name,
     DateAdded,
+    DateUpdated,
+    /// the author name, for authors it's the same as `Name`
+    Author,
+    /// the total duration, for authors the sum of their books
+    Duration,
+    /// the last time the current user played the book, or any book of the author
+    RecentlyPlayed,
 }
+
 #[derive(Debug, serde::Deserialize)]
 struct ListArgs {
     page: u64,
     page_size: u64,
+    #[serde(default)]
+    sort_by: QueryBy,
+    #[serde(default)]
+    order: QueryAscDesc,
+    /// also count the books by author, narrator, tag, language, series and listening state
+    #[serde(default)]
+    facets: bool,
+}
+
+/// order books by `sort_by`, then by id so the pages are stable
+fn sort_books(
+    query: Select<Music>,
+    sort_by: QueryBy,
+    order: QueryAscDesc,
+    user_id: i32,
+) -> Select<Music> {
+    let query = match sort_by {
+        QueryBy::Id => query,
+        QueryBy::Name => query.order_by(music::Column::Name, order.into()),
+        QueryBy::DateAdded => query.order_by(music::Column::CreatedAt, order.into()),
+        QueryBy::DateUpdated => query.order_by(music::Column::UpdatedAt, order.into()),
+        QueryBy::Author => query
+            .join(JoinType::InnerJoin, music::Relation::Author.def())
+            .order_by(author::Column::Name, order.into()),
+        QueryBy::Duration => query.order_by(music::Column::Duration, order.into()),
+        QueryBy::RecentlyPlayed => query.order_by(
+            Expr::cust_with_values(
+                "(SELECT MAX(`progress`.`last_played_at`) FROM `progress` \
+                 WHERE `progress`.`music_id` = `music`.`id` AND `progress`.`account_id` = ?)",
+                [user_id],
+            ),
+            order.into(),
+        ),
+    };
+    query.order_by(music::Column::Id, order.into())
+}
+
+/// order authors by `sort_by`, then by id so the pages are stable
+fn sort_authors(
+    query: Select<Author>,
+    sort_by: QueryBy,
+    order: QueryAscDesc,
+    user_id: i32,
+) -> Select<Author> {
+    let query = match sort_by {
+        QueryBy::Id => query,
+        QueryBy::Name | QueryBy::Author => query.order_by(author::Column::Name, order.into()),
+        QueryBy::DateAdded => query.order_by(author::Column::CreatedAt, order.into()),
+        QueryBy::DateUpdated => query.order_by(author::Column::UpdatedAt, order.into()),
+        QueryBy::Duration => query.order_by(
+            Expr::cust(
+                "(SELECT SUM(`music`.`duration`) FROM `music` \
+                 WHERE `music`.`author_id` = `author`.`id` AND `music`.`deleted_at` IS NULL)",
+            ),
+            order.into(),
+        ),
+        QueryBy::RecentlyPlayed => query.order_by(
+            Expr::cust_with_values(
+                "(SELECT MAX(`progress`.`last_played_at`) FROM `progress` \
+                 JOIN `music` ON `music`.`id` = `progress`.`music_id` \
+                 WHERE `music`.`author_id` = `author`.`id` AND `progress`.`account_id` = ?)",
+                [user_id],
+            ),
+            order.into(),
+        ),
+    };
+    query.order_by(author::Column::Id, order.into())
+}
+
+/// the name contains `query` or its simplified characters, or for a latin query the pinyin contains
+/// it or the initials start with it
+pub(crate) fn name_filter<C: ColumnTrait>(
+    query: &str,
+    name: C,
+    pinyin: C,
+    initials: C,
+) -> Condition {
+    let mut condition = Condition::any().add(name.contains(query));
+    let simplified = romanize::to_simplified(query);
+    if simplified != query {
+        condition = condition.add(name.contains(&simplified));
+    }
+    let latin = romanize::normalize_query(query);
+    if latin.is_empty() || !latin.is_ascii() {
+        return condition;
+    }
+    condition
+        .add(pinyin.contains(&latin))
+        .add(initials.starts_with(&latin))
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -50,93 +175,303 @@ struct ListResult<T> {
     total_pages: u64,
     page: u64,
     books: Vec<T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    facets: Option<Facets>,
 }
-async fn list_book(
-    State(state): State<AppStat>,
-    Form(args): Form<ListArgs>,
-) -> Json<ListResult<music::Model>> {
-    debug!("list book");
-    let books = Music::find()
-        .order_by_asc(music::Column::Id)
-        .paginate(&state.connections.db, args.page_size);
-    let total_pages = books.num_pages().await.unwrap();
-    let pages = books.fetch_page(args.page).await.unwrap();
-    Json(ListResult {
-        total_pages,
-        page: args.page,
-        books: pages.into_iter().map(Into::into).collect(),
+
+impl<T> ListResult<T> {
+    fn new(paged: Paged<T>, page: u64) -> Self {
+        Self {
+            total_pages: paged.total_pages,
+            page,
+            books: paged.items,
+            facets: paged.facets,
+        }
+    }
+}
+
+/// the page, the order and whether to count the facets, shared by the list endpoints
+#[derive(Debug, Clone, serde::Deserialize)]
+pub(crate) struct Listing {
+    #[serde(default)]
+    pub page: u64,
+    #[serde(default = "default_page_size")]
+    pub page_size: u64,
+    #[serde(default)]
+    pub sort_by: QueryBy,
+    #[serde(default)]
+    pub order: QueryAscDesc,
+    /// also count the books by author, narrator, tag, language, series and listening state
+    #[serde(default)]
+    pub facets: bool,
+}
+
+impl Listing {
+    /// the page size within 1 and [`MAX_PAGE_SIZE`]
+    pub fn clamped_page_size(&self) -> u64 {
+        self.page_size.clamp(1, MAX_PAGE_SIZE)
+    }
+
+    /// the index of the first item of the page, a page too far to count is a bad request
+    pub fn offset(&self) -> AppResult<u64> {
+        self.page
+            .checked_mul(self.clamped_page_size())
+            .ok_or_else(|| AppError::invalid_input(format!("page {} is out of range", self.page)))
+    }
+}
+
+impl From<ListArgs> for Listing {
+    fn from(args: ListArgs) -> Self {
+        Self {
+            page: args.page,
+            page_size: args.page_size,
+            sort_by: args.sort_by,
+            order: args.order,
+            facets: args.facets,
+        }
+    }
+}
+
+impl From<&SearchArgs> for Listing {
+    fn from(args: &SearchArgs) -> Self {
+        Self {
+            page: args.page,
+            page_size: args.page_size,
+            sort_by: args.sort_by,
+            order: args.order,
+            facets: args.facets,
+        }
+    }
+}
+
+/// one page of a list
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct Paged<T> {
+    pub items: Vec<T>,
+    pub total_items: u64,
+    pub total_pages: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub facets: Option<Facets>,
+}
+
+impl<T> Paged<T> {
+    /// the same page with every item turned into `U`
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> Paged<U> {
+        Paged {
+            items: self.items.into_iter().map(f).collect(),
+            total_items: self.total_items,
+            total_pages: self.total_pages,
+            facets: self.facets,
+        }
+    }
+}
+
+/// a book as the api shows it, without where its files are nor its fingerprint
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub(crate) struct BookResponse {
+    pub id: i32,
+    pub author_id: i32,
+    pub name: String,
+    pub chapters: i32,
+    pub duration: Option<f64>,
+    pub narrator: Option<String>,
+    pub series: Option<String>,
+    pub description: Option<String>,
+    pub language: Option<String>,
+    pub name_pinyin: Option<String>,
+    pub name_initials: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+impl From<music::Model> for BookResponse {
+    fn from(book: music::Model) -> Self {
+        Self {
+            id: book.id,
+            author_id: book.author_id,
+            name: book.name,
+            chapters: book.chapters,
+            duration: book.duration,
+            narrator: book.narrator,
+            series: book.series,
+            description: book.description,
+            language: book.language,
+            name_pinyin: book.name_pinyin,
+            name_initials: book.name_initials,
+            created_at: book.created_at,
+            updated_at: book.updated_at,
+        }
+    }
+}
+
+/// an author as the api shows it
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub(crate) struct AuthorResponse {
+    pub id: i32,
+    pub name: String,
+    /// the avatar image, empty when there is none
+    pub avatar: String,
+    pub description: String,
+    pub name_pinyin: Option<String>,
+    pub name_initials: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+impl From<author::Model> for AuthorResponse {
+    fn from(author: author::Model) -> Self {
+        Self {
+            id: author.id,
+            name: author.name,
+            avatar: author.avatar,
+            description: author.description,
+            name_pinyin: author.name_pinyin,
+            name_initials: author.name_initials,
+            created_at: author.created_at,
+            updated_at: author.updated_at,
+        }
+    }
+}
+
+/// the facets of `base` when they are asked for
+async fn facets_if(
+    state: &AppStat,
+    wanted: bool,
+    base: Select<Music>,
+    filter: &BookFilter,
+    user_id: i32,
+) -> AppResult<Option<Facets>> {
+    if !wanted {
+        return Ok(None);
+    }
+    let facets = filter::facets(&state.connections.db, base, filter, user_id).await?;
+    Ok(Some(facets))
+}
+
+/// the live books whose name matches `name`, or all of them, filtered and sorted
+pub(crate) async fn find_books(
+    state: &AppStat,
+    user_id: i32,
+    name: Option<&str>,
+    filter: &BookFilter,
+    listing: &Listing,
+) -> AppResult<Paged<music::Model>> {
+    let mut base = Music::find().filter(music::Column::DeletedAt.is_null());
+    if let Some(name) = name.filter(|name| !name.is_empty()) {
+        base = base.filter(name_filter(
+            name,
+            music::Column::Name,
+            music::Column::NamePinyin,
+            music::Column::NameInitials,
+        ));
+    }
+    let books = filter.apply(base.clone(), user_id);
+    let books = sort_books(books, listing.sort_by, listing.order, user_id)
+        .paginate(&state.connections.db, listing.clamped_page_size());
+    // `fetch_page` multiplies the page by its size unchecked
+    listing.offset()?;
+    let totals = books.num_items_and_pages().await?;
+    Ok(Paged {
+        items: books.fetch_page(listing.page).await?,
+        total_items: totals.number_of_items,
+        total_pages: totals.number_of_pages,
+        facets: facets_if(state, listing.facets, base, filter, user_id).await?,
     })
 }
 
-async fn listauthor(
+/// the live authors whose name matches `name`, or all of them, sorted
+pub(crate) async fn find_authors(
+    state: &AppStat,
+    user_id: i32,
+    name: Option<&str>,
+    listing: &Listing,
+) -> AppResult<Paged<author::Model>> {
+    let mut authors = Author::find().filter(author::Column::DeletedAt.is_null());
+    if let Some(name) = name.filter(|name| !name.is_empty()) {
+        authors = authors.filter(name_filter(
+            name,
+            author::Column::Name,
+            author::Column::NamePinyin,
+            author::Column::NameInitials,
+        ));
+    }
+    let authors = sort_authors(authors, listing.sort_by, listing.order, user_id)
+        .paginate(&state.connections.db, listing.clamped_page_size());
+    // `fetch_page` multiplies the page by its size unchecked
+    listing.offset()?;
+    let totals = authors.num_items_and_pages().await?;
+    Ok(Paged {
+        items: authors.fetch_page(listing.page).await?,
+        total_items: totals.number_of_items,
+        total_pages: totals.number_of_pages,
+        facets: None,
+    })
+}
+
+/// a book that is not in the trash
+pub(crate) async fn find_book(state: &AppStat, id: i32) -> AppResult<music::Model> {
+    Music::find_by_id(id)
+        .filter(music::Column::DeletedAt.is_null())
+        .one(&state.connections.db)
+        .await?
+        .ok_or_else(|| AppError::not_found(format!("book {} not found", id)))
+}
+
+/// an author that is not in the trash
+pub(crate) async fn find_author(state: &AppStat, id: i32) -> AppResult<author::Model> {
+    Author::find_by_id(id)
+        .filter(author::Column::DeletedAt.is_null())
+        .one(&state.connections.db)
+        .await?
+        .ok_or_else(|| AppError::not_found(format!("author {} not found", id)))
+}
+
+async fn list_book(
     State(state): State<AppStat>,
-    Form(args): Form<ListArgs>,
-) -> Json<ListResult<author::Model>> {
+    login: LoginInfo,
+    AppQuery(filter): AppQuery<BookFilter>,
+    AppForm(args): AppForm<ListArgs>,
+) -> AppResult<Json<ListResult<music::Model>>> {
     debug!("list book");
-    let authors = Author::find()
-        .order_by_asc(author::Column::Id)
-        .paginate(&state.connections.db, args.page_size);
-    let total_pages = authors.num_pages().await.unwrap();
-    let pages = authors.fetch_page(args.page).await.unwrap();
-    Json(ListResult {
-        total_pages,
-        page: args.page,
-        books: pages,
-    })
+    let page = args.page;
+    let books = find_books(&state, login.user_id, None, &filter, &args.into()).await?;
+    Ok(Json(ListResult::new(books, page)))
 }
 
-#[derive(Debug, serde::Serialize)]
-enum GetResult<T> {
-    Found(T),
-    NotFound(String),
+async fn listauthor(
+    State(state): State<AppStat>,
+    login: LoginInfo,
+    AppForm(args): AppForm<ListArgs>,
+) -> AppResult<Json<ListResult<author::Model>>> {
+    debug!("list author");
+    let page = args.page;
+    let authors = find_authors(&state, login.user_id, None, &args.into()).await?;
+    Ok(Json(ListResult::new(authors, page)))
 }
 
 async fn get_author_by_id(
     State(state): State<AppStat>,
     Path(id): Path<i32>,
-) -> Json<GetResult<author::Model>> {
+) -> AppResult<Json<author::Model>> {
     debug!("get author by id:{}", id);
-    let author = Author::find_by_id(id)
-        .one(&state.connections.db)
-        .await
-        .unwrap();
-    match author {
-        Some(author) => Json(GetResult::Found(author)),
-        None => Json(GetResult::NotFound(format!("author {} not found", id))),
-    }
+    Ok(Json(find_author(&state, id).await?))
 }
 
 async fn get_authors_by_name(
     State(state): State<AppStat>,
-    Form(args): Form<SearchArgs>,
-) -> Json<ListResult<author::Model>> {
-    // search book by name
-    let authors = Author::find()
-        .filter(author::Column::Name.contains(args.name))
-        .order_by_asc(author::Column::Id)
-        .paginate(&state.connections.db, args.page_size);
-    let total_pages = authors.num_pages().await.unwrap();
-    let pages = authors.fetch_page(args.page).await.unwrap();
-    Json(ListResult {
-        total_pages,
-        page: args.page,
-        books: pages,
-    })
+    login: LoginInfo,
+    AppForm(args): AppForm<SearchArgs>,
+) -> AppResult<Json<ListResult<author::Model>>> {
+    let authors = find_authors(&state, login.user_id, Some(&args.name), &(&args).into()).await?;
+    Ok(Json(ListResult::new(authors, args.page)))
 }
 
 async fn getbook_by_id(
     State(state): State<AppStat>,
     Path(id): Path<i32>,
-) -> Json<GetResult<music::Model>> {
-    debug!("get author by id:{}", id);
-    let music = Music::find_by_id(id)
-        .one(&state.connections.db)
-        .await
-        .unwrap();
-    match music {
-        Some(music) => Json(GetResult::Found(music)),
-        None => Json(GetResult::NotFound(format!("author {} not found", id))),
-    }
+) -> AppResult<Json<music::Model>> {
+    debug!("get book by id:{}", id);
+    Ok(Json(find_book(&state, id).await?))
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -144,26 +479,189 @@ struct SearchArgs {
     name: String,
     page: u64,
     page_size: u64,
+    #[serde(default)]
+    sort_by: QueryBy,
+    #[serde(default)]
+    order: QueryAscDesc,
+    #[serde(default)]
+    facets: bool,
+}
+
+/// the readium manifest of the book, for the audiobook readers
+async fn getbook_manifest(
+    State(state): State<AppStat>,
+    Path(book_id): Path<i32>,
+) -> AppResult<Response> {
+    let href = format!("/music/getbook/{}/manifest.json", book_id);
+    let manifest = publication::book_manifest(&state, book_id, &href).await?;
+    Ok((
+        [(header::CONTENT_TYPE, publication::MANIFEST_TYPE)],
+        Json(manifest),
+    )
+        .into_response())
 }
 
 async fn getbooks_by_name(
     State(state): State<AppStat>,
-    Form(args): Form<SearchArgs>,
-) -> Json<ListResult<music::Model>> {
-    // search book by name
-    let books = Music::find()
-        .filter(music::Column::Name.contains(args.name))
-        .order_by_asc(music::Column::Id)
-        .paginate(&state.connections.db, args.page_size);
-    let total_pages = books.num_pages().await.unwrap();
-    let pages = books.fetch_page(args.page).await.unwrap();
-    Json(ListResult {
-        total_pages,
-        page: args.page,
-        books: pages,
+    login: LoginInfo,
+    AppQuery(filter): AppQuery<BookFilter>,
+    AppForm(args): AppForm<SearchArgs>,
+) -> AppResult<Json<ListResult<music::Model>>> {
+    let listing = (&args).into();
+    let books = find_books(&state, login.user_id, Some(&args.name), &filter, &listing).await?;
+    Ok(Json(ListResult::new(books, args.page)))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct FullTextArgs {
+    q: String,
+    #[serde(default)]
+    page: u64,
+    #[serde(default = "default_page_size")]
+    page_size: u64,
+    #[serde(default)]
+    facets: bool,
+}
+
+/// the largest page a client can ask for
+pub(crate) const MAX_PAGE_SIZE: u64 = 100;
+
+pub(crate) fn default_page_size() -> u64 {
+    20
+}
+
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct SearchHit {
+    book: BookResponse,
+    author: Option<AuthorResponse>,
+    score: f32,
+    /// html fragments by field, the matches are wrapped in `<b>`
+    highlights: BTreeMap<String, Vec<String>>,
+    /// how far the user is in the book
+    progress: Option<BookProgress>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct SearchResult {
+    total_hits: usize,
+    total_pages: u64,
+    page: u64,
+    hits: Vec<SearchHit>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    facets: Option<Facets>,
+}
+
+/// full-text search across titles, authors, narrators, series, descriptions and chapter titles,
+/// ranked by relevance
+pub(crate) async fn full_text_search(
+    state: &AppStat,
+    user_id: i32,
+    q: &str,
+    filter: &BookFilter,
+    listing: &Listing,
+) -> AppResult<Paged<SearchHit>> {
+    let page_size = listing.clamped_page_size();
+    let offset = usize::try_from(listing.offset()?)
+        .map_err(|_| AppError::invalid_input(format!("page {} is out of range", listing.page)))?;
+    // the filters and facets are computed in the database over the books matching `q`
+    let mut facets = None;
+    let mut allowed = None;
+    if listing.facets || !filter.is_empty() {
+        let index = state.search.clone();
+        let q = q.to_string();
+        let matching = tokio::task::spawn_blocking(move || index.matching_ids(&q))
+            .await
+            .map_err(AppError::internal)?
+            .map_err(AppError::internal)?;
+        let base = Music::find()
+            .filter(music::Column::Id.is_in(matching))
+            .filter(music::Column::DeletedAt.is_null());
+        if !filter.is_empty() {
+            let ids: Vec<i32> = filter
+                .apply(base.clone(), user_id)
+                .select_only()
+                .column(music::Column::Id)
+                .into_tuple()
+                .all(&state.connections.db)
+                .await?;
+            allowed = Some(ids);
+        }
+        facets = facets_if(state, listing.facets, base, filter, user_id).await?;
+    }
+    let index = state.search.clone();
+    let q = q.to_string();
+    let (total_hits, raw_hits) = tokio::task::spawn_blocking(move || {
+        index.search(&q, offset, page_size as usize, allowed.as_deref())
+    })
+    .await
+    .map_err(AppError::internal)?
+    .map_err(AppError::internal)?;
+    let ids = raw_hits.iter().map(|hit| hit.id).collect::<Vec<_>>();
+    let mut books = Music::find()
+        .find_also_related(Author)
+        .filter(music::Column::Id.is_in(ids))
+        .filter(music::Column::DeletedAt.is_null())
+        .all(&state.connections.db)
+        .await?
+        .into_iter()
+        .map(|(book, author)| (book.id, (book, author)))
+        .collect::<HashMap<_, _>>();
+    // keep the ranking, books trashed since they were indexed are skipped
+    let found = raw_hits
+        .into_iter()
+        .filter_map(|hit| {
+            let found = books.remove(&hit.id)?;
+            Some((hit, found))
+        })
+        .collect::<Vec<_>>();
+    let found_books = found
+        .iter()
+        .map(|(_, (book, _))| book.clone())
+        .collect::<Vec<_>>();
+    let progress =
+        crate::progress::progress_in_books(&state.connections.db, user_id, &found_books).await?;
+    let hits = found
+        .into_iter()
+        .zip(progress)
+        .map(|((hit, (book, author)), progress)| SearchHit {
+            book: book.into(),
+            author: author.map(Into::into),
+            score: hit.score,
+            highlights: hit.highlights,
+            progress,
+        })
+        .collect::<Vec<_>>();
+    Ok(Paged {
+        items: hits,
+        total_items: total_hits as u64,
+        total_pages: (total_hits as u64).div_ceil(page_size),
+        facets,
     })
 }
 
+async fn search(
+    State(state): State<AppStat>,
+    login: LoginInfo,
+    AppQuery(filter): AppQuery<BookFilter>,
+    AppForm(args): AppForm<FullTextArgs>,
+) -> AppResult<Json<SearchResult>> {
+    let listing = Listing {
+        page: args.page,
+        page_size: args.page_size,
+        sort_by: QueryBy::default(),
+        order: QueryAscDesc::default(),
+        facets: args.facets,
+    };
+    let found = full_text_search(&state, login.user_id, &args.q, &filter, &listing).await?;
+    Ok(Json(SearchResult {
+        total_hits: found.total_items as usize,
+        total_pages: found.total_pages,
+        page: args.page,
+        hits: found.items,
+        facets: found.facets,
+    }))
+}
+
 // async fn getfile_by_id(
 //     State(state): State<AppStat>,
 //     Path((bookid, chapterid)): Path<(i32, i32)>,
@@ -211,3 +709,82 @@ async fn getbooks_by_name(
 //         None => return Err((StatusCode::NOT_FOUND, format!("book {} not found", bookid))),
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use sea_orm::{DbBackend, EntityTrait, QueryFilter, QueryTrait};
+
+    use super::{name_filter, sort_authors, sort_books, Listing, QueryAscDesc, QueryBy};
+    use crate::entities::{music, prelude::*};
+
+    #[test]
+    fn test_sort_books() {
+        let sql = sort_books(Music::find(), QueryBy::Author, QueryAscDesc::Desc, 1)
+            .build(DbBackend::MySql)
+            .to_string();
+        assert!(sql.contains("INNER JOIN `author`"));
+        assert!(sql.ends_with("ORDER BY `author`.`name` DESC, `music`.`id` DESC"));
+
+        let sql = sort_books(
+            Music::find(),
+            QueryBy::RecentlyPlayed,
+            QueryAscDesc::Desc,
+            7,
+        )
+        .build(DbBackend::MySql)
+        .to_string();
+        assert!(sql.contains("`progress`.`account_id` = 7) DESC"));
+
+        let sql = sort_books(Music::find(), QueryBy::Id, QueryAscDesc::Asc, 1)
+            .build(DbBackend::MySql)
+            .to_string();
+        assert!(sql.ends_with("ORDER BY `music`.`id` ASC"));
+    }
+
+    #[test]
+    fn test_name_filter() {
+        let filter = |query| {
+            let columns = (
+                music::Column::Name,
+                music::Column::NamePinyin,
+                music::Column::NameInitials,
+            );
+            Music::find()
+                .filter(name_filter(query, columns.0, columns.1, columns.2))
+                .build(DbBackend::MySql)
+                .to_string()
+        };
+        assert!(filter("San Guo").ends_with(
+            "WHERE `music`.`name` LIKE '%San Guo%' OR `music`.`name_pinyin` LIKE '%sanguo%' \
+             OR `music`.`name_initials` LIKE 'sanguo%'"
+        ));
+        assert!(filter("三国").ends_with("WHERE `music`.`name` LIKE '%三国%'"));
+        // traditional characters find the titles stored simplified
+        assert!(filter("三國")
+            .ends_with("WHERE `music`.`name` LIKE '%三國%' OR `music`.`name` LIKE '%三国%'"));
+    }
+
+    #[test]
+    fn test_listing_offset() {
+        let listing = |page, page_size| Listing {
+            page,
+            page_size,
+            sort_by: QueryBy::Id,
+            order: QueryAscDesc::Asc,
+            facets: false,
+        };
+        assert_eq!(listing(3, 20).offset().unwrap(), 60);
+        // the page size is clamped before the offset is computed
+        assert_eq!(listing(3, 1000).offset().unwrap(), 300);
+        assert_eq!(listing(3, 0).clamped_page_size(), 1);
+        assert!(listing(u64::MAX / 2, 20).offset().is_err());
+    }
+
+    #[test]
+    fn test_sort_authors() {
+        let sql = sort_authors(Author::find(), QueryBy::DateAdded, QueryAscDesc::Asc, 1)
+            .build(DbBackend::MySql)
+            .to_string();
+        assert!(sql.ends_with("ORDER BY `author`.`created_at` ASC, `author`.`id` ASC"));
+    }
+}
@@ -0,0 +1,440 @@
+//! the readium web publication manifest of a book, with the audiobook profile, and the opds 2.0
+//! catalog of the library, for the audiobook readers following the standards
+//!
+//! the audio files and the covers are linked under `/fetchbook`, so a reader needs the session
+//! cookie like the web player.
+
+use std::path::{Path, PathBuf};
+
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+use serde_json::{json, Value};
+
+use super::{filter::BookFilter, Listing, Paged};
+use crate::entities::{prelude::*, *};
+use crate::error::{AppError, AppResult};
+use crate::{tools, AppStat};
+
+pub(crate) const MANIFEST_TYPE: &str = "application/audiobook+json";
+pub(crate) const OPDS_TYPE: &str = "application/opds+json";
+const AUDIOBOOK_PROFILE: &str = "https://readium.org/webpub-manifest/profiles/audiobook";
+const OPEN_ACCESS: &str = "http://opds-spec.org/acquisition/open-access";
+/// where the opds catalog is mounted
+pub(crate) const OPDS_ROOT: &str = "/api/v1/opds";
+
+/// the characters escaped in a path segment
+const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// the url of a file under the book dir, given relative to it
+pub(crate) fn fetch_url(relative: &Path) -> String {
+    let segments = relative
+        .components()
+        .map(|part| utf8_percent_encode(&part.as_os_str().to_string_lossy(), SEGMENT).to_string())
+        .collect::<Vec<_>>();
+    format!("/fetchbook/{}", segments.join("/"))
+}
+
+/// the manifest of a book in `/api/v1`
+pub(crate) fn manifest_path(book_id: i32) -> String {
+    format!("/api/v1/books/{}/manifest.json", book_id)
+}
+
+/// the cover of a book, or the avatar of its author, relative to the book dir
+pub(crate) fn cover_path(
+    book_dir: &Path,
+    book: &music::Model,
+    author: Option<&author::Model>,
+) -> Option<PathBuf> {
+    tools::book_cover(book_dir.join(&book.file_folder))
+        .and_then(|cover| cover.strip_prefix(book_dir).ok().map(Path::to_path_buf))
+        .or_else(|| {
+            author
+                .filter(|author| !author.avatar.is_empty())
+                .map(|author| PathBuf::from(&author.avatar))
+        })
+}
+
+/// a link to the `cover` file at `href`
+pub(crate) fn link_to_cover(cover: &Path, href: String) -> Value {
+    json!({
+        "rel": "cover",
+        "href": href,
+        "type": mime_guess::from_path(cover).first_or_octet_stream().to_string(),
+    })
+}
+
+/// the cover of a book, or the avatar of its author, as a link under `/fetchbook`
+fn cover_link(
+    book_dir: &Path,
+    book: &music::Model,
+    author: Option<&author::Model>,
+) -> Option<Value> {
+    cover_path(book_dir, book, author).map(|cover| link_to_cover(&cover, fetch_url(&cover)))
+}
+
+fn metadata(book: &music::Model, author: Option<&author::Model>) -> Value {
+    let mut metadata = json!({
+        "@type": "http://schema.org/Audiobook",
+        "conformsTo": AUDIOBOOK_PROFILE,
+        "identifier": format!("urn:{}:book:{}", env!("CARGO_PKG_NAME"), book.id),
+        "title": book.name,
+        "modified": book.updated_at.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+    });
+    if let Some(author) = author {
+        metadata["author"] = json!({ "name": author.name });
+    }
+    if let Some(narrator) = &book.narrator {
+        metadata["narrator"] = json!({ "name": narrator });
+    }
+    if let Some(language) = &book.language {
+        metadata["language"] = language.as_str().into();
+    }
+    if let Some(description) = &book.description {
+        metadata["description"] = description.as_str().into();
+    }
+    if let Some(duration) = book.duration {
+        metadata["duration"] = duration.into();
+    }
+    if let Some(series) = &book.series {
+        metadata["belongsTo"] = json!({ "series": [{ "name": series }] });
+    }
+    metadata
+}
+
+/// a chapter in the reading order
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Track {
+    pub href: String,
+    pub mime: String,
+    pub title: String,
+    /// seconds, when the file could be decoded
+    pub duration: Option<f64>,
+}
+
+/// the readium manifest of a book read in the order of `tracks`
+pub(crate) fn manifest(
+    self_href: &str,
+    book: &music::Model,
+    author: Option<&author::Model>,
+    tracks: &[Track],
+    cover: Option<Value>,
+) -> Value {
+    let reading_order = tracks
+        .iter()
+        .map(|track| {
+            let mut link = json!({ "href": track.href, "type": track.mime, "title": track.title });
+            if let Some(duration) = track.duration {
+                link["duration"] = duration.into();
+            }
+            link
+        })
+        .collect::<Vec<_>>();
+    let toc = tracks
+        .iter()
+        .map(|track| json!({ "href": track.href, "title": track.title }))
+        .collect::<Vec<_>>();
+    json!({
+        "@context": "https://readium.org/webpub-manifest/context.jsonld",
+        "metadata": metadata(book, author),
+        "links": [{ "rel": "self", "href": self_href, "type": MANIFEST_TYPE }],
+        "readingOrder": reading_order,
+        "toc": toc,
+        "resources": cover.into_iter().collect::<Vec<_>>(),
+    })
+}
+
+/// a chapter file in the reading order
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ChapterFile {
+    pub chapter_no: i32,
+    pub path: PathBuf,
+    /// linked under `/fetchbook`
+    pub track: Track,
+}
+
+/// the chapter files of a book in order, with their titles and durations
+///
+/// the durations are the stored ones, probed on import and by the audio info task, a chapter
+/// not probed yet has none.
+pub(crate) async fn chapter_files(
+    state: &AppStat,
+    book: &music::Model,
+) -> AppResult<Vec<ChapterFile>> {
+    let chapters = Chapter::find()
+        .filter(chapter::Column::MusicId.eq(book.id))
+        .all(&state.connections.db)
+        .await?;
+    let files =
+        tools::chapter_files(state.book_dir.join(&book.file_folder)).map_err(AppError::internal)?;
+    Ok(files
+        .into_iter()
+        .map(|(chapter_no, path)| {
+            let chapter = chapters
+                .iter()
+                .find(|chapter| chapter.chapter_no == chapter_no);
+            ChapterFile {
+                chapter_no,
+                track: Track {
+                    href: fetch_url(path.strip_prefix(&state.book_dir).unwrap_or(&path)),
+                    mime: mime_guess::from_path(&path)
+                        .first_or_octet_stream()
+                        .to_string(),
+                    title: chapter.map_or_else(
+                        || format!("Chapter {}", chapter_no),
+                        |chapter| chapter.title.clone(),
+                    ),
+                    duration: chapter.and_then(|chapter| chapter.duration),
+                },
+                path,
+            }
+        })
+        .collect())
+}
+
+/// the manifest of a live book, `self_href` is the url it's served at
+pub(crate) async fn book_manifest(
+    state: &AppStat,
+    book_id: i32,
+    self_href: &str,
+) -> AppResult<Value> {
+    let book = super::find_book(state, book_id).await?;
+    let author = Author::find_by_id(book.author_id)
+        .one(&state.connections.db)
+        .await?;
+    let tracks = chapter_files(state, &book)
+        .await?
+        .into_iter()
+        .map(|file| file.track)
+        .collect::<Vec<_>>();
+    let cover = cover_link(&state.book_dir, &book, author.as_ref());
+    Ok(manifest(self_href, &book, author.as_ref(), &tracks, cover))
+}
+
+fn opds_link(rel: &str, href: impl Into<String>) -> Value {
+    json!({ "rel": rel, "href": href.into(), "type": OPDS_TYPE })
+}
+
+/// the entry point of the catalog
+pub(crate) fn opds_root() -> Value {
+    let books = format!("{}/books", OPDS_ROOT);
+    json!({
+        "metadata": { "title": env!("CARGO_PKG_NAME") },
+        "links": [
+            opds_link("self", OPDS_ROOT),
+            opds_link("start", OPDS_ROOT),
+            {
+                "rel": "search",
+                "href": format!("{}{{?query}}", books),
+                "type": OPDS_TYPE,
+                "templated": true,
+            },
+        ],
+        "navigation": [
+            { "title": "All books", "href": books, "type": OPDS_TYPE, "rel": "subsection" },
+            {
+                "title": "Recently added",
+                "href": format!("{}?sort_by=DateAdded&order=Desc", books),
+                "type": OPDS_TYPE,
+                "rel": "subsection",
+            },
+            {
+                "title": "Authors",
+                "href": format!("{}/authors", OPDS_ROOT),
+                "type": OPDS_TYPE,
+                "rel": "subsection",
+            },
+        ],
+    })
+}
+
+/// the self, first, previous, next and last links of page `listing.page` of `total_pages`,
+/// keeping the other parameters in `pairs`
+fn page_links(
+    href: &str,
+    pairs: &[(&str, String)],
+    listing: &Listing,
+    total_pages: u64,
+) -> Vec<Value> {
+    let page_url = |page: u64| {
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        for (key, value) in pairs {
+            query.append_pair(key, value);
+        }
+        query.append_pair("sort_by", &format!("{:?}", listing.sort_by));
+        query.append_pair("order", &format!("{:?}", listing.order));
+        query.append_pair("page_size", &listing.page_size.to_string());
+        query.append_pair("page", &page.to_string());
+        format!("{}?{}", href, query.finish())
+    };
+    let last = total_pages.saturating_sub(1);
+    let mut links = vec![
+        opds_link("self", page_url(listing.page)),
+        opds_link("first", page_url(0)),
+        opds_link("last", page_url(last)),
+    ];
+    if listing.page > 0 {
+        links.push(opds_link(
+            "previous",
+            page_url((listing.page - 1).min(last)),
+        ));
+    }
+    if listing.page < last {
+        links.push(opds_link("next", page_url(listing.page + 1)));
+    }
+    links
+}
+
+fn page_metadata(title: &str, listing: &Listing, total_items: u64) -> Value {
+    json!({
+        "title": title,
+        "numberOfItems": total_items,
+        "itemsPerPage": listing.page_size,
+        "currentPage": listing.page + 1,
+    })
+}
+
+/// a page of books as opds publications, linking to their manifests
+pub(crate) async fn opds_books(
+    state: &AppStat,
+    query: Option<&str>,
+    filter: &BookFilter,
+    listing: &Listing,
+    books: Paged<music::Model>,
+) -> AppResult<Value> {
+    let author_ids = books
+        .items
+        .iter()
+        .map(|book| book.author_id)
+        .collect::<Vec<_>>();
+    let authors = if author_ids.is_empty() {
+        vec![]
+    } else {
+        Author::find()
+            .filter(author::Column::Id.is_in(author_ids))
+            .all(&state.connections.db)
+            .await?
+    };
+    let publications = books
+        .items
+        .iter()
+        .map(|book| {
+            let author = authors.iter().find(|author| author.id == book.author_id);
+            let manifest = manifest_path(book.id);
+            json!({
+                "metadata": metadata(book, author),
+                "links": [
+                    { "rel": "self", "href": manifest, "type": MANIFEST_TYPE },
+                    { "rel": OPEN_ACCESS, "href": manifest, "type": MANIFEST_TYPE },
+                ],
+                "images": cover_link(&state.book_dir, book, author).into_iter().collect::<Vec<_>>(),
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut pairs = filter.query_pairs();
+    if let Some(query) = query.filter(|query| !query.is_empty()) {
+        pairs.push(("query", query.to_string()));
+    }
+    let title = match query {
+        Some(query) if !query.is_empty() => format!("Search: {}", query),
+        _ => "Books".to_string(),
+    };
+    Ok(json!({
+        "metadata": page_metadata(&title, listing, books.total_items),
+        "links": page_links(&format!("{}/books", OPDS_ROOT), &pairs, listing, books.total_pages),
+        "publications": publications,
+    }))
+}
+
+/// a page of authors as navigation links to their books
+pub(crate) fn opds_authors(listing: &Listing, authors: Paged<author::Model>) -> Value {
+    let navigation = authors
+        .items
+        .iter()
+        .map(|author| {
+            json!({
+                "title": author.name,
+                "href": format!("{}/books?author_id={}", OPDS_ROOT, author.id),
+                "type": OPDS_TYPE,
+                "rel": "subsection",
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "metadata": page_metadata("Authors", listing, authors.total_items),
+        "links": page_links(&format!("{}/authors", OPDS_ROOT), &[], listing, authors.total_pages),
+        "navigation": navigation,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::entities::music;
+    use crate::fixtures;
+    use crate::music::{Listing, QueryAscDesc, QueryBy};
+
+    use super::{fetch_url, manifest, page_links, Track};
+
+    #[test]
+    fn test_manifest() {
+        assert_eq!(
+            fetch_url(Path::new("金庸/射雕 英雄传/0001.mp3")),
+            "/fetchbook/%E9%87%91%E5%BA%B8/%E5%B0%84%E9%9B%95%20%E8%8B%B1%E9%9B%84%E4%BC%A0/0001.mp3"
+        );
+        let book = music::Model {
+            duration: Some(61.5),
+            narrator: Some("reader".to_string()),
+            ..fixtures::book(3, "book")
+        };
+        let tracks = [Track {
+            href: "/fetchbook/author/book/0001.mp3".to_string(),
+            mime: "audio/mpeg".to_string(),
+            title: "one".to_string(),
+            duration: Some(61.5),
+        }];
+        let manifest = manifest("/self", &book, None, &tracks, None);
+        let metadata = &manifest["metadata"];
+        assert_eq!(metadata["conformsTo"], super::AUDIOBOOK_PROFILE);
+        assert_eq!(metadata["duration"], 61.5);
+        assert_eq!(metadata["narrator"]["name"], "reader");
+        assert!(metadata.get("author").is_none());
+        assert_eq!(manifest["readingOrder"][0]["duration"], 61.5);
+        assert_eq!(manifest["toc"][0]["title"], "one");
+        assert_eq!(manifest["links"][0]["type"], "application/audiobook+json");
+    }
+
+    #[test]
+    fn test_page_links() {
+        let listing = Listing {
+            page: 1,
+            page_size: 10,
+            sort_by: QueryBy::Name,
+            order: QueryAscDesc::Asc,
+            facets: false,
+        };
+        let links = page_links("/opds/books", &[("tag", "a b".to_string())], &listing, 3);
+        let href = |rel: &str| {
+            links
+                .iter()
+                .find(|link| link["rel"] == rel)
+                .map(|link| link["href"].as_str().unwrap().to_string())
+        };
+        assert_eq!(
+            href("next").unwrap(),
+            "/opds/books?tag=a+b&sort_by=Name&order=Asc&page_size=10&page=2"
+        );
+        assert!(href("previous").unwrap().ends_with("page=0"));
+        assert!(href("last").unwrap().ends_with("page=2"));
+    }
+}
@@ -0,0 +1,201 @@
+//! the listening history, a log of sessions fed by the progress updates
+//!
+//! an update continues the latest session of the user on the book when it comes from the same
+//! device within [`SESSION_GAP`], otherwise it starts a new one from the previous progress.
+
+use hyper::{header, HeaderMap};
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, ConnectionTrait, DbErr, EntityTrait,
+    IntoActiveModel, PaginatorTrait, QueryFilter, QueryOrder,
+};
+
+use crate::entities::{prelude::*, *};
+use crate::music::{BookResponse, Paged};
+
+/// updates further apart than this are separate sessions
+const SESSION_GAP: chrono::Duration = chrono::Duration::minutes(10);
+/// the longest device name kept, in characters
+const MAX_DEVICE_LEN: usize = 255;
+
+/// a session with the book it was spent on
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct HistoryEntry {
+    #[serde(flatten)]
+    pub session: listening_session::Model,
+    pub book: Option<BookResponse>,
+}
+
+/// the device name as stored, trimmed and cut, `None` when empty
+pub(crate) fn device_name(device: Option<&str>) -> Option<String> {
+    let device = device?.trim();
+    if device.is_empty() {
+        return None;
+    }
+    Some(device.chars().take(MAX_DEVICE_LEN).collect())
+}
+
+/// the user agent, the device of the clients that don't name theirs
+pub(crate) fn user_agent(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// whether an update at `now` from `device` still belongs to `session`
+fn continues(
+    session: &listening_session::Model,
+    device: Option<&str>,
+    now: chrono::NaiveDateTime,
+) -> bool {
+    session.device.as_deref() == device && now - session.ended_at <= SESSION_GAP
+}
+
+/// log the move from `previous` to `chapter_no` and `position`
+pub(crate) async fn record(
+    db: &impl ConnectionTrait,
+    previous: &progress::Model,
+    chapter_no: i32,
+    position: f64,
+    device: Option<&str>,
+) -> Result<listening_session::Model, DbErr> {
+    let now = chrono::Utc::now().naive_utc();
+    let device = device_name(device);
+    let latest = ListeningSession::find()
+        .filter(listening_session::Column::AccountId.eq(previous.account_id))
+        .filter(listening_session::Column::MusicId.eq(previous.music_id))
+        .order_by_desc(listening_session::Column::EndedAt)
+        .one(db)
+        .await?;
+    match latest {
+        Some(session) if continues(&session, device.as_deref(), now) => {
+            let mut session = session.into_active_model();
+            session.end_chapter_no = ActiveValue::Set(chapter_no);
+            session.end_position = ActiveValue::Set(position);
+            session.ended_at = ActiveValue::Set(now);
+            session.update(db).await
+        }
+        _ => {
+            listening_session::ActiveModel {
+                account_id: ActiveValue::Set(previous.account_id),
+                music_id: ActiveValue::Set(previous.music_id),
+                start_chapter_no: ActiveValue::Set(previous.chapter_no),
+                start_position: ActiveValue::Set(previous.progress),
+                end_chapter_no: ActiveValue::Set(chapter_no),
+                end_position: ActiveValue::Set(position),
+                started_at: ActiveValue::Set(now),
+                ended_at: ActiveValue::Set(now),
+                device: ActiveValue::Set(device),
+                ..Default::default()
+            }
+            .insert(db)
+            .await
+        }
+    }
+}
+
+/// the sessions of `user_id`, the latest first, only on `book_id` when given
+pub(crate) async fn history(
+    db: &impl ConnectionTrait,
+    user_id: i32,
+    book_id: Option<i32>,
+    page: u64,
+    page_size: u64,
+) -> Result<Paged<HistoryEntry>, DbErr> {
+    let mut query = ListeningSession::find()
+        .filter(listening_session::Column::AccountId.eq(user_id))
+        .order_by_desc(listening_session::Column::EndedAt)
+        .order_by_desc(listening_session::Column::Id);
+    if let Some(book_id) = book_id {
+        query = query.filter(listening_session::Column::MusicId.eq(book_id));
+    }
+    let paginator = query.find_also_related(Music).paginate(db, page_size);
+    let counts = paginator.num_items_and_pages().await?;
+    let items = paginator
+        .fetch_page(page)
+        .await?
+        .into_iter()
+        .map(|(session, book)| HistoryEntry {
+            session,
+            book: book.map(Into::into),
+        })
+        .collect();
+    Ok(Paged {
+        items,
+        total_items: counts.number_of_items,
+        total_pages: counts.number_of_pages,
+        facets: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use sea_orm::{DatabaseBackend, MockDatabase, MockExecResult};
+
+    use super::{continues, device_name, record};
+    use crate::entities::listening_session;
+    use crate::fixtures;
+
+    fn session(device: Option<&str>, ended_at: chrono::NaiveDateTime) -> listening_session::Model {
+        listening_session::Model {
+            id: 1,
+            account_id: 1,
+            music_id: 2,
+            start_chapter_no: 1,
+            start_position: 0.,
+            end_chapter_no: 1,
+            end_position: 300.,
+            started_at: ended_at - chrono::Duration::minutes(5),
+            ended_at,
+            device: device.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_continues() {
+        let now = chrono::Utc::now().naive_utc();
+        let recent = session(Some("phone"), now - chrono::Duration::minutes(3));
+        assert!(continues(&recent, Some("phone"), now));
+        assert!(!continues(&recent, Some("laptop"), now));
+        assert!(!continues(&recent, None, now));
+        let old = session(Some("phone"), now - chrono::Duration::minutes(30));
+        assert!(!continues(&old, Some("phone"), now));
+
+        assert_eq!(device_name(Some("  ")), None);
+        assert_eq!(device_name(Some(" phone ")).as_deref(), Some("phone"));
+        assert_eq!(
+            device_name(Some(&"设".repeat(300)))
+                .unwrap()
+                .chars()
+                .count(),
+            255
+        );
+    }
+
+    #[tokio::test]
+    async fn test_record() {
+        let previous = fixtures::progress(2, 3, 42.);
+        let old = session(Some("phone"), chrono::NaiveDateTime::default());
+        let started = listening_session::Model {
+            id: 2,
+            start_chapter_no: 3,
+            start_position: 42.,
+            end_chapter_no: 3,
+            end_position: 100.,
+            ..old.clone()
+        };
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([[old]])
+            .append_exec_results([MockExecResult {
+                last_insert_id: 2,
+                rows_affected: 1,
+            }])
+            .append_query_results([[started.clone()]])
+            .into_connection();
+        let recorded = record(&db, &previous, 3, 100., Some("phone"))
+            .await
+            .unwrap();
+        assert_eq!(recorded, started);
+        let log = format!("{:?}", db.into_transaction_log());
+        assert!(log.contains("INSERT INTO `listening_session`"));
+    }
+}
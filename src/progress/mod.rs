@@ -1,21 +1,30 @@
 //! get the progress of a user of some book
 //!
+//! a progress is a chapter and the seconds into it, once the durations of the chapters are known
+//! it's also placed in the whole book as a [`BookProgress`].
+
+use std::collections::HashMap;
 
 use axum::{
     extract::State,
-    response::IntoResponse,
     routing::{get, post},
-    Form, Json, Router,
+    Json, Router,
 };
-use hyper::StatusCode;
+use hyper::HeaderMap;
 use sea_orm::{
-    ActiveModelTrait, ActiveValue, ColumnTrait, Condition, DatabaseConnection, EntityTrait,
-    QueryFilter, TryIntoModel,
+    ActiveModelTrait, ActiveValue, ColumnTrait, Condition, ConnectionTrait, DatabaseConnection,
+    DbErr, EntityTrait, IntoActiveModel, QueryFilter, TryIntoModel,
 };
 use tracing::debug;
 
+use crate::entities::sea_orm_active_enums::ProgressState;
 use crate::entities::{prelude::*, *};
-use crate::{middleware::LoginInfo, AppStat};
+use crate::error::{AppError, AppForm, AppJson, AppResult};
+use crate::{middleware::LoginInfo, music::BookResponse, AppStat};
+
+pub(crate) mod history;
+pub(crate) mod stats;
+
 pub(crate) fn route(state: AppStat) -> Router<AppStat> {
     Router::new()
         .route("/getprogress", get(getprogress))
@@ -36,12 +45,181 @@ struct FormArgs {
     book_id: i32,
 }
 
-pub(crate) async fn get_or_create_progress(
-    db: &DatabaseConnection,
+/// how far a progress is in the whole book, in seconds
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub(crate) struct BookProgress {
+    pub total_duration: f64,
+    pub elapsed: f64,
+    pub remaining: f64,
+    /// from 0 to 100, to one decimal
+    pub percent: f64,
+}
+
+impl BookProgress {
+    /// `position` seconds into chapter `chapter_no` of a book of `chapters` chapters, `None` until
+    /// the duration of every chapter is known
+    ///
+    /// chapter 0 is a book not started yet.
+    pub fn new(
+        chapters: i32,
+        durations: &[(i32, Option<f64>)],
+        chapter_no: i32,
+        position: f64,
+    ) -> Option<Self> {
+        let durations = (1..=chapters)
+            .map(|no| {
+                durations
+                    .iter()
+                    .find(|(chapter, _)| *chapter == no)
+                    .and_then(|(_, duration)| *duration)
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let total_duration = durations.iter().sum::<f64>();
+        let elapsed = match usize::try_from(chapter_no - 1) {
+            Ok(index) if index < durations.len() => {
+                // `max` also turns a nan position into the start
+                durations[..index].iter().sum::<f64>() + position.max(0.).min(durations[index])
+            }
+            Ok(_) => total_duration,
+            Err(_) => 0.,
+        };
+        let percent = if total_duration > 0. {
+            (elapsed / total_duration * 1000.).round() / 10.
+        } else {
+            0.
+        };
+        Some(Self {
+            total_duration,
+            elapsed,
+            remaining: total_duration - elapsed,
+            percent,
+        })
+    }
+
+    /// like `43% done, 5h12m left`
+    pub fn summary(&self) -> String {
+        let minutes = (self.remaining / 60.).round() as u64;
+        let left = match minutes / 60 {
+            0 => format!("{}m", minutes),
+            hours => format!("{}h{:02}m", hours, minutes % 60),
+        };
+        format!("{}% done, {} left", self.percent.floor(), left)
+    }
+}
+
+/// a progress with its place in the whole book, when known
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct ProgressResponse {
+    #[serde(flatten)]
+    pub progress: progress::Model,
+    #[serde(flatten)]
+    pub book: Option<BookProgress>,
+}
+
+/// a book with how far the user is in it, when known
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct BookWithProgress {
+    #[serde(flatten)]
+    pub book: BookResponse,
+    pub progress: Option<BookProgress>,
+}
+
+/// the stored chapter durations of some books, by book id
+pub(crate) async fn chapter_durations(
+    db: &impl ConnectionTrait,
+    book_ids: &[i32],
+) -> Result<HashMap<i32, Vec<(i32, Option<f64>)>>, DbErr> {
+    let mut durations: HashMap<i32, Vec<(i32, Option<f64>)>> = HashMap::new();
+    if book_ids.is_empty() {
+        return Ok(durations);
+    }
+    let chapters = Chapter::find()
+        .filter(chapter::Column::MusicId.is_in(book_ids.iter().copied()))
+        .all(db)
+        .await?;
+    for chapter in chapters {
+        durations
+            .entry(chapter.music_id)
+            .or_default()
+            .push((chapter.chapter_no, chapter.duration));
+    }
+    Ok(durations)
+}
+
+/// place a progress of `book` in the whole book
+pub(crate) async fn with_book_progress(
+    db: &impl ConnectionTrait,
+    book: &music::Model,
+    progress: progress::Model,
+) -> Result<ProgressResponse, DbErr> {
+    let durations = chapter_durations(db, &[book.id]).await?;
+    let book = BookProgress::new(
+        book.chapters,
+        durations.get(&book.id).map_or(&[], Vec::as_slice),
+        progress.chapter_no,
+        progress.progress,
+    );
+    Ok(ProgressResponse { progress, book })
+}
+
+/// the progress of `user_id` in each of `books`, a book never played is at its start
+pub(crate) async fn progress_in_books(
+    db: &impl ConnectionTrait,
+    user_id: i32,
+    books: &[music::Model],
+) -> Result<Vec<Option<BookProgress>>, DbErr> {
+    let ids = books.iter().map(|book| book.id).collect::<Vec<_>>();
+    let durations = chapter_durations(db, &ids).await?;
+    let progresses = if ids.is_empty() {
+        vec![]
+    } else {
+        Progress::find()
+            .filter(progress::Column::AccountId.eq(user_id))
+            .filter(progress::Column::MusicId.is_in(ids))
+            .all(db)
+            .await?
+    };
+    Ok(books
+        .iter()
+        .map(|book| {
+            let (chapter_no, position) = progresses
+                .iter()
+                .find(|p| p.music_id == book.id)
+                .map_or((0, 0.), |p| (p.chapter_no, p.progress));
+            BookProgress::new(
+                book.chapters,
+                durations.get(&book.id).map_or(&[], Vec::as_slice),
+                chapter_no,
+                position,
+            )
+        })
+        .collect())
+}
+
+/// `books` along with the progress of `user_id` in them
+pub(crate) async fn books_with_progress(
+    db: &impl ConnectionTrait,
+    user_id: i32,
+    books: Vec<music::Model>,
+) -> Result<Vec<BookWithProgress>, DbErr> {
+    let progress = progress_in_books(db, user_id, &books).await?;
+    Ok(books
+        .into_iter()
+        .zip(progress)
+        .map(|(book, progress)| BookWithProgress {
+            book: book.into(),
+            progress,
+        })
+        .collect())
+}
+
+/// the stored progress of `user_id` in `book_id`
+async fn stored_progress(
+    db: &impl ConnectionTrait,
     user_id: i32,
     book_id: i32,
-) -> progress::Model {
-    let p = Progress::find()
+) -> Result<Option<progress::Model>, DbErr> {
+    Progress::find()
         .filter(
             Condition::all()
                 .add(progress::Column::AccountId.eq(user_id))
@@ -49,9 +227,40 @@ pub(crate) async fn get_or_create_progress(
         )
         .one(db)
         .await
-        .unwrap();
-    match p {
-        Some(model) => model,
+}
+
+/// the progress of `user_id` in `book_id`, a book never played has an unsaved progress at
+/// chapter 0 with id 0 until the first position or state update saves it
+pub(crate) async fn find_progress(
+    db: &impl ConnectionTrait,
+    user_id: i32,
+    book_id: i32,
+) -> Result<progress::Model, DbErr> {
+    Ok(stored_progress(db, user_id, book_id)
+        .await?
+        .unwrap_or_else(|| progress::Model {
+            id: 0,
+            account_id: user_id,
+            music_id: book_id,
+            chapter_no: 0,
+            progress: 0.,
+            last_played_at: None,
+            state: ProgressState::default(),
+            finished_at: None,
+            finish_count: 0,
+            relisten_count: 0,
+        }))
+}
+
+/// the stored progress of `user_id` in `book_id`, saved at chapter 0 when there is none, only
+/// for an update
+async fn get_or_create_progress(
+    db: &DatabaseConnection,
+    user_id: i32,
+    book_id: i32,
+) -> Result<progress::Model, DbErr> {
+    match stored_progress(db, user_id, book_id).await? {
+        Some(model) => Ok(model),
         None => {
             let model = progress::ActiveModel {
                 account_id: ActiveValue::Set(user_id),
@@ -60,11 +269,114 @@ pub(crate) async fn get_or_create_progress(
                 progress: ActiveValue::Set(0.),
                 ..Default::default()
             };
-            let result = model.save(db).await.unwrap();
-            let model = result.try_into_model().unwrap();
-            model
+            let result = model.save(db).await?;
+            result.try_into_model()
+        }
+    }
+}
+
+/// a progress closer than this to the end of the book finishes it, in seconds
+const FINISH_MARGIN: f64 = 30.;
+
+/// `current` moved to `position` in `chapter_no`, `remaining` is then left in the book when known
+///
+/// reaching the end finishes the book, going back in a finished book is listening to it again and
+/// playing an abandoned or wanted book resumes it.
+fn moved(
+    mut current: progress::Model,
+    chapter_no: i32,
+    position: f64,
+    remaining: Option<f64>,
+    now: chrono::NaiveDateTime,
+) -> progress::Model {
+    let at_end = remaining.is_some_and(|remaining| remaining <= FINISH_MARGIN);
+    let back = (chapter_no, position) < (current.chapter_no, current.progress);
+    match current.state {
+        ProgressState::Finished if back && !at_end => {
+            current.state = ProgressState::InProgress;
+            current.relisten_count += 1;
+        }
+        ProgressState::Finished => {}
+        _ if at_end => {
+            current.state = ProgressState::Finished;
+            current.finished_at = Some(now);
+            current.finish_count += 1;
+        }
+        _ => current.state = ProgressState::InProgress,
+    }
+    current.chapter_no = chapter_no;
+    current.progress = position;
+    current.last_played_at = Some(now);
+    current
+}
+
+/// `current` put in `state` by hand, a finished book back in progress starts over
+fn with_state(
+    mut current: progress::Model,
+    state: ProgressState,
+    now: chrono::NaiveDateTime,
+) -> progress::Model {
+    match (current.state, state) {
+        (ProgressState::Finished, ProgressState::Finished) => {}
+        (_, ProgressState::Finished) => {
+            current.finished_at = Some(now);
+            current.finish_count += 1;
         }
+        (ProgressState::Finished, ProgressState::InProgress) => {
+            current.relisten_count += 1;
+            current.chapter_no = 0;
+            current.progress = 0.;
+        }
+        _ => {}
     }
+    current.state = state;
+    current
+}
+
+/// move the progress of `user_id` on `book` to `position`, creating it when needed, and log the
+/// listening session of `device`
+pub(crate) async fn set_position(
+    db: &DatabaseConnection,
+    user_id: i32,
+    book: &music::Model,
+    chapter_no: i32,
+    position: f64,
+    device: Option<&str>,
+) -> Result<progress::Model, DbErr> {
+    let current = get_or_create_progress(db, user_id, book.id).await?;
+    history::record(db, &current, chapter_no, position, device).await?;
+    let durations = chapter_durations(db, &[book.id]).await?;
+    let remaining = BookProgress::new(
+        book.chapters,
+        durations.get(&book.id).map_or(&[], Vec::as_slice),
+        chapter_no,
+        position,
+    )
+    .map(|progress| progress.remaining);
+    let now = chrono::Utc::now().naive_utc();
+    moved(current, chapter_no, position, remaining, now)
+        .into_active_model()
+        .reset_all()
+        .update(db)
+        .await
+}
+
+/// put the progress of `user_id` on `book_id` in `state`, creating it when needed
+pub(crate) async fn set_state(
+    db: &DatabaseConnection,
+    user_id: i32,
+    book_id: i32,
+    state: ProgressState,
+) -> Result<progress::Model, DbErr> {
+    let current = get_or_create_progress(db, user_id, book_id).await?;
+    if current.state == state {
+        return Ok(current);
+    }
+    with_state(current, state, chrono::Utc::now().naive_utc())
+        .into_active_model()
+        .reset_all()
+        .update(db)
+        .await
 }
 
 async fn getprogress(
@@ -74,19 +386,194 @@ async fn getprogress(
         role_level,
         user_name: _,
     }: LoginInfo,
-    Form(para): Form<FormArgs>,
-) -> impl IntoResponse {
+    AppForm(para): AppForm<FormArgs>,
+) -> AppResult<Json<ProgressResponse>> {
     debug!("getprogress: {:?},{} {}", para, user_id, role_level);
-    let model = get_or_create_progress(&state.connections.db, user_id, para.book_id).await;
-    (StatusCode::OK, Json(model))
+    let db = &state.connections.db;
+    let model = find_progress(db, user_id, para.book_id).await?;
+    let response = match Music::find_by_id(para.book_id).one(db).await? {
+        Some(book) => with_book_progress(db, &book, model).await?,
+        None => ProgressResponse {
+            progress: model,
+            book: None,
+        },
+    };
+    Ok(Json(response))
 }
-async fn setprogress(State(state): State<AppStat>, Json(modle): Json<progress::Model>) {
+
+/// the progress `id` of `user_id`, the progress of another user looks missing
+async fn own_progress(
+    db: &impl ConnectionTrait,
+    user_id: i32,
+    id: i32,
+) -> AppResult<progress::Model> {
+    Progress::find_by_id(id)
+        .one(db)
+        .await?
+        .filter(|progress| progress.account_id == user_id)
+        .ok_or_else(|| AppError::not_found(format!("progress {} not found", id)))
+}
+
+async fn setprogress(
+    State(state): State<AppStat>,
+    login: LoginInfo,
+    headers: HeaderMap,
+    AppJson(modle): AppJson<progress::Model>,
+) -> AppResult<()> {
     debug!("setprogress: {:?}", modle);
-    let chapter = modle.chapter_no;
-    let progress = modle.progress;
-    let mut model: progress::ActiveModel = modle.into();
-    model.chapter_no = ActiveValue::Set(chapter);
-    model.progress = ActiveValue::Set(progress);
-    debug!("setprogress: {:?}", model);
-    model.save(&state.connections.db).await.unwrap();
+    let db = &state.connections.db;
+    // a book not played yet has no progress, its id is 0 until this first update saves it
+    let book_id = match modle.id {
+        0 => modle.music_id,
+        id => own_progress(db, login.user_id, id).await?.music_id,
+    };
+    let book = Music::find_by_id(book_id)
+        .one(db)
+        .await?
+        .ok_or_else(|| AppError::not_found(format!("book {} not found", book_id)))?;
+    let device = history::user_agent(&headers);
+    set_position(
+        db,
+        login.user_id,
+        &book,
+        modle.chapter_no,
+        modle.progress,
+        device,
+    )
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use sea_orm::{DatabaseBackend, MockDatabase};
+
+    use super::{find_progress, moved, own_progress, with_state, BookProgress};
+    use crate::entities::{progress, sea_orm_active_enums::ProgressState};
+    use crate::error::ErrorCode;
+    use crate::fixtures;
+
+    fn progress(state: ProgressState, chapter_no: i32, position: f64) -> progress::Model {
+        progress::Model {
+            state,
+            ..fixtures::progress(2, chapter_no, position)
+        }
+    }
+
+    #[test]
+    fn test_book_progress() {
+        let durations = [(1, Some(3600.)), (2, Some(7200.)), (3, Some(7200.))];
+        let progress = BookProgress::new(3, &durations, 2, 600.).unwrap();
+        assert_eq!(progress.total_duration, 18000.);
+        assert_eq!(progress.elapsed, 4200.);
+        assert_eq!(progress.remaining, 13800.);
+        assert_eq!(progress.percent, 23.3);
+        assert_eq!(progress.summary(), "23% done, 3h50m left");
+
+        // not started, past the end of a chapter, past the last chapter
+        assert_eq!(BookProgress::new(3, &durations, 0, 0.).unwrap().elapsed, 0.);
+        assert_eq!(
+            BookProgress::new(3, &durations, 1, 5000.).unwrap().elapsed,
+            3600.
+        );
+        let done = BookProgress::new(3, &durations, 4, 0.).unwrap();
+        assert_eq!((done.percent, done.remaining), (100., 0.));
+        assert_eq!(done.summary(), "100% done, 0m left");
+
+        // a chapter without a known duration, or without a row
+        assert_eq!(
+            BookProgress::new(3, &[(1, Some(60.)), (2, None), (3, Some(60.))], 1, 0.),
+            None
+        );
+        assert_eq!(BookProgress::new(3, &durations[..2], 1, 0.), None);
+    }
+
+    #[test]
+    fn test_moved() {
+        let now = chrono::Utc::now().naive_utc();
+        let listening = progress(ProgressState::InProgress, 2, 600.);
+        let moved_on = moved(listening.clone(), 2, 700., Some(3600.), now);
+        assert_eq!(moved_on.state, ProgressState::InProgress);
+        assert_eq!((moved_on.chapter_no, moved_on.progress), (2, 700.));
+        assert_eq!(moved_on.last_played_at, Some(now));
+        // unknown durations never finish a book
+        assert_eq!(
+            moved(listening.clone(), 3, 7200., None, now).state,
+            ProgressState::InProgress
+        );
+
+        let finished = moved(listening, 3, 7190., Some(10.), now);
+        assert_eq!(finished.state, ProgressState::Finished);
+        assert_eq!(finished.finished_at, Some(now));
+        assert_eq!(finished.finish_count, 1);
+        // the last seconds played again keep it finished
+        let again = moved(finished.clone(), 3, 7195., Some(5.), now);
+        assert_eq!(
+            (again.state, again.finish_count),
+            (ProgressState::Finished, 1)
+        );
+
+        let relistened = moved(finished, 1, 0., Some(18000.), now);
+        assert_eq!(relistened.state, ProgressState::InProgress);
+        assert_eq!(relistened.relisten_count, 1);
+        assert_eq!(relistened.finish_count, 1);
+
+        let resumed = moved(
+            progress(ProgressState::Abandoned, 2, 600.),
+            2,
+            610.,
+            None,
+            now,
+        );
+        assert_eq!(resumed.state, ProgressState::InProgress);
+    }
+
+    #[test]
+    fn test_with_state() {
+        let now = chrono::Utc::now().naive_utc();
+        let finished = with_state(
+            progress(ProgressState::InProgress, 2, 600.),
+            ProgressState::Finished,
+            now,
+        );
+        assert_eq!(finished.finished_at, Some(now));
+        assert_eq!(finished.finish_count, 1);
+        assert_eq!(
+            with_state(finished.clone(), ProgressState::Finished, now).finish_count,
+            1
+        );
+
+        let started_over = with_state(finished.clone(), ProgressState::InProgress, now);
+        assert_eq!(started_over.relisten_count, 1);
+        assert_eq!((started_over.chapter_no, started_over.progress), (0, 0.));
+
+        let abandoned = with_state(finished, ProgressState::Abandoned, now);
+        assert_eq!(abandoned.state, ProgressState::Abandoned);
+        assert_eq!((abandoned.chapter_no, abandoned.finish_count), (2, 1));
+    }
+
+    #[tokio::test]
+    async fn test_own_progress() {
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([[fixtures::progress(2, 1, 0.)]])
+            .append_query_results([[fixtures::progress(2, 1, 0.)]])
+            .into_connection();
+        assert_eq!(own_progress(&db, 1, 1).await.unwrap().music_id, 2);
+        // the progress of account 1 is not found for account 2
+        let error = own_progress(&db, 2, 1).await.unwrap_err();
+        assert_eq!(error.code, ErrorCode::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_find_progress() {
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([Vec::<progress::Model>::new()])
+            .into_connection();
+        let progress = find_progress(&db, 1, 2).await.unwrap();
+        assert_eq!((progress.id, progress.chapter_no), (0, 0));
+        assert_eq!(progress.state, ProgressState::InProgress);
+        // looking is not starting, nothing is saved
+        let log = format!("{:?}", db.into_transaction_log());
+        assert!(!log.contains("INSERT"), "{}", log);
+    }
 }
@@ -0,0 +1,589 @@
+//! listening statistics of a user and of the whole server, aggregated from the session log
+//!
+//! the time of a session is the time between its first and its last update, the database sums it
+//! per user, book and day. The speed is how much of the book a session covered in that time, which
+//! needs the chapter durations, so it only looks at the sessions of the last 30 days.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use chrono::{Datelike, NaiveDate, NaiveDateTime};
+use sea_orm::{
+    sea_query::Expr, ColumnTrait, ConnectionTrait, DbErr, EntityTrait, FromQueryResult, JoinType,
+    PaginatorTrait, QueryFilter, QuerySelect, RelationTrait, Select,
+};
+
+use super::{chapter_durations, BookProgress};
+use crate::entities::{prelude::*, *};
+
+/// the number of days, weeks and months in the charts
+const DAYS: usize = 30;
+const WEEKS: usize = 12;
+const MONTHS: usize = 12;
+/// the length of the top lists
+const TOP: usize = 10;
+/// a listener with a session in these last days is active
+const ACTIVE_DAYS: i64 = 7;
+
+/// the day a session started on
+const SESSION_DAY: &str = "DATE(`listening_session`.`started_at`)";
+/// the time of the sessions of a group in seconds, a session ending before it started counts 0
+const SESSION_SECONDS: &str = "SUM(GREATEST(TIMESTAMPDIFF(MICROSECOND, \
+    `listening_session`.`started_at`, `listening_session`.`ended_at`), 0)) / 1e6";
+
+/// the time a user listened to a book on a day, summed by the database
+#[derive(Debug, Clone, PartialEq, FromQueryResult)]
+pub(crate) struct Listened {
+    pub account_id: i32,
+    pub book_id: i32,
+    pub author_id: i32,
+    pub day: NaiveDate,
+    /// seconds
+    pub time: f64,
+    /// the end of the last session of the day
+    pub last_played_at: NaiveDateTime,
+}
+
+/// the time listened in a day, a week or a month
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub(crate) struct PeriodTime {
+    /// the first day of the period
+    pub start: NaiveDate,
+    /// seconds
+    pub seconds: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub(crate) struct AuthorTime {
+    pub id: i32,
+    pub name: String,
+    pub seconds: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub(crate) struct ListenerTime {
+    pub id: i32,
+    pub name: String,
+    pub seconds: f64,
+    pub last_played_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub(crate) struct BookTime {
+    pub id: i32,
+    pub name: String,
+    pub seconds: f64,
+    /// the number of users who listened to it
+    pub listeners: usize,
+}
+
+/// the stats of some sessions, the periods are the oldest first
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub(crate) struct ListeningStats {
+    /// seconds
+    pub total_seconds: f64,
+    pub days: Vec<PeriodTime>,
+    pub weeks: Vec<PeriodTime>,
+    pub months: Vec<PeriodTime>,
+    /// the days in a row with a session, up to today or yesterday
+    pub current_streak: u32,
+    pub longest_streak: u32,
+    pub books_finished: u64,
+    pub top_authors: Vec<AuthorTime>,
+    /// the book seconds covered per second listened in the last 30 days, 1 at normal speed
+    pub average_speed: Option<f64>,
+}
+
+/// the stats of the whole server, for the admins
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub(crate) struct ServerStats {
+    #[serde(flatten)]
+    pub listening: ListeningStats,
+    /// the users with a session in the last week, the most listening first
+    pub active_listeners: Vec<ListenerTime>,
+    pub most_played: Vec<BookTime>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Period {
+    Day,
+    Week,
+    Month,
+}
+
+impl Period {
+    /// the first day of the period holding `day`, weeks start on monday
+    fn start(self, day: NaiveDate) -> NaiveDate {
+        match self {
+            Period::Day => day,
+            Period::Week => {
+                day - chrono::Duration::days(day.weekday().num_days_from_monday().into())
+            }
+            Period::Month => day.with_day(1).unwrap_or(day),
+        }
+    }
+
+    /// the first day of the period before the one starting on `start`
+    fn previous(self, start: NaiveDate) -> NaiveDate {
+        match self {
+            Period::Day => start - chrono::Duration::days(1),
+            Period::Week => start - chrono::Duration::weeks(1),
+            Period::Month => Period::Month.start(start - chrono::Duration::days(1)),
+        }
+    }
+}
+
+/// the time listened in each of the last `count` periods up to `today`
+fn periods(
+    listened: &[Listened],
+    period: Period,
+    count: usize,
+    today: NaiveDate,
+) -> Vec<PeriodTime> {
+    let mut starts = vec![period.start(today)];
+    while starts.len() < count {
+        let last = starts[starts.len() - 1];
+        starts.push(period.previous(last));
+    }
+    starts.reverse();
+    let mut seconds = HashMap::<NaiveDate, f64>::new();
+    for listened in listened {
+        *seconds.entry(period.start(listened.day)).or_default() += listened.time;
+    }
+    starts
+        .into_iter()
+        .map(|start| PeriodTime {
+            start,
+            seconds: seconds.get(&start).copied().unwrap_or(0.),
+        })
+        .collect()
+}
+
+/// the current and the longest runs of consecutive days in `days`, a run still counts today
+/// until the day is over
+fn streaks(days: &BTreeSet<NaiveDate>, today: NaiveDate) -> (u32, u32) {
+    let mut longest = 0;
+    let mut run = 0;
+    let mut last: Option<NaiveDate> = None;
+    for day in days {
+        run = match last {
+            Some(last) if *day - last == chrono::Duration::days(1) => run + 1,
+            _ => 1,
+        };
+        longest = longest.max(run);
+        last = Some(*day);
+    }
+    let yesterday = today - chrono::Duration::days(1);
+    let current = match last {
+        Some(last) if last == today || last == yesterday => run,
+        _ => 0,
+    };
+    (current, longest)
+}
+
+/// the stats of `listened`, the authors are named from `authors`
+pub(crate) fn summarize(
+    listened: &[Listened],
+    authors: &HashMap<i32, String>,
+    books_finished: u64,
+    average_speed: Option<f64>,
+    today: NaiveDate,
+) -> ListeningStats {
+    let days = listened
+        .iter()
+        .map(|listened| listened.day)
+        .collect::<BTreeSet<_>>();
+    let (current_streak, longest_streak) = streaks(&days, today);
+    let mut by_author = HashMap::<i32, f64>::new();
+    for listened in listened {
+        *by_author.entry(listened.author_id).or_default() += listened.time;
+    }
+    let mut top_authors = by_author
+        .into_iter()
+        .map(|(id, seconds)| AuthorTime {
+            id,
+            name: authors.get(&id).cloned().unwrap_or_default(),
+            seconds,
+        })
+        .collect::<Vec<_>>();
+    top_authors.sort_by(|a, b| b.seconds.total_cmp(&a.seconds).then(a.id.cmp(&b.id)));
+    top_authors.truncate(TOP);
+    ListeningStats {
+        total_seconds: listened.iter().map(|listened| listened.time).sum(),
+        days: periods(listened, Period::Day, DAYS, today),
+        weeks: periods(listened, Period::Week, WEEKS, today),
+        months: periods(listened, Period::Month, MONTHS, today),
+        current_streak,
+        longest_streak,
+        books_finished,
+        top_authors,
+        average_speed,
+    }
+}
+
+/// the book seconds covered per second listened in the `(time, covered)` of some sessions, a
+/// session going back or without time is left out
+fn average_speed(sessions: &[(f64, f64)]) -> Option<f64> {
+    let (covered, timed) = sessions
+        .iter()
+        .filter(|(time, covered)| *time > 0. && *covered >= 0.)
+        .fold((0., 0.), |(covered, timed), (t, c)| {
+            (covered + c, timed + t)
+        });
+    (timed > 0.).then(|| (covered / timed * 100.).round() / 100.)
+}
+
+/// the listeners with a session since `since`, the most listening first
+pub(crate) fn active_listeners(
+    listened: &[Listened],
+    accounts: &HashMap<i32, String>,
+    since: NaiveDateTime,
+) -> Vec<ListenerTime> {
+    let mut listeners = HashMap::<i32, (f64, NaiveDateTime)>::new();
+    for listened in listened.iter().filter(|l| l.last_played_at >= since) {
+        let entry = listeners
+            .entry(listened.account_id)
+            .or_insert((0., listened.last_played_at));
+        entry.0 += listened.time;
+        entry.1 = entry.1.max(listened.last_played_at);
+    }
+    let mut listeners = listeners
+        .into_iter()
+        .map(|(id, (seconds, last_played_at))| ListenerTime {
+            id,
+            name: accounts.get(&id).cloned().unwrap_or_default(),
+            seconds,
+            last_played_at,
+        })
+        .collect::<Vec<_>>();
+    listeners.sort_by(|a, b| b.seconds.total_cmp(&a.seconds).then(a.id.cmp(&b.id)));
+    listeners
+}
+
+/// the books listened to the longest among `books`, the live ones by name
+pub(crate) fn most_played(listened: &[Listened], books: &HashMap<i32, String>) -> Vec<BookTime> {
+    let mut played = HashMap::<i32, (f64, HashSet<i32>)>::new();
+    for listened in listened.iter().filter(|l| books.contains_key(&l.book_id)) {
+        let entry = played.entry(listened.book_id).or_default();
+        entry.0 += listened.time;
+        entry.1.insert(listened.account_id);
+    }
+    let mut played = played
+        .into_iter()
+        .map(|(id, (seconds, listeners))| BookTime {
+            id,
+            name: books[&id].clone(),
+            seconds,
+            listeners: listeners.len(),
+        })
+        .collect::<Vec<_>>();
+    played.sort_by(|a, b| b.seconds.total_cmp(&a.seconds).then(a.id.cmp(&b.id)));
+    played.truncate(TOP);
+    played
+}
+
+/// the time listened per user, book and day, by `user_id` or by everyone
+fn per_day(user_id: Option<i32>) -> Select<ListeningSession> {
+    let mut query = ListeningSession::find()
+        .select_only()
+        .column(listening_session::Column::AccountId)
+        .column_as(listening_session::Column::MusicId, "book_id")
+        .column(music::Column::AuthorId)
+        .column_as(Expr::cust(SESSION_DAY), "day")
+        .column_as(Expr::cust(SESSION_SECONDS), "time")
+        .column_as(listening_session::Column::EndedAt.max(), "last_played_at")
+        .join(
+            JoinType::InnerJoin,
+            listening_session::Relation::Music.def(),
+        )
+        .group_by(listening_session::Column::AccountId)
+        .group_by(listening_session::Column::MusicId)
+        .group_by(music::Column::AuthorId)
+        .group_by(Expr::cust(SESSION_DAY));
+    if let Some(user_id) = user_id {
+        query = query.filter(listening_session::Column::AccountId.eq(user_id));
+    }
+    query
+}
+
+/// the average speed of the sessions of `user_id`, or of everyone, started since `since`
+async fn recent_speed(
+    db: &impl ConnectionTrait,
+    user_id: Option<i32>,
+    since: NaiveDateTime,
+) -> Result<Option<f64>, DbErr> {
+    let mut query = ListeningSession::find()
+        .find_also_related(Music)
+        .filter(listening_session::Column::StartedAt.gte(since));
+    if let Some(user_id) = user_id {
+        query = query.filter(listening_session::Column::AccountId.eq(user_id));
+    }
+    let sessions = query.all(db).await?;
+    let book_ids = sessions
+        .iter()
+        .filter_map(|(_, book)| book.as_ref().map(|book| book.id))
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    let durations = chapter_durations(db, &book_ids).await?;
+    let timed = sessions
+        .iter()
+        .filter_map(|(session, book)| {
+            let book = book.as_ref()?;
+            let durations = durations.get(&book.id).map_or(&[][..], Vec::as_slice);
+            let at = |chapter_no, position| {
+                BookProgress::new(book.chapters, durations, chapter_no, position)
+                    .map(|progress| progress.elapsed)
+            };
+            let covered = at(session.end_chapter_no, session.end_position)?
+                - at(session.start_chapter_no, session.start_position)?;
+            let time = (session.ended_at - session.started_at).num_milliseconds() as f64 / 1000.;
+            Some((time, covered))
+        })
+        .collect::<Vec<_>>();
+    Ok(average_speed(&timed))
+}
+
+/// the names of the authors in `listened`
+async fn author_names(
+    db: &impl ConnectionTrait,
+    listened: &[Listened],
+) -> Result<HashMap<i32, String>, DbErr> {
+    let ids = listened
+        .iter()
+        .map(|listened| listened.author_id)
+        .collect::<HashSet<_>>();
+    if ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+    Ok(Author::find()
+        .filter(author::Column::Id.is_in(ids))
+        .all(db)
+        .await?
+        .into_iter()
+        .map(|author| (author.id, author.name))
+        .collect())
+}
+
+/// the names of the books in `listened` that are not in the trash
+async fn live_book_names(
+    db: &impl ConnectionTrait,
+    listened: &[Listened],
+) -> Result<HashMap<i32, String>, DbErr> {
+    let ids = listened
+        .iter()
+        .map(|listened| listened.book_id)
+        .collect::<HashSet<_>>();
+    if ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+    Ok(Music::find()
+        .filter(music::Column::Id.is_in(ids))
+        .filter(music::Column::DeletedAt.is_null())
+        .all(db)
+        .await?
+        .into_iter()
+        .map(|book| (book.id, book.name))
+        .collect())
+}
+
+/// the number of live books finished at least once, by `user_id` or by everyone
+async fn books_finished(db: &impl ConnectionTrait, user_id: Option<i32>) -> Result<u64, DbErr> {
+    let mut query = Progress::find()
+        .join(JoinType::InnerJoin, progress::Relation::Music.def())
+        .filter(music::Column::DeletedAt.is_null())
+        .filter(progress::Column::FinishCount.gt(0));
+    if let Some(user_id) = user_id {
+        query = query.filter(progress::Column::AccountId.eq(user_id));
+    }
+    query.count(db).await
+}
+
+/// the stats of one user
+pub(crate) async fn user_stats(
+    db: &impl ConnectionTrait,
+    user_id: i32,
+) -> Result<ListeningStats, DbErr> {
+    let listened = per_day(Some(user_id)).into_model().all(db).await?;
+    let authors = author_names(db, &listened).await?;
+    let finished = books_finished(db, Some(user_id)).await?;
+    let now = chrono::Utc::now().naive_utc();
+    let since = now - chrono::Duration::days(DAYS as i64);
+    let speed = recent_speed(db, Some(user_id), since).await?;
+    Ok(summarize(&listened, &authors, finished, speed, now.date()))
+}
+
+/// the stats of every user together, with the active listeners and the most played books
+pub(crate) async fn server_stats(db: &impl ConnectionTrait) -> Result<ServerStats, DbErr> {
+    let listened = per_day(None).into_model().all(db).await?;
+    let authors = author_names(db, &listened).await?;
+    let finished = books_finished(db, None).await?;
+    let now = chrono::Utc::now().naive_utc();
+    let since = now - chrono::Duration::days(DAYS as i64);
+    let speed = recent_speed(db, None, since).await?;
+    let accounts = Account::find()
+        .all(db)
+        .await?
+        .into_iter()
+        .map(|account| (account.id, account.name))
+        .collect();
+    let book_names = live_book_names(db, &listened).await?;
+    Ok(ServerStats {
+        listening: summarize(&listened, &authors, finished, speed, now.date()),
+        active_listeners: active_listeners(
+            &listened,
+            &accounts,
+            now - chrono::Duration::days(ACTIVE_DAYS),
+        ),
+        most_played: most_played(&listened, &book_names),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeSet, HashMap};
+
+    use chrono::NaiveDate;
+    use sea_orm::{DbBackend, QueryTrait};
+
+    use super::{
+        active_listeners, average_speed, most_played, per_day, streaks, summarize, Listened,
+        ServerStats,
+    };
+
+    fn day(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2023, 10, day).unwrap()
+    }
+
+    fn listened(account_id: i32, book_id: i32, on: u32, time: f64) -> Listened {
+        Listened {
+            account_id,
+            book_id,
+            author_id: book_id * 10,
+            day: day(on),
+            time,
+            last_played_at: day(on).and_hms_opt(20, 0, 0).unwrap()
+                + chrono::Duration::seconds(time as i64),
+        }
+    }
+
+    #[test]
+    fn test_streaks() {
+        let days = [1, 2, 3, 10, 11, 30]
+            .into_iter()
+            .map(day)
+            .collect::<BTreeSet<_>>();
+        assert_eq!(streaks(&days, day(31)), (1, 3));
+        assert_eq!(streaks(&days, day(30)), (1, 3));
+        let days = [29, 30, 31].into_iter().map(day).collect::<BTreeSet<_>>();
+        // today has no session yet, the streak goes on until the day is over
+        assert_eq!(
+            streaks(&days, NaiveDate::from_ymd_opt(2023, 11, 1).unwrap()),
+            (3, 3)
+        );
+        assert_eq!(
+            streaks(&days, NaiveDate::from_ymd_opt(2023, 11, 2).unwrap()),
+            (0, 3)
+        );
+        assert_eq!(streaks(&BTreeSet::new(), day(1)), (0, 0));
+    }
+
+    #[test]
+    fn test_summarize() {
+        let listened = [
+            listened(1, 1, 2, 600.),
+            listened(1, 1, 30, 1200.),
+            listened(1, 2, 31, 1800.),
+        ];
+        let authors = HashMap::from([(10, "Liu Cixin".to_string())]);
+        let stats = summarize(&listened, &authors, 1, Some(1.25), day(31));
+        assert_eq!(stats.total_seconds, 3600.);
+        assert_eq!(stats.days.len(), 30);
+        assert_eq!(stats.days[29].start, day(31));
+        assert_eq!(stats.days[29].seconds, 1800.);
+        assert_eq!(stats.days[0].start, day(2));
+        assert_eq!(stats.days[0].seconds, 600.);
+        // monday the 30th starts the last week
+        assert_eq!(stats.weeks[11].start, day(30));
+        assert_eq!(stats.weeks[11].seconds, 3000.);
+        assert_eq!(stats.months.len(), 12);
+        assert_eq!(stats.months[11].start, day(1));
+        assert_eq!(stats.months[11].seconds, 3600.);
+        assert_eq!(
+            stats.months[0].start,
+            NaiveDate::from_ymd_opt(2022, 11, 1).unwrap()
+        );
+        assert_eq!((stats.current_streak, stats.longest_streak), (2, 2));
+        assert_eq!(stats.top_authors[0].name, "Liu Cixin");
+        assert_eq!(stats.top_authors[0].seconds, 1800.);
+        assert_eq!(stats.top_authors.len(), 2);
+        assert_eq!(stats.average_speed, Some(1.25));
+    }
+
+    #[test]
+    fn test_average_speed() {
+        // going back and sessions without time don't count
+        let sessions = [
+            (600., 900.),
+            (1200., 1800.),
+            (1800., 1800.),
+            (60., -600.),
+            (0., 30.),
+        ];
+        assert_eq!(average_speed(&sessions), Some(1.25));
+        assert_eq!(average_speed(&[]), None);
+    }
+
+    #[test]
+    fn test_per_day() {
+        let sql = per_day(Some(7)).build(DbBackend::MySql).to_string();
+        assert!(sql.contains("DATE(`listening_session`.`started_at`) AS `day`"));
+        assert!(sql.contains("MAX(`listening_session`.`ended_at`) AS `last_played_at`"));
+        assert!(sql.contains("WHERE `listening_session`.`account_id` = 7"));
+        assert!(sql.ends_with(
+            "GROUP BY `listening_session`.`account_id`, `listening_session`.`music_id`, \
+             `music`.`author_id`, DATE(`listening_session`.`started_at`)"
+        ));
+    }
+
+    #[test]
+    fn test_server_lists() {
+        let listened = [
+            listened(1, 1, 2, 600.),
+            listened(2, 1, 30, 600.),
+            listened(2, 2, 31, 1800.),
+            // book 3 is in the trash
+            listened(1, 3, 2, 9000.),
+        ];
+        let accounts = HashMap::from([(1, "alice".to_string()), (2, "bob".to_string())]);
+        let active = active_listeners(&listened, &accounts, day(25).and_hms_opt(0, 0, 0).unwrap());
+        assert_eq!(active.len(), 1);
+        assert_eq!((active[0].name.as_str(), active[0].seconds), ("bob", 2400.));
+        let books = HashMap::from([(1, "三体".to_string()), (2, "球状闪电".to_string())]);
+        let played = most_played(&listened, &books);
+        assert_eq!(played.len(), 2);
+        assert_eq!(played[0].name, "球状闪电");
+        assert_eq!((played[1].seconds, played[1].listeners), (1200., 2));
+    }
+
+    #[test]
+    fn test_render_stats() {
+        let listened = [listened(1, 1, 30, 5400.)];
+        let names = HashMap::from([(1, "三体".to_string()), (10, "Liu Cixin".to_string())]);
+        let stats = summarize(&listened, &names, 0, Some(1.), day(31));
+        let server = ServerStats {
+            listening: stats.clone(),
+            active_listeners: active_listeners(&listened, &names, Default::default()),
+            most_played: most_played(&listened, &names),
+        };
+        let mut context = tera::Context::new();
+        context.insert("title", "stats");
+        context.insert("user_name", "alice");
+        context.insert("stats", &stats);
+        context.insert("server", &server);
+        let html = crate::setup_tera().render("stats.tera", &context).unwrap();
+        assert!(html.contains("listened: 1.5h"));
+        assert!(html.contains("Liu Cixin"));
+        assert!(html.contains("三体</a>"));
+        assert!(html.contains("<progress max=\"5400\" value=\"5400\">"));
+    }
+}
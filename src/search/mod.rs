@@ -0,0 +1,827 @@
+//! full-text search over titles, authors, narrators, series, descriptions and chapter titles
+//!
+//! the index lives in memory: it's rebuilt from the database at startup and every
+//! [`REBUILD_INTERVAL`], and handlers that import or edit a book refresh its document right away.
+//! Chinese text has no spaces, so CJK runs are indexed as single characters plus bigrams, and
+//! other text as lowercase words.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use eyre::eyre;
+use sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, QueryOrder};
+use tantivy::{
+    collector::{Count, DocSetCollector, TopDocs},
+    query::{BooleanQuery, BoostQuery, FuzzyTermQuery, Occur, Query, TermQuery, TermSetQuery},
+    schema::{
+        Field, IndexRecordOption, Schema, TextFieldIndexing, TextOptions, Value, FAST, INDEXED,
+        STORED,
+    },
+    tokenizer::{TextAnalyzer, Token, TokenStream, Tokenizer},
+    Index, IndexReader, IndexWriter, ReloadPolicy, TantivyDocument, Term,
+};
+use tracing::{error, info};
+
+use crate::{
+    entities::{prelude::*, *},
+    tools::romanize,
+    AppStat,
+};
+
+/// the full rebuild also picks up books imported by the command line tools
+pub const REBUILD_INTERVAL: Duration = Duration::from_secs(600);
+const TOKENIZER: &str = "cjk";
+const WRITER_MEMORY: usize = 15_000_000;
+/// the longest highlighted fragment, in characters
+const FRAGMENT_CHARS: usize = 80;
+/// the most chapter titles highlighted per book
+const MAX_CHAPTER_HIGHLIGHTS: usize = 3;
+
+/// words at least this long also match with one typo
+const FUZZY_MIN_CHARS: usize = 5;
+/// the share of the field weight a match with a typo gets
+const FUZZY_WEIGHT: f32 = 0.5;
+/// a query of at least this many CJK characters still matches with one of them wrong
+const LEAVE_ONE_OUT_MIN: usize = 3;
+
+/// the searchable fields, with their weight in the ranking
+const FIELDS: [(&str, f32); 6] = [
+    ("title", 3.),
+    ("author", 2.),
+    ("series", 1.5),
+    ("narrator", 1.5),
+    ("description", 1.),
+    ("chapters", 1.),
+];
+
+/// the pinyin of the title and the author, only matched by latin queries
+const PINYIN_FIELDS: [(&str, f32); 2] = [("title_pinyin", 2.5), ("author_pinyin", 1.5)];
+
+fn boosted(query: Box<dyn Query>, weight: f32) -> (Occur, Box<dyn Query>) {
+    (Occur::Should, Box::new(BoostQuery::new(query, weight)))
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c as u32,
+        0x3040..=0x30FF // hiragana, katakana
+        | 0x3400..=0x4DBF // cjk extension a
+        | 0x4E00..=0x9FFF // cjk unified ideographs
+        | 0xAC00..=0xD7AF // hangul
+        | 0xF900..=0xFAFF // cjk compatibility ideographs
+        | 0x20000..=0x2FA1F) // cjk extensions b to f
+}
+
+/// lowercase words, and CJK characters as unigrams plus bigrams of neighbours
+#[derive(Debug, Clone, Default)]
+pub struct CjkTokenizer;
+
+pub struct VecTokenStream {
+    tokens: Vec<Token>,
+    index: usize,
+}
+
+impl TokenStream for VecTokenStream {
+    fn advance(&mut self) -> bool {
+        self.index += 1;
+        self.index <= self.tokens.len()
+    }
+
+    fn token(&self) -> &Token {
+        &self.tokens[self.index - 1]
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        &mut self.tokens[self.index - 1]
+    }
+}
+
+impl Tokenizer for CjkTokenizer {
+    type TokenStream<'a> = VecTokenStream;
+
+    fn token_stream<'a>(&'a mut self, text: &'a str) -> VecTokenStream {
+        VecTokenStream {
+            tokens: tokenize(text),
+            index: 0,
+        }
+    }
+}
+
+/// split `text` into tokens, the offsets point into `text`
+pub fn tokenize(text: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut position = 0;
+    let mut push = |tokens: &mut Vec<Token>, from: usize, to: usize, text: String, next: bool| {
+        tokens.push(Token {
+            offset_from: from,
+            offset_to: to,
+            position,
+            text,
+            position_length: 1,
+        });
+        if next {
+            position += 1;
+        }
+    };
+    let mut word: Option<usize> = None;
+    // the start and the simplified form of the previous char when it's CJK
+    let mut previous_cjk: Option<(usize, char)> = None;
+    // traditional characters are indexed and searched as simplified ones
+    let simplified = romanize::to_simplified(text);
+    for ((start, c), simple) in text.char_indices().zip(simplified.chars()) {
+        let end = start + c.len_utf8();
+        if let (Some(from), false) = (word, c.is_alphanumeric() && !is_cjk(c)) {
+            push(
+                &mut tokens,
+                from,
+                start,
+                text[from..start].to_lowercase(),
+                true,
+            );
+            word = None;
+        }
+        if is_cjk(c) {
+            if let Some((from, previous)) = previous_cjk {
+                push(
+                    &mut tokens,
+                    from,
+                    end,
+                    format!("{}{}", previous, simple),
+                    false,
+                );
+            }
+            push(&mut tokens, start, end, simple.to_string(), true);
+            previous_cjk = Some((start, simple));
+        } else {
+            previous_cjk = None;
+            if c.is_alphanumeric() && word.is_none() {
+                word = Some(start);
+            }
+        }
+    }
+    if let Some(from) = word {
+        push(
+            &mut tokens,
+            from,
+            text.len(),
+            text[from..].to_lowercase(),
+            true,
+        );
+    }
+    tokens
+}
+
+/// wrap the tokens of `text` found in `terms` in `<b>`, the rest is html escaped
+///
+/// long text is cut to a window around the first match. Returns `None` without a match.
+pub fn highlight(text: &str, terms: &HashSet<String>) -> Option<String> {
+    let mut ranges = tokenize(text)
+        .into_iter()
+        .filter(|token| terms.contains(&token.text))
+        .map(|token| (token.offset_from, token.offset_to))
+        .collect::<Vec<_>>();
+    ranges.sort();
+    let mut merged: Vec<(usize, usize)> = vec![];
+    for (from, to) in ranges {
+        match merged.last_mut() {
+            Some((_, last_to)) if from <= *last_to => *last_to = (*last_to).max(to),
+            _ => merged.push((from, to)),
+        }
+    }
+    let first = merged.first()?.0;
+    // start a bit before the first match, on a char boundary
+    let window_start = text[..first]
+        .char_indices()
+        .rev()
+        .nth(FRAGMENT_CHARS / 4)
+        .map(|(i, _)| i)
+        .unwrap_or(0);
+    let window_end = text[window_start..]
+        .char_indices()
+        .nth(FRAGMENT_CHARS)
+        .map(|(i, _)| window_start + i)
+        .unwrap_or(text.len());
+    let mut fragment = String::new();
+    if window_start > 0 {
+        fragment.push('…');
+    }
+    let mut cursor = window_start;
+    for (from, to) in merged {
+        if from >= window_end {
+            break;
+        }
+        let to = to.min(window_end);
+        fragment.push_str(&tera::escape_html(&text[cursor..from]));
+        fragment.push_str("<b>");
+        fragment.push_str(&tera::escape_html(&text[from..to]));
+        fragment.push_str("</b>");
+        cursor = to;
+    }
+    fragment.push_str(&tera::escape_html(&text[cursor..window_end]));
+    if window_end < text.len() {
+        fragment.push('…');
+    }
+    Some(fragment)
+}
+
+/// what gets indexed of a book
+#[derive(Debug, Clone, Default)]
+pub struct BookDoc {
+    pub id: i32,
+    pub title: String,
+    pub author: String,
+    pub narrator: Option<String>,
+    pub series: Option<String>,
+    pub description: Option<String>,
+    pub chapters: Vec<String>,
+    /// the stored pinyin and initials of the title
+    pub title_pinyin: Vec<String>,
+    /// the stored pinyin and initials of the author name
+    pub author_pinyin: Vec<String>,
+}
+
+/// a matching book id with its score and the highlighted fragments by field
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct RawHit {
+    pub id: i32,
+    pub score: f32,
+    pub highlights: BTreeMap<String, Vec<String>>,
+}
+
+struct Inner {
+    reader: IndexReader,
+    writer: Mutex<IndexWriter>,
+    id: Field,
+    fields: Vec<(Field, f32)>,
+    pinyin_fields: Vec<(Field, f32)>,
+}
+
+/// the in-memory search index, cheap to clone
+#[derive(Clone)]
+pub struct SearchIndex {
+    inner: Arc<Inner>,
+}
+
+impl SearchIndex {
+    /// an empty index, fill it with [`SearchIndex::rebuild`]
+    pub fn new() -> eyre::Result<Self> {
+        let mut builder = Schema::builder();
+        let id = builder.add_i64_field("id", INDEXED | STORED | FAST);
+        let text = TextOptions::default()
+            .set_indexing_options(
+                TextFieldIndexing::default()
+                    .set_tokenizer(TOKENIZER)
+                    .set_index_option(IndexRecordOption::WithFreqsAndPositions),
+            )
+            .set_stored();
+        let fields = FIELDS
+            .iter()
+            .map(|(name, weight)| (builder.add_text_field(name, text.clone()), *weight))
+            .collect();
+        // the pinyin is already lowercase without spaces, one value is one term
+        let pinyin = TextOptions::default().set_indexing_options(
+            TextFieldIndexing::default()
+                .set_tokenizer("raw")
+                .set_index_option(IndexRecordOption::WithFreqs),
+        );
+        let pinyin_fields = PINYIN_FIELDS
+            .iter()
+            .map(|(name, weight)| (builder.add_text_field(name, pinyin.clone()), *weight))
+            .collect();
+        let index = Index::create_in_ram(builder.build());
+        index
+            .tokenizers()
+            .register(TOKENIZER, TextAnalyzer::from(CjkTokenizer));
+        let writer = index.writer_with_num_threads(1, WRITER_MEMORY)?;
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::Manual)
+            .try_into()?;
+        Ok(Self {
+            inner: Arc::new(Inner {
+                reader,
+                writer: Mutex::new(writer),
+                id,
+                fields,
+                pinyin_fields,
+            }),
+        })
+    }
+
+    fn document(&self, book: BookDoc) -> TantivyDocument {
+        let inner = &self.inner;
+        let mut doc = TantivyDocument::new();
+        doc.add_i64(inner.id, book.id as i64);
+        let values = [
+            vec![book.title],
+            vec![book.author],
+            book.series.into_iter().collect(),
+            book.narrator.into_iter().collect(),
+            book.description.into_iter().collect(),
+            book.chapters,
+        ];
+        for ((field, _), values) in inner.fields.iter().zip(values) {
+            for value in values {
+                doc.add_text(*field, value);
+            }
+        }
+        let pinyin = [book.title_pinyin, book.author_pinyin];
+        for ((field, _), values) in inner.pinyin_fields.iter().zip(pinyin) {
+            for value in values {
+                doc.add_text(*field, value);
+            }
+        }
+        doc
+    }
+
+    /// replace the documents of `books`, remove the books in `removed`, or everything first when
+    /// `clear` is set
+    pub fn update(&self, books: Vec<BookDoc>, removed: &[i32], clear: bool) -> eyre::Result<()> {
+        let inner = &self.inner;
+        let mut writer = inner
+            .writer
+            .lock()
+            .map_err(|_| eyre!("search index writer poisoned"))?;
+        if clear {
+            writer.delete_all_documents()?;
+        }
+        for id in removed.iter().chain(books.iter().map(|book| &book.id)) {
+            writer.delete_term(Term::from_field_i64(inner.id, *id as i64));
+        }
+        for book in books {
+            writer.add_document(self.document(book))?;
+        }
+        writer.commit()?;
+        inner.reader.reload()?;
+        Ok(())
+    }
+
+    /// `text` in any of the text fields, words of [`FUZZY_MIN_CHARS`] or more also match with one
+    /// typo at a lower weight
+    fn any_field(&self, text: &str) -> Box<dyn Query> {
+        let fuzzy = text.chars().count() >= FUZZY_MIN_CHARS && !text.chars().any(is_cjk);
+        let mut clauses = vec![];
+        for (field, weight) in &self.inner.fields {
+            let term = Term::from_field_text(*field, text);
+            if fuzzy {
+                let query = FuzzyTermQuery::new(term.clone(), 1, true);
+                clauses.push(boosted(Box::new(query), weight * FUZZY_WEIGHT));
+            }
+            let query = TermQuery::new(term, IndexRecordOption::WithFreqs);
+            clauses.push(boosted(Box::new(query), *weight));
+        }
+        Box::new(BooleanQuery::new(clauses))
+    }
+
+    /// every word must match in some field, CJK bigrams only add to the score so adjacent
+    /// characters rank first. Of [`LEAVE_ONE_OUT_MIN`] or more CJK characters one may be missing,
+    /// for a mistyped character
+    fn text_query(&self, tokens: &[Token]) -> BooleanQuery {
+        let mut clauses = vec![];
+        let mut unigrams = vec![];
+        for token in tokens {
+            let cjk = token.text.chars().all(is_cjk);
+            match token.text.chars().count() {
+                1 if cjk => unigrams.push(token.text.as_str()),
+                2 if cjk => clauses.push((Occur::Should, self.any_field(&token.text))),
+                _ => clauses.push((Occur::Must, self.any_field(&token.text))),
+            }
+        }
+        if unigrams.len() < LEAVE_ONE_OUT_MIN {
+            clauses.extend(
+                unigrams
+                    .iter()
+                    .map(|text| (Occur::Must, self.any_field(text))),
+            );
+        } else {
+            let variants = (0..unigrams.len())
+                .map(|left_out| {
+                    let variant = unigrams
+                        .iter()
+                        .enumerate()
+                        .filter(|(i, _)| *i != left_out)
+                        .map(|(_, text)| (Occur::Must, self.any_field(text)))
+                        .collect();
+                    let variant: Box<dyn Query> = Box::new(BooleanQuery::new(variant));
+                    (Occur::Should, variant)
+                })
+                .collect();
+            clauses.push((Occur::Must, Box::new(BooleanQuery::new(variants))));
+        }
+        BooleanQuery::new(clauses)
+    }
+
+    /// a latin query against the pinyin of the title and author: the full pinyin or the initials,
+    /// or the start of them with a typo or two
+    fn pinyin_query(&self, q: &str) -> Option<BooleanQuery> {
+        let q = romanize::normalize_query(q);
+        if q.is_empty() || !q.is_ascii() {
+            return None;
+        }
+        let distance = match q.len() {
+            0..=3 => 0,
+            4..=7 => 1,
+            _ => 2,
+        };
+        let mut clauses = vec![];
+        for (field, weight) in &self.inner.pinyin_fields {
+            let term = Term::from_field_text(*field, &q);
+            let prefix = FuzzyTermQuery::new_prefix(term.clone(), distance, true);
+            clauses.push(boosted(Box::new(prefix), *weight));
+            let exact = TermQuery::new(term, IndexRecordOption::WithFreqs);
+            clauses.push(boosted(Box::new(exact), *weight));
+        }
+        Some(BooleanQuery::new(clauses))
+    }
+
+    /// the text and the pinyin queries, a book matching either is a hit
+    fn query(&self, q: &str, tokens: &[Token]) -> BooleanQuery {
+        let mut clauses: Vec<(Occur, Box<dyn Query>)> =
+            vec![(Occur::Should, Box::new(self.text_query(tokens)))];
+        if let Some(pinyin) = self.pinyin_query(q) {
+            clauses.push((Occur::Should, Box::new(pinyin)));
+        }
+        BooleanQuery::new(clauses)
+    }
+
+    /// the ids of every book matching `q`, in no particular order
+    pub fn matching_ids(&self, q: &str) -> eyre::Result<Vec<i32>> {
+        let mut tokens = tokenize(q);
+        let mut seen = HashSet::new();
+        tokens.retain(|token| seen.insert(token.text.clone()));
+        if tokens.is_empty() {
+            return Ok(vec![]);
+        }
+        let searcher = self.inner.reader.searcher();
+        let addresses = searcher.search(&self.query(q, &tokens), &DocSetCollector)?;
+        let mut ids = vec![];
+        for address in addresses {
+            let column = searcher
+                .segment_reader(address.segment_ord)
+                .fast_fields()
+                .i64("id")?;
+            ids.extend(column.first(address.doc_id).map(|id| id as i32));
+        }
+        Ok(ids)
+    }
+
+    /// search `q` among the books in `allowed`, or all books when it's `None`
+    ///
+    /// returns the total number of hits and the hits of the page.
+    pub fn search(
+        &self,
+        q: &str,
+        offset: usize,
+        limit: usize,
+        allowed: Option<&[i32]>,
+    ) -> eyre::Result<(usize, Vec<RawHit>)> {
+        let mut tokens = tokenize(q);
+        let mut seen = HashSet::new();
+        tokens.retain(|token| seen.insert(token.text.clone()));
+        if tokens.is_empty() || limit == 0 {
+            return Ok((0, vec![]));
+        }
+        let mut query: Box<dyn Query> = Box::new(self.query(q, &tokens));
+        if let Some(allowed) = allowed {
+            let ids = allowed
+                .iter()
+                .map(|id| Term::from_field_i64(self.inner.id, *id as i64));
+            query = Box::new(BooleanQuery::new(vec![
+                (Occur::Must, query),
+                (Occur::Must, Box::new(TermSetQuery::new(ids))),
+            ]));
+        }
+        let searcher = self.inner.reader.searcher();
+        // the collector keeps room for the skipped hits, a page past the last book only counts
+        if offset as u64 >= searcher.num_docs() {
+            return Ok((searcher.search(&query, &Count)?, vec![]));
+        }
+        let (top, total) = searcher.search(
+            &query,
+            &(TopDocs::with_limit(limit).and_offset(offset), Count),
+        )?;
+        let names = FIELDS.map(|(name, _)| name);
+        let mut hits = vec![];
+        for (score, address) in top {
+            let doc = searcher.doc::<TantivyDocument>(address)?;
+            let id = doc
+                .get_first(self.inner.id)
+                .and_then(|value| value.as_i64())
+                .ok_or_else(|| eyre!("search document without id"))?;
+            let mut highlights = BTreeMap::new();
+            for ((field, _), name) in self.inner.fields.iter().zip(names) {
+                let fragments = doc
+                    .get_all(*field)
+                    .filter_map(|value| value.as_str())
+                    .filter_map(|text| highlight(text, &seen))
+                    .take(MAX_CHAPTER_HIGHLIGHTS)
+                    .collect::<Vec<_>>();
+                if !fragments.is_empty() {
+                    highlights.insert(name.to_string(), fragments);
+                }
+            }
+            hits.push(RawHit {
+                id: id as i32,
+                score,
+                highlights,
+            });
+        }
+        Ok((total, hits))
+    }
+
+    /// reindex every live book
+    pub async fn rebuild(&self, db: &DatabaseConnection) -> eyre::Result<usize> {
+        let books = load_books(db, None).await?;
+        let count = books.len();
+        let index = self.clone();
+        tokio::task::spawn_blocking(move || index.update(books, &[], true)).await??;
+        Ok(count)
+    }
+
+    /// reindex the given books, the ones gone or in the trash are removed from the index
+    pub async fn reindex_books(&self, db: &DatabaseConnection, ids: Vec<i32>) -> eyre::Result<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let books = load_books(db, Some(music::Column::Id.is_in(ids.clone()))).await?;
+        let live = books.iter().map(|book| book.id).collect::<HashSet<_>>();
+        let removed = ids
+            .into_iter()
+            .filter(|id| !live.contains(id))
+            .collect::<Vec<_>>();
+        let index = self.clone();
+        tokio::task::spawn_blocking(move || index.update(books, &removed, false)).await??;
+        Ok(())
+    }
+
+    /// reindex the books of an author, after it was renamed or merged
+    pub async fn reindex_author(
+        &self,
+        db: &DatabaseConnection,
+        author_id: i32,
+    ) -> eyre::Result<()> {
+        let books = load_books(db, Some(music::Column::AuthorId.eq(author_id))).await?;
+        let index = self.clone();
+        tokio::task::spawn_blocking(move || index.update(books, &[], false)).await??;
+        Ok(())
+    }
+}
+
+/// the live books matching `filter`, with their author and chapter titles
+async fn load_books(
+    db: &DatabaseConnection,
+    filter: Option<sea_orm::sea_query::SimpleExpr>,
+) -> eyre::Result<Vec<BookDoc>> {
+    let mut query = Music::find()
+        .find_also_related(Author)
+        .filter(music::Column::DeletedAt.is_null());
+    if let Some(filter) = filter {
+        query = query.filter(filter);
+    }
+    let books = query.all(db).await?;
+    let ids = books.iter().map(|(book, _)| book.id).collect::<Vec<_>>();
+    let mut chapters: HashMap<i32, Vec<String>> = HashMap::new();
+    if !ids.is_empty() {
+        for chapter in Chapter::find()
+            .filter(chapter::Column::MusicId.is_in(ids))
+            .order_by_asc(chapter::Column::ChapterNo)
+            .all(db)
+            .await?
+        {
+            chapters
+                .entry(chapter.music_id)
+                .or_default()
+                .push(chapter.title);
+        }
+    }
+    Ok(books
+        .into_iter()
+        .map(|(book, author)| {
+            let (author, author_pinyin) = match author {
+                Some(author) => {
+                    let pinyin = author.name_pinyin.into_iter().chain(author.name_initials);
+                    (author.name, pinyin.collect())
+                }
+                None => (String::new(), vec![]),
+            };
+            BookDoc {
+                id: book.id,
+                title: book.name,
+                author,
+                narrator: book.narrator,
+                series: book.series,
+                description: book.description,
+                chapters: chapters.remove(&book.id).unwrap_or_default(),
+                title_pinyin: book
+                    .name_pinyin
+                    .into_iter()
+                    .chain(book.name_initials)
+                    .collect(),
+                author_pinyin,
+            }
+        })
+        .collect())
+}
+
+/// [`SearchIndex::reindex_books`] in the background, errors are only logged
+pub(crate) fn refresh_books(state: &AppStat, ids: Vec<i32>) {
+    let state = state.clone();
+    tokio::spawn(async move {
+        if let Err(e) = state.search.reindex_books(&state.connections.db, ids).await {
+            error!("fail to update the search index: {}", e);
+        }
+    });
+}
+
+/// [`SearchIndex::reindex_author`] in the background, errors are only logged
+pub(crate) fn refresh_author(state: &AppStat, author_id: i32) {
+    let state = state.clone();
+    tokio::spawn(async move {
+        let result = state
+            .search
+            .reindex_author(&state.connections.db, author_id)
+            .await;
+        if let Err(e) = result {
+            error!("fail to update the search index: {}", e);
+        }
+    });
+}
+
+/// [`SearchIndex::rebuild`] in the background, errors are only logged
+pub(crate) fn refresh_all(state: &AppStat) {
+    let state = state.clone();
+    tokio::spawn(async move {
+        if let Err(e) = state.search.rebuild(&state.connections.db).await {
+            error!("fail to rebuild the search index: {}", e);
+        }
+    });
+}
+
+/// rebuild the index every [`REBUILD_INTERVAL`], the first tick builds it at startup
+pub(crate) async fn rebuild_task(state: AppStat) {
+    if let Err(e) = romanize::fill_missing_pinyin(&state.connections.db).await {
+        error!("fail to fill in the missing pinyin: {}", e);
+    }
+    let mut interval = tokio::time::interval(REBUILD_INTERVAL);
+    loop {
+        interval.tick().await;
+        match state.search.rebuild(&state.connections.db).await {
+            Ok(count) => info!("search index rebuilt with {} books", count),
+            Err(e) => error!("fail to rebuild the search index: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::{highlight, tokenize, BookDoc, SearchIndex};
+    use crate::tools::romanize;
+
+    fn texts(text: &str) -> Vec<String> {
+        tokenize(text).into_iter().map(|token| token.text).collect()
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            texts("三国演义"),
+            ["三", "三国", "国", "国演", "演", "演义", "义"]
+        );
+        assert_eq!(texts("The Hobbit, 2nd"), ["the", "hobbit", "2nd"]);
+        assert_eq!(texts("第1章 序"), ["第", "1", "章", "序"]);
+    }
+
+    #[test]
+    fn test_highlight() {
+        let terms = HashSet::from(["hobbit".to_string()]);
+        assert_eq!(
+            highlight("The <Hobbit>", &terms).unwrap(),
+            "The &lt;<b>Hobbit</b>&gt;"
+        );
+        let terms = HashSet::from(["三".to_string(), "三国".to_string(), "国".to_string()]);
+        assert_eq!(highlight("三国演义", &terms).unwrap(), "<b>三国</b>演义");
+        assert_eq!(highlight("水浒传", &terms), None);
+
+        let long = format!("{}hobbit{}", "a ".repeat(100), " b".repeat(100));
+        let terms = HashSet::from(["hobbit".to_string()]);
+        let fragment = highlight(&long, &terms).unwrap();
+        assert!(fragment.starts_with('…') && fragment.ends_with('…'));
+        assert!(fragment.contains("<b>hobbit</b>"));
+    }
+
+    fn book(id: i32, title: &str, author: &str) -> BookDoc {
+        let pinyin = |name: &str| {
+            let romanized = romanize::romanize(name);
+            vec![romanized.pinyin, romanized.initials]
+        };
+        BookDoc {
+            id,
+            title: title.to_string(),
+            author: author.to_string(),
+            title_pinyin: pinyin(title),
+            author_pinyin: pinyin(author),
+            ..Default::default()
+        }
+    }
+
+    fn hit_ids(index: &SearchIndex, q: &str) -> Vec<i32> {
+        let (_, hits) = index.search(q, 0, 10, None).unwrap();
+        hits.into_iter().map(|hit| hit.id).collect()
+    }
+
+    #[test]
+    fn test_pinyin_and_fuzzy_search() {
+        let index = SearchIndex::new().unwrap();
+        index
+            .update(
+                vec![
+                    book(1, "三國演義", "羅貫中"),
+                    book(2, "西游记", "吴承恩"),
+                    book(3, "The Hobbit", "Tolkien"),
+                ],
+                &[],
+                false,
+            )
+            .unwrap();
+        // traditional and simplified
+        assert_eq!(hit_ids(&index, "三国"), [1]);
+        assert_eq!(hit_ids(&index, "羅貫中"), [1]);
+        // full pinyin, initials, a prefix and a typo
+        assert_eq!(hit_ids(&index, "sanguoyanyi"), [1]);
+        assert_eq!(hit_ids(&index, "sgyy"), [1]);
+        assert_eq!(hit_ids(&index, "San Guo"), [1]);
+        assert_eq!(hit_ids(&index, "sanguoyanyu"), [1]);
+        assert_eq!(hit_ids(&index, "lgz"), [1]);
+        assert_eq!(hit_ids(&index, "xiyouji"), [2]);
+        // a wrong character or letter
+        assert_eq!(hit_ids(&index, "三国演意"), [1]);
+        assert_eq!(hit_ids(&index, "hobit"), [3]);
+        assert!(hit_ids(&index, "红楼梦").is_empty());
+    }
+
+    #[test]
+    fn test_search() {
+        let index = SearchIndex::new().unwrap();
+        index
+            .update(
+                vec![
+                    book(1, "三国演义", "罗贯中"),
+                    BookDoc {
+                        chapters: vec!["第一回 宴桃园豪杰三结义".to_string()],
+                        ..book(2, "水浒传", "施耐庵")
+                    },
+                    BookDoc {
+                        narrator: Some("Andy Serkis".to_string()),
+                        ..book(3, "The Hobbit", "J. R. R. Tolkien")
+                    },
+                ],
+                &[],
+                false,
+            )
+            .unwrap();
+        assert_eq!(index.inner.reader.searcher().num_docs(), 3);
+
+        let (total, hits) = index.search("三国", 0, 10, None).unwrap();
+        assert_eq!(total, 1);
+        assert_eq!(hits[0].id, 1);
+        assert_eq!(hits[0].highlights["title"], ["<b>三国</b>演义"]);
+
+        // the title weighs more than a chapter title
+        let (total, hits) = index.search("三", 0, 10, None).unwrap();
+        assert_eq!(total, 2);
+        assert_eq!(hits.iter().map(|hit| hit.id).collect::<Vec<_>>(), [1, 2]);
+        assert!(hits[1].highlights.contains_key("chapters"));
+        // a page past the end still counts the hits
+        let (total, hits) = index.search("三", usize::MAX, 10, None).unwrap();
+        assert_eq!((total, hits.len()), (2, 0));
+
+        // words may match in different fields
+        let (_, hits) = index.search("hobbit serkis", 0, 10, None).unwrap();
+        assert_eq!(hits[0].id, 3);
+        let (total, _) = index.search("hobbit rowling", 0, 10, None).unwrap();
+        assert_eq!(total, 0);
+
+        // replacing and removing documents
+        index
+            .update(vec![book(1, "红楼梦", "曹雪芹")], &[3], false)
+            .unwrap();
+        assert_eq!(index.search("三国", 0, 10, None).unwrap().0, 0);
+        assert_eq!(index.search("hobbit", 0, 10, None).unwrap().0, 0);
+        assert_eq!(index.search("红楼", 0, 10, None).unwrap().1[0].id, 1);
+        assert_eq!(index.search("", 0, 10, None).unwrap().0, 0);
+
+        // only among the allowed books
+        index
+            .update(vec![book(4, "红楼梦续", "佚名")], &[], false)
+            .unwrap();
+        let mut ids = index.matching_ids("红楼").unwrap();
+        ids.sort();
+        assert_eq!(ids, [1, 4]);
+        let (total, hits) = index.search("红楼", 0, 10, Some(&[4])).unwrap();
+        assert_eq!((total, hits[0].id), (1, 4));
+    }
+}
@@ -0,0 +1,982 @@
+//! a subsonic compatible api at `/rest`, for the mobile players that speak subsonic
+//!
+//! the authors are artists, the books are albums and the chapters are songs, with the ids
+//! `ar-{author}`, `al-{book}` and `tr-{book}-{chapter}`. the scrobbles and the play queue are
+//! written to the progress of the user.
+//!
+//! a client logs in with `p`, the account password or the subsonic password, or with the token
+//! `t = md5(password + s)`. the account password is only stored hashed, so the token scheme
+//! needs the subsonic password, generated at `/api/v1/me/subsonic-password`.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path as FsPath, PathBuf},
+    str::FromStr,
+};
+
+use axum::{
+    body::Bytes,
+    extract::{Path, RawQuery, State},
+    response::Response,
+    routing::get,
+    Router,
+};
+use hyper::{header, HeaderMap};
+use sea_orm::{
+    ColumnTrait, Condition, DatabaseConnection, DbErr, EntityTrait, QueryFilter, QueryOrder,
+    QuerySelect, RelationTrait,
+};
+use serde_json::{json, Value};
+use tracing::{debug, warn};
+
+use crate::entities::sea_orm_active_enums::ProgressState;
+use crate::entities::{prelude::*, *};
+use crate::{
+    music::name_filter,
+    tools,
+    transcode::{Codec, Target},
+    AppStat,
+};
+use response::{envelope, render, Format, SubsonicError};
+
+pub(crate) mod response;
+
+pub(crate) fn route() -> Router<AppStat> {
+    Router::new()
+        .route("/:method", get(dispatch).post(dispatch))
+        .route_layer(axum::middleware::from_fn(
+            super::middleware::log_system::log_sys,
+        ))
+}
+
+/// the parameters of a request, from the query and from a form body
+#[derive(Debug, Default)]
+pub(crate) struct Params(Vec<(String, String)>);
+
+impl Params {
+    pub(crate) fn parse(input: &[u8]) -> Self {
+        Self(form_urlencoded::parse(input).into_owned().collect())
+    }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// every value of a repeated parameter, like the `id` of `scrobble`
+    fn all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> {
+        self.0
+            .iter()
+            .filter(move |(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn required(&self, key: &str) -> Result<&str, SubsonicError> {
+        self.get(key).ok_or_else(|| SubsonicError::missing(key))
+    }
+
+    fn number<T: FromStr>(&self, key: &str, default: T) -> Result<T, SubsonicError> {
+        match self.get(key) {
+            Some(value) => value.parse().map_err(|_| {
+                SubsonicError::new(
+                    SubsonicError::GENERIC,
+                    format!("invalid parameter {}: {}", key, value),
+                )
+            }),
+            None => Ok(default),
+        }
+    }
+
+    fn id(&self, key: &str) -> Result<Id, SubsonicError> {
+        let id = self.required(key)?;
+        Id::parse(id).ok_or_else(|| SubsonicError::not_found(format!("id {}", id)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Id {
+    Artist(i32),
+    Album(i32),
+    /// a book and a chapter
+    Song(i32, i32),
+}
+
+impl Id {
+    fn parse(id: &str) -> Option<Self> {
+        if let Some(id) = id.strip_prefix("ar-") {
+            id.parse().ok().map(Id::Artist)
+        } else if let Some(id) = id.strip_prefix("al-") {
+            id.parse().ok().map(Id::Album)
+        } else {
+            let (book, chapter) = id.strip_prefix("tr-")?.split_once('-')?;
+            Some(Id::Song(book.parse().ok()?, chapter.parse().ok()?))
+        }
+    }
+}
+
+impl std::fmt::Display for Id {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Id::Artist(id) => write!(f, "ar-{}", id),
+            Id::Album(id) => write!(f, "al-{}", id),
+            Id::Song(book, chapter) => write!(f, "tr-{}-{}", book, chapter),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Reply {
+    /// the fields of the `subsonic-response`
+    Body(Value),
+    File(PathBuf),
+    /// a chapter file to transcode
+    Transcode(PathBuf, Target),
+}
+
+async fn dispatch(
+    State(state): State<AppStat>,
+    Path(method): Path<String>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let mut params = Params::parse(query.unwrap_or_default().as_bytes());
+    // the formPost extension of opensubsonic sends the parameters in the body
+    let is_form = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"));
+    if is_form {
+        params.0.extend(Params::parse(&body).0);
+    }
+    let format = Format::from_param(params.get("f"));
+    let method = method.strip_suffix(".view").unwrap_or(&method);
+    debug!("subsonic {} from {:?}", method, params.get("c"));
+    match call(&state.connections.db, &state.book_dir, method, &params).await {
+        Ok(Reply::Body(body)) => render(format, envelope(Ok(body))),
+        Ok(Reply::File(path)) => crate::serve_file(path, headers).await,
+        Ok(Reply::Transcode(path, target)) => {
+            match state.transcoder.respond(path, target, headers).await {
+                Ok(response) => response,
+                Err(err) => render(
+                    format,
+                    envelope(Err(SubsonicError::new(SubsonicError::GENERIC, err.message))),
+                ),
+            }
+        }
+        Err(err) => render(format, envelope(Err(err))),
+    }
+}
+
+/// authenticate the user and run `method`
+pub(crate) async fn call(
+    db: &DatabaseConnection,
+    book_dir: &FsPath,
+    method: &str,
+    params: &Params,
+) -> Result<Reply, SubsonicError> {
+    let user = authenticate(db, params).await?;
+    let body = match method {
+        "ping" => json!({}),
+        "getLicense" => json!({ "license": { "valid": true } }),
+        "getOpenSubsonicExtensions" => json!({
+            "openSubsonicExtensions": [{ "name": "formPost", "versions": [1] }]
+        }),
+        "getArtists" => get_artists(db).await?,
+        "getAlbum" => get_album(db, book_dir, params).await?,
+        "search3" => search3(db, book_dir, params).await?,
+        "scrobble" => scrobble(db, &user, params).await?,
+        "savePlayQueue" => save_play_queue(db, &user, params).await?,
+        "getPlayQueue" => get_play_queue(db, book_dir, &user).await?,
+        "stream" => return stream(db, book_dir, params).await,
+        "getCoverArt" => return cover_art(db, book_dir, params).await.map(Reply::File),
+        method => {
+            return Err(SubsonicError::new(
+                SubsonicError::GENERIC,
+                format!("unsupported method: {}", method),
+            ))
+        }
+    };
+    Ok(Reply::Body(body))
+}
+
+/// `p` in clear or as `enc:` and hex
+fn decode_password(password: &str) -> Option<String> {
+    match password.strip_prefix("enc:") {
+        Some(encoded) => String::from_utf8(hex::decode(encoded).ok()?).ok(),
+        None => Some(password.to_string()),
+    }
+}
+
+fn check_token(password: &str, salt: &str, token: &str) -> bool {
+    format!("{:x}", md5::compute(format!("{}{}", password, salt))).eq_ignore_ascii_case(token)
+}
+
+async fn authenticate(
+    db: &DatabaseConnection,
+    params: &Params,
+) -> Result<account::Model, SubsonicError> {
+    let name = params.required("u")?;
+    let user = Account::find()
+        .filter(account::Column::Name.eq(name))
+        .filter(account::Column::DeletedAt.is_null())
+        .one(db)
+        .await?
+        .ok_or_else(SubsonicError::wrong_credentials)?;
+    let valid = match (params.get("t"), params.get("s"), params.get("p")) {
+        (Some(token), Some(salt), _) => match &user.subsonic_password {
+            Some(password) => check_token(password, salt, token),
+            None => {
+                return Err(SubsonicError::new(
+                    SubsonicError::TOKEN_NOT_SUPPORTED,
+                    "no subsonic password is set for this account, use the password",
+                ))
+            }
+        },
+        (_, _, Some(password)) => {
+            let password =
+                decode_password(password).ok_or_else(SubsonicError::wrong_credentials)?;
+            format!("{:x}", md5::compute(&password)) == user.password
+                || user.subsonic_password.as_deref() == Some(password.as_str())
+        }
+        _ => return Err(SubsonicError::missing("p or t and s")),
+    };
+    if valid {
+        Ok(user)
+    } else {
+        Err(SubsonicError::wrong_credentials())
+    }
+}
+
+async fn live_book(db: &DatabaseConnection, id: i32) -> Result<music::Model, SubsonicError> {
+    Music::find_by_id(id)
+        .filter(music::Column::DeletedAt.is_null())
+        .one(db)
+        .await?
+        .ok_or_else(|| SubsonicError::not_found(Id::Album(id)))
+}
+
+async fn live_author(db: &DatabaseConnection, id: i32) -> Result<author::Model, SubsonicError> {
+    Author::find_by_id(id)
+        .filter(author::Column::DeletedAt.is_null())
+        .one(db)
+        .await?
+        .ok_or_else(|| SubsonicError::not_found(Id::Artist(id)))
+}
+
+/// the names of the authors of `books`
+async fn author_names(
+    db: &DatabaseConnection,
+    books: &[music::Model],
+) -> Result<HashMap<i32, String>, DbErr> {
+    let ids = books.iter().map(|book| book.author_id).collect::<Vec<_>>();
+    if ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+    Ok(Author::find()
+        .filter(author::Column::Id.is_in(ids))
+        .all(db)
+        .await?
+        .into_iter()
+        .map(|author| (author.id, author.name))
+        .collect())
+}
+
+/// the chapter files of `book`, none when its folder can't be read
+fn chapter_files(book_dir: &FsPath, book: &music::Model) -> Vec<(i32, PathBuf)> {
+    tools::chapter_files(book_dir.join(&book.file_folder)).unwrap_or_else(|e| {
+        warn!("fail to list the chapters of book {}: {}", book.id, e);
+        vec![]
+    })
+}
+
+fn timestamp(time: chrono::NaiveDateTime) -> String {
+    time.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+fn artist(author: &author::Model, album_count: i64) -> Value {
+    let mut artist = json!({
+        "id": Id::Artist(author.id).to_string(),
+        "name": author.name,
+        "albumCount": album_count,
+    });
+    if !author.avatar.is_empty() {
+        artist["coverArt"] = Id::Artist(author.id).to_string().into();
+    }
+    artist
+}
+
+fn album(book: &music::Model, artist: &str, song_count: usize) -> Value {
+    let mut album = json!({
+        "id": Id::Album(book.id).to_string(),
+        "name": book.name,
+        "artist": artist,
+        "artistId": Id::Artist(book.author_id).to_string(),
+        "coverArt": Id::Album(book.id).to_string(),
+        "songCount": song_count,
+        "created": timestamp(book.created_at),
+    });
+    if let Some(duration) = book.duration {
+        album["duration"] = (duration.round() as i64).into();
+    }
+    album
+}
+
+fn song(
+    book: &music::Model,
+    artist: &str,
+    chapter_no: i32,
+    title: Option<&str>,
+    file: Option<&FsPath>,
+) -> Value {
+    let mut song = json!({
+        "id": Id::Song(book.id, chapter_no).to_string(),
+        "parent": Id::Album(book.id).to_string(),
+        "isDir": false,
+        "title": title.map_or_else(|| format!("Chapter {}", chapter_no), str::to_string),
+        "album": book.name,
+        "artist": artist,
+        "track": chapter_no,
+        "coverArt": Id::Album(book.id).to_string(),
+        "albumId": Id::Album(book.id).to_string(),
+        "artistId": Id::Artist(book.author_id).to_string(),
+        "type": "music",
+        "mediaType": "song",
+    });
+    if let Some(file) = file {
+        if let Some(suffix) = file.extension().and_then(|ext| ext.to_str()) {
+            song["suffix"] = suffix.into();
+        }
+        song["contentType"] = mime_guess::from_path(file)
+            .first_or_octet_stream()
+            .to_string()
+            .into();
+        if let Ok(metadata) = std::fs::metadata(file) {
+            song["size"] = metadata.len().into();
+        }
+    }
+    song
+}
+
+/// the songs of the chapter files of `book`
+async fn songs(
+    db: &DatabaseConnection,
+    book_dir: &FsPath,
+    book: &music::Model,
+    artist: &str,
+) -> Result<Vec<Value>, DbErr> {
+    let titles = Chapter::find()
+        .filter(chapter::Column::MusicId.eq(book.id))
+        .all(db)
+        .await?
+        .into_iter()
+        .map(|chapter| (chapter.chapter_no, chapter.title))
+        .collect::<HashMap<_, _>>();
+    Ok(chapter_files(book_dir, book)
+        .iter()
+        .map(|(no, file)| {
+            song(
+                book,
+                artist,
+                *no,
+                titles.get(no).map(String::as_str),
+                Some(file),
+            )
+        })
+        .collect())
+}
+
+/// the letter an artist is listed under
+fn index_letter(author: &author::Model) -> String {
+    author
+        .name_initials
+        .as_deref()
+        .and_then(|initials| initials.chars().next())
+        .or_else(|| author.name.chars().next())
+        .filter(|c| c.is_ascii_alphabetic())
+        .map_or_else(|| "#".to_string(), |c| c.to_ascii_uppercase().to_string())
+}
+
+async fn get_artists(db: &DatabaseConnection) -> Result<Value, SubsonicError> {
+    let authors = Author::find()
+        .filter(author::Column::DeletedAt.is_null())
+        .order_by_asc(author::Column::Name)
+        .all(db)
+        .await?;
+    let counts: HashMap<i32, i64> = Music::find()
+        .select_only()
+        .column(music::Column::AuthorId)
+        .column_as(music::Column::Id.count(), "count")
+        .filter(music::Column::DeletedAt.is_null())
+        .group_by(music::Column::AuthorId)
+        .into_tuple::<(i32, i64)>()
+        .all(db)
+        .await?
+        .into_iter()
+        .collect();
+    let mut index: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+    for author in &authors {
+        let count = counts.get(&author.id).copied().unwrap_or(0);
+        index
+            .entry(index_letter(author))
+            .or_default()
+            .push(artist(author, count));
+    }
+    let index = index
+        .into_iter()
+        .map(|(name, artists)| json!({ "name": name, "artist": artists }))
+        .collect::<Vec<_>>();
+    Ok(json!({ "artists": { "ignoredArticles": "", "index": index } }))
+}
+
+async fn get_album(
+    db: &DatabaseConnection,
+    book_dir: &FsPath,
+    params: &Params,
+) -> Result<Value, SubsonicError> {
+    let Id::Album(id) = params.id("id")? else {
+        return Err(SubsonicError::not_found(params.required("id")?));
+    };
+    let book = live_book(db, id).await?;
+    let artist = Author::find_by_id(book.author_id)
+        .one(db)
+        .await?
+        .map(|author| author.name)
+        .unwrap_or_default();
+    let songs = songs(db, book_dir, &book, &artist).await?;
+    let mut album = album(&book, &artist, songs.len());
+    album["song"] = songs.into();
+    Ok(json!({ "album": album }))
+}
+
+/// a chapter file, transcoded when the client asks for a format or a bitrate
+async fn stream(
+    db: &DatabaseConnection,
+    book_dir: &FsPath,
+    params: &Params,
+) -> Result<Reply, SubsonicError> {
+    let id = params.id("id")?;
+    let Id::Song(book_id, chapter_no) = id else {
+        return Err(SubsonicError::not_found(id));
+    };
+    let target = transcode_target(params)?;
+    let book = live_book(db, book_id).await?;
+    let file = chapter_files(book_dir, &book)
+        .into_iter()
+        .find(|(no, _)| *no == chapter_no)
+        .map(|(_, file)| file)
+        .ok_or_else(|| SubsonicError::not_found(id))?;
+    Ok(match target {
+        Some(target) => Reply::Transcode(file, target),
+        None => Reply::File(file),
+    })
+}
+
+/// what `stream` transcodes to, none for the original file. a `maxBitRate` of 0 is no limit and
+/// a format we can't encode, like `raw` or `flac`, is the original file.
+fn transcode_target(params: &Params) -> Result<Option<Target>, SubsonicError> {
+    let bitrate = params.number("maxBitRate", 0u32)?;
+    let codec = match params.get("format") {
+        Some(format) => match Codec::from_name(format) {
+            Some(codec) => codec,
+            None => return Ok(None),
+        },
+        None if bitrate == 0 => return Ok(None),
+        // what the subsonic clients expect without a format
+        None => Codec::Mp3,
+    };
+    let start = params.number("timeOffset", 0.)?;
+    Ok(Some(Target::new(
+        codec,
+        (bitrate > 0).then_some(bitrate),
+        Some(start),
+    )))
+}
+
+/// the cover of a book, or the avatar of its author
+async fn cover_art(
+    db: &DatabaseConnection,
+    book_dir: &FsPath,
+    params: &Params,
+) -> Result<PathBuf, SubsonicError> {
+    let id = params.id("id")?;
+    let author_id = match id {
+        Id::Album(book_id) | Id::Song(book_id, _) => {
+            let book = live_book(db, book_id).await?;
+            if let Some(cover) = tools::book_cover(book_dir.join(&book.file_folder)) {
+                return Ok(cover);
+            }
+            book.author_id
+        }
+        Id::Artist(author_id) => author_id,
+    };
+    let author = live_author(db, author_id).await?;
+    if author.avatar.is_empty() {
+        return Err(SubsonicError::not_found(format!("cover of {}", id)));
+    }
+    Ok(book_dir.join(author.avatar))
+}
+
+/// the search query, clients may quote it
+fn search_query(params: &Params) -> &str {
+    params.get("query").unwrap_or("").trim().trim_matches('"')
+}
+
+/// [`name_filter`], an empty query matches everything
+fn matching<C: ColumnTrait>(query: &str, name: C, pinyin: C, initials: C) -> Condition {
+    if query.is_empty() {
+        Condition::all()
+    } else {
+        name_filter(query, name, pinyin, initials)
+    }
+}
+
+async fn search3(
+    db: &DatabaseConnection,
+    book_dir: &FsPath,
+    params: &Params,
+) -> Result<Value, SubsonicError> {
+    let query = search_query(params);
+
+    let authors = Author::find()
+        .filter(author::Column::DeletedAt.is_null())
+        .filter(matching(
+            query,
+            author::Column::Name,
+            author::Column::NamePinyin,
+            author::Column::NameInitials,
+        ))
+        .order_by_asc(author::Column::Name)
+        .offset(params.number("artistOffset", 0)?)
+        .limit(params.number("artistCount", 20)?)
+        .all(db)
+        .await?;
+    let books = Music::find()
+        .filter(music::Column::DeletedAt.is_null())
+        .filter(matching(
+            query,
+            music::Column::Name,
+            music::Column::NamePinyin,
+            music::Column::NameInitials,
+        ))
+        .order_by_asc(music::Column::Name)
+        .offset(params.number("albumOffset", 0)?)
+        .limit(params.number("albumCount", 20)?)
+        .all(db)
+        .await?;
+    let chapters = Chapter::find()
+        .join(sea_orm::JoinType::InnerJoin, chapter::Relation::Music.def())
+        .filter(music::Column::DeletedAt.is_null())
+        .filter(chapter::Column::Title.contains(query))
+        .order_by_asc(chapter::Column::MusicId)
+        .order_by_asc(chapter::Column::ChapterNo)
+        .offset(params.number("songOffset", 0)?)
+        .limit(params.number("songCount", 20)?)
+        .all(db)
+        .await?;
+
+    let mut song_books = vec![];
+    let book_ids = chapters
+        .iter()
+        .map(|chapter| chapter.music_id)
+        .collect::<Vec<_>>();
+    if !book_ids.is_empty() {
+        song_books = Music::find()
+            .filter(music::Column::Id.is_in(book_ids))
+            .all(db)
+            .await?;
+    }
+    let names = author_names(db, &[books.as_slice(), song_books.as_slice()].concat()).await?;
+    let artist_name = |book: &music::Model| names.get(&book.author_id).cloned().unwrap_or_default();
+
+    let mut files = HashMap::new();
+    let songs = chapters
+        .iter()
+        .filter_map(|chapter| {
+            let book = song_books.iter().find(|book| book.id == chapter.music_id)?;
+            let files = files
+                .entry(book.id)
+                .or_insert_with(|| chapter_files(book_dir, book));
+            let file = files
+                .iter()
+                .find(|(no, _)| *no == chapter.chapter_no)
+                .map(|(_, file)| file.as_path());
+            Some(song(
+                book,
+                &artist_name(book),
+                chapter.chapter_no,
+                Some(&chapter.title),
+                file,
+            ))
+        })
+        .collect::<Vec<_>>();
+    let albums = books
+        .iter()
+        .map(|book| album(book, &artist_name(book), book.chapters as usize))
+        .collect::<Vec<_>>();
+    // the album counts are not needed by the clients for search results
+    let artists = authors
+        .iter()
+        .map(|author| artist(author, 0))
+        .collect::<Vec<_>>();
+    Ok(json!({
+        "searchResult3": { "artist": artists, "album": albums, "song": songs }
+    }))
+}
+
+/// the book and the chapter of a song id, the chapter must be in the book
+async fn song_in_book(
+    db: &DatabaseConnection,
+    id: &str,
+) -> Result<(music::Model, i32), SubsonicError> {
+    let Some(Id::Song(book_id, chapter_no)) = Id::parse(id) else {
+        return Err(SubsonicError::not_found(format!("song {}", id)));
+    };
+    let book = live_book(db, book_id).await?;
+    if chapter_no < 1 || chapter_no > book.chapters {
+        return Err(SubsonicError::not_found(format!("song {}", id)));
+    }
+    Ok((book, chapter_no))
+}
+
+/// a played song moves the progress to the start of the next chapter unless it is already past
+/// it, and finishes the book when it is the last one, a song now playing moves the progress to the
+/// start of its chapter unless the progress is already in it
+async fn scrobble(
+    db: &DatabaseConnection,
+    user: &account::Model,
+    params: &Params,
+) -> Result<Value, SubsonicError> {
+    let submission = params.get("submission") != Some("false");
+    let ids = params.all("id").collect::<Vec<_>>();
+    if ids.is_empty() {
+        return Err(SubsonicError::missing("id"));
+    }
+    // the client name is the device of the listening history
+    let device = params.get("c");
+    for id in ids {
+        let (book, chapter_no) = song_in_book(db, id).await?;
+        if submission && chapter_no == book.chapters {
+            // the last song played through is the end of the book
+            crate::progress::set_state(db, user.id, book.id, ProgressState::Finished).await?;
+        } else if submission {
+            // a late scrobble of a song before the progress leaves it where it is
+            let current = crate::progress::find_progress(db, user.id, book.id).await?;
+            if (chapter_no + 1, 0.) > (current.chapter_no, current.progress) {
+                crate::progress::set_position(db, user.id, &book, chapter_no + 1, 0., device)
+                    .await?;
+            }
+        } else {
+            let current = crate::progress::find_progress(db, user.id, book.id).await?;
+            if current.chapter_no != chapter_no {
+                crate::progress::set_position(db, user.id, &book, chapter_no, 0., device).await?;
+            }
+        }
+    }
+    Ok(json!({}))
+}
+
+/// the progress is the queue, only the current song and the position are kept
+async fn save_play_queue(
+    db: &DatabaseConnection,
+    user: &account::Model,
+    params: &Params,
+) -> Result<Value, SubsonicError> {
+    // an empty queue clears nothing, the progress stays
+    let Some(current) = params.get("current") else {
+        return Ok(json!({}));
+    };
+    let (book, chapter_no) = song_in_book(db, current).await?;
+    let position: i64 = params.number("position", 0)?;
+    crate::progress::set_position(
+        db,
+        user.id,
+        &book,
+        chapter_no,
+        position.max(0) as f64 / 1000.,
+        params.get("c"),
+    )
+    .await?;
+    Ok(json!({}))
+}
+
+/// the chapters of the book played last, from the chapter of the progress
+async fn get_play_queue(
+    db: &DatabaseConnection,
+    book_dir: &FsPath,
+    user: &account::Model,
+) -> Result<Value, SubsonicError> {
+    let latest = Progress::find()
+        .join(
+            sea_orm::JoinType::InnerJoin,
+            progress::Relation::Music.def(),
+        )
+        .filter(progress::Column::AccountId.eq(user.id))
+        .filter(progress::Column::LastPlayedAt.is_not_null())
+        .filter(music::Column::DeletedAt.is_null())
+        .order_by_desc(progress::Column::LastPlayedAt)
+        .one(db)
+        .await?;
+    let Some(latest) = latest else {
+        return Ok(json!({}));
+    };
+    let book = live_book(db, latest.music_id).await?;
+    let artist = Author::find_by_id(book.author_id)
+        .one(db)
+        .await?
+        .map(|author| author.name)
+        .unwrap_or_default();
+    let songs = songs(db, book_dir, &book, &artist).await?;
+    let mut queue = json!({
+        "current": Id::Song(book.id, latest.chapter_no.max(1)).to_string(),
+        "position": (latest.progress * 1000.) as i64,
+        "username": user.name,
+        "changedBy": env!("CARGO_PKG_NAME"),
+        "entry": songs,
+    });
+    if let Some(changed) = latest.last_played_at {
+        queue["changed"] = timestamp(changed).into();
+    }
+    Ok(json!({ "playQueue": queue }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use sea_orm::{DatabaseBackend, DatabaseConnection, MockDatabase, MockExecResult};
+
+    use super::{call, Params, Reply};
+    use crate::entities::{
+        account, author, chapter, listening_session, music, progress,
+        sea_orm_active_enums::ProgressState,
+    };
+    use crate::fixtures;
+    use crate::transcode::{Codec, Target};
+
+    // recorded from the clients, alice logs in with "sesame"
+    const DSUB_PING: &str = "u=alice&t=26719a1196d2a940705a59634eb18eab&s=c19b2d&v=1.2.0&c=DSub";
+    const ULTRASONIC_PING: &str = "u=alice&p=enc:736573616d65&v=1.13.0&c=Ultrasonic&f=json";
+    const SYMFONIUM_GET_ALBUM: &str =
+        "u=alice&t=26719a1196d2a940705a59634eb18eab&s=c19b2d&v=1.16.1&c=Symfonium&f=json&id=al-1";
+    const SUBSTREAMER_STREAM: &str =
+        "id=tr-1-2&maxBitRate=0&u=alice&p=sesame&v=1.16.1&c=substreamer";
+    const DSUB_STREAM: &str = "u=alice&p=enc:736573616d65&v=1.2.0&c=DSub&id=tr-1-2\
+        &maxBitRate=96&format=opus&timeOffset=30&estimateContentLength=true";
+    const TEMPO_SCROBBLE: &str =
+        "u=alice&p=enc:736573616d65&v=1.16.1&c=Tempo&f=json&id=tr-1-1&submission=true&time=1698300000000";
+
+    fn account(subsonic_password: Option<&str>) -> account::Model {
+        account::Model {
+            // md5 of "sesame"
+            password: "c8dae1c50e092f3d877192fc555b1dcf".to_string(),
+            subsonic_password: subsonic_password.map(str::to_string),
+            ..fixtures::account(1, "alice")
+        }
+    }
+
+    fn book() -> music::Model {
+        music::Model {
+            chapters: 2,
+            duration: Some(120.4),
+            ..fixtures::book(1, "book")
+        }
+    }
+
+    fn author() -> author::Model {
+        fixtures::author(1, "author")
+    }
+
+    fn create_book(book_dir: &Path) {
+        let folder = book_dir.join("author/book");
+        std::fs::create_dir_all(&folder).unwrap();
+        std::fs::write(folder.join("0001.mp3"), b"1").unwrap();
+        std::fs::write(folder.join("0002.mp3"), b"22").unwrap();
+    }
+
+    fn accounts(subsonic_password: Option<&str>) -> DatabaseConnection {
+        MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([[account(subsonic_password)]])
+            .into_connection()
+    }
+
+    async fn error_code(db: &DatabaseConnection, query: &str) -> u32 {
+        call(db, Path::new("."), "ping", &Params::parse(query.as_bytes()))
+            .await
+            .unwrap_err()
+            .code
+    }
+
+    #[tokio::test]
+    async fn test_authenticate() {
+        let ping = |db, query: &str| {
+            let params = Params::parse(query.as_bytes());
+            async move { call(&db, Path::new("."), "ping", &params).await }
+        };
+        assert!(ping(accounts(Some("sesame")), DSUB_PING).await.is_ok());
+        assert!(ping(accounts(None), ULTRASONIC_PING).await.is_ok());
+        // the token scheme needs the subsonic password
+        assert_eq!(error_code(&accounts(None), DSUB_PING).await, 41);
+        assert_eq!(error_code(&accounts(Some("other")), DSUB_PING).await, 40);
+        assert_eq!(
+            error_code(&accounts(None), "u=alice&p=wrong&v=1.2.0&c=DSub").await,
+            40
+        );
+        assert_eq!(
+            error_code(&accounts(None), "u=alice&v=1.2.0&c=DSub").await,
+            10
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_album() {
+        let book_dir = tempfile::tempdir().unwrap();
+        create_book(book_dir.path());
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([[account(Some("sesame"))]])
+            .append_query_results([[book()]])
+            .append_query_results([[author()]])
+            .append_query_results([[chapter::Model {
+                title: "the beginning".to_string(),
+                ..fixtures::chapter(1, 1)
+            }]])
+            .into_connection();
+        let params = Params::parse(SYMFONIUM_GET_ALBUM.as_bytes());
+        let Reply::Body(body) = call(&db, book_dir.path(), "getAlbum", &params)
+            .await
+            .unwrap()
+        else {
+            panic!("getAlbum replied a file");
+        };
+        let album = &body["album"];
+        assert_eq!(album["id"], "al-1");
+        assert_eq!(album["artist"], "author");
+        assert_eq!(album["duration"], 120);
+        assert_eq!(album["songCount"], 2);
+        let songs = album["song"].as_array().unwrap();
+        assert_eq!(songs[0]["id"], "tr-1-1");
+        assert_eq!(songs[0]["title"], "the beginning");
+        assert_eq!(songs[1]["title"], "Chapter 2");
+        assert_eq!(songs[1]["contentType"], "audio/mpeg");
+        assert_eq!(songs[1]["size"], 2);
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        let book_dir = tempfile::tempdir().unwrap();
+        create_book(book_dir.path());
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([[account(None)]])
+            .append_query_results([[book()]])
+            .into_connection();
+        let params = Params::parse(SUBSTREAMER_STREAM.as_bytes());
+        let reply = call(&db, book_dir.path(), "stream", &params).await;
+        assert_eq!(
+            reply,
+            Ok(Reply::File(book_dir.path().join("author/book/0002.mp3")))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream_transcoded() {
+        let book_dir = tempfile::tempdir().unwrap();
+        create_book(book_dir.path());
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([[account(None)]])
+            .append_query_results([[book()]])
+            .into_connection();
+        let params = Params::parse(DSUB_STREAM.as_bytes());
+        let reply = call(&db, book_dir.path(), "stream", &params).await;
+        assert_eq!(
+            reply,
+            Ok(Reply::Transcode(
+                book_dir.path().join("author/book/0002.mp3"),
+                Target::new(Codec::Opus, Some(96), Some(30.))
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scrobble() {
+        let progress = fixtures::progress(1, 1, 30.);
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([[account(None)]])
+            .append_query_results([[book()]])
+            .append_query_results([[progress.clone()]])
+            .append_query_results([[progress.clone()]])
+            // no session to continue, a new one is logged
+            .append_query_results([Vec::<listening_session::Model>::new()])
+            .append_exec_results([MockExecResult {
+                last_insert_id: 1,
+                rows_affected: 1,
+            }])
+            .append_query_results([[listening_session::Model {
+                id: 1,
+                account_id: 1,
+                music_id: 1,
+                start_chapter_no: 1,
+                start_position: 30.,
+                end_chapter_no: 2,
+                end_position: 0.,
+                started_at: Default::default(),
+                ended_at: Default::default(),
+                device: None,
+            }]])
+            // the chapter durations are unknown, the book can't be finished
+            .append_query_results([Vec::<chapter::Model>::new()])
+            .append_exec_results([MockExecResult {
+                last_insert_id: 1,
+                rows_affected: 1,
+            }])
+            .append_query_results([[progress]])
+            .into_connection();
+        let params = Params::parse(TEMPO_SCROBBLE.as_bytes());
+        let reply = call(&db, Path::new("."), "scrobble", &params).await;
+        assert!(matches!(reply, Ok(Reply::Body(_))));
+        // the played chapter moves the progress to the start of the next one
+        let log = format!("{:?}", db.into_transaction_log());
+        assert!(log.contains("INSERT INTO `listening_session`"));
+        assert!(log.contains("String(Some(\"Tempo\"))"));
+        assert!(log.contains("UPDATE `progress`"));
+        assert!(log.contains("Int(Some(2))"));
+    }
+
+    #[tokio::test]
+    async fn test_scrobble_finished() {
+        // the last song, already saved near its end by the play queue
+        let finished = progress::Model {
+            state: ProgressState::Finished,
+            finish_count: 1,
+            ..fixtures::progress(1, 2, 55.)
+        };
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([[account(None)]])
+            .append_query_results([[book()]])
+            .append_query_results([[finished]])
+            .into_connection();
+        let params = Params::parse(TEMPO_SCROBBLE.replace("tr-1-1", "tr-1-2").as_bytes());
+        let reply = call(&db, Path::new("."), "scrobble", &params).await;
+        assert!(matches!(reply, Ok(Reply::Body(_))));
+        // neither counted again as finished nor as listened to again
+        let log = format!("{:?}", db.into_transaction_log());
+        assert!(!log.contains("UPDATE"));
+        assert!(!log.contains("INSERT"));
+    }
+
+    #[tokio::test]
+    async fn test_scrobble_behind() {
+        // the progress is already in the last chapter when the first song is scrobbled
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([[account(None)]])
+            .append_query_results([[book()]])
+            .append_query_results([[fixtures::progress(1, 2, 10.)]])
+            .into_connection();
+        let params = Params::parse(TEMPO_SCROBBLE.as_bytes());
+        let reply = call(&db, Path::new("."), "scrobble", &params).await;
+        assert!(matches!(reply, Ok(Reply::Body(_))));
+        let log = format!("{:?}", db.into_transaction_log());
+        assert!(!log.contains("UPDATE"));
+    }
+}
@@ -0,0 +1,190 @@
+//! the `subsonic-response` envelope, in xml by default or in json with `f=json`
+
+use axum::response::{IntoResponse, Response};
+use hyper::header;
+use sea_orm::DbErr;
+use serde_json::{json, Map, Value};
+use tracing::error;
+
+use crate::tools::escape_xml;
+
+/// the subsonic api version implemented
+pub(crate) const API_VERSION: &str = "1.16.1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+    Xml,
+    Json,
+}
+
+impl Format {
+    pub(crate) fn from_param(f: Option<&str>) -> Self {
+        match f {
+            Some("json") => Format::Json,
+            _ => Format::Xml,
+        }
+    }
+}
+
+/// a subsonic error, sent with a 200 status as the clients expect
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SubsonicError {
+    pub code: u32,
+    pub message: String,
+}
+
+impl SubsonicError {
+    pub(crate) const GENERIC: u32 = 0;
+    pub(crate) const MISSING_PARAMETER: u32 = 10;
+    pub(crate) const WRONG_CREDENTIALS: u32 = 40;
+    pub(crate) const TOKEN_NOT_SUPPORTED: u32 = 41;
+    pub(crate) const NOT_FOUND: u32 = 70;
+
+    pub(crate) fn new(code: u32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn missing(param: &str) -> Self {
+        Self::new(
+            Self::MISSING_PARAMETER,
+            format!("required parameter is missing: {}", param),
+        )
+    }
+
+    pub(crate) fn not_found(what: impl std::fmt::Display) -> Self {
+        Self::new(Self::NOT_FOUND, format!("{} not found", what))
+    }
+
+    pub(crate) fn wrong_credentials() -> Self {
+        Self::new(Self::WRONG_CREDENTIALS, "wrong username or password")
+    }
+}
+
+impl From<DbErr> for SubsonicError {
+    fn from(err: DbErr) -> Self {
+        error!("subsonic database error: {}", err);
+        Self::new(Self::GENERIC, "internal error")
+    }
+}
+
+/// the envelope of the fields of `body`, or of the error
+pub(crate) fn envelope(result: Result<Value, SubsonicError>) -> Value {
+    let mut response = Map::new();
+    let (status, body) = match result {
+        Ok(body) => ("ok", body),
+        Err(err) => (
+            "failed",
+            json!({ "error": { "code": err.code, "message": err.message } }),
+        ),
+    };
+    response.insert("status".into(), status.into());
+    response.insert("version".into(), API_VERSION.into());
+    response.insert("type".into(), env!("CARGO_PKG_NAME").into());
+    response.insert("serverVersion".into(), env!("CARGO_PKG_VERSION").into());
+    response.insert("openSubsonic".into(), true.into());
+    if let Value::Object(body) = body {
+        response.extend(body);
+    }
+    Value::Object(response)
+}
+
+pub(crate) fn render(format: Format, envelope: Value) -> Response {
+    match format {
+        Format::Json => axum::Json(json!({ "subsonic-response": envelope })).into_response(),
+        Format::Xml => {
+            let mut xml = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+            write_element(
+                &mut xml,
+                "subsonic-response",
+                &envelope,
+                Some("http://subsonic.org/restapi"),
+            );
+            ([(header::CONTENT_TYPE, "text/xml; charset=utf-8")], xml).into_response()
+        }
+    }
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// the xml form of the json body: the scalars are attributes, the objects are child elements
+/// and an array is repeated as elements of its name
+fn write_element(out: &mut String, name: &str, value: &Value, xmlns: Option<&str>) {
+    out.push('<');
+    out.push_str(name);
+    if let Some(xmlns) = xmlns {
+        out.push_str(&format!(r#" xmlns="{}""#, xmlns));
+    }
+    let Value::Object(map) = value else {
+        match scalar(value) {
+            Some(text) => out.push_str(&format!(">{}</{}>", escape_xml(&text), name)),
+            None => out.push_str("/>"),
+        }
+        return;
+    };
+    for (key, value) in map {
+        if let Some(text) = scalar(value) {
+            out.push_str(&format!(r#" {}="{}""#, key, escape_xml(&text)));
+        }
+    }
+    let children = map
+        .iter()
+        .filter(|(_, value)| value.is_object() || value.is_array())
+        .collect::<Vec<_>>();
+    if children.is_empty() {
+        out.push_str("/>");
+        return;
+    }
+    out.push('>');
+    for (key, value) in children {
+        match value {
+            Value::Array(items) => {
+                for item in items {
+                    write_element(out, key, item, None);
+                }
+            }
+            value => write_element(out, key, value, None),
+        }
+    }
+    out.push_str(&format!("</{}>", name));
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{envelope, write_element, SubsonicError};
+
+    #[test]
+    fn test_xml() {
+        let body = json!({
+            "album": {
+                "id": "al-1",
+                "name": "Tom & Jerry",
+                "song": [{ "id": "tr-1-1", "track": 1 }, { "id": "tr-1-2", "track": 2 }]
+            }
+        });
+        let mut xml = String::new();
+        write_element(&mut xml, "r", &body, None);
+        assert_eq!(
+            xml,
+            r#"<r><album id="al-1" name="Tom &amp; Jerry"><song id="tr-1-1" track="1"/><song id="tr-1-2" track="2"/></album></r>"#
+        );
+
+        let failed = envelope(Err(SubsonicError::wrong_credentials()));
+        assert_eq!(failed["status"], "failed");
+        assert_eq!(failed["error"]["code"], 40);
+        let mut xml = String::new();
+        write_element(&mut xml, "subsonic-response", &failed, None);
+        assert!(xml.contains(r#"<error code="40" message="wrong username or password"/>"#));
+    }
+}
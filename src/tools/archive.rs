@@ -0,0 +1,297 @@
+//! safe extraction of `.zip`, `.tar`, `.tar.gz` and `.tgz` archives
+//!
+//! entries with absolute paths, `..` components or links are rejected, and the bytes actually
+//! written are counted so a zip bomb stops at [`ExtractLimits`] instead of filling the disk.
+
+use std::{
+    fs::File,
+    io::{Read, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use eyre::{bail, eyre};
+use tracing::debug;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl ArchiveKind {
+    /// detect the archive kind from the file name
+    pub fn detect(path: impl AsRef<Path>) -> Option<Self> {
+        let name = path.as_ref().file_name()?.to_str()?.to_ascii_lowercase();
+        if name.ends_with(".zip") {
+            Some(Self::Zip)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Self::TarGz)
+        } else if name.ends_with(".tar") {
+            Some(Self::Tar)
+        } else {
+            None
+        }
+    }
+}
+
+/// the file name without the archive extension, `book.tar.gz` -> `book`
+pub fn archive_stem(path: impl AsRef<Path>) -> Option<String> {
+    let name = path.as_ref().file_name()?.to_str()?;
+    let lower = name.to_ascii_lowercase();
+    let ext_len = [".tar.gz", ".tgz", ".tar", ".zip"]
+        .iter()
+        .find(|ext| lower.ends_with(*ext))?
+        .len();
+    Some(name[..name.len() - ext_len].to_string())
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ExtractLimits {
+    /// the max number of bytes written for the whole archive
+    pub max_total_size: u64,
+    /// the max number of entries
+    pub max_entries: usize,
+    /// the max ratio between the extracted size and the archive size
+    pub max_ratio: u64,
+}
+
+impl Default for ExtractLimits {
+    fn default() -> Self {
+        Self {
+            max_total_size: 64 * 1024 * 1024 * 1024,
+            max_entries: 10_000,
+            // audio barely compresses, anything above this is suspicious
+            max_ratio: 100,
+        }
+    }
+}
+
+/// turn an entry name into a relative path, rejecting anything escaping the target dir
+fn sanitize_entry_path(name: &Path) -> eyre::Result<PathBuf> {
+    let mut out = PathBuf::new();
+    for component in name.components() {
+        match component {
+            Component::Normal(part) => out.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                bail!("unsafe path in archive: {:?}", name)
+            }
+        }
+    }
+    if out.as_os_str().is_empty() {
+        bail!("empty path in archive");
+    }
+    Ok(out)
+}
+
+struct Extractor<'a> {
+    target_dir: &'a Path,
+    budget: u64,
+    max_entries: usize,
+    entries: usize,
+}
+
+impl<'a> Extractor<'a> {
+    fn new(target_dir: &'a Path, archive_size: u64, limits: ExtractLimits) -> Self {
+        let budget = limits
+            .max_total_size
+            .min(archive_size.saturating_mul(limits.max_ratio));
+        Self {
+            target_dir,
+            budget,
+            max_entries: limits.max_entries,
+            entries: 0,
+        }
+    }
+
+    fn count_entry(&mut self) -> eyre::Result<()> {
+        self.entries += 1;
+        if self.entries > self.max_entries {
+            bail!("too many entries in archive");
+        }
+        Ok(())
+    }
+
+    fn create_dir(&mut self, name: &Path) -> eyre::Result<()> {
+        self.count_entry()?;
+        let path = self.target_dir.join(sanitize_entry_path(name)?);
+        std::fs::create_dir_all(path)?;
+        Ok(())
+    }
+
+    fn write_file(&mut self, name: &Path, reader: &mut impl Read) -> eyre::Result<()> {
+        self.count_entry()?;
+        let path = self.target_dir.join(sanitize_entry_path(name)?);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = File::create(&path)?;
+        // read one byte more than allowed to detect the overflow
+        let written = std::io::copy(&mut reader.take(self.budget + 1), &mut file)?;
+        file.flush()?;
+        if written > self.budget {
+            bail!("archive expands beyond the size limit");
+        }
+        self.budget -= written;
+        Ok(())
+    }
+}
+
+fn extract_zip(file: File, extractor: &mut Extractor) -> eyre::Result<()> {
+    let mut archive = zip::ZipArchive::new(file)?;
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let name = PathBuf::from(entry.name());
+        let is_link = entry
+            .unix_mode()
+            .map(|mode| mode & 0o170000 == 0o120000)
+            .unwrap_or(false);
+        if is_link {
+            bail!("link in archive: {:?}", name);
+        }
+        if entry.is_dir() {
+            extractor.create_dir(&name)?;
+        } else {
+            extractor.write_file(&name, &mut entry)?;
+        }
+    }
+    Ok(())
+}
+
+fn extract_tar(reader: impl Read, extractor: &mut Extractor) -> eyre::Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.into_owned();
+        match entry.header().entry_type() {
+            tar::EntryType::Regular | tar::EntryType::Continuous => {
+                extractor.write_file(&name, &mut entry)?
+            }
+            tar::EntryType::Directory => extractor.create_dir(&name)?,
+            other => bail!("unsupported entry {:?} in archive: {:?}", other, name),
+        }
+    }
+    Ok(())
+}
+
+/// extract `archive` into `target_dir`, which is created if needed
+pub fn extract_archive(
+    archive: impl AsRef<Path>,
+    target_dir: impl AsRef<Path>,
+    limits: ExtractLimits,
+) -> eyre::Result<()> {
+    let archive = archive.as_ref();
+    let target_dir = target_dir.as_ref();
+    debug!("extracting {:?} to {:?}", archive, target_dir);
+    let kind =
+        ArchiveKind::detect(archive).ok_or_else(|| eyre!("not an archive: {:?}", archive))?;
+    let file = File::open(archive)?;
+    let archive_size = file.metadata()?.len();
+    std::fs::create_dir_all(target_dir)?;
+    let mut extractor = Extractor::new(target_dir, archive_size, limits);
+    match kind {
+        ArchiveKind::Zip => extract_zip(file, &mut extractor),
+        ArchiveKind::Tar => extract_tar(file, &mut extractor),
+        ArchiveKind::TarGz => extract_tar(flate2::read::GzDecoder::new(file), &mut extractor),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+    use std::path::Path;
+
+    use super::{archive_stem, extract_archive, ArchiveKind, ExtractLimits};
+
+    fn write_zip(path: &Path, files: &[(&str, &[u8])]) {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in files {
+            zip.start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            zip.write_all(data).unwrap();
+        }
+        std::fs::write(path, zip.finish().unwrap().into_inner()).unwrap();
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(ArchiveKind::detect("a/b.ZIP"), Some(ArchiveKind::Zip));
+        assert_eq!(ArchiveKind::detect("b.tar.gz"), Some(ArchiveKind::TarGz));
+        assert_eq!(ArchiveKind::detect("b.tgz"), Some(ArchiveKind::TarGz));
+        assert_eq!(ArchiveKind::detect("b.tar"), Some(ArchiveKind::Tar));
+        assert_eq!(ArchiveKind::detect("b.mp3"), None);
+        assert_eq!(archive_stem("dir/book.tar.gz").unwrap(), "book");
+    }
+
+    #[test]
+    fn test_extract_zip() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("book.zip");
+        write_zip(&archive, &[("cd1/01.mp3", b"1"), ("cd1/02.mp3", b"2")]);
+        let target = dir.path().join("out");
+        extract_archive(&archive, &target, ExtractLimits::default()).unwrap();
+        assert_eq!(std::fs::read(target.join("cd1/02.mp3")).unwrap(), b"2");
+    }
+
+    #[test]
+    fn test_extract_zip_traversal() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("book.zip");
+        write_zip(&archive, &[("../evil.mp3", b"1")]);
+        let target = dir.path().join("out");
+        assert!(extract_archive(&archive, &target, ExtractLimits::default()).is_err());
+        assert!(!dir.path().join("evil.mp3").exists());
+    }
+
+    #[test]
+    fn test_extract_zip_bomb() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("book.zip");
+        write_zip(&archive, &[("01.mp3", &vec![0u8; 1024 * 1024])]);
+        let target = dir.path().join("out");
+        let result = extract_archive(&archive, &target, ExtractLimits::default());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_extract_tar_gz() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("book.tar.gz");
+        let encoder = flate2::write::GzEncoder::new(
+            std::fs::File::create(&archive).unwrap(),
+            flate2::Compression::default(),
+        );
+        let mut builder = tar::Builder::new(encoder);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(1);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "book/01.mp3", &b"1"[..])
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let target = dir.path().join("out");
+        extract_archive(&archive, &target, ExtractLimits::default()).unwrap();
+        assert_eq!(std::fs::read(target.join("book/01.mp3")).unwrap(), b"1");
+    }
+
+    #[test]
+    fn test_extract_tar_traversal() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("book.tar");
+        let mut builder = tar::Builder::new(std::fs::File::create(&archive).unwrap());
+        let mut header = tar::Header::new_old();
+        // `set_path` refuses `..`, so write the raw name
+        let name = b"../evil.mp3";
+        header.as_old_mut().name[..name.len()].copy_from_slice(name);
+        header.set_size(1);
+        header.set_cksum();
+        builder.append(&header, &b"1"[..]).unwrap();
+        builder.finish().unwrap();
+
+        let target = dir.path().join("out");
+        assert!(extract_archive(&archive, &target, ExtractLimits::default()).is_err());
+        assert!(!dir.path().join("evil.mp3").exists());
+    }
+}
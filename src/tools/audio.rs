@@ -0,0 +1,313 @@
+//! audio probing: chapter durations and a coarse fingerprint to spot the same recording
+//!
+//! the fingerprint is one bit per [`FRAME_MS`] frame of the first [`FINGERPRINT_SECONDS`],
+//! set when the frame is louder than the previous one. It survives re-encoding and resampling,
+//! which is all we need to find the same book imported twice.
+
+use std::path::Path;
+
+use eyre::{bail, eyre};
+use symphonia::core::{
+    audio::SampleBuffer,
+    codecs::{CodecParameters, DecoderOptions},
+    errors::Error as SymphoniaError,
+    formats::{FormatOptions, FormatReader},
+    io::MediaSourceStream,
+    meta::{MetadataOptions, MetadataRevision, StandardTagKey},
+    probe::{Hint, ProbeResult},
+    units::TimeBase,
+};
+use tracing::warn;
+
+/// the length of one fingerprint frame
+const FRAME_MS: u32 = 50;
+/// how much of the first chapter is fingerprinted
+const FINGERPRINT_SECONDS: u32 = 60;
+/// the max shift in frames tried when comparing, to absorb trimmed silence
+const MAX_SHIFT: usize = 20;
+/// fingerprints overlapping on fewer bits than this are not compared
+const MIN_BITS: usize = 100;
+
+/// the duration and fingerprint of a book, `None` when the files can't be decoded
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AudioInfo {
+    pub duration: Option<f64>,
+    pub fingerprint: Option<String>,
+    /// the durations by chapter number, of the chapters that could be decoded
+    pub chapter_durations: Vec<(i32, f64)>,
+}
+
+fn probe(path: &Path) -> eyre::Result<ProbeResult> {
+    let file = std::fs::File::open(path)?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension().and_then(|ext| ext.to_str()) {
+        hint.with_extension(ext);
+    }
+    Ok(symphonia::default::get_probe().format(
+        &hint,
+        stream,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?)
+}
+
+fn open(path: &Path) -> eyre::Result<(Box<dyn FormatReader>, u32, CodecParameters)> {
+    let probed = probe(path)?;
+    let track = probed
+        .format
+        .default_track()
+        .ok_or_else(|| eyre!("no audio track in {:?}", path))?;
+    let (track_id, params) = (track.id, track.codec_params.clone());
+    Ok((probed.format, track_id, params))
+}
+
+/// the title tag of an audio file, in the container or in a tag before it like id3
+pub fn title_tag(path: &Path) -> Option<String> {
+    let mut probed = probe(path).ok()?;
+    let title = |revision: &MetadataRevision| {
+        revision
+            .tags()
+            .iter()
+            .find(|tag| tag.std_key == Some(StandardTagKey::TrackTitle))
+            .map(|tag| tag.value.to_string().trim().to_string())
+            .filter(|title| !title.is_empty())
+    };
+    if let Some(found) = probed.format.metadata().current().and_then(title) {
+        return Some(found);
+    }
+    let metadata = probed.metadata.get()?;
+    metadata.current().and_then(title)
+}
+
+fn is_end_of_stream(e: &SymphoniaError) -> bool {
+    matches!(e, SymphoniaError::IoError(e) if e.kind() == std::io::ErrorKind::UnexpectedEof)
+}
+
+/// the duration of an audio file in seconds
+pub fn audio_duration(path: &Path) -> eyre::Result<f64> {
+    let (mut format, track_id, params) = open(path)?;
+    let time_base = params
+        .time_base
+        .or_else(|| params.sample_rate.map(|rate| TimeBase::new(1, rate)))
+        .ok_or_else(|| eyre!("unknown time base in {:?}", path))?;
+    let frames = match params.n_frames {
+        Some(frames) => frames,
+        // no frame count in the header, add up the packets
+        None => {
+            let mut frames = 0;
+            loop {
+                match format.next_packet() {
+                    Ok(packet) if packet.track_id() == track_id => frames += packet.dur,
+                    Ok(_) => {}
+                    Err(e) if is_end_of_stream(&e) => break,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            frames
+        }
+    };
+    let time = time_base.calc_time(frames);
+    Ok(time.seconds as f64 + time.frac)
+}
+
+/// whether the first packet of an audio file decodes, `None` when there is no decoder for its
+/// codec, like opus in an ogg file
+///
+/// a header that parses is not enough, a corrupt or truncated file fails here.
+pub fn first_packet_decodes(path: &Path) -> Option<bool> {
+    let Ok((mut format, track_id, params)) = open(path) else {
+        return Some(false);
+    };
+    let mut decoder =
+        match symphonia::default::get_codecs().make(&params, &DecoderOptions::default()) {
+            Ok(decoder) => decoder,
+            Err(SymphoniaError::Unsupported(_)) => return None,
+            Err(_) => return Some(false),
+        };
+    loop {
+        match format.next_packet() {
+            Ok(packet) if packet.track_id() == track_id => {
+                return Some(decoder.decode(&packet).is_ok())
+            }
+            Ok(_) => {}
+            Err(_) => return Some(false),
+        }
+    }
+}
+
+/// the fingerprint of the beginning of an audio file, as hex
+pub fn fingerprint(path: &Path) -> eyre::Result<String> {
+    let (mut format, track_id, params) = open(path)?;
+    let sample_rate = params
+        .sample_rate
+        .ok_or_else(|| eyre!("unknown sample rate in {:?}", path))?;
+    let mut decoder = symphonia::default::get_codecs().make(&params, &DecoderOptions::default())?;
+    let frame_len = (sample_rate * FRAME_MS / 1000).max(1) as usize;
+    let max_samples = (sample_rate * FINGERPRINT_SECONDS) as usize;
+    let mut energies = vec![];
+    let (mut energy, mut in_frame, mut samples) = (0f64, 0, 0);
+    'decode: loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(e) if is_end_of_stream(&e) => break,
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // a corrupted packet, skip it like a player would
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let channels = decoded.spec().channels.count().max(1);
+        let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+        buffer.copy_interleaved_ref(decoded);
+        for frame in buffer.samples().chunks(channels) {
+            let mono = frame.iter().sum::<f32>() / channels as f32;
+            energy += (mono * mono) as f64;
+            in_frame += 1;
+            if in_frame == frame_len {
+                energies.push(energy);
+                (energy, in_frame) = (0., 0);
+            }
+            samples += 1;
+            if samples >= max_samples {
+                break 'decode;
+            }
+        }
+    }
+    if energies.len() < 2 {
+        bail!("{:?} is too short to fingerprint", path);
+    }
+    let bits = energies.windows(2).map(|w| w[1] > w[0]).collect::<Vec<_>>();
+    let bytes = bits
+        .chunks(8)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .enumerate()
+                .fold(0u8, |byte, (i, bit)| byte | (u8::from(*bit) << i))
+        })
+        .collect::<Vec<_>>();
+    Ok(hex::encode(bytes))
+}
+
+fn fingerprint_bits(fingerprint: &str) -> Option<Vec<bool>> {
+    let bytes = hex::decode(fingerprint).ok()?;
+    Some(
+        bytes
+            .iter()
+            .flat_map(|byte| (0..8).map(move |i| byte & (1 << i) != 0))
+            .collect(),
+    )
+}
+
+/// the share of equal bits between two fingerprints at the best small time shift, `1.0` for the
+/// same recording and around `0.5` for unrelated ones
+pub fn fingerprint_similarity(a: &str, b: &str) -> Option<f64> {
+    let (a, b) = (fingerprint_bits(a)?, fingerprint_bits(b)?);
+    (0..=MAX_SHIFT)
+        .flat_map(|shift| [(shift, 0), (0, shift)])
+        .filter_map(|(shift_a, shift_b)| {
+            let (a, b) = (a.get(shift_a..)?, b.get(shift_b..)?);
+            let len = a.len().min(b.len());
+            if len < MIN_BITS {
+                return None;
+            }
+            let equal = a.iter().zip(b).filter(|(a, b)| a == b).count();
+            Some(equal as f64 / len as f64)
+        })
+        .max_by(|a, b| a.total_cmp(b))
+}
+
+/// the durations of the chapters and of the book, and the fingerprint of the first chapter of a
+/// book folder
+///
+/// this decodes audio, run it on a blocking thread.
+pub fn probe_book(folder: &Path) -> AudioInfo {
+    let chapters = match super::chapter_files(folder) {
+        Ok(chapters) => chapters,
+        Err(e) => {
+            warn!("fail to list chapters in {:?}: {}", folder, e);
+            return AudioInfo::default();
+        }
+    };
+    let mut chapter_durations = vec![];
+    for (chapter_no, path) in &chapters {
+        match audio_duration(path) {
+            Ok(duration) => chapter_durations.push((*chapter_no, duration)),
+            Err(e) => warn!("fail to get the duration of {:?}: {}", path, e),
+        }
+    }
+    // the total is only known when every chapter is
+    let duration = (chapter_durations.len() == chapters.len())
+        .then(|| chapter_durations.iter().map(|(_, duration)| duration).sum());
+    let fingerprint = chapters.first().and_then(|(_, path)| {
+        fingerprint(path)
+            .map_err(|e| warn!("fail to fingerprint {:?}: {}", path, e))
+            .ok()
+    });
+    AudioInfo {
+        duration,
+        fingerprint,
+        chapter_durations,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        audio_duration, fingerprint, fingerprint_similarity, first_packet_decodes, probe_book,
+    };
+    use crate::fixtures::write_wav;
+
+    #[test]
+    fn test_duration_and_fingerprint() {
+        let dir = tempfile::tempdir().unwrap();
+        let (original, quieter, other) = (
+            dir.path().join("original.wav"),
+            dir.path().join("quieter.wav"),
+            dir.path().join("other.wav"),
+        );
+        write_wav(&original, 10, 1, 1.);
+        write_wav(&quieter, 10, 1, 0.5);
+        write_wav(&other, 10, 7, 1.);
+        assert!((audio_duration(&original).unwrap() - 10.).abs() < 0.01);
+
+        let original = fingerprint(&original).unwrap();
+        let same = fingerprint_similarity(&original, &fingerprint(&quieter).unwrap()).unwrap();
+        let different = fingerprint_similarity(&original, &fingerprint(&other).unwrap()).unwrap();
+        assert!(same > 0.95, "{}", same);
+        assert!(different < 0.8, "{}", different);
+    }
+
+    #[test]
+    fn test_probe_book() {
+        let dir = tempfile::tempdir().unwrap();
+        write_wav(&dir.path().join("0001.wav"), 3, 1, 1.);
+        write_wav(&dir.path().join("0002.wav"), 2, 2, 1.);
+        let info = probe_book(dir.path());
+        assert!((info.duration.unwrap() - 5.).abs() < 0.01);
+        assert!(info.fingerprint.is_some());
+        assert_eq!(info.chapter_durations.len(), 2);
+        assert_eq!(info.chapter_durations[1].0, 2);
+        assert!((info.chapter_durations[1].1 - 2.).abs() < 0.01);
+
+        assert_eq!(
+            first_packet_decodes(&dir.path().join("0001.wav")),
+            Some(true)
+        );
+        std::fs::write(dir.path().join("0003.mp3"), b"not audio").unwrap();
+        assert_eq!(
+            first_packet_decodes(&dir.path().join("0003.mp3")),
+            Some(false)
+        );
+        let info = probe_book(dir.path());
+        assert_eq!(info.duration, None);
+        // the chapters that decode keep their duration
+        assert_eq!(info.chapter_durations.len(), 2);
+    }
+}
@@ -0,0 +1,333 @@
+//! edit authors: rename, merge duplicates, description and avatar
+//!
+//! the books of an author live in `{author}/{book}`, so renaming or merging an author moves the
+//! book folders too, inside the same transaction as the rows.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use eyre::{bail, eyre};
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, ConnectionTrait, DatabaseConnection, EntityTrait,
+    IntoActiveModel, QueryFilter, TransactionTrait,
+};
+use tracing::info;
+
+use super::{
+    check_folder_name,
+    edit::{remove_empty_parent, rename_all, reversed},
+    romanize,
+};
+use crate::entities::{prelude::*, *};
+
+/// the folder under `book_dir` holding the resized author avatars
+pub const AVATAR_DIR: &str = ".avatars";
+/// avatars are resized to fit in a square of this size
+const AVATAR_SIZE: u32 = 256;
+
+async fn find_author(db: &impl ConnectionTrait, author_id: i32) -> eyre::Result<author::Model> {
+    Author::find_by_id(author_id)
+        .one(db)
+        .await?
+        .ok_or_else(|| eyre!("author {} not found", author_id))
+}
+
+/// normalize an author name for duplicate detection: `J. K. Rowling` and `j.k.rowling` are the same
+pub fn normalize_author_name(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// group the authors whose normalized names are equal, only groups with more than one author
+pub fn duplicate_authors(authors: Vec<author::Model>) -> Vec<Vec<author::Model>> {
+    let mut groups: BTreeMap<String, Vec<author::Model>> = BTreeMap::new();
+    for author in authors {
+        groups
+            .entry(normalize_author_name(&author.name))
+            .or_default()
+            .push(author);
+    }
+    groups
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .collect()
+}
+
+/// repoint `books` to the author `author_id` named `author_name`, and plan the folder moves
+async fn move_books(
+    db: &impl ConnectionTrait,
+    book_dir: &Path,
+    books: Vec<music::Model>,
+    author_id: i32,
+    author_name: &str,
+) -> eyre::Result<Vec<(PathBuf, PathBuf)>> {
+    let mut moves = vec![];
+    for book in books {
+        let book_folder = book
+            .file_folder
+            .rsplit_once('/')
+            .map(|(_, book)| book.to_string())
+            .unwrap_or_else(|| book.file_folder.clone());
+        let new_folder = format!("{}/{}", author_name, book_folder);
+        let (from, to) = (book_dir.join(&book.file_folder), book_dir.join(&new_folder));
+        if from != to {
+            if to.exists() {
+                bail!("target dir {:?} already exists", to);
+            }
+            moves.push((from, to));
+        }
+        let mut book = book.into_active_model();
+        book.author_id = ActiveValue::Set(author_id);
+        book.file_folder = ActiveValue::Set(new_folder);
+        book.update(db).await?;
+    }
+    Ok(moves)
+}
+
+/// run the planned folder moves, then commit; a failure on either side undoes the other
+async fn commit_with_moves(
+    txn: sea_orm::DatabaseTransaction,
+    target_author_dir: &Path,
+    moves: Vec<(PathBuf, PathBuf)>,
+) -> eyre::Result<()> {
+    if !moves.is_empty() {
+        std::fs::create_dir_all(target_author_dir)?;
+    }
+    rename_all(&moves)?;
+    if let Err(e) = txn.commit().await {
+        let _ = rename_all(&reversed(&moves));
+        return Err(e.into());
+    }
+    for (from, _) in &moves {
+        remove_empty_parent(from);
+    }
+    Ok(())
+}
+
+/// rename an author and move the folders of all its books to `{new_name}/{book}`
+pub async fn rename_author(
+    db: &DatabaseConnection,
+    book_dir: &Path,
+    author_id: i32,
+    new_name: String,
+) -> eyre::Result<author::Model> {
+    check_folder_name(&new_name)?;
+    let txn = db.begin().await?;
+    let author = find_author(&txn, author_id).await?;
+    let books = Music::find()
+        .filter(music::Column::AuthorId.eq(author.id))
+        .all(&txn)
+        .await?;
+    let mut active = author.into_active_model();
+    let romanized = romanize::romanize(&new_name);
+    active.name = ActiveValue::Set(new_name.clone());
+    active.name_pinyin = ActiveValue::Set(Some(romanized.pinyin));
+    active.name_initials = ActiveValue::Set(Some(romanized.initials));
+    let author = active.update(&txn).await?;
+    let moves = move_books(&txn, book_dir, books, author.id, &new_name).await?;
+    commit_with_moves(txn, &book_dir.join(&new_name), moves).await?;
+    info!("author {} renamed to {}", author_id, new_name);
+    Ok(author)
+}
+
+/// move all books of author `from` to author `into`, then delete `from`
+///
+/// the description and avatar of `from` are kept when `into` has none.
+pub async fn merge_authors(
+    db: &DatabaseConnection,
+    book_dir: &Path,
+    from: i32,
+    into: i32,
+) -> eyre::Result<author::Model> {
+    if from == into {
+        bail!("can't merge an author into itself");
+    }
+    let txn = db.begin().await?;
+    let from_author = find_author(&txn, from).await?;
+    let into_author = find_author(&txn, into).await?;
+    let books = Music::find()
+        .filter(music::Column::AuthorId.eq(from))
+        .all(&txn)
+        .await?;
+    let moves = move_books(&txn, book_dir, books, into, &into_author.name).await?;
+    let mut merged = into_author.clone().into_active_model();
+    if into_author.description.is_empty() {
+        merged.description = ActiveValue::Set(from_author.description.clone());
+    }
+    if into_author.avatar.is_empty() {
+        merged.avatar = ActiveValue::Set(from_author.avatar.clone());
+    }
+    let merged = merged.update(&txn).await?;
+    Author::delete_by_id(from).exec(&txn).await?;
+    commit_with_moves(txn, &book_dir.join(&into_author.name), moves).await?;
+    info!("author {} merged into {}", from, into);
+    Ok(merged)
+}
+
+pub async fn set_author_description(
+    db: &DatabaseConnection,
+    author_id: i32,
+    description: String,
+) -> eyre::Result<author::Model> {
+    let mut author = find_author(db, author_id).await?.into_active_model();
+    author.description = ActiveValue::Set(description);
+    Ok(author.update(db).await?)
+}
+
+/// decode an uploaded image and resize it to fit in [`AVATAR_SIZE`], encoded as jpeg
+pub fn resize_avatar(image: &[u8]) -> eyre::Result<Vec<u8>> {
+    let image = image::load_from_memory(image)?;
+    let image = image.thumbnail(AVATAR_SIZE, AVATAR_SIZE).into_rgb8();
+    let mut out = std::io::Cursor::new(Vec::new());
+    image.write_to(&mut out, image::ImageOutputFormat::Jpeg(85))?;
+    Ok(out.into_inner())
+}
+
+/// save the avatar to `book_dir/.avatars/{id}.jpg` and store its path relative to `book_dir`
+pub async fn set_author_avatar(
+    db: &DatabaseConnection,
+    book_dir: &Path,
+    author_id: i32,
+    image: Vec<u8>,
+) -> eyre::Result<author::Model> {
+    let author = find_author(db, author_id).await?;
+    let jpeg = tokio::task::spawn_blocking(move || resize_avatar(&image)).await??;
+    let relative = format!("{}/{}.jpg", AVATAR_DIR, author.id);
+    std::fs::create_dir_all(book_dir.join(AVATAR_DIR))?;
+    tokio::fs::write(book_dir.join(&relative), jpeg).await?;
+    let mut author = author.into_active_model();
+    author.avatar = ActiveValue::Set(relative);
+    Ok(author.update(db).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use sea_orm::{DatabaseBackend, MockDatabase, MockExecResult};
+
+    use super::{duplicate_authors, normalize_author_name, resize_avatar};
+    use crate::entities::music;
+    use crate::fixtures::{self, author};
+
+    fn book(id: i32, author_id: i32, folder: &str) -> music::Model {
+        music::Model {
+            author_id,
+            file_folder: folder.to_string(),
+            ..fixtures::book(id, &format!("book{}", id))
+        }
+    }
+
+    fn exec_ok() -> MockExecResult {
+        MockExecResult {
+            last_insert_id: 1,
+            rows_affected: 1,
+        }
+    }
+
+    fn create_book(book_dir: &Path, folder: &str) {
+        std::fs::create_dir_all(book_dir.join(folder)).unwrap();
+        std::fs::write(book_dir.join(folder).join("0001.mp3"), folder).unwrap();
+    }
+
+    #[test]
+    fn test_duplicate_authors() {
+        assert_eq!(normalize_author_name("J. K. Rowling"), "jkrowling");
+        assert_eq!(normalize_author_name("刘 慈欣"), "刘慈欣");
+        let groups = duplicate_authors(vec![
+            author(1, "J.K. Rowling"),
+            author(2, "刘慈欣"),
+            author(3, "J. K. Rowling"),
+        ]);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(
+            groups[0].iter().map(|a| a.id).collect::<Vec<_>>(),
+            vec![1, 3]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rename_author() {
+        let book_dir = tempfile::tempdir().unwrap();
+        create_book(book_dir.path(), "old/book1");
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([[author(1, "old")]])
+            .append_query_results([[book(1, 1, "old/book1")]])
+            .append_query_results([[author(1, "new")]])
+            .append_query_results([[book(1, 1, "new/book1")]])
+            .append_exec_results([exec_ok(), exec_ok()])
+            .into_connection();
+        let renamed = super::rename_author(&db, book_dir.path(), 1, "new".to_string())
+            .await
+            .unwrap();
+        assert_eq!(renamed.name, "new");
+        assert!(book_dir.path().join("new/book1/0001.mp3").exists());
+        assert!(!book_dir.path().join("old").exists());
+    }
+
+    #[tokio::test]
+    async fn test_merge_authors_conflict() {
+        let book_dir = tempfile::tempdir().unwrap();
+        create_book(book_dir.path(), "J.K. Rowling/book1");
+        create_book(book_dir.path(), "J. K. Rowling/book1");
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([[author(1, "J.K. Rowling")]])
+            .append_query_results([[author(2, "J. K. Rowling")]])
+            .append_query_results([[book(1, 1, "J.K. Rowling/book1")]])
+            .into_connection();
+        assert!(super::merge_authors(&db, book_dir.path(), 1, 2)
+            .await
+            .is_err());
+        // nothing moved
+        assert!(book_dir.path().join("J.K. Rowling/book1/0001.mp3").exists());
+    }
+
+    #[tokio::test]
+    async fn test_merge_authors() {
+        let book_dir = tempfile::tempdir().unwrap();
+        create_book(book_dir.path(), "J.K. Rowling/book1");
+        create_book(book_dir.path(), "J. K. Rowling/book2");
+        let mut from = author(1, "J.K. Rowling");
+        from.description = "British author".to_string();
+        let mut merged = author(2, "J. K. Rowling");
+        merged.description = from.description.clone();
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([[from]])
+            .append_query_results([[author(2, "J. K. Rowling")]])
+            .append_query_results([[book(1, 1, "J.K. Rowling/book1")]])
+            .append_query_results([[book(1, 2, "J. K. Rowling/book1")]])
+            .append_query_results([[merged.clone()]])
+            .append_exec_results([exec_ok(), exec_ok(), exec_ok()])
+            .into_connection();
+        let result = super::merge_authors(&db, book_dir.path(), 1, 2)
+            .await
+            .unwrap();
+        assert_eq!(result, merged);
+        assert!(book_dir
+            .path()
+            .join("J. K. Rowling/book1/0001.mp3")
+            .exists());
+        assert!(book_dir
+            .path()
+            .join("J. K. Rowling/book2/0001.mp3")
+            .exists());
+        assert!(!book_dir.path().join("J.K. Rowling").exists());
+    }
+
+    #[test]
+    fn test_resize_avatar() {
+        let image = image::RgbImage::new(1024, 512);
+        let mut png = std::io::Cursor::new(Vec::new());
+        image
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        let jpeg = resize_avatar(png.get_ref()).unwrap();
+        let resized = image::load_from_memory(&jpeg).unwrap();
+        assert_eq!((resized.width(), resized.height()), (256, 128));
+        assert!(resize_avatar(b"not an image").is_err());
+    }
+}
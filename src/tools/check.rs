@@ -0,0 +1,404 @@
+//! library integrity checker: compares the catalog with the files under `book_dir`
+//!
+//! the repair mode only does what can be undone: books whose folder is gone go to the trash, and
+//! the chapter count is updated when the chapter files are still numbered `1..=n`. Orphaned
+//! folders, broken chapters and stray files in book folders are only reported.
+
+use std::{
+    collections::HashSet,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use sea_orm::{
+    sea_query::Expr, ActiveModelTrait, ActiveValue, ColumnTrait, DatabaseConnection, EntityTrait,
+    IntoActiveModel, QueryFilter, TransactionTrait,
+};
+use tracing::info;
+
+use super::trash::{self, TrashKind};
+use crate::entities::{prelude::*, *};
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Issue {
+    MissingFolder {
+        book_id: i32,
+        folder: String,
+    },
+    MissingChapter {
+        book_id: i32,
+        chapter_no: i32,
+    },
+    ExtraChapter {
+        book_id: i32,
+        chapter_no: i32,
+        path: PathBuf,
+    },
+    EmptyFile {
+        book_id: i32,
+        path: PathBuf,
+    },
+    /// the first packet doesn't decode, or the header doesn't match the format symphonia can't
+    /// decode
+    UndecodableFile {
+        book_id: i32,
+        path: PathBuf,
+    },
+    /// a file or folder in the book folder that is neither a chapter nor the cover
+    UnexpectedFile {
+        book_id: i32,
+        path: PathBuf,
+    },
+    /// a folder under `book_dir` no book points to, relative to `book_dir`
+    OrphanedFolder {
+        path: PathBuf,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Repair {
+    MovedToTrash { book_id: i32 },
+    ChaptersUpdated { book_id: i32, from: i32, to: i32 },
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct CheckReport {
+    pub books_checked: usize,
+    pub issues: Vec<Issue>,
+    pub repaired: Vec<Repair>,
+}
+
+/// the extensions symphonia reads, the others are only sniffed
+const DECODED_EXTENSIONS: [&str; 7] = ["mp3", "aac", "m4a", "m4b", "flac", "ogg", "wav"];
+
+/// decode the first packet of an audio file, the formats symphonia can't decode are sniffed
+fn looks_decodable(path: &Path) -> std::io::Result<bool> {
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    if DECODED_EXTENSIONS.contains(&ext.as_str()) {
+        if let Some(decodes) = super::audio::first_packet_decodes(path) {
+            return Ok(decodes);
+        }
+    }
+    let mut header = [0u8; 12];
+    let mut file = std::fs::File::open(path)?;
+    let mut len = 0;
+    while len < header.len() {
+        match file.read(&mut header[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    let header = &header[..len];
+    // any format may start with an id3 tag
+    if header.starts_with(b"ID3") {
+        return Ok(true);
+    }
+    Ok(match ext.as_str() {
+        "ogg" | "opus" => header.starts_with(b"OggS"),
+        "wma" => header.starts_with(&[0x30, 0x26, 0xB2, 0x75]),
+        "ape" => header.starts_with(b"MAC "),
+        _ => true,
+    })
+}
+
+/// the files of a book folder that are neither a chapter nor the cover
+fn unexpected_files(folder: &Path, chapters: &[(i32, PathBuf)]) -> std::io::Result<Vec<PathBuf>> {
+    let chapters = chapters
+        .iter()
+        .map(|(_, path)| path)
+        .collect::<HashSet<_>>();
+    let mut unexpected = vec![];
+    for entry in std::fs::read_dir(folder)? {
+        let path = entry?.path();
+        let cover = super::is_cover_file(&path) && path.is_file();
+        if !cover && !chapters.contains(&path) {
+            unexpected.push(path);
+        }
+    }
+    unexpected.sort();
+    Ok(unexpected)
+}
+
+/// check the folder and chapter files of one book
+///
+/// returns the issues and the number of the last chapter if the files are numbered `1..=n`.
+fn check_book(book_dir: &Path, book: &music::Model) -> (Vec<Issue>, Option<i32>) {
+    let folder = book_dir.join(&book.file_folder);
+    let chapters = match super::chapter_files(&folder) {
+        Ok(chapters) => chapters,
+        Err(_) => {
+            let issue = Issue::MissingFolder {
+                book_id: book.id,
+                folder: book.file_folder.clone(),
+            };
+            return (vec![issue], None);
+        }
+    };
+    let mut issues = vec![];
+    let found = chapters.iter().map(|(no, _)| *no).collect::<HashSet<_>>();
+    for chapter_no in 1..=book.chapters {
+        if !found.contains(&chapter_no) {
+            issues.push(Issue::MissingChapter {
+                book_id: book.id,
+                chapter_no,
+            });
+        }
+    }
+    for (chapter_no, path) in &chapters {
+        let path = path.clone();
+        if *chapter_no < 1 || *chapter_no > book.chapters {
+            issues.push(Issue::ExtraChapter {
+                book_id: book.id,
+                chapter_no: *chapter_no,
+                path,
+            });
+        } else if path.metadata().map(|m| m.len() == 0).unwrap_or(false) {
+            issues.push(Issue::EmptyFile {
+                book_id: book.id,
+                path,
+            });
+        } else if !super::is_audio_file(&path) || !looks_decodable(&path).unwrap_or(false) {
+            issues.push(Issue::UndecodableFile {
+                book_id: book.id,
+                path,
+            });
+        }
+    }
+    // the folder was just listed, a failure here only loses this part of the report
+    for path in unexpected_files(&folder, &chapters).unwrap_or_default() {
+        issues.push(Issue::UnexpectedFile {
+            book_id: book.id,
+            path,
+        });
+    }
+    let contiguous = chapters
+        .iter()
+        .map(|(no, _)| *no)
+        .eq(1..=chapters.len() as i32);
+    (issues, contiguous.then_some(chapters.len() as i32))
+}
+
+/// the `{author}/{book}` folders under `book_dir` that no book points to
+///
+/// the hidden folders (`.staging`, `.uploads`, `.avatars`) are skipped, and an author folder
+/// without any book folder left is reported as well.
+fn find_orphaned_folders(book_dir: &Path, books: &[music::Model]) -> std::io::Result<Vec<PathBuf>> {
+    let referenced = books
+        .iter()
+        .map(|book| PathBuf::from(&book.file_folder))
+        .collect::<HashSet<_>>();
+    let visible_dirs = |dir: &Path| -> std::io::Result<Vec<PathBuf>> {
+        let mut dirs = vec![];
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let hidden = entry.file_name().to_string_lossy().starts_with('.');
+            if entry.file_type()?.is_dir() && !hidden {
+                dirs.push(PathBuf::from(entry.file_name()));
+            }
+        }
+        dirs.sort();
+        Ok(dirs)
+    };
+    let mut orphans = vec![];
+    for author_dir in visible_dirs(book_dir)? {
+        let book_dirs = visible_dirs(&book_dir.join(&author_dir))?;
+        if book_dirs.is_empty() {
+            orphans.push(author_dir.clone());
+        }
+        for book_folder in book_dirs {
+            let path = author_dir.join(book_folder);
+            if !referenced.contains(&path) {
+                orphans.push(path);
+            }
+        }
+    }
+    Ok(orphans)
+}
+
+/// walk the catalog and `book_dir`, fixing what can be fixed safely when `repair` is set
+pub async fn check_library(
+    db: &DatabaseConnection,
+    book_dir: &Path,
+    repair: bool,
+) -> eyre::Result<CheckReport> {
+    // trashed books still own their folder
+    let books = Music::find().all(db).await?;
+    let mut report = CheckReport::default();
+    for book in books.iter().filter(|book| book.deleted_at.is_none()) {
+        report.books_checked += 1;
+        let (issues, last_chapter) = check_book(book_dir, book);
+        let missing_folder = matches!(issues.first(), Some(Issue::MissingFolder { .. }));
+        report.issues.extend(issues);
+        if !repair {
+            continue;
+        }
+        if missing_folder {
+            trash::trash(db, TrashKind::Book, book.id).await?;
+            report
+                .repaired
+                .push(Repair::MovedToTrash { book_id: book.id });
+        } else if let Some(last_chapter) = last_chapter.filter(|n| *n > 0 && *n != book.chapters) {
+            update_chapters(db, book.clone(), last_chapter).await?;
+            report.repaired.push(Repair::ChaptersUpdated {
+                book_id: book.id,
+                from: book.chapters,
+                to: last_chapter,
+            });
+        }
+    }
+    for path in find_orphaned_folders(book_dir, &books)? {
+        report.issues.push(Issue::OrphanedFolder { path });
+    }
+    info!(
+        "checked {} books, {} issues, {} repaired",
+        report.books_checked,
+        report.issues.len(),
+        report.repaired.len()
+    );
+    Ok(report)
+}
+
+/// set the chapter count, progress past the last chapter is moved to it
+async fn update_chapters(
+    db: &DatabaseConnection,
+    book: music::Model,
+    chapters: i32,
+) -> eyre::Result<()> {
+    let txn = db.begin().await?;
+    Progress::update_many()
+        .col_expr(progress::Column::ChapterNo, Expr::value(chapters))
+        .filter(progress::Column::MusicId.eq(book.id))
+        .filter(progress::Column::ChapterNo.gt(chapters))
+        .exec(&txn)
+        .await?;
+    let mut book = book.into_active_model();
+    book.chapters = ActiveValue::Set(chapters);
+    book.update(&txn).await?;
+    txn.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{check_book, find_orphaned_folders, Issue};
+    use crate::entities::music;
+    use crate::fixtures::{self, write_wav};
+
+    fn book(id: i32, chapters: i32, folder: &str) -> music::Model {
+        music::Model {
+            chapters,
+            file_folder: folder.to_string(),
+            ..fixtures::book(id, &format!("book{}", id))
+        }
+    }
+
+    #[test]
+    fn test_check_book() {
+        let dir = tempfile::tempdir().unwrap();
+        let folder = dir.path().join("author/book");
+        std::fs::create_dir_all(&folder).unwrap();
+        write_wav(&folder.join("0001.wav"), 1, 1, 1.);
+        std::fs::write(folder.join("0002.mp3"), b"").unwrap();
+        std::fs::write(folder.join("0003.m4a"), b"not audio").unwrap();
+        // a valid header cut before the first sample
+        let wav = std::fs::read(folder.join("0001.wav")).unwrap();
+        std::fs::write(folder.join("0004.wav"), &wav[..44]).unwrap();
+        // an id3 tag in front of garbage is not an mp3
+        std::fs::write(
+            folder.join("0005.mp3"),
+            b"ID3\x04\x00\x00\x00\x00\x00\x00garbage",
+        )
+        .unwrap();
+        write_wav(&folder.join("0007.wav"), 1, 2, 1.);
+        std::fs::write(folder.join("cover.jpg"), b"jpg").unwrap();
+        std::fs::write(folder.join("notes.txt"), b"notes").unwrap();
+        std::fs::create_dir(folder.join("extras")).unwrap();
+
+        let (issues, last_chapter) = check_book(dir.path(), &book(1, 6, "author/book"));
+        assert_eq!(last_chapter, None);
+        assert_eq!(
+            issues,
+            vec![
+                Issue::MissingChapter {
+                    book_id: 1,
+                    chapter_no: 6
+                },
+                Issue::EmptyFile {
+                    book_id: 1,
+                    path: folder.join("0002.mp3")
+                },
+                Issue::UndecodableFile {
+                    book_id: 1,
+                    path: folder.join("0003.m4a")
+                },
+                Issue::UndecodableFile {
+                    book_id: 1,
+                    path: folder.join("0004.wav")
+                },
+                Issue::UndecodableFile {
+                    book_id: 1,
+                    path: folder.join("0005.mp3")
+                },
+                Issue::ExtraChapter {
+                    book_id: 1,
+                    chapter_no: 7,
+                    path: folder.join("0007.wav")
+                },
+                Issue::UnexpectedFile {
+                    book_id: 1,
+                    path: folder.join("extras")
+                },
+                Issue::UnexpectedFile {
+                    book_id: 1,
+                    path: folder.join("notes.txt")
+                },
+            ]
+        );
+
+        let (issues, _) = check_book(dir.path(), &book(2, 1, "author/gone"));
+        assert!(matches!(issues[..], [Issue::MissingFolder { .. }]));
+    }
+
+    #[test]
+    fn test_check_book_contiguous() {
+        let dir = tempfile::tempdir().unwrap();
+        let folder = dir.path().join("author/book");
+        std::fs::create_dir_all(&folder).unwrap();
+        for no in 1..=3 {
+            write_wav(&folder.join(format!("{:04}.wav", no)), 1, no, 1.);
+        }
+        let (issues, last_chapter) = check_book(dir.path(), &book(1, 2, "author/book"));
+        assert_eq!(issues.len(), 1);
+        assert_eq!(last_chapter, Some(3));
+    }
+
+    #[test]
+    fn test_find_orphaned_folders() {
+        let dir = tempfile::tempdir().unwrap();
+        for folder in [
+            "author/book",
+            "author/orphan",
+            "empty_author",
+            ".staging/abc",
+            ".avatars",
+        ] {
+            std::fs::create_dir_all(dir.path().join(folder)).unwrap();
+        }
+        let orphans = find_orphaned_folders(dir.path(), &[book(1, 1, "author/book")]).unwrap();
+        assert_eq!(
+            orphans,
+            vec![
+                PathBuf::from("author/orphan"),
+                PathBuf::from("empty_author")
+            ]
+        );
+    }
+}
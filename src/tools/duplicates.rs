@@ -0,0 +1,280 @@
+//! find books imported more than once, by normalized title, author, total duration and
+//! fingerprint
+//!
+//! the audio info is computed on import; books imported before, or whose chapters were edited
+//! since, get it computed by a background task, the report only reads it.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+use sea_orm::{
+    ActiveModelTrait, ActiveValue, ColumnTrait, DatabaseConnection, EntityTrait, IntoActiveModel,
+    QueryFilter, QueryOrder, QuerySelect,
+};
+use tracing::info;
+use unicode_normalization::UnicodeNormalization;
+
+use super::audio::{self, AudioInfo};
+use crate::entities::{prelude::*, *};
+
+/// books whose durations differ by less than this share look like the same recording
+const DURATION_TOLERANCE: f64 = 0.005;
+/// fingerprints at least this similar come from the same recording
+const FINGERPRINT_THRESHOLD: f64 = 0.9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateReason {
+    Title,
+    Author,
+    Duration,
+    Fingerprint,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct DuplicateGroup {
+    pub books: Vec<music::Model>,
+    pub reasons: Vec<DuplicateReason>,
+}
+
+/// normalize a title for duplicate detection: `三体（有声书）`, `三体 [Unabridged]` and `三 体` are
+/// the same, while `三体2` stays different
+pub fn normalize_title(name: &str) -> String {
+    let mut depth = 0usize;
+    let mut normalized = String::new();
+    for c in name.nfkc() {
+        match c {
+            '(' | '[' | '{' | '【' | '《' | '〔' => depth += 1,
+            ')' | ']' | '}' | '】' | '》' | '〕' => depth = depth.saturating_sub(1),
+            c if depth == 0 && c.is_alphanumeric() => normalized.extend(c.to_lowercase()),
+            _ => {}
+        }
+    }
+    // a title made only of brackets keeps its content
+    if normalized.is_empty() {
+        return super::authors::normalize_author_name(name);
+    }
+    normalized
+}
+
+/// why `a` and `b` look like the same book, empty when they don't
+///
+/// many unrelated books have about the same length, so a duration only confirms a title: books
+/// are the same when their fingerprints match, or their titles along with the author or the
+/// duration.
+fn duplicate_reasons(a: &music::Model, b: &music::Model) -> Vec<DuplicateReason> {
+    let mut reasons = vec![];
+    if normalize_title(&a.name) == normalize_title(&b.name) {
+        reasons.push(DuplicateReason::Title);
+    }
+    if a.author_id == b.author_id {
+        reasons.push(DuplicateReason::Author);
+    }
+    if let (Some(x), Some(y)) = (a.duration, b.duration) {
+        if x > 0. && y > 0. && (x - y).abs() <= x.max(y) * DURATION_TOLERANCE {
+            reasons.push(DuplicateReason::Duration);
+        }
+    }
+    if let (Some(x), Some(y)) = (&a.fingerprint, &b.fingerprint) {
+        if audio::fingerprint_similarity(x, y).unwrap_or(0.) >= FINGERPRINT_THRESHOLD {
+            reasons.push(DuplicateReason::Fingerprint);
+        }
+    }
+    let same = reasons.contains(&DuplicateReason::Fingerprint)
+        || (reasons.contains(&DuplicateReason::Title) && reasons.len() > 1);
+    if same {
+        reasons
+    } else {
+        vec![]
+    }
+}
+
+/// group the books that look like the same book, only groups with more than one book
+///
+/// books are linked by [`duplicate_reasons`], and a group collects the reasons of its links.
+pub fn find_duplicates(books: Vec<music::Model>) -> Vec<DuplicateGroup> {
+    // union find over the book indices
+    let mut parent = (0..books.len()).collect::<Vec<_>>();
+    fn root(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+    let mut links = vec![];
+    for i in 0..books.len() {
+        for j in i + 1..books.len() {
+            let reasons = duplicate_reasons(&books[i], &books[j]);
+            if !reasons.is_empty() {
+                let (a, b) = (root(&mut parent, i), root(&mut parent, j));
+                parent[b] = a;
+                links.push((i, reasons));
+            }
+        }
+    }
+    let mut groups: BTreeMap<usize, DuplicateGroup> = BTreeMap::new();
+    for (i, reasons) in links {
+        let group = groups
+            .entry(root(&mut parent, i))
+            .or_insert(DuplicateGroup {
+                books: vec![],
+                reasons: vec![],
+            });
+        group.reasons.extend(reasons);
+    }
+    for (i, book) in books.into_iter().enumerate() {
+        if let Some(group) = groups.get_mut(&root(&mut parent, i)) {
+            group.books.push(book);
+        }
+    }
+    groups
+        .into_values()
+        .map(|mut group| {
+            group.reasons.sort();
+            group.reasons.dedup();
+            group
+        })
+        .collect()
+}
+
+/// compute the missing durations and fingerprint of the live books and their chapters, returns
+/// the number of books updated
+pub async fn refresh_audio_info(db: &DatabaseConnection, book_dir: &Path) -> eyre::Result<usize> {
+    // the number of chapters with a known duration, by book
+    let timed: HashMap<i32, i64> = Chapter::find()
+        .select_only()
+        .column(chapter::Column::MusicId)
+        .column_as(chapter::Column::Duration.count(), "timed")
+        .group_by(chapter::Column::MusicId)
+        .into_tuple::<(i32, i64)>()
+        .all(db)
+        .await?
+        .into_iter()
+        .collect();
+    let books = Music::find()
+        .filter(music::Column::DeletedAt.is_null())
+        .all(db)
+        .await?
+        .into_iter()
+        .filter(|book| {
+            book.duration.is_none()
+                || book.fingerprint.is_none()
+                || timed.get(&book.id).copied().unwrap_or(0) < i64::from(book.chapters)
+        });
+    let mut updated = 0;
+    for book in books {
+        let folder = book_dir.join(&book.file_folder);
+        let AudioInfo {
+            duration,
+            fingerprint,
+            chapter_durations,
+        } = tokio::task::spawn_blocking(move || audio::probe_book(&folder)).await?;
+        if duration.is_none() && fingerprint.is_none() && chapter_durations.is_empty() {
+            continue;
+        }
+        super::store_chapter_durations(db, book.id, &chapter_durations).await?;
+        let mut book = book.into_active_model();
+        book.duration = ActiveValue::Set(duration);
+        book.fingerprint = ActiveValue::Set(fingerprint);
+        book.update(db).await?;
+        updated += 1;
+    }
+    if updated > 0 {
+        info!("audio info computed for {} books", updated);
+    }
+    Ok(updated)
+}
+
+/// the duplicate groups among the live books, with the audio info known so far
+pub async fn duplicate_report(db: &DatabaseConnection) -> eyre::Result<Vec<DuplicateGroup>> {
+    let books = Music::find()
+        .filter(music::Column::DeletedAt.is_null())
+        .order_by_asc(music::Column::Id)
+        .all(db)
+        .await?;
+    Ok(find_duplicates(books))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_duplicates, normalize_title, DuplicateReason};
+    use crate::entities::music;
+    use crate::fixtures;
+
+    fn book(id: i32, name: &str, duration: Option<f64>) -> music::Model {
+        music::Model {
+            author_id: id,
+            file_folder: format!("author{}/{}", id, name),
+            duration,
+            ..fixtures::book(id, name)
+        }
+    }
+
+    #[test]
+    fn test_normalize_title() {
+        assert_eq!(normalize_title("三体（有声书）"), "三体");
+        assert_eq!(normalize_title("三 体 [Unabridged]"), "三体");
+        assert_eq!(normalize_title("Ｔｈｅ Hobbit!"), "thehobbit");
+        assert_ne!(normalize_title("三体2"), normalize_title("三体"));
+        assert_eq!(normalize_title("(demo)"), "demo");
+    }
+
+    #[test]
+    fn test_find_duplicates() {
+        let groups = find_duplicates(vec![
+            book(1, "三体", Some(36000.)),
+            // the same title by the same author, no audio info yet
+            music::Model {
+                author_id: 1,
+                ..book(2, "三体（有声书）", None)
+            },
+            // the same title and length under another author
+            book(3, "三 体", Some(36010.)),
+            book(4, "other", Some(100.)),
+            // the same title by another author, of another length
+            book(5, "三体", Some(20000.)),
+        ]);
+        assert_eq!(groups.len(), 1);
+        let ids = groups[0].books.iter().map(|b| b.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![1, 2, 3]);
+        assert_eq!(
+            groups[0].reasons,
+            vec![
+                DuplicateReason::Title,
+                DuplicateReason::Author,
+                DuplicateReason::Duration
+            ]
+        );
+    }
+
+    #[test]
+    fn test_same_length_is_not_a_duplicate() {
+        // three unrelated books within the duration tolerance of each other
+        let groups = find_duplicates(vec![
+            book(1, "Dune", Some(36000.)),
+            book(2, "Emma", Some(36100.)),
+            book(3, "Ulysses", Some(36200.)),
+        ]);
+        assert!(groups.is_empty());
+    }
+
+    #[test]
+    fn test_fingerprint_duplicate() {
+        let fingerprint = Some("a5".repeat(40));
+        let groups = find_duplicates(vec![
+            music::Model {
+                fingerprint: fingerprint.clone(),
+                ..book(1, "Dune", None)
+            },
+            music::Model {
+                fingerprint,
+                ..book(2, "Dune Part One", None)
+            },
+        ]);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].reasons, vec![DuplicateReason::Fingerprint]);
+    }
+}
@@ -0,0 +1,709 @@
+//! edit books after import: rename, change author, delete, reorder and replace chapters
+//!
+//! rows are changed inside a transaction and the files are moved before the commit, so a failed
+//! move rolls the rows back and a failed commit moves the files back.
+
+use std::path::{Path, PathBuf};
+
+use eyre::{bail, eyre};
+use sea_orm::{
+    sea_query::{CaseStatement, Expr},
+    ActiveModelTrait, ActiveValue, ColumnTrait, ConnectionTrait, DatabaseConnection,
+    DatabaseTransaction, DbErr, EntityTrait, IntoActiveModel, QueryFilter, TransactionTrait,
+};
+use tracing::{error, info};
+
+use super::{audio, chapter_files, check_folder_name, find_or_create_author, romanize, StagingDir};
+use crate::entities::{prelude::*, *};
+
+pub(crate) async fn find_book(db: &DatabaseConnection, book_id: i32) -> eyre::Result<music::Model> {
+    Music::find_by_id(book_id)
+        .one(db)
+        .await?
+        .ok_or_else(|| eyre!("book {} not found", book_id))
+}
+
+/// rename a list of files, moving the already renamed ones back if one fails
+pub(super) fn rename_all(pairs: &[(PathBuf, PathBuf)]) -> std::io::Result<()> {
+    for (done, (from, to)) in pairs.iter().enumerate() {
+        if let Err(e) = std::fs::rename(from, to) {
+            for (from, to) in pairs[..done].iter().rev() {
+                let _ = std::fs::rename(to, from);
+            }
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+pub(super) fn reversed(pairs: &[(PathBuf, PathBuf)]) -> Vec<(PathBuf, PathBuf)> {
+    pairs
+        .iter()
+        .rev()
+        .map(|(from, to)| (to.clone(), from.clone()))
+        .collect()
+}
+
+/// remove the parent (author) folder once its last book moved away
+pub(super) fn remove_empty_parent(dir: &Path) {
+    if let Some(parent) = dir.parent() {
+        // fails if the folder is not empty
+        let _ = std::fs::remove_dir(parent);
+    }
+}
+
+/// save `active` and move the book folder to `new_folder` in the same step, committing `txn`
+///
+/// an error drops `txn`, rolling back whatever the caller changed in it too.
+async fn update_book_folder(
+    txn: DatabaseTransaction,
+    book_dir: &Path,
+    book: &music::Model,
+    mut active: music::ActiveModel,
+    new_folder: String,
+) -> eyre::Result<music::Model> {
+    let old_dir = book_dir.join(&book.file_folder);
+    let new_dir = book_dir.join(&new_folder);
+    let moved = old_dir != new_dir;
+    if moved && new_dir.exists() {
+        bail!("target dir {:?} already exists", new_dir);
+    }
+    active.file_folder = ActiveValue::Set(new_folder);
+    let model = active.update(&txn).await?;
+    if moved {
+        if let Some(parent) = new_dir.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        if let Err(e) = std::fs::rename(&old_dir, &new_dir) {
+            remove_empty_parent(&new_dir);
+            return Err(e.into());
+        }
+    }
+    if let Err(e) = txn.commit().await {
+        if moved {
+            let _ = std::fs::rename(&new_dir, &old_dir);
+            remove_empty_parent(&new_dir);
+        }
+        return Err(e.into());
+    }
+    if moved {
+        remove_empty_parent(&old_dir);
+    }
+    Ok(model)
+}
+
+/// the author folder of a book, the first component of `file_folder`
+fn author_folder(book: &music::Model) -> &str {
+    book.file_folder
+        .split_once('/')
+        .map(|(author, _)| author)
+        .unwrap_or("")
+}
+
+/// the book folder of a book, the last component of `file_folder`
+fn book_folder(book: &music::Model) -> &str {
+    book.file_folder
+        .rsplit_once('/')
+        .map(|(_, book)| book)
+        .unwrap_or(&book.file_folder)
+}
+
+/// rename a book and its folder `{author}/{book}`
+pub async fn rename_book(
+    db: &DatabaseConnection,
+    book_dir: &Path,
+    book_id: i32,
+    new_name: String,
+) -> eyre::Result<music::Model> {
+    check_folder_name(&new_name)?;
+    let book = find_book(db, book_id).await?;
+    let new_folder = format!("{}/{}", author_folder(&book), new_name);
+    let mut active = book.clone().into_active_model();
+    let romanized = romanize::romanize(&new_name);
+    active.name = ActiveValue::Set(new_name);
+    active.name_pinyin = ActiveValue::Set(Some(romanized.pinyin));
+    active.name_initials = ActiveValue::Set(Some(romanized.initials));
+    let txn = db.begin().await?;
+    let model = update_book_folder(txn, book_dir, &book, active, new_folder).await?;
+    info!("book {} renamed to {}", book_id, model.name);
+    Ok(model)
+}
+
+/// move a book to another author, creating the author if needed
+pub async fn change_book_author(
+    db: &DatabaseConnection,
+    book_dir: &Path,
+    book_id: i32,
+    author_name: String,
+) -> eyre::Result<music::Model> {
+    check_folder_name(&author_name)?;
+    let book = find_book(db, book_id).await?;
+    let txn = db.begin().await?;
+    // a failed move also rolls back the author created for it
+    let author_id = find_or_create_author(&txn, author_name.clone()).await?;
+    let new_folder = format!("{}/{}", author_name, book_folder(&book));
+    let mut active = book.clone().into_active_model();
+    active.author_id = ActiveValue::Set(author_id);
+    let model = update_book_folder(txn, book_dir, &book, active, new_folder).await?;
+    info!("book {} moved to author {}", book_id, author_name);
+    Ok(model)
+}
+
+/// the descriptive fields of a book
+#[derive(Debug, Clone, Default)]
+pub struct BookMetadata {
+    pub narrator: String,
+    pub series: String,
+    pub description: String,
+    pub language: String,
+}
+
+/// set the narrator, series, description and language of a book, blank values are cleared
+pub async fn set_book_metadata(
+    db: &DatabaseConnection,
+    book_id: i32,
+    metadata: BookMetadata,
+) -> eyre::Result<music::Model> {
+    let blank_to_none = |value: String| Some(value.trim().to_string()).filter(|v| !v.is_empty());
+    let mut book = find_book(db, book_id).await?.into_active_model();
+    book.narrator = ActiveValue::Set(blank_to_none(metadata.narrator));
+    book.series = ActiveValue::Set(blank_to_none(metadata.series));
+    book.description = ActiveValue::Set(blank_to_none(metadata.description));
+    book.language = ActiveValue::Set(blank_to_none(metadata.language));
+    Ok(book.update(db).await?)
+}
+
+/// replace the tags of a book, unknown tags are created
+pub async fn set_book_tags(
+    db: &DatabaseConnection,
+    book_id: i32,
+    tags: Vec<String>,
+) -> eyre::Result<Vec<tag::Model>> {
+    let book = find_book(db, book_id).await?;
+    let mut names = tags
+        .iter()
+        .map(|tag| tag.trim().to_string())
+        .filter(|tag| !tag.is_empty())
+        .collect::<Vec<_>>();
+    names.sort();
+    names.dedup();
+    let txn = db.begin().await?;
+    MusicTag::delete_many()
+        .filter(music_tag::Column::MusicId.eq(book.id))
+        .exec(&txn)
+        .await?;
+    let mut tags = vec![];
+    for name in names {
+        let tag = match Tag::find()
+            .filter(tag::Column::Name.eq(&name))
+            .one(&txn)
+            .await?
+        {
+            Some(tag) => tag,
+            None => {
+                let inserted = Tag::insert(tag::ActiveModel {
+                    name: ActiveValue::Set(name.clone()),
+                    ..Default::default()
+                })
+                .exec(&txn)
+                .await?;
+                tag::Model {
+                    id: inserted.last_insert_id,
+                    name,
+                }
+            }
+        };
+        MusicTag::insert(music_tag::ActiveModel {
+            music_id: ActiveValue::Set(book.id),
+            tag_id: ActiveValue::Set(tag.id),
+        })
+        .exec_without_returning(&txn)
+        .await?;
+        tags.push(tag);
+    }
+    txn.commit().await?;
+    info!("book {} tagged with {} tags", book_id, tags.len());
+    Ok(tags)
+}
+
+/// delete a book and the progress of all users on it, the files are kept unless `delete_files`
+pub async fn delete_book(
+    db: &DatabaseConnection,
+    book_dir: &Path,
+    book_id: i32,
+    delete_files: bool,
+) -> eyre::Result<()> {
+    let book = find_book(db, book_id).await?;
+    let txn = db.begin().await?;
+    // progress references the book with `Restrict`
+    Progress::delete_many()
+        .filter(progress::Column::MusicId.eq(book.id))
+        .exec(&txn)
+        .await?;
+    Music::delete_by_id(book.id).exec(&txn).await?;
+    let book_folder = book_dir.join(&book.file_folder);
+    // the files wait in the staging dir until the rows are gone, and are removed with it
+    let trash = if delete_files && book_folder.exists() {
+        let trash = StagingDir::new(book_dir)?;
+        std::fs::rename(&book_folder, trash.path().join("book"))?;
+        Some(trash)
+    } else {
+        None
+    };
+    if let Err(e) = txn.commit().await {
+        if let Some(trash) = &trash {
+            let _ = std::fs::rename(trash.path().join("book"), &book_folder);
+        }
+        return Err(e.into());
+    }
+    if trash.is_some() {
+        remove_empty_parent(&book_folder);
+    }
+    info!("book {} deleted, files deleted: {}", book_id, delete_files);
+    Ok(())
+}
+
+fn chapter_name(chapter_no: i32, path: &Path) -> String {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => format!("{:04}.{}", chapter_no, ext),
+        None => format!("{:04}", chapter_no),
+    }
+}
+
+/// forget the duration and fingerprint after the chapters changed, the audio info task computes
+/// them again, the chapter durations move along with the titles
+async fn clear_audio_info(db: &impl ConnectionTrait, book_id: i32) -> Result<(), DbErr> {
+    Music::update_many()
+        .col_expr(music::Column::Duration, Expr::value(Option::<f64>::None))
+        .col_expr(
+            music::Column::Fingerprint,
+            Expr::value(Option::<String>::None),
+        )
+        .filter(music::Column::Id.eq(book_id))
+        .exec(db)
+        .await?;
+    Ok(())
+}
+
+/// move the chapter titles along with their files
+async fn renumber_chapter_titles(
+    db: &impl ConnectionTrait,
+    book_id: i32,
+    order: &[i32],
+) -> Result<(), DbErr> {
+    let renumber = (1..)
+        .zip(order)
+        .fold(CaseStatement::new(), |case, (new_no, old_no)| {
+            case.case(chapter::Column::ChapterNo.eq(*old_no), new_no)
+        })
+        .finally(Expr::col(chapter::Column::ChapterNo));
+    Chapter::update_many()
+        .col_expr(chapter::Column::ChapterNo, renumber.into())
+        .filter(chapter::Column::MusicId.eq(book_id))
+        .exec(db)
+        .await?;
+    Ok(())
+}
+
+/// reorder the chapters, `order[i]` is the old chapter number of the new chapter `i + 1`
+///
+/// the saved progress of every user follows its chapter to the new number.
+pub async fn reorder_chapters(
+    db: &DatabaseConnection,
+    book_dir: &Path,
+    book_id: i32,
+    order: &[i32],
+) -> eyre::Result<()> {
+    let book = find_book(db, book_id).await?;
+    let mut sorted = order.to_vec();
+    sorted.sort();
+    if sorted != (1..=book.chapters).collect::<Vec<_>>() {
+        bail!("order should be a permutation of 1..={}", book.chapters);
+    }
+    let folder = book_dir.join(&book.file_folder);
+    let files = chapter_files(&folder)?;
+    let mut to_tmp = vec![];
+    let mut to_new = vec![];
+    for (new_no, old_no) in (1..).zip(order) {
+        let (_, old_path) = files
+            .iter()
+            .find(|(no, _)| no == old_no)
+            .ok_or_else(|| eyre!("chapter {} not found", old_no))?;
+        let tmp = folder.join(format!(".reorder-{}", chapter_name(new_no, old_path)));
+        to_tmp.push((old_path.clone(), tmp.clone()));
+        to_new.push((tmp, folder.join(chapter_name(new_no, old_path))));
+    }
+
+    let txn = db.begin().await?;
+    let progresses = Progress::find()
+        .filter(progress::Column::MusicId.eq(book.id))
+        .all(&txn)
+        .await?;
+    for p in progresses {
+        let Some(position) = order.iter().position(|no| *no == p.chapter_no) else {
+            continue;
+        };
+        let mut p = p.into_active_model();
+        p.chapter_no = ActiveValue::Set(position as i32 + 1);
+        p.update(&txn).await?;
+    }
+    renumber_chapter_titles(&txn, book.id, order).await?;
+    clear_audio_info(&txn, book.id).await?;
+    rename_all(&to_tmp)?;
+    if let Err(e) = rename_all(&to_new) {
+        let _ = rename_all(&reversed(&to_tmp));
+        return Err(e.into());
+    }
+    if let Err(e) = txn.commit().await {
+        if rename_all(&reversed(&to_new))
+            .and_then(|_| rename_all(&reversed(&to_tmp)))
+            .is_err()
+        {
+            error!("fail to restore the chapter order of book {}", book_id);
+        }
+        return Err(e.into());
+    }
+    info!("book {} chapters reordered", book_id);
+    Ok(())
+}
+
+/// replace the file of one chapter with `new_file`, which should be on the same filesystem
+///
+/// the chapter takes the title tag and the duration of the new file, `title` is the title when
+/// the file has no tag, like the name it was uploaded as.
+pub async fn replace_chapter(
+    db: &DatabaseConnection,
+    book_dir: &Path,
+    book_id: i32,
+    chapter_no: i32,
+    new_file: &Path,
+    title: Option<String>,
+) -> eyre::Result<()> {
+    let book = find_book(db, book_id).await?;
+    if chapter_no < 1 || chapter_no > book.chapters {
+        bail!("chapter {} out of range 1..={}", chapter_no, book.chapters);
+    }
+    let probed = new_file.to_path_buf();
+    let (tag, duration) = tokio::task::spawn_blocking(move || {
+        (
+            audio::title_tag(&probed),
+            audio::audio_duration(&probed).ok(),
+        )
+    })
+    .await?;
+    let txn = db.begin().await?;
+    // the book duration and fingerprint are computed again in the background
+    clear_audio_info(&txn, book.id).await?;
+    let mut update = Chapter::update_many()
+        .col_expr(chapter::Column::Duration, Expr::value(duration))
+        .filter(chapter::Column::MusicId.eq(book.id))
+        .filter(chapter::Column::ChapterNo.eq(chapter_no));
+    if let Some(title) = tag.or(title) {
+        update = update.col_expr(chapter::Column::Title, Expr::value(title));
+    }
+    update.exec(&txn).await?;
+    let folder = book_dir.join(&book.file_folder);
+    let old_files = chapter_files(&folder)?
+        .into_iter()
+        .filter(|(no, _)| *no == chapter_no)
+        .map(|(_, path)| path)
+        .collect::<Vec<_>>();
+    // keep the old files until the new one is in place
+    let backup = StagingDir::new(book_dir)?;
+    let to_backup = old_files
+        .iter()
+        .map(|path| (path.clone(), backup.path().join(path.file_name().unwrap())))
+        .collect::<Vec<_>>();
+    rename_all(&to_backup)?;
+    let target = folder.join(chapter_name(chapter_no, new_file));
+    if let Err(e) = std::fs::rename(new_file, &target) {
+        let _ = rename_all(&reversed(&to_backup));
+        return Err(e.into());
+    }
+    if let Err(e) = txn.commit().await {
+        let _ = std::fs::rename(&target, new_file);
+        let _ = rename_all(&reversed(&to_backup));
+        return Err(e.into());
+    }
+    info!("book {} chapter {} replaced", book_id, chapter_no);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use sea_orm::{DatabaseBackend, DbErr, MockDatabase, MockExecResult};
+
+    use crate::entities::{author, music, progress, tag};
+    use crate::fixtures;
+
+    fn book(chapters: i32) -> music::Model {
+        music::Model {
+            chapters,
+            ..fixtures::book(1, "book")
+        }
+    }
+
+    fn exec_ok() -> MockExecResult {
+        MockExecResult {
+            last_insert_id: 1,
+            rows_affected: 1,
+        }
+    }
+
+    fn create_book(book_dir: &Path, chapters: i32) {
+        let folder = book_dir.join("author/book");
+        std::fs::create_dir_all(&folder).unwrap();
+        for no in 1..=chapters {
+            std::fs::write(folder.join(format!("{:04}.mp3", no)), no.to_string()).unwrap();
+        }
+    }
+
+    fn read_chapter(book_dir: &Path, folder: &str, no: i32) -> String {
+        std::fs::read_to_string(book_dir.join(folder).join(format!("{:04}.mp3", no))).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_rename_book() {
+        let book_dir = tempfile::tempdir().unwrap();
+        create_book(book_dir.path(), 2);
+        let mut renamed = book(2);
+        renamed.name = "new".to_string();
+        renamed.file_folder = "author/new".to_string();
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([[book(2)], [renamed.clone()]])
+            .append_exec_results([exec_ok()])
+            .into_connection();
+        let model = super::rename_book(&db, book_dir.path(), 1, "new".to_string())
+            .await
+            .unwrap();
+        assert_eq!(model, renamed);
+        assert_eq!(read_chapter(book_dir.path(), "author/new", 2), "2");
+        assert!(!book_dir.path().join("author/book").exists());
+    }
+
+    #[tokio::test]
+    async fn test_set_book_metadata() {
+        let updated = music::Model {
+            narrator: Some("narrator".to_string()),
+            ..book(2)
+        };
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([[book(2)], [updated.clone()]])
+            .append_exec_results([exec_ok()])
+            .into_connection();
+        let metadata = super::BookMetadata {
+            narrator: " narrator ".to_string(),
+            series: " ".to_string(),
+            ..Default::default()
+        };
+        let model = super::set_book_metadata(&db, 1, metadata).await.unwrap();
+        assert_eq!(model, updated);
+        let log = format!("{:?}", db.into_transaction_log());
+        assert!(log.contains("String(Some(\"narrator\"))"));
+        assert!(log.contains("`series` = ?"));
+    }
+
+    #[tokio::test]
+    async fn test_set_book_tags() {
+        let existing = tag::Model {
+            id: 3,
+            name: "fantasy".to_string(),
+        };
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([[book(2)]])
+            .append_query_results([[existing.clone()]])
+            .append_query_results([Vec::<tag::Model>::new()])
+            .append_exec_results([exec_ok(), exec_ok(), exec_ok(), exec_ok()])
+            .into_connection();
+        let tags = super::set_book_tags(
+            &db,
+            1,
+            vec![
+                "fantasy".to_string(),
+                " novel ".to_string(),
+                "fantasy".to_string(),
+                "".to_string(),
+            ],
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            tags,
+            vec![
+                existing,
+                tag::Model {
+                    id: 1,
+                    name: "novel".to_string()
+                }
+            ]
+        );
+        let log = format!("{:?}", db.into_transaction_log());
+        assert!(log.contains("DELETE FROM `music_tag`"));
+    }
+
+    #[tokio::test]
+    async fn test_rename_book_failed() {
+        let book_dir = tempfile::tempdir().unwrap();
+        create_book(book_dir.path(), 2);
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([[book(2)]])
+            .append_exec_errors([DbErr::Custom("Duplicate entry 'new'".to_string())])
+            .into_connection();
+        let result = super::rename_book(&db, book_dir.path(), 1, "new".to_string()).await;
+        assert!(result.is_err());
+        assert_eq!(read_chapter(book_dir.path(), "author/book", 2), "2");
+        assert!(!book_dir.path().join("author/new").exists());
+        assert!(
+            super::rename_book(&db, book_dir.path(), 1, "../new".to_string())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_change_book_author() {
+        let book_dir = tempfile::tempdir().unwrap();
+        create_book(book_dir.path(), 1);
+        let mut moved = book(1);
+        moved.author_id = 2;
+        moved.file_folder = "other/book".to_string();
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([[book(1)]])
+            .append_query_results([Vec::<author::Model>::new()])
+            .append_query_results([[moved.clone()]])
+            .append_exec_results([exec_ok(), exec_ok()])
+            .into_connection();
+        let model = super::change_book_author(&db, book_dir.path(), 1, "other".to_string())
+            .await
+            .unwrap();
+        assert_eq!(model, moved);
+        assert_eq!(read_chapter(book_dir.path(), "other/book", 1), "1");
+        // the empty author folder is removed
+        assert!(!book_dir.path().join("author").exists());
+    }
+
+    #[tokio::test]
+    async fn test_change_book_author_move_failed() {
+        // no book folder on disk, the move fails after the author is created
+        let book_dir = tempfile::tempdir().unwrap();
+        let mut moved = book(1);
+        moved.author_id = 2;
+        moved.file_folder = "other/book".to_string();
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([[book(1)]])
+            .append_query_results([Vec::<author::Model>::new()])
+            .append_query_results([[moved]])
+            .append_exec_results([exec_ok(), exec_ok()])
+            .into_connection();
+        assert!(
+            super::change_book_author(&db, book_dir.path(), 1, "other".to_string())
+                .await
+                .is_err()
+        );
+        let log = db.into_transaction_log();
+        let txn = format!("{:?}", log.last().unwrap());
+        assert!(txn.contains("INSERT INTO `author`"));
+        assert!(txn.contains("ROLLBACK"));
+        assert!(!book_dir.path().join("other").exists());
+    }
+
+    #[tokio::test]
+    async fn test_delete_book() {
+        let book_dir = tempfile::tempdir().unwrap();
+        create_book(book_dir.path(), 1);
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([[book(1)]])
+            .append_exec_results([exec_ok(), exec_ok()])
+            .into_connection();
+        super::delete_book(&db, book_dir.path(), 1, true)
+            .await
+            .unwrap();
+        assert!(!book_dir.path().join("author").exists());
+        assert_eq!(
+            std::fs::read_dir(book_dir.path().join(super::super::STAGING_DIR))
+                .unwrap()
+                .count(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_book_keep_files() {
+        let book_dir = tempfile::tempdir().unwrap();
+        create_book(book_dir.path(), 1);
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([[book(1)]])
+            .append_exec_results([exec_ok()])
+            .append_exec_errors([DbErr::Custom("foreign key".to_string())])
+            .into_connection();
+        assert!(super::delete_book(&db, book_dir.path(), 1, true)
+            .await
+            .is_err());
+        assert_eq!(read_chapter(book_dir.path(), "author/book", 1), "1");
+    }
+
+    #[tokio::test]
+    async fn test_reorder_chapters() {
+        let book_dir = tempfile::tempdir().unwrap();
+        create_book(book_dir.path(), 3);
+        let listening = fixtures::progress(1, 3, 10.);
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([[book(3)]])
+            .append_query_results([[listening.clone()]])
+            .append_query_results([[progress::Model {
+                chapter_no: 1,
+                ..listening
+            }]])
+            .append_exec_results([exec_ok(), exec_ok(), exec_ok()])
+            .into_connection();
+        super::reorder_chapters(&db, book_dir.path(), 1, &[3, 1, 2])
+            .await
+            .unwrap();
+        assert_eq!(read_chapter(book_dir.path(), "author/book", 1), "3");
+        assert_eq!(read_chapter(book_dir.path(), "author/book", 2), "1");
+        assert_eq!(read_chapter(book_dir.path(), "author/book", 3), "2");
+        let log = format!("{:?}", db.into_transaction_log());
+        assert!(log.contains("UPDATE `progress`"));
+        assert!(log.contains("UPDATE `chapter` SET `chapter_no` = (CASE WHEN"));
+
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([[book(3)]])
+            .into_connection();
+        assert!(super::reorder_chapters(&db, book_dir.path(), 1, &[1, 1, 2])
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_replace_chapter() {
+        let book_dir = tempfile::tempdir().unwrap();
+        create_book(book_dir.path(), 2);
+        let new_file = book_dir.path().join("new.wav");
+        fixtures::write_wav(&new_file, 2, 1, 1.);
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([[book(2)]])
+            .append_exec_results([exec_ok(), exec_ok()])
+            .into_connection();
+        super::replace_chapter(
+            &db,
+            book_dir.path(),
+            1,
+            2,
+            &new_file,
+            Some("the new one".to_string()),
+        )
+        .await
+        .unwrap();
+        let log = format!("{:?}", db.into_transaction_log());
+        assert!(log.contains("UPDATE `chapter` SET `duration` = ?, `title` = ? WHERE"));
+        // the duration and title of the new file
+        assert!(log.contains("Double(Some(2.0))"));
+        assert!(log.contains("String(Some(\"the new one\"))"));
+        assert!(log.contains("COMMIT"));
+        let folder = book_dir.path().join("author/book");
+        assert!(!folder.join("0002.mp3").exists());
+        assert!(folder.join("0002.wav").exists());
+    }
+}
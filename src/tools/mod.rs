@@ -1,59 +1,131 @@
 use std::path::{Path, PathBuf};
 
 use crate::entities::{prelude::*, *};
-use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
-use tracing::{debug, info};
-pub async fn arrange_new_folder(src_dir: impl AsRef<Path>, target_dir: impl AsRef<Path>) -> i32 {
+use archive::{ArchiveKind, ExtractLimits};
+use sea_orm::{
+    sea_query::Expr, ActiveModelTrait, ColumnTrait, ConnectionTrait, DbErr, EntityTrait,
+    IntoActiveModel, QueryFilter, TransactionTrait,
+};
+use tracing::{debug, error, info};
+
+pub mod archive;
+pub mod audio;
+pub mod authors;
+pub mod check;
+pub mod duplicates;
+pub mod edit;
+pub mod romanize;
+pub mod trash;
+pub mod zipstream;
+/// link the audio files of `src_dir` into `target_dir` as `{:04}.{ext}`, returns the chapter titles
+/// taken from the source file names
+pub async fn arrange_new_folder(
+    src_dir: impl AsRef<Path>,
+    target_dir: impl AsRef<Path>,
+) -> eyre::Result<Vec<String>> {
     debug!(
         "moving {:?} target_dir: {:?}",
         src_dir.as_ref(),
         target_dir.as_ref()
     );
-    let files = get_files_in_dir(src_dir);
-    let count = files.len();
+    let files = get_files_in_dir(src_dir)?;
+    let mut titles = Vec::with_capacity(files.len());
     // create target dir if not exists
-    std::fs::create_dir_all(target_dir.as_ref()).unwrap();
+    std::fs::create_dir_all(target_dir.as_ref())?;
 
     for (src, target_index) in files.into_iter().zip(1..) {
         // create a hard link from src to target_dir, with new filename target_index+src.ext
-        let target = target_dir.as_ref().join(format!(
-            "{:04}.{}",
-            target_index,
-            src.extension().unwrap().to_str().unwrap()
-        ));
-        tokio::fs::hard_link(src, target).await.unwrap();
+        let ext = src
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .ok_or_else(|| eyre::eyre!("file without extension: {:?}", src))?;
+        let target = target_dir
+            .as_ref()
+            .join(format!("{:04}.{}", target_index, ext));
+        titles.push(
+            src.file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        );
+        tokio::fs::hard_link(&src, &target).await?;
+        // a file swapped for a symlink since it was listed is linked as the symlink itself
+        if tokio::fs::symlink_metadata(&target)
+            .await?
+            .file_type()
+            .is_symlink()
+        {
+            tokio::fs::remove_file(&target).await?;
+            eyre::bail!("{:?} became a symlink during the import", src);
+        }
+    }
+    Ok(titles)
+}
+/// list the chapter files `{:04}.{ext}` in a book folder, sorted by chapter number
+pub fn chapter_files(book_folder: impl AsRef<Path>) -> std::io::Result<Vec<(i32, PathBuf)>> {
+    let mut chapters = vec![];
+    for entry in std::fs::read_dir(book_folder)? {
+        let path = entry?.path();
+        let chapter_no = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .filter(|stem| stem.len() == 4)
+            .and_then(|stem| stem.parse::<i32>().ok());
+        if let (Some(chapter_no), true) = (chapter_no, path.is_file()) {
+            chapters.push((chapter_no, path));
+        }
     }
-    count as i32
+    chapters.sort();
+    Ok(chapters)
 }
+
 fn sort_with_number(paths: Vec<PathBuf>) -> Vec<PathBuf> {
     let fist_numer_reg = regex::Regex::new(r"\d+").unwrap();
 
     let mut out = paths
         .into_iter()
         .filter_map(|file| {
-            let fd = fist_numer_reg.find(file.file_name().unwrap().to_str().unwrap());
-            match fd {
-                Some(m) => {
-                    let num = m.as_str().parse::<u32>().unwrap();
-                    Some((num, file))
-                }
-                None => None,
-            }
+            let name = file.file_name()?.to_string_lossy().into_owned();
+            let m = fist_numer_reg.find(&name)?;
+            // a number too long for u64 sorts last instead of failing the import
+            let num = m.as_str().parse::<u64>().unwrap_or(u64::MAX);
+            Some((num, file))
         })
         .collect::<Vec<_>>();
     out.sort_by_key(|(num, _)| *num);
     out.into_iter().map(|(_, file)| file).collect()
 }
-fn get_files_in_dir(dir: impl AsRef<Path>) -> Vec<PathBuf> {
-    let files_and_dirs = std::fs::read_dir(dir).unwrap();
-    let entries: Vec<_> = files_and_dirs.into_iter().map(|e| e.unwrap()).collect();
+/// the audio files under `dir`, sorted by the first number in their name, sub folders last
+///
+/// the files are hard linked into the book folder and served as is, so a symlink or anything
+/// resolving outside of `dir` fails the whole import instead of exposing files of the server.
+fn get_files_in_dir(dir: impl AsRef<Path>) -> std::io::Result<Vec<PathBuf>> {
+    let root = dir.as_ref().canonicalize()?;
+    files_under(&root, &root)
+}
+
+fn files_under(root: &Path, dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let outside = |path: &Path| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{:?} is a symlink or outside of the imported folder", path),
+        )
+    };
+    let files_and_dirs = std::fs::read_dir(dir)?;
+    let entries = files_and_dirs.collect::<Result<Vec<_>, _>>()?;
     let mut files = vec![];
     let mut dirs = vec![];
     for e in entries {
-        if e.file_type().unwrap().is_dir() {
-            dirs.push(e.path());
-        } else {
-            files.push(e.path());
+        // the type of the entry itself, a symlink is not followed
+        let file_type = e.file_type()?;
+        let path = e.path();
+        if file_type.is_symlink() || !path.canonicalize()?.starts_with(root) {
+            return Err(outside(&path));
+        }
+        if file_type.is_dir() {
+            dirs.push(path);
+        } else if file_type.is_file() && is_audio_file(&path) {
+            // covers, playlists and notes shipped with the book are not chapters
+            files.push(path);
         }
     }
     let files_sorted = sort_with_number(files);
@@ -61,64 +133,354 @@ fn get_files_in_dir(dir: impl AsRef<Path>) -> Vec<PathBuf> {
     let mut out = vec![];
     out.extend(files_sorted);
     for dir in dirs_sorted {
-        out.extend(get_files_in_dir(&dir));
+        out.extend(files_under(root, &dir)?);
+    }
+    Ok(out)
+}
+
+/// the folder under `book_dir` where new books are prepared before being moved into place
+pub const STAGING_DIR: &str = ".staging";
+/// the folder under `book_dir` where uploaded files wait to be imported
+pub const UPLOAD_DIR: &str = ".uploads";
+/// the file extensions treated as playable chapters
+pub const AUDIO_EXTENSIONS: [&str; 10] = [
+    "mp3", "m4a", "m4b", "aac", "flac", "ogg", "opus", "wav", "wma", "ape",
+];
+
+/// check the extension of `path` against [`AUDIO_EXTENSIONS`], ignoring case
+pub fn is_audio_file(path: impl AsRef<Path>) -> bool {
+    path.as_ref()
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| {
+            AUDIO_EXTENSIONS
+                .iter()
+                .any(|audio| audio.eq_ignore_ascii_case(ext))
+        })
+        .unwrap_or(false)
+}
+
+/// the file names, without extension, of the cover image in a book folder
+pub const COVER_NAMES: [&str; 3] = ["cover", "folder", "front"];
+/// the file extensions of a cover image
+pub const COVER_EXTENSIONS: [&str; 4] = ["jpg", "jpeg", "png", "webp"];
+
+/// check the name of `path` against [`COVER_NAMES`] and [`COVER_EXTENSIONS`], ignoring case
+pub fn is_cover_file(path: impl AsRef<Path>) -> bool {
+    let matches = |part: Option<&std::ffi::OsStr>, names: &[&str]| {
+        part.and_then(|part| part.to_str())
+            .is_some_and(|part| names.iter().any(|name| name.eq_ignore_ascii_case(part)))
+    };
+    matches(path.as_ref().file_stem(), &COVER_NAMES)
+        && matches(path.as_ref().extension(), &COVER_EXTENSIONS)
+}
+
+/// the cover image in a book folder, like `cover.jpg`
+pub fn book_cover(book_folder: impl AsRef<Path>) -> Option<PathBuf> {
+    let mut covers = std::fs::read_dir(book_folder)
+        .ok()?
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| is_cover_file(path) && path.is_file())
+        .collect::<Vec<_>>();
+    covers.sort();
+    covers.into_iter().next()
+}
+
+/// escape `text` for an xml attribute or element
+pub fn escape_xml(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c => out.push(c),
+        }
     }
     out
 }
 
-pub async fn create_new_book(
+/// a temporary folder under `book_dir/.staging`, removed on drop unless it was persisted
+pub(crate) struct StagingDir {
+    path: PathBuf,
+    persisted: bool,
+}
+
+impl StagingDir {
+    pub(crate) fn new(book_dir: &Path) -> eyre::Result<Self> {
+        let path = book_dir
+            .join(STAGING_DIR)
+            .join(hex::encode(rand::random::<[u8; 8]>()));
+        std::fs::create_dir_all(&path)?;
+        Ok(Self {
+            path,
+            persisted: false,
+        })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// move the staged folder to `target_dir`, creating the parent folder when needed
+    fn persist(mut self, target_dir: &Path) -> eyre::Result<()> {
+        let parent = target_dir
+            .parent()
+            .ok_or_else(|| eyre::eyre!("invalid target dir: {:?}", target_dir))?;
+        let parent_created = !parent.exists();
+        std::fs::create_dir_all(parent)?;
+        if let Err(e) = std::fs::rename(&self.path, target_dir) {
+            if parent_created {
+                // only removes the folder if it is still empty
+                let _ = std::fs::remove_dir(parent);
+            }
+            return Err(e.into());
+        }
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for StagingDir {
+    fn drop(&mut self) {
+        if !self.persisted {
+            debug!("cleaning staging dir: {:?}", self.path);
+            if let Err(e) = std::fs::remove_dir_all(&self.path) {
+                error!("fail to clean staging dir {:?}: {}", self.path, e);
+            }
+        }
+    }
+}
+
+/// find the author by exact name or create a new one, returns the author id
+pub(crate) async fn find_or_create_author(
+    db: &impl ConnectionTrait,
     author_name: String,
-    new_book_name: String,
-    book_dir: &Path,
-    source_dir: &Path,
-    db: &sea_orm::DatabaseConnection,
-) -> eyre::Result<()> {
-    let db_book_dir = format!("{}/{}", author_name, new_book_name);
-    // let target_dir = format!("{:?}/{}/{}", book_dir, author_name, new_book_name);
-    let target_dir = book_dir.join(&author_name).join(&new_book_name);
-    let count = arrange_new_folder(source_dir, target_dir).await;
-    // create the book in db
-    // first create the author
+) -> Result<i32, DbErr> {
     let current_author = Author::find()
         .filter(author::Column::Name.eq(&author_name))
         .one(db)
         .await?;
     // if it's none, insert a new one
-    let author_id = match current_author {
-        Some(author) => author.id,
+    match current_author {
+        Some(author) => {
+            // a new book brings a trashed author back
+            if author.deleted_at.is_some() {
+                Author::update_many()
+                    .col_expr(
+                        author::Column::DeletedAt,
+                        Expr::value(Option::<chrono::NaiveDateTime>::None),
+                    )
+                    .filter(author::Column::Id.eq(author.id))
+                    .exec(db)
+                    .await?;
+            }
+            Ok(author.id)
+        }
         None => {
+            let romanized = romanize::romanize(&author_name);
             let author = Author::insert(author::ActiveModel {
                 name: sea_orm::ActiveValue::Set(author_name),
                 avatar: sea_orm::ActiveValue::Set("".to_string()),
                 description: sea_orm::ActiveValue::Set("".to_string()),
-
+                name_pinyin: sea_orm::ActiveValue::Set(Some(romanized.pinyin)),
+                name_initials: sea_orm::ActiveValue::Set(Some(romanized.initials)),
                 ..Default::default()
             })
             .exec(db)
             .await?;
-            author.last_insert_id
+            Ok(author.last_insert_id)
         }
-    };
+    }
+}
 
-    // insert the book
+/// author and book names become folder names, so they must be a single plain path component
+pub(crate) fn check_folder_name(name: &str) -> eyre::Result<()> {
+    if name.trim().is_empty()
+        || name.starts_with('.')
+        || name.contains(['/', '\\'])
+        || Path::new(name)
+            .file_name()
+            .map(|f| f != name)
+            .unwrap_or(true)
+    {
+        eyre::bail!("invalid name: {:?}", name);
+    }
+    Ok(())
+}
+
+/// find the author by name or create a new one, then insert the book, returns the new book id
+async fn insert_book(
+    db: &impl ConnectionTrait,
+    author_name: String,
+    new_book_name: String,
+    chapters: i32,
+    file_folder: String,
+    audio: audio::AudioInfo,
+) -> Result<i32, DbErr> {
+    let author_id = find_or_create_author(db, author_name).await?;
+
+    let romanized = romanize::romanize(&new_book_name);
     let book = Music::insert(music::ActiveModel {
         name: sea_orm::ActiveValue::Set(new_book_name),
+        name_pinyin: sea_orm::ActiveValue::Set(Some(romanized.pinyin)),
+        name_initials: sea_orm::ActiveValue::Set(Some(romanized.initials)),
         author_id: sea_orm::ActiveValue::Set(author_id),
-        chapters: sea_orm::ActiveValue::Set(count),
-        file_folder: sea_orm::ActiveValue::Set(db_book_dir.clone()),
+        chapters: sea_orm::ActiveValue::Set(chapters),
+        file_folder: sea_orm::ActiveValue::Set(file_folder),
+        duration: sea_orm::ActiveValue::Set(audio.duration),
+        fingerprint: sea_orm::ActiveValue::Set(audio.fingerprint),
         ..Default::default()
     })
     .exec(db)
     .await?;
-    let book_id = book.last_insert_id;
+    Ok(book.last_insert_id)
+}
+
+/// insert the chapter titles of a book, numbered from 1, with the known durations
+pub(crate) async fn insert_chapters(
+    db: &impl ConnectionTrait,
+    book_id: i32,
+    titles: Vec<String>,
+    durations: &[(i32, f64)],
+) -> Result<(), DbErr> {
+    if titles.is_empty() {
+        return Ok(());
+    }
+    Chapter::insert_many(titles.into_iter().zip(1..).map(|(title, chapter_no)| {
+        let duration = durations
+            .iter()
+            .find(|(no, _)| *no == chapter_no)
+            .map(|(_, duration)| *duration);
+        chapter::ActiveModel {
+            music_id: sea_orm::ActiveValue::Set(book_id),
+            chapter_no: sea_orm::ActiveValue::Set(chapter_no),
+            title: sea_orm::ActiveValue::Set(title),
+            duration: sea_orm::ActiveValue::Set(duration),
+            ..Default::default()
+        }
+    }))
+    .exec(db)
+    .await?;
+    Ok(())
+}
+
+/// save the durations of the chapters of a book, the chapters without a row get one with the
+/// default title
+pub(crate) async fn store_chapter_durations(
+    db: &impl ConnectionTrait,
+    book_id: i32,
+    durations: &[(i32, f64)],
+) -> Result<(), DbErr> {
+    let existing = Chapter::find()
+        .filter(chapter::Column::MusicId.eq(book_id))
+        .all(db)
+        .await?;
+    for (chapter_no, duration) in durations {
+        match existing.iter().find(|c| c.chapter_no == *chapter_no) {
+            Some(c) if c.duration == Some(*duration) => {}
+            Some(c) => {
+                let mut c = c.clone().into_active_model();
+                c.duration = sea_orm::ActiveValue::Set(Some(*duration));
+                c.update(db).await?;
+            }
+            None => {
+                chapter::ActiveModel {
+                    music_id: sea_orm::ActiveValue::Set(book_id),
+                    chapter_no: sea_orm::ActiveValue::Set(*chapter_no),
+                    title: sea_orm::ActiveValue::Set(format!("Chapter {}", chapter_no)),
+                    duration: sea_orm::ActiveValue::Set(Some(*duration)),
+                    ..Default::default()
+                }
+                .insert(db)
+                .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// import a book from `source_dir` into `book_dir/{author_name}/{new_book_name}`
+///
+/// `source_dir` is either a folder or a `.zip`/`.tar`/`.tar.gz` archive, which is extracted into
+/// the staging area first.
+///
+/// the files are first linked into a staging folder, then the author and book are inserted in one
+/// transaction, and only then the staging folder is renamed into place. On any failure the
+/// transaction is rolled back and the staging folder removed, so neither files nor rows are left behind.
+/// returns the new book id.
+pub async fn create_new_book(
+    author_name: String,
+    new_book_name: String,
+    book_dir: &Path,
+    source_dir: &Path,
+    db: &sea_orm::DatabaseConnection,
+) -> eyre::Result<i32> {
+    check_folder_name(&author_name)?;
+    check_folder_name(&new_book_name)?;
+    let db_book_dir = format!("{}/{}", author_name, new_book_name);
+    let target_dir = book_dir.join(&author_name).join(&new_book_name);
+    if target_dir.exists() {
+        eyre::bail!("target dir {:?} already exists", target_dir);
+    }
+    // keep the extracted files alive until they are linked into the staging folder
+    let extracted = match ArchiveKind::detect(source_dir) {
+        Some(_) if source_dir.is_file() => {
+            let extracted = StagingDir::new(book_dir)?;
+            let (archive, target) = (source_dir.to_path_buf(), extracted.path().to_path_buf());
+            tokio::task::spawn_blocking(move || {
+                archive::extract_archive(archive, target, ExtractLimits::default())
+            })
+            .await??;
+            Some(extracted)
+        }
+        _ => None,
+    };
+    let source_dir = extracted.as_ref().map(|e| e.path()).unwrap_or(source_dir);
+    let staging = StagingDir::new(book_dir)?;
+    let titles = arrange_new_folder(source_dir, staging.path()).await?;
+    if titles.is_empty() {
+        return Err(eyre::eyre!("no audio file in {:?}", source_dir));
+    }
+    let count = titles.len() as i32;
+    let staged = staging.path().to_path_buf();
+    let audio = tokio::task::spawn_blocking(move || audio::probe_book(&staged)).await?;
+
+    // create the book in db
+    let chapter_durations = audio.chapter_durations.clone();
+    let txn = db.begin().await?;
+    let book_id = insert_book(
+        &txn,
+        author_name,
+        new_book_name,
+        count,
+        db_book_dir.clone(),
+        audio,
+    )
+    .await?;
+    insert_chapters(&txn, book_id, titles, &chapter_durations).await?;
+    // dropping `txn` on error rolls back the inserts
+    staging.persist(&target_dir)?;
+    if let Err(e) = txn.commit().await {
+        // the files are hard links, the source is still intact
+        let _ = std::fs::remove_dir_all(&target_dir);
+        return Err(e.into());
+    }
     info!("book created:{}", book_id);
     info!("book dir:{}", db_book_dir);
     info!("book chapters:{}", count);
-    Ok(())
+    Ok(book_id)
 }
 #[cfg(test)]
 mod tests {
-    use super::get_files_in_dir;
+    use std::path::Path;
+
+    use sea_orm::{DatabaseBackend, DbErr, MockDatabase, MockExecResult};
+
+    use super::{get_files_in_dir, STAGING_DIR};
+    use crate::entities::author;
 
     #[test]
     fn test_regex() {
@@ -136,7 +498,7 @@ mod tests {
 
     #[test]
     fn test_get_files() {
-        let files = get_files_in_dir("./test_dir");
+        let files = get_files_in_dir("./test_dir").unwrap();
         for f in files {
             println!("{:?}", f);
         }
@@ -145,10 +507,254 @@ mod tests {
     #[tokio::test]
     async fn test_link() {
         let src_dir = "./test_dir";
+        // hard links need the target on the same filesystem as ./test_dir
+        let target_dir = tempfile::tempdir_in(".").unwrap();
+        let target_dir = target_dir.path().join("book");
+        let titles = super::arrange_new_folder(src_dir, &target_dir)
+            .await
+            .unwrap();
+        assert_eq!(titles.len(), 9);
+        assert_eq!(titles[0], "1");
+        assert!(target_dir.join("0009.mp3").exists());
+    }
+
+    #[test]
+    fn test_get_files_audio_only() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in [
+            "1.mp3",
+            "2.jpg",
+            "3.nfo",
+            "4.txt",
+            "5.M4A",
+            "cd1/6.flac",
+            "cd1/7.cue",
+        ] {
+            let path = dir.path().join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, name).unwrap();
+        }
+        let names = get_files_in_dir(dir.path())
+            .unwrap()
+            .into_iter()
+            .map(|file| file.file_name().unwrap().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["1.mp3", "5.M4A", "6.flac"]);
+    }
+
+    fn staging_is_empty(book_dir: &Path) -> bool {
+        let staging = book_dir.join(STAGING_DIR);
+        !staging.exists() || std::fs::read_dir(staging).unwrap().next().is_none()
+    }
+
+    fn exec_ok(last_insert_id: u64) -> MockExecResult {
+        MockExecResult {
+            last_insert_id,
+            rows_affected: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_new_book() {
+        let book_dir = tempfile::tempdir_in(".").unwrap();
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([Vec::<author::Model>::new()])
+            .append_exec_results([exec_ok(1), exec_ok(1), exec_ok(1)])
+            .into_connection();
+        super::create_new_book(
+            "author".to_string(),
+            "book".to_string(),
+            book_dir.path(),
+            Path::new("./test_dir"),
+            &db,
+        )
+        .await
+        .unwrap();
+        assert!(book_dir.path().join("author/book/0001.mp3").exists());
+        assert!(staging_is_empty(book_dir.path()));
+    }
+
+    #[tokio::test]
+    async fn test_create_new_book_from_archive() {
+        let book_dir = tempfile::tempdir_in(".").unwrap();
+        let archive = book_dir.path().join("book.zip");
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&archive).unwrap());
+        for name in ["cd2/1.mp3", "cd1/2.mp3", "cd1/1.mp3"] {
+            zip.start_file(name, zip::write::FileOptions::default())
+                .unwrap();
+            std::io::Write::write_all(&mut zip, name.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([Vec::<author::Model>::new()])
+            .append_exec_results([exec_ok(1), exec_ok(1), exec_ok(1)])
+            .into_connection();
+        super::create_new_book(
+            "author".to_string(),
+            "book".to_string(),
+            book_dir.path(),
+            &archive,
+            &db,
+        )
+        .await
+        .unwrap();
+        let chapter = |no: &str| {
+            std::fs::read_to_string(book_dir.path().join("author/book").join(no)).unwrap()
+        };
+        assert_eq!(chapter("0001.mp3"), "cd1/1.mp3");
+        assert_eq!(chapter("0003.mp3"), "cd2/1.mp3");
+        assert!(staging_is_empty(book_dir.path()));
+    }
 
-        let target_dir = "./test_dir2";
-        //delete test_dir2
-        tokio::fs::remove_dir_all(target_dir).await.unwrap();
-        super::arrange_new_folder(src_dir, target_dir).await;
+    #[tokio::test]
+    async fn test_create_new_book_missing_source() {
+        let book_dir = tempfile::tempdir_in(".").unwrap();
+        let db = MockDatabase::new(DatabaseBackend::MySql).into_connection();
+        let result = super::create_new_book(
+            "author".to_string(),
+            "book".to_string(),
+            book_dir.path(),
+            Path::new("./no_such_dir"),
+            &db,
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(staging_is_empty(book_dir.path()));
+        assert!(!book_dir.path().join("author").exists());
+        assert!(db.into_transaction_log().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_symlink_escape() {
+        let dir = tempfile::tempdir_in(".").unwrap();
+        let source = dir.path().join("book");
+        std::fs::create_dir_all(source.join("cd1")).unwrap();
+        std::fs::write(source.join("00.mp3"), "0").unwrap();
+        let secret = dir.path().join("secret");
+        std::fs::write(&secret, "secret").unwrap();
+        std::os::unix::fs::symlink(&secret, source.join("cd1/01.mp3")).unwrap();
+        assert!(get_files_in_dir(&source).is_err());
+
+        // a link to a file inside the folder is refused all the same
+        std::fs::remove_file(source.join("cd1/01.mp3")).unwrap();
+        std::os::unix::fs::symlink(source.join("00.mp3"), source.join("cd1/01.mp3")).unwrap();
+        assert!(get_files_in_dir(&source).is_err());
+
+        // nothing of the source lands in the book folder
+        let book_dir = dir.path().join("books");
+        let db = MockDatabase::new(DatabaseBackend::MySql).into_connection();
+        let result = super::create_new_book(
+            "author".to_string(),
+            "book".to_string(),
+            &book_dir,
+            &source,
+            &db,
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(!book_dir.join("author/book").exists());
+        assert!(staging_is_empty(&book_dir));
+        assert!(db.into_transaction_log().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_create_new_book_without_audio() {
+        let book_dir = tempfile::tempdir_in(".").unwrap();
+        let source = tempfile::tempdir_in(".").unwrap();
+        std::fs::write(source.path().join("1.txt"), "notes").unwrap();
+        std::fs::write(source.path().join("cover.jpg"), "jpeg").unwrap();
+        let db = MockDatabase::new(DatabaseBackend::MySql).into_connection();
+        let result = super::create_new_book(
+            "author".to_string(),
+            "book".to_string(),
+            book_dir.path(),
+            source.path(),
+            &db,
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(staging_is_empty(book_dir.path()));
+        assert!(db.into_transaction_log().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_create_new_book_author_query_failed() {
+        let book_dir = tempfile::tempdir_in(".").unwrap();
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_errors([DbErr::Custom("connection lost".to_string())])
+            .into_connection();
+        let result = super::create_new_book(
+            "author".to_string(),
+            "book".to_string(),
+            book_dir.path(),
+            Path::new("./test_dir"),
+            &db,
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(staging_is_empty(book_dir.path()));
+        assert!(!book_dir.path().join("author").exists());
+    }
+
+    #[tokio::test]
+    async fn test_create_new_book_duplicated_name() {
+        let book_dir = tempfile::tempdir_in(".").unwrap();
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([Vec::<author::Model>::new()])
+            .append_exec_results([exec_ok(1)])
+            .append_exec_errors([DbErr::Custom("Duplicate entry 'book'".to_string())])
+            .into_connection();
+        let result = super::create_new_book(
+            "author".to_string(),
+            "book".to_string(),
+            book_dir.path(),
+            Path::new("./test_dir"),
+            &db,
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(staging_is_empty(book_dir.path()));
+        assert!(!book_dir.path().join("author").exists());
+    }
+
+    #[tokio::test]
+    async fn test_create_new_book_rename_failed() {
+        let book_dir = tempfile::tempdir_in(".").unwrap();
+        // a file occupies the author folder, so the staged book can't be moved into place
+        std::fs::write(book_dir.path().join("author"), "").unwrap();
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([Vec::<author::Model>::new()])
+            .append_exec_results([exec_ok(1), exec_ok(1), exec_ok(1)])
+            .into_connection();
+        let result = super::create_new_book(
+            "author".to_string(),
+            "book".to_string(),
+            book_dir.path(),
+            Path::new("./test_dir"),
+            &db,
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(staging_is_empty(book_dir.path()));
+        assert!(book_dir.path().join("author").is_file());
+    }
+
+    #[tokio::test]
+    async fn test_create_new_book_target_exists() {
+        let book_dir = tempfile::tempdir_in(".").unwrap();
+        std::fs::create_dir_all(book_dir.path().join("author/book")).unwrap();
+        let db = MockDatabase::new(DatabaseBackend::MySql).into_connection();
+        let result = super::create_new_book(
+            "author".to_string(),
+            "book".to_string(),
+            book_dir.path(),
+            Path::new("./test_dir"),
+            &db,
+        )
+        .await;
+        assert!(result.is_err());
+        assert!(staging_is_empty(book_dir.path()));
+        assert!(db.into_transaction_log().is_empty());
     }
 }
@@ -0,0 +1,128 @@
+//! pinyin of book and author names, so Chinese titles can be searched from a latin keyboard
+//!
+//! `三國演義` is stored as `sanguoyanyi` with the initials `sgyy`. Traditional characters are
+//! converted to simplified first, other letters and digits are kept lowercased.
+
+use pinyin::ToPinyin;
+use sea_orm::{
+    sea_query::Expr, ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, QuerySelect,
+};
+use tracing::info;
+
+use crate::entities::{prelude::*, *};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Romanized {
+    pub pinyin: String,
+    pub initials: String,
+}
+
+/// convert traditional characters to simplified ones, char by char
+pub fn to_simplified(text: &str) -> String {
+    fast2s::convert(text)
+}
+
+pub fn romanize(name: &str) -> Romanized {
+    let simplified = to_simplified(name);
+    let mut pinyin = String::new();
+    let mut initials = String::new();
+    let mut in_word = false;
+    for (c, syllable) in simplified.chars().zip(simplified.as_str().to_pinyin()) {
+        match syllable {
+            Some(syllable) => {
+                pinyin.push_str(syllable.plain());
+                initials.push_str(syllable.first_letter());
+                in_word = false;
+            }
+            None if c.is_alphanumeric() => {
+                pinyin.extend(c.to_lowercase());
+                // a latin word or a number counts as one syllable
+                if !in_word {
+                    initials.extend(c.to_lowercase());
+                }
+                in_word = true;
+            }
+            None => in_word = false,
+        }
+    }
+    Romanized { pinyin, initials }
+}
+
+/// the letters and digits of a query, lowercased, to be compared with the stored pinyin
+pub fn normalize_query(query: &str) -> String {
+    query
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// compute the pinyin of the books and authors imported before it was stored, returns the number
+/// of rows updated
+pub async fn fill_missing_pinyin(db: &DatabaseConnection) -> eyre::Result<usize> {
+    let books: Vec<(i32, String)> = Music::find()
+        .select_only()
+        .columns([music::Column::Id, music::Column::Name])
+        .filter(music::Column::NamePinyin.is_null())
+        .into_tuple()
+        .all(db)
+        .await?;
+    let authors: Vec<(i32, String)> = Author::find()
+        .select_only()
+        .columns([author::Column::Id, author::Column::Name])
+        .filter(author::Column::NamePinyin.is_null())
+        .into_tuple()
+        .all(db)
+        .await?;
+    let updated = books.len() + authors.len();
+    for (id, name) in books {
+        let romanized = romanize(&name);
+        Music::update_many()
+            .col_expr(music::Column::NamePinyin, Expr::value(romanized.pinyin))
+            .col_expr(music::Column::NameInitials, Expr::value(romanized.initials))
+            .filter(music::Column::Id.eq(id))
+            .exec(db)
+            .await?;
+    }
+    for (id, name) in authors {
+        let romanized = romanize(&name);
+        Author::update_many()
+            .col_expr(author::Column::NamePinyin, Expr::value(romanized.pinyin))
+            .col_expr(
+                author::Column::NameInitials,
+                Expr::value(romanized.initials),
+            )
+            .filter(author::Column::Id.eq(id))
+            .exec(db)
+            .await?;
+    }
+    if updated > 0 {
+        info!("pinyin computed for {} names", updated);
+    }
+    Ok(updated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize_query, romanize, Romanized};
+
+    fn romanized(pinyin: &str, initials: &str) -> Romanized {
+        Romanized {
+            pinyin: pinyin.to_string(),
+            initials: initials.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_romanize() {
+        assert_eq!(romanize("三国演义"), romanized("sanguoyanyi", "sgyy"));
+        assert_eq!(romanize("三國演義"), romanized("sanguoyanyi", "sgyy"));
+        assert_eq!(romanize("三体2 (Audio)"), romanized("santi2audio", "st2a"));
+        assert_eq!(romanize("The Hobbit"), romanized("thehobbit", "th"));
+    }
+
+    #[test]
+    fn test_normalize_query() {
+        assert_eq!(normalize_query("San Guo-Yan yi"), "sanguoyanyi");
+    }
+}
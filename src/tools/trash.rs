@@ -0,0 +1,288 @@
+//! soft delete: accounts, books and authors go to the trash first, and can be restored until
+//! they are purged by hand or after the retention time
+
+use std::path::Path;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use eyre::{bail, eyre};
+use redis::AsyncCommands;
+use sea_orm::{
+    sea_query::Expr, ColumnTrait, DatabaseConnection, EntityTrait, PaginatorTrait, QueryFilter,
+    TransactionTrait,
+};
+use tracing::{error, info};
+
+use super::edit;
+use crate::entities::{prelude::*, *};
+use crate::middleware::LoginInfo;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum TrashKind {
+    Account,
+    Book,
+    Author,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Trash {
+    pub accounts: Vec<account::Model>,
+    pub books: Vec<music::Model>,
+    pub authors: Vec<author::Model>,
+}
+
+/// log out every session of `account_id`, a trashed account can't keep using the server until
+/// its sessions expire
+///
+/// the sessions are not indexed by account, all of them are scanned, which is fine for the
+/// few sessions of a self-hosted server and the rare trashing of an account.
+pub async fn revoke_sessions(
+    redis: &mut redis::aio::Connection,
+    account_id: i32,
+) -> redis::RedisResult<usize> {
+    let mut passkeys = vec![];
+    // the redis keys of the sessions are the 32 hex digits of their passkey
+    let pattern = "[0-9a-f]".repeat(32);
+    let mut keys = redis.scan_match::<_, String>(pattern).await?;
+    while let Some(key) = keys.next_item().await {
+        passkeys.push(key);
+    }
+    drop(keys);
+    let mut revoked = 0;
+    for passkey in passkeys {
+        // other values, or sessions expired meanwhile, are skipped
+        let Ok(login_info) = redis.get::<_, LoginInfo>(&passkey).await else {
+            continue;
+        };
+        if login_info.user_id == account_id {
+            redis.del::<_, ()>(&passkey).await?;
+            revoked += 1;
+        }
+    }
+    info!("{} sessions of account {} revoked", revoked, account_id);
+    Ok(revoked)
+}
+
+fn now() -> NaiveDateTime {
+    Utc::now().naive_utc()
+}
+
+/// set or clear `deleted_at`, fails if the row doesn't exist or is already in that state
+async fn set_deleted_at(
+    db: &DatabaseConnection,
+    kind: TrashKind,
+    id: i32,
+    deleted_at: Option<NaiveDateTime>,
+) -> eyre::Result<()> {
+    // restoring only applies to trashed rows, trashing only to live ones
+    let in_trash = deleted_at.is_none();
+    let result = match kind {
+        TrashKind::Account => {
+            Account::update_many()
+                .col_expr(account::Column::DeletedAt, Expr::value(deleted_at))
+                .filter(account::Column::Id.eq(id))
+                .filter(if in_trash {
+                    account::Column::DeletedAt.is_not_null()
+                } else {
+                    account::Column::DeletedAt.is_null()
+                })
+                .exec(db)
+                .await?
+        }
+        TrashKind::Book => {
+            Music::update_many()
+                .col_expr(music::Column::DeletedAt, Expr::value(deleted_at))
+                .filter(music::Column::Id.eq(id))
+                .filter(if in_trash {
+                    music::Column::DeletedAt.is_not_null()
+                } else {
+                    music::Column::DeletedAt.is_null()
+                })
+                .exec(db)
+                .await?
+        }
+        TrashKind::Author => {
+            Author::update_many()
+                .col_expr(author::Column::DeletedAt, Expr::value(deleted_at))
+                .filter(author::Column::Id.eq(id))
+                .filter(if in_trash {
+                    author::Column::DeletedAt.is_not_null()
+                } else {
+                    author::Column::DeletedAt.is_null()
+                })
+                .exec(db)
+                .await?
+        }
+    };
+    if result.rows_affected == 0 {
+        bail!("{:?} {} not found", kind, id);
+    }
+    Ok(())
+}
+
+/// move a row to the trash, an author can only be trashed once it has no book left
+pub async fn trash(db: &DatabaseConnection, kind: TrashKind, id: i32) -> eyre::Result<()> {
+    if kind == TrashKind::Author {
+        let books = Music::find()
+            .filter(music::Column::AuthorId.eq(id))
+            .filter(music::Column::DeletedAt.is_null())
+            .count(db)
+            .await?;
+        if books > 0 {
+            bail!("author {} still has {} books", id, books);
+        }
+    }
+    set_deleted_at(db, kind, id, Some(now())).await?;
+    info!("{:?} {} moved to trash", kind, id);
+    Ok(())
+}
+
+pub async fn restore(db: &DatabaseConnection, kind: TrashKind, id: i32) -> eyre::Result<()> {
+    set_deleted_at(db, kind, id, None).await?;
+    info!("{:?} {} restored", kind, id);
+    Ok(())
+}
+
+/// delete a trashed row for good, books are deleted with their files
+pub async fn purge(
+    db: &DatabaseConnection,
+    book_dir: &Path,
+    kind: TrashKind,
+    id: i32,
+) -> eyre::Result<()> {
+    match kind {
+        TrashKind::Account => {
+            let account = Account::find_by_id(id)
+                .filter(account::Column::DeletedAt.is_not_null())
+                .one(db)
+                .await?
+                .ok_or_else(|| eyre!("account {} is not in trash", id))?;
+            let txn = db.begin().await?;
+            // progress references the account with `Restrict`
+            Progress::delete_many()
+                .filter(progress::Column::AccountId.eq(account.id))
+                .exec(&txn)
+                .await?;
+            Account::delete_by_id(account.id).exec(&txn).await?;
+            txn.commit().await?;
+        }
+        TrashKind::Book => {
+            Music::find_by_id(id)
+                .filter(music::Column::DeletedAt.is_not_null())
+                .one(db)
+                .await?
+                .ok_or_else(|| eyre!("book {} is not in trash", id))?;
+            edit::delete_book(db, book_dir, id, true).await?;
+        }
+        TrashKind::Author => {
+            let result = Author::delete_many()
+                .filter(author::Column::Id.eq(id))
+                .filter(author::Column::DeletedAt.is_not_null())
+                .exec(db)
+                .await?;
+            if result.rows_affected == 0 {
+                bail!("author {} is not in trash", id);
+            }
+        }
+    }
+    info!("{:?} {} purged", kind, id);
+    Ok(())
+}
+
+pub async fn list_trash(db: &DatabaseConnection) -> eyre::Result<Trash> {
+    Ok(Trash {
+        accounts: Account::find()
+            .filter(account::Column::DeletedAt.is_not_null())
+            .all(db)
+            .await?,
+        books: Music::find()
+            .filter(music::Column::DeletedAt.is_not_null())
+            .all(db)
+            .await?,
+        authors: Author::find()
+            .filter(author::Column::DeletedAt.is_not_null())
+            .all(db)
+            .await?,
+    })
+}
+
+/// purge everything trashed before `now - retention`, returns the number of purged rows
+///
+/// books go first, so authors emptied by the purge can follow.
+pub async fn purge_expired(
+    db: &DatabaseConnection,
+    book_dir: &Path,
+    retention: Duration,
+) -> eyre::Result<usize> {
+    let cutoff = now() - retention;
+    let mut expired = vec![];
+    for book in Music::find()
+        .filter(music::Column::DeletedAt.lt(cutoff))
+        .all(db)
+        .await?
+    {
+        expired.push((TrashKind::Book, book.id));
+    }
+    for account in Account::find()
+        .filter(account::Column::DeletedAt.lt(cutoff))
+        .all(db)
+        .await?
+    {
+        expired.push((TrashKind::Account, account.id));
+    }
+    for author in Author::find()
+        .filter(author::Column::DeletedAt.lt(cutoff))
+        .all(db)
+        .await?
+    {
+        expired.push((TrashKind::Author, author.id));
+    }
+    let mut purged = 0;
+    for (kind, id) in expired {
+        match purge(db, book_dir, kind, id).await {
+            Ok(()) => purged += 1,
+            // keep going, the next run retries
+            Err(e) => error!("fail to purge {:?} {}: {}", kind, id, e),
+        }
+    }
+    Ok(purged)
+}
+
+#[cfg(test)]
+mod tests {
+    use sea_orm::{DatabaseBackend, MockDatabase, MockExecResult};
+
+    use super::TrashKind;
+
+    fn rows_affected(rows_affected: u64) -> MockExecResult {
+        MockExecResult {
+            last_insert_id: 0,
+            rows_affected,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_trash_and_restore() {
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_exec_results([rows_affected(1), rows_affected(1), rows_affected(0)])
+            .into_connection();
+        super::trash(&db, TrashKind::Account, 1).await.unwrap();
+        super::restore(&db, TrashKind::Account, 1).await.unwrap();
+        // not in trash anymore
+        assert!(super::restore(&db, TrashKind::Account, 1).await.is_err());
+        let log = format!("{:?}", db.into_transaction_log());
+        assert!(log.contains("`deleted_at` IS NULL"));
+        assert!(log.contains("`deleted_at` IS NOT NULL"));
+    }
+
+    #[tokio::test]
+    async fn test_trash_author_with_books() {
+        let db = MockDatabase::new(DatabaseBackend::MySql)
+            .append_query_results([[count_row(2)]])
+            .into_connection();
+        assert!(super::trash(&db, TrashKind::Author, 1).await.is_err());
+    }
+
+    fn count_row(count: i64) -> std::collections::BTreeMap<String, sea_orm::Value> {
+        std::collections::BTreeMap::from([("num_items".to_string(), count.into())])
+    }
+}
@@ -0,0 +1,257 @@
+//! a zip written front to back, for streaming an archive without holding it in memory
+//!
+//! the entries are stored, audio doesn't compress. the crc and the size of an entry follow its
+//! data in a data descriptor, so each file is read once. the archive gets zip64 records when its
+//! central directory starts past 4 GiB, an entry itself stays below 4 GiB.
+
+use chrono::{Datelike, NaiveDateTime, Timelike};
+use eyre::bail;
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR: u32 = 0x0807_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR: u32 = 0x0706_4b50;
+/// the data descriptor follows the data, the name is utf-8
+const FLAGS: u16 = 0x0808;
+const VERSION: u16 = 20;
+const VERSION_ZIP64: u16 = 45;
+
+#[derive(Debug)]
+struct Entry {
+    name: String,
+    date: u16,
+    time: u16,
+    crc: u32,
+    size: u32,
+    offset: u64,
+}
+
+/// the bytes of an archive, in the order they are sent
+#[derive(Debug, Default)]
+pub struct ZipStream {
+    /// the bytes produced so far
+    offset: u64,
+    entries: Vec<Entry>,
+    /// the entry whose data is being sent
+    open: Option<Entry>,
+}
+
+/// the date and the time in ms-dos format, which starts in 1980
+fn dos_date_time(at: NaiveDateTime) -> (u16, u16) {
+    if at.year() < 1980 {
+        return (0x21, 0);
+    }
+    let date =
+        ((at.year() - 1980).min(127) as u16) << 9 | (at.month() as u16) << 5 | at.day() as u16;
+    let time = (at.hour() as u16) << 11 | (at.minute() as u16) << 5 | (at.second() / 2) as u16;
+    (date, time)
+}
+
+impl ZipStream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the header of an entry, its data comes next and then [`Self::finish_entry`]
+    pub fn start_entry(&mut self, name: &str, modified: NaiveDateTime) -> eyre::Result<Vec<u8>> {
+        if self.open.is_some() {
+            bail!("the entry before {} is not finished", name);
+        }
+        let (date, time) = dos_date_time(modified);
+        let mut header = vec![];
+        header.extend(LOCAL_HEADER.to_le_bytes());
+        header.extend(VERSION.to_le_bytes());
+        header.extend(FLAGS.to_le_bytes());
+        // stored
+        header.extend(0u16.to_le_bytes());
+        header.extend(time.to_le_bytes());
+        header.extend(date.to_le_bytes());
+        // the crc and the sizes are in the data descriptor
+        header.extend([0; 12]);
+        header.extend((name.len() as u16).to_le_bytes());
+        header.extend(0u16.to_le_bytes());
+        header.extend(name.as_bytes());
+        self.open = Some(Entry {
+            name: name.to_string(),
+            date,
+            time,
+            crc: 0,
+            size: 0,
+            offset: self.offset,
+        });
+        self.offset += header.len() as u64;
+        Ok(header)
+    }
+
+    /// the data descriptor closing the entry, after `size` bytes of data with this crc
+    pub fn finish_entry(&mut self, crc: u32, size: u64) -> eyre::Result<Vec<u8>> {
+        let Some(mut entry) = self.open.take() else {
+            bail!("no entry to finish");
+        };
+        let Ok(size) = u32::try_from(size) else {
+            bail!("{} is larger than 4 GiB", entry.name);
+        };
+        entry.crc = crc;
+        entry.size = size;
+        let mut descriptor = vec![];
+        descriptor.extend(DATA_DESCRIPTOR.to_le_bytes());
+        descriptor.extend(crc.to_le_bytes());
+        descriptor.extend(size.to_le_bytes());
+        descriptor.extend(size.to_le_bytes());
+        self.offset += u64::from(size) + descriptor.len() as u64;
+        self.entries.push(entry);
+        Ok(descriptor)
+    }
+
+    /// a whole entry, for the small files made on the fly
+    pub fn entry(
+        &mut self,
+        name: &str,
+        modified: NaiveDateTime,
+        data: &[u8],
+    ) -> eyre::Result<Vec<u8>> {
+        let mut bytes = self.start_entry(name, modified)?;
+        bytes.extend(data);
+        bytes.extend(self.finish_entry(crc32fast::hash(data), data.len() as u64)?);
+        Ok(bytes)
+    }
+
+    /// the central directory ending the archive
+    pub fn finish(self) -> eyre::Result<Vec<u8>> {
+        if let Some(entry) = self.open {
+            bail!("{} is not finished", entry.name);
+        }
+        let start = self.offset;
+        let mut directory = vec![];
+        for entry in &self.entries {
+            let zip64 = entry.offset >= u64::from(u32::MAX);
+            let version = if zip64 { VERSION_ZIP64 } else { VERSION };
+            directory.extend(CENTRAL_HEADER.to_le_bytes());
+            directory.extend(version.to_le_bytes());
+            directory.extend(version.to_le_bytes());
+            directory.extend(FLAGS.to_le_bytes());
+            directory.extend(0u16.to_le_bytes());
+            directory.extend(entry.time.to_le_bytes());
+            directory.extend(entry.date.to_le_bytes());
+            directory.extend(entry.crc.to_le_bytes());
+            directory.extend(entry.size.to_le_bytes());
+            directory.extend(entry.size.to_le_bytes());
+            directory.extend((entry.name.len() as u16).to_le_bytes());
+            directory.extend((if zip64 { 12u16 } else { 0 }).to_le_bytes());
+            // the comment, the disk, the internal and the external attributes
+            directory.extend([0; 10]);
+            directory.extend((if zip64 { u32::MAX } else { entry.offset as u32 }).to_le_bytes());
+            directory.extend(entry.name.as_bytes());
+            if zip64 {
+                // the zip64 extra field with the offset of the local header
+                directory.extend(1u16.to_le_bytes());
+                directory.extend(8u16.to_le_bytes());
+                directory.extend(entry.offset.to_le_bytes());
+            }
+        }
+        let size = directory.len() as u64;
+        let count = self.entries.len() as u64;
+        let zip64 = start >= u64::from(u32::MAX) || count >= u64::from(u16::MAX);
+        if zip64 {
+            let record = start + size;
+            directory.extend(ZIP64_END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+            // the size of the rest of the record
+            directory.extend(44u64.to_le_bytes());
+            directory.extend(VERSION_ZIP64.to_le_bytes());
+            directory.extend(VERSION_ZIP64.to_le_bytes());
+            directory.extend([0; 8]);
+            directory.extend(count.to_le_bytes());
+            directory.extend(count.to_le_bytes());
+            directory.extend(size.to_le_bytes());
+            directory.extend(start.to_le_bytes());
+            directory.extend(ZIP64_LOCATOR.to_le_bytes());
+            directory.extend(0u32.to_le_bytes());
+            directory.extend(record.to_le_bytes());
+            directory.extend(1u32.to_le_bytes());
+        }
+        let count = if zip64 { u16::MAX } else { count as u16 };
+        directory.extend(END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+        directory.extend([0; 4]);
+        directory.extend(count.to_le_bytes());
+        directory.extend(count.to_le_bytes());
+        directory.extend((size as u32).to_le_bytes());
+        directory.extend((if zip64 { u32::MAX } else { start as u32 }).to_le_bytes());
+        directory.extend(0u16.to_le_bytes());
+        Ok(directory)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use super::ZipStream;
+
+    #[test]
+    fn test_zip_stream() {
+        let modified = chrono::NaiveDate::from_ymd_opt(2023, 10, 29)
+            .unwrap()
+            .and_hms_opt(12, 30, 10)
+            .unwrap();
+        let mut zip = ZipStream::new();
+        let mut bytes = zip.entry("playlist.m3u", modified, b"#EXTM3U\n").unwrap();
+        // a file sent in chunks
+        bytes.extend(zip.start_entry("0001 第一章.mp3", modified).unwrap());
+        let data = vec![7u8; 100_000];
+        for chunk in data.chunks(4096) {
+            bytes.extend(chunk);
+        }
+        bytes.extend(
+            zip.finish_entry(crc32fast::hash(&data), data.len() as u64)
+                .unwrap(),
+        );
+        assert!(zip.finish_entry(0, 0).is_err());
+        bytes.extend(zip.finish().unwrap());
+
+        let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(archive.len(), 2);
+        let mut playlist = String::new();
+        archive
+            .by_name("playlist.m3u")
+            .unwrap()
+            .read_to_string(&mut playlist)
+            .unwrap();
+        assert_eq!(playlist, "#EXTM3U\n");
+        let mut chapter = archive.by_index(1).unwrap();
+        assert_eq!(chapter.name(), "0001 第一章.mp3");
+        assert_eq!(chapter.last_modified().second(), 10);
+        let mut read = vec![];
+        chapter.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+    }
+
+    #[test]
+    fn test_zip64() {
+        // as if 5 GiB of entries were sent before
+        let mut zip = ZipStream {
+            offset: 5 << 30,
+            ..Default::default()
+        };
+        zip.entry("0002.mp3", Default::default(), b"data").unwrap();
+        let directory = zip.finish().unwrap();
+        let end = &directory[directory.len() - 22..];
+        assert_eq!(end[..4], super::END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+        assert_eq!(end[16..20], u32::MAX.to_le_bytes());
+        let locator = &directory[directory.len() - 42..directory.len() - 22];
+        assert_eq!(locator[..4], super::ZIP64_LOCATOR.to_le_bytes());
+        // the zip64 record, of 56 bytes, sits right before the locator
+        let record = &directory[directory.len() - 98..];
+        assert_eq!(
+            record[..4],
+            super::ZIP64_END_OF_CENTRAL_DIRECTORY.to_le_bytes()
+        );
+        let start = u64::from_le_bytes(record[48..56].try_into().unwrap());
+        let size = u64::from_le_bytes(record[40..48].try_into().unwrap());
+        assert_eq!(
+            u64::from_le_bytes(locator[8..16].try_into().unwrap()),
+            start + size
+        );
+    }
+}
@@ -0,0 +1,207 @@
+//! a whole book as one hls playlist, so a player goes from a chapter to the next without a gap
+//! and seeks in the whole book
+//!
+//! each chapter is cut in segments of [`SEGMENT_SECONDS`], named like `3-12.ts` for the 13th
+//! segment of chapter 3. the timestamps of a segment count from the start of the book, so the
+//! playlist needs no discontinuity between chapters. the title of each segment is the title of
+//! its chapter, which marks the chapters without dates.
+//!
+//! a chapter is encoded in one go and cut by the segment muxer of ffmpeg, separate encodes of
+//! each segment would start with the silence of the encoder delay and leave gaps.
+
+use std::{ffi::OsString, path::Path};
+
+use super::{Codec, Target};
+
+/// the length of a segment, the last one of a chapter is shorter
+pub(crate) const SEGMENT_SECONDS: f64 = 10.;
+pub(crate) const PLAYLIST_TYPE: &str = "application/vnd.apple.mpegurl";
+pub(crate) const SEGMENT_TYPE: &str = "video/mp2t";
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct PlaylistChapter {
+    pub chapter_no: i32,
+    pub title: String,
+    /// seconds
+    pub duration: f64,
+}
+
+/// a segment of a chapter, in seconds
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Segment {
+    pub chapter_no: i32,
+    /// the position in the chapter, from 0
+    pub index: usize,
+    /// into the chapter
+    pub start: f64,
+    pub length: f64,
+    /// the start of the chapter in the book
+    pub offset: f64,
+    /// kbps
+    pub bitrate: u32,
+}
+
+/// the start and the length of each segment of a chapter
+fn chapter_segments(duration: f64) -> impl Iterator<Item = (f64, f64)> {
+    let count = (duration / SEGMENT_SECONDS).ceil().max(0.) as u32;
+    (0..count).map(move |index| {
+        let start = f64::from(index) * SEGMENT_SECONDS;
+        (start, SEGMENT_SECONDS.min(duration - start))
+    })
+}
+
+/// the media playlist of the book, the segments are relative to it
+pub(crate) fn playlist(chapters: &[PlaylistChapter], bitrate: Option<u32>) -> String {
+    let query = bitrate.map_or_else(String::new, |bitrate| format!("?bitrate={}", bitrate));
+    let mut m3u8 = format!(
+        concat!(
+            "#EXTM3U\n",
+            "#EXT-X-VERSION:3\n",
+            "#EXT-X-TARGETDURATION:{}\n",
+            "#EXT-X-MEDIA-SEQUENCE:0\n",
+            "#EXT-X-PLAYLIST-TYPE:VOD\n",
+            "#EXT-X-INDEPENDENT-SEGMENTS\n"
+        ),
+        SEGMENT_SECONDS.ceil()
+    );
+    for chapter in chapters {
+        // a comma or a line break would end the title early
+        let title = chapter.title.replace([',', '\r', '\n'], " ");
+        for (index, (_, length)) in chapter_segments(chapter.duration).enumerate() {
+            m3u8.push_str(&format!(
+                "#EXTINF:{:.3},{}\n{}-{}.ts{}\n",
+                length, title, chapter.chapter_no, index, query
+            ));
+        }
+    }
+    m3u8.push_str("#EXT-X-ENDLIST\n");
+    m3u8
+}
+
+/// the segment named `name` in the playlist of the chapters, given as their number and duration
+pub(crate) fn find_segment(
+    chapters: &[(i32, f64)],
+    name: &str,
+    bitrate: Option<u32>,
+) -> Option<Segment> {
+    let (chapter_no, index) = name.strip_suffix(".ts")?.split_once('-')?;
+    let chapter_no = chapter_no.parse::<i32>().ok()?;
+    let index = index.parse::<usize>().ok()?;
+    let position = chapters.iter().position(|(no, _)| *no == chapter_no)?;
+    let offset = chapters[..position]
+        .iter()
+        .map(|(_, duration)| duration)
+        .sum();
+    let (start, length) = chapter_segments(chapters[position].1).nth(index)?;
+    Some(Segment {
+        chapter_no,
+        index,
+        start,
+        length,
+        offset,
+        bitrate: Target::new(Codec::Aac, bitrate, None).bitrate,
+    })
+}
+
+/// the whole chapter of `segment` as aac in mpeg-ts segments numbered from 0 in `pattern`, at
+/// the same rate and channels for every chapter, which players can join
+pub(crate) fn chapter_args(source: &Path, segment: &Segment, pattern: &Path) -> Vec<OsString> {
+    let mut args: Vec<OsString> = vec![];
+    for arg in ["-nostdin", "-hide_banner", "-loglevel", "error", "-i"] {
+        args.push(arg.into());
+    }
+    args.push(source.into());
+    for arg in [
+        "-vn",
+        "-map_metadata",
+        "-1",
+        "-c:a",
+        "aac",
+        "-b:a",
+        &format!("{}k", segment.bitrate),
+        "-ar",
+        "44100",
+        "-ac",
+        "2",
+        "-f",
+        "segment",
+        "-segment_time",
+        &format!("{:.3}", SEGMENT_SECONDS),
+        "-segment_format",
+        "mpegts",
+        "-segment_format_options",
+        "max_delay=0",
+        // the cuts are made on the chapter time, the offset is added to what is written
+        "-initial_offset",
+        &format!("{:.3}", segment.offset),
+    ] {
+        args.push(arg.into());
+    }
+    args.push(pattern.into());
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{chapter_args, find_segment, playlist, PlaylistChapter, Segment};
+
+    #[test]
+    fn test_playlist() {
+        let chapters = [
+            PlaylistChapter {
+                chapter_no: 1,
+                title: "One, the start".to_string(),
+                duration: 25.,
+            },
+            PlaylistChapter {
+                chapter_no: 2,
+                title: "Two".to_string(),
+                duration: 10.,
+            },
+        ];
+        assert_eq!(
+            playlist(&chapters, Some(96)),
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:10\n#EXT-X-MEDIA-SEQUENCE:0\n\
+             #EXT-X-PLAYLIST-TYPE:VOD\n#EXT-X-INDEPENDENT-SEGMENTS\n\
+             #EXTINF:10.000,One  the start\n1-0.ts?bitrate=96\n\
+             #EXTINF:10.000,One  the start\n1-1.ts?bitrate=96\n\
+             #EXTINF:5.000,One  the start\n1-2.ts?bitrate=96\n\
+             #EXTINF:10.000,Two\n2-0.ts?bitrate=96\n\
+             #EXT-X-ENDLIST\n"
+        );
+    }
+
+    #[test]
+    fn test_find_segment() {
+        let chapters = [(1, 25.), (2, 10.), (3, 12.5)];
+        assert_eq!(
+            find_segment(&chapters, "3-1.ts", None),
+            Some(Segment {
+                chapter_no: 3,
+                index: 1,
+                start: 10.,
+                length: 2.5,
+                offset: 35.,
+                bitrate: 64,
+            })
+        );
+        assert_eq!(find_segment(&chapters, "2-1.ts", None), None);
+        assert_eq!(find_segment(&chapters, "4-0.ts", None), None);
+        assert_eq!(find_segment(&chapters, "1-0.mp3", None), None);
+        let segment = find_segment(&chapters, "2-0.ts", Some(1000)).unwrap();
+        assert_eq!(segment.bitrate, 320);
+        let args = chapter_args(Path::new("0002.mp3"), &segment, Path::new("a.%d.part"))
+            .into_iter()
+            .map(|arg| arg.into_string().unwrap())
+            .collect::<Vec<_>>()
+            .join(" ");
+        assert_eq!(
+            args,
+            "-nostdin -hide_banner -loglevel error -i 0002.mp3 -vn -map_metadata -1 -c:a aac \
+             -b:a 320k -ar 44100 -ac 2 -f segment -segment_time 10.000 -segment_format mpegts \
+             -segment_format_options max_delay=0 -initial_offset 25.000 a.%d.part"
+        );
+    }
+}
@@ -0,0 +1,173 @@
+//! the chapters of a book merged by ffmpeg into one `.m4b` with chapter markers
+//!
+//! the files are joined with the concat demuxer and the markers come from an ffmetadata file.
+//! chapters already in aac are copied, others are encoded. mp4 needs a seekable output, so the
+//! book is written to the transcode cache before it's served.
+
+use std::{ffi::OsString, path::Path};
+
+/// a chapter in the merged book
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MergeChapter<'a> {
+    pub path: &'a Path,
+    pub title: &'a str,
+    /// seconds
+    pub duration: f64,
+}
+
+/// the tags of the merged book
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MergeTags<'a> {
+    pub title: &'a str,
+    pub author: &'a str,
+    pub narrator: Option<&'a str>,
+    pub description: Option<&'a str>,
+}
+
+/// escape the special characters of a value in an ffmetadata file
+fn escape_metadata(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// the ffmetadata file with the tags and the chapter markers, in milliseconds
+pub(crate) fn ffmetadata(tags: &MergeTags, chapters: &[MergeChapter]) -> String {
+    let mut metadata = format!(
+        ";FFMETADATA1\ntitle={title}\nalbum={title}\nartist={author}\nalbum_artist={author}\ngenre=Audiobook\n",
+        title = escape_metadata(tags.title),
+        author = escape_metadata(tags.author),
+    );
+    if let Some(narrator) = tags.narrator {
+        metadata.push_str(&format!("composer={}\n", escape_metadata(narrator)));
+    }
+    if let Some(description) = tags.description {
+        metadata.push_str(&format!("comment={}\n", escape_metadata(description)));
+    }
+    let mut start = 0.;
+    for chapter in chapters {
+        let end = start + chapter.duration;
+        metadata.push_str(&format!(
+            "\n[CHAPTER]\nTIMEBASE=1/1000\nSTART={}\nEND={}\ntitle={}\n",
+            (start * 1000.).round() as u64,
+            (end * 1000.).round() as u64,
+            escape_metadata(chapter.title)
+        ));
+        start = end;
+    }
+    metadata
+}
+
+/// the input list of the concat demuxer
+pub(crate) fn concat_list(chapters: &[MergeChapter]) -> String {
+    chapters
+        .iter()
+        .map(|chapter| {
+            // quoted, a quote is closed, escaped and opened again
+            let path = chapter.path.to_string_lossy().replace('\'', r"'\''");
+            format!("file '{}'\n", path)
+        })
+        .collect()
+}
+
+/// whether every chapter is aac in an mp4, which can be copied
+pub(crate) fn can_copy(chapters: &[MergeChapter]) -> bool {
+    chapters.iter().all(|chapter| {
+        chapter
+            .path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| matches!(ext.to_ascii_lowercase().as_str(), "m4a" | "m4b" | "mp4"))
+    })
+}
+
+pub(crate) fn merge_args(
+    list: &Path,
+    metadata: &Path,
+    copy: bool,
+    bitrate: u32,
+    output: &Path,
+) -> Vec<OsString> {
+    let mut args: Vec<OsString> = vec![];
+    for arg in [
+        "-nostdin",
+        "-hide_banner",
+        "-loglevel",
+        "error",
+        "-f",
+        "concat",
+        "-safe",
+        "0",
+        "-i",
+    ] {
+        args.push(arg.into());
+    }
+    args.push(list.into());
+    args.push("-i".into());
+    args.push(metadata.into());
+    for arg in ["-map", "0:a", "-map_metadata", "1", "-map_chapters", "1"] {
+        args.push(arg.into());
+    }
+    if copy {
+        args.extend(["-c:a".into(), "copy".into()]);
+    } else {
+        args.extend([
+            "-c:a".into(),
+            "aac".into(),
+            "-b:a".into(),
+            format!("{}k", bitrate).into(),
+        ]);
+    }
+    for arg in ["-movflags", "+faststart", "-f", "mp4"] {
+        args.push(arg.into());
+    }
+    args.push(output.into());
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{can_copy, concat_list, ffmetadata, MergeChapter, MergeTags};
+
+    #[test]
+    fn test_ffmetadata() {
+        let chapters = [
+            MergeChapter {
+                path: Path::new("/books/a/0001.mp3"),
+                title: "One; the start",
+                duration: 61.5,
+            },
+            MergeChapter {
+                path: Path::new("/books/a/it's/0002.m4a"),
+                title: "Two",
+                duration: 30.,
+            },
+        ];
+        let tags = MergeTags {
+            title: "A=B",
+            author: "Author",
+            narrator: Some("Reader"),
+            description: None,
+        };
+        assert_eq!(
+            ffmetadata(&tags, &chapters),
+            ";FFMETADATA1\ntitle=A\\=B\nalbum=A\\=B\nartist=Author\nalbum_artist=Author\n\
+             genre=Audiobook\ncomposer=Reader\n\
+             \n[CHAPTER]\nTIMEBASE=1/1000\nSTART=0\nEND=61500\ntitle=One\\; the start\n\
+             \n[CHAPTER]\nTIMEBASE=1/1000\nSTART=61500\nEND=91500\ntitle=Two\n"
+        );
+        assert_eq!(
+            concat_list(&chapters),
+            "file '/books/a/0001.mp3'\nfile '/books/a/it'\\''s/0002.m4a'\n"
+        );
+        assert!(!can_copy(&chapters));
+        assert!(can_copy(&chapters[1..]));
+    }
+}
@@ -0,0 +1,849 @@
+//! transcode a chapter with a local ffmpeg to a lower bitrate, for playing over a slow connection
+//!
+//! a whole chapter is written to the cache under `book_dir/.transcode` while it's streamed, and
+//! served from there with range requests the next time. the cache is trimmed to its size cap by
+//! removing the files used least recently. a request starting at a time offset is transcoded
+//! from that offset and streamed without caching.
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::{boxed, Body, Bytes},
+    response::{IntoResponse, Response},
+};
+use hyper::{header, HeaderMap};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    process::{Child, Command},
+    sync::{watch, OwnedSemaphorePermit, Semaphore},
+};
+use tracing::{debug, warn};
+
+use crate::error::{AppError, AppResult, ErrorCode};
+
+pub(crate) mod hls;
+pub(crate) mod m4b;
+
+/// the folder under `book_dir` caching the transcoded chapters
+pub(crate) const CACHE_DIR: &str = ".transcode";
+/// the bitrate in kbps when none is asked for
+pub(crate) const DEFAULT_BITRATE: u32 = 64;
+const MIN_BITRATE: u32 = 16;
+const MAX_BITRATE: u32 = 320;
+/// the bitrate of a merged book when its chapters are encoded
+const MERGE_BITRATE: u32 = 64;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Codec {
+    #[default]
+    Opus,
+    Aac,
+    Mp3,
+}
+
+impl Codec {
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "opus" | "ogg" => Some(Codec::Opus),
+            "aac" | "m4a" => Some(Codec::Aac),
+            "mp3" => Some(Codec::Mp3),
+            _ => None,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Codec::Opus => "opus",
+            Codec::Aac => "aac",
+            Codec::Mp3 => "mp3",
+        }
+    }
+
+    pub(crate) fn mime(self) -> &'static str {
+        match self {
+            Codec::Opus => "audio/ogg",
+            Codec::Aac => "audio/aac",
+            Codec::Mp3 => "audio/mpeg",
+        }
+    }
+
+    /// the ffmpeg encoder and the muxer, all of them can be written to a pipe
+    fn encoder_and_format(self) -> (&'static str, &'static str) {
+        match self {
+            Codec::Opus => ("libopus", "ogg"),
+            Codec::Aac => ("aac", "adts"),
+            Codec::Mp3 => ("libmp3lame", "mp3"),
+        }
+    }
+}
+
+/// what a chapter is transcoded to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Target {
+    pub codec: Codec,
+    /// kbps
+    pub bitrate: u32,
+    /// seconds into the chapter
+    pub start: f64,
+}
+
+impl Target {
+    /// the bitrate is clamped to what the encoders accept, a negative start is the beginning
+    pub(crate) fn new(codec: Codec, bitrate: Option<u32>, start: Option<f64>) -> Self {
+        Self {
+            codec,
+            bitrate: bitrate
+                .unwrap_or(DEFAULT_BITRATE)
+                .clamp(MIN_BITRATE, MAX_BITRATE),
+            start: start
+                .filter(|start| start.is_finite())
+                .unwrap_or(0.)
+                .max(0.),
+        }
+    }
+}
+
+/// the outcome of a job, `None` while it runs
+type JobState = watch::Receiver<Option<AppResult<()>>>;
+
+/// a clone shares the processes and the jobs, a job runs on its own clone
+#[derive(Clone)]
+pub(crate) struct Transcoder {
+    ffmpeg: PathBuf,
+    cache_dir: PathBuf,
+    /// bytes
+    cache_size: u64,
+    /// a permit per ffmpeg process allowed to run
+    processes: Arc<Semaphore>,
+    /// the jobs running, by the cache file they make
+    jobs: Arc<Mutex<HashMap<PathBuf, JobState>>>,
+}
+
+impl Transcoder {
+    pub(crate) fn new(
+        ffmpeg: PathBuf,
+        cache_dir: PathBuf,
+        cache_size: u64,
+        max_processes: usize,
+    ) -> Self {
+        Self {
+            ffmpeg,
+            cache_dir,
+            cache_size,
+            processes: Arc::new(Semaphore::new(max_processes.max(1))),
+            jobs: Default::default(),
+        }
+    }
+
+    /// the cache file of the output described by `key`
+    fn cache_file(&self, key: &str, extension: &str) -> PathBuf {
+        self.cache_dir
+            .join(format!("{:x}.{}", md5::compute(key), extension))
+    }
+
+    /// the cache file of `source` transcoded as described by `variant`, a changed source gets a
+    /// new one
+    fn cache_path(&self, source: &Path, variant: &str, extension: &str) -> io::Result<PathBuf> {
+        Ok(self.cache_file(&format!("{}|{}", source_key(source)?, variant), extension))
+    }
+
+    /// the cached file if it's there, marked as just used
+    fn cached(path: &Path) -> bool {
+        if !path.is_file() {
+            return false;
+        }
+        if let Err(e) = touch(path) {
+            warn!("fail to touch {:?}: {}", path, e);
+        }
+        true
+    }
+
+    /// a new file in the cache dir, which the eviction leaves alone until it's renamed
+    async fn part_file(&self, cached: &Path, suffix: &str) -> AppResult<PathBuf> {
+        tokio::fs::create_dir_all(&self.cache_dir)
+            .await
+            .map_err(AppError::internal)?;
+        Ok(part_path(cached, suffix))
+    }
+
+    /// run ffmpeg until it exits
+    async fn run(&self, args: Vec<OsString>) -> AppResult<()> {
+        let (child, _permit) = self.spawn(args, Stdio::null()).await?;
+        let output = child.wait_with_output().await.map_err(AppError::internal)?;
+        if !output.status.success() {
+            return Err(AppError::internal(format!(
+                "ffmpeg failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+        Ok(())
+    }
+
+    /// run ffmpeg writing to `part`, then move it to `cached` and trim the cache
+    async fn run_to_cache(&self, args: Vec<OsString>, part: &Path, cached: &Path) -> AppResult<()> {
+        if let Err(e) = self.run(args).await {
+            let _ = tokio::fs::remove_file(part).await;
+            return Err(e);
+        }
+        tokio::fs::rename(part, cached)
+            .await
+            .map_err(AppError::internal)?;
+        self.trim(&[cached.to_path_buf()]);
+        Ok(())
+    }
+
+    /// evict the files used least recently but the `made` ones, which are about to be served
+    fn trim(&self, made: &[PathBuf]) {
+        if let Err(e) = evict(&self.cache_dir, self.cache_size, made) {
+            warn!("fail to trim the transcode cache: {}", e);
+        }
+    }
+
+    /// run `job` making `cached` in the background and wait for it, or wait for the one already
+    /// running. the job goes on when the request waiting for it goes away.
+    async fn once(
+        &self,
+        cached: &Path,
+        job: impl Future<Output = AppResult<()>> + Send + 'static,
+    ) -> AppResult<()> {
+        let mut state = {
+            let mut jobs = self.jobs.lock().unwrap_or_else(|e| e.into_inner());
+            match jobs.get(cached) {
+                Some(state) => state.clone(),
+                None => {
+                    let (sender, state) = watch::channel(None);
+                    jobs.insert(cached.to_path_buf(), state.clone());
+                    let (jobs, cached) = (self.jobs.clone(), cached.to_path_buf());
+                    tokio::spawn(async move {
+                        let result = job.await;
+                        jobs.lock()
+                            .unwrap_or_else(|e| e.into_inner())
+                            .remove(&cached);
+                        let _ = sender.send(Some(result));
+                    });
+                    state
+                }
+            }
+        };
+        let done = state
+            .wait_for(Option::is_some)
+            .await
+            .map_err(|_| AppError::internal("the transcode job stopped"))?;
+        done.clone().unwrap_or(Ok(()))
+    }
+
+    /// start ffmpeg once fewer than the max processes run, keep the permit until it exits
+    async fn spawn(
+        &self,
+        args: Vec<OsString>,
+        stdout: Stdio,
+    ) -> AppResult<(Child, OwnedSemaphorePermit)> {
+        let permit = self
+            .processes
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(AppError::internal)?;
+        let child = Command::new(&self.ffmpeg)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(stdout)
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => {
+                    AppError::new(ErrorCode::Unavailable, "transcoding needs ffmpeg")
+                }
+                _ => AppError::internal(e),
+            })?;
+        Ok((child, permit))
+    }
+
+    /// the transcoded `source`, from the cache when it's there
+    pub(crate) async fn respond(
+        &self,
+        source: PathBuf,
+        target: Target,
+        headers: HeaderMap,
+    ) -> AppResult<Response> {
+        if target.start > 0. {
+            return self.stream(&source, &target, None).await;
+        }
+        let variant = format!("{:?}|{}", target.codec, target.bitrate);
+        let cached = self
+            .cache_path(&source, &variant, target.codec.extension())
+            .map_err(AppError::internal)?;
+        if Self::cached(&cached) {
+            return Ok(crate::serve_file(cached, headers).await);
+        }
+        self.stream(&source, &target, Some(cached)).await
+    }
+
+    /// the cached hls segment of `source`, its whole chapter is transcoded when one of its
+    /// segments is first asked for
+    pub(crate) async fn segment(
+        &self,
+        source: &Path,
+        segment: &hls::Segment,
+    ) -> AppResult<PathBuf> {
+        let variant = format!("hls|{}|{}", segment.offset, segment.bitrate);
+        // no file is cached at the path of the chapter, it names its segments
+        let chapter = self
+            .cache_path(source, &variant, "ts")
+            .map_err(AppError::internal)?;
+        let cached = segment_file(&chapter, segment.index);
+        if Self::cached(&cached) {
+            return Ok(cached);
+        }
+        let (this, source, first) = (self.clone(), source.to_path_buf(), segment.clone());
+        let job_chapter = chapter.clone();
+        self.once(&chapter, async move {
+            this.segment_chapter(&source, &first, &job_chapter).await
+        })
+        .await?;
+        if Self::cached(&cached) {
+            Ok(cached)
+        } else {
+            Err(AppError::not_found(format!(
+                "segment {} of chapter {} not found",
+                segment.index, segment.chapter_no
+            )))
+        }
+    }
+
+    /// transcode the chapter of `segment` to its cached segments
+    async fn segment_chapter(
+        &self,
+        source: &Path,
+        segment: &hls::Segment,
+        chapter: &Path,
+    ) -> AppResult<()> {
+        let part = self.part_file(chapter, "").await?;
+        // ffmpeg numbers the segments it writes
+        let numbered = |index: &str| part.with_extension(format!("{}.part", index));
+        debug!("transcoding {:?} to hls segments", source);
+        let result = self
+            .run(hls::chapter_args(source, segment, &numbered("%d")))
+            .await;
+        let mut made = vec![];
+        loop {
+            let written = numbered(&made.len().to_string());
+            if !written.exists() {
+                break;
+            }
+            let cached = segment_file(chapter, made.len());
+            let moved = match result {
+                Ok(()) => tokio::fs::rename(&written, &cached).await,
+                Err(_) => tokio::fs::remove_file(&written).await,
+            };
+            moved.map_err(AppError::internal)?;
+            made.push(cached);
+        }
+        result?;
+        self.trim(&made);
+        Ok(())
+    }
+
+    /// the chapters merged into an m4b, made on first use and cached
+    ///
+    /// the merge runs in the background, a request gone before it ends leaves it running and
+    /// the requests coming meanwhile wait for the same merge.
+    pub(crate) async fn merge(
+        &self,
+        tags: &m4b::MergeTags<'_>,
+        chapters: &[m4b::MergeChapter<'_>],
+    ) -> AppResult<PathBuf> {
+        let metadata = m4b::ffmetadata(tags, chapters);
+        let mut key = format!("m4b|{}|{}", MERGE_BITRATE, metadata);
+        for chapter in chapters {
+            key.push('|');
+            key.push_str(&source_key(chapter.path).map_err(AppError::internal)?);
+        }
+        let cached = self.cache_file(&key, "m4b");
+        if Self::cached(&cached) {
+            return Ok(cached);
+        }
+        let (this, list, copy) = (
+            self.clone(),
+            m4b::concat_list(chapters),
+            m4b::can_copy(chapters),
+        );
+        let (job_cached, count) = (cached.clone(), chapters.len());
+        self.once(&cached, async move {
+            let part = this.part_file(&job_cached, "").await?;
+            let list_file = part.with_extension("list.part");
+            let metadata_file = part.with_extension("metadata.part");
+            let written = async {
+                tokio::fs::write(&list_file, list).await?;
+                tokio::fs::write(&metadata_file, &metadata).await
+            };
+            let result = match written.await {
+                Ok(()) => {
+                    debug!("merging {} chapters to {:?}", count, job_cached);
+                    let args =
+                        m4b::merge_args(&list_file, &metadata_file, copy, MERGE_BITRATE, &part);
+                    this.run_to_cache(args, &part, &job_cached).await
+                }
+                Err(e) => Err(AppError::internal(e)),
+            };
+            let _ = tokio::fs::remove_file(&list_file).await;
+            let _ = tokio::fs::remove_file(&metadata_file).await;
+            result
+        })
+        .await?;
+        Ok(cached)
+    }
+
+    /// start ffmpeg and stream its output, writing it to `cache` as well
+    async fn stream(
+        &self,
+        source: &Path,
+        target: &Target,
+        cache: Option<PathBuf>,
+    ) -> AppResult<Response> {
+        debug!("transcoding {:?} to {:?}", source, target);
+        let (mut child, permit) = self
+            .spawn(ffmpeg_args(source, target), Stdio::piped())
+            .await?;
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| AppError::internal("no stdout from ffmpeg"))?;
+        let (sender, body) = Body::channel();
+        let cache = cache.map(|path| Cache {
+            part: part_path(&path, ""),
+            path,
+            dir: self.cache_dir.clone(),
+            size: self.cache_size,
+        });
+        tokio::spawn(pump(child, permit, stdout, sender, cache));
+        Ok(([(header::CONTENT_TYPE, target.codec.mime())], boxed(body)).into_response())
+    }
+}
+
+fn ffmpeg_args(source: &Path, target: &Target) -> Vec<OsString> {
+    let (encoder, format) = target.codec.encoder_and_format();
+    let mut args: Vec<OsString> = vec![
+        "-nostdin".into(),
+        "-hide_banner".into(),
+        "-loglevel".into(),
+        "error".into(),
+    ];
+    if target.start > 0. {
+        // before the input, ffmpeg seeks instead of decoding up to the offset
+        args.extend(["-ss".into(), format!("{:.3}", target.start).into()]);
+    }
+    args.extend(["-i".into(), source.into()]);
+    for arg in [
+        "-vn",
+        "-map_metadata",
+        "-1",
+        "-c:a",
+        encoder,
+        "-b:a",
+        &format!("{}k", target.bitrate),
+        "-f",
+        format,
+        "pipe:1",
+    ] {
+        args.push(arg.into());
+    }
+    args
+}
+
+/// a file next to `cached` for one writer, which the eviction leaves alone until it's renamed
+fn part_path(cached: &Path, suffix: &str) -> PathBuf {
+    // the same output may be asked for twice at once, each writes its own file
+    let random = hex::encode(rand::random::<[u8; 4]>());
+    cached.with_extension(format!("{}{}.part", random, suffix))
+}
+
+/// the cache file of segment `index` of the hls `chapter`
+fn segment_file(chapter: &Path, index: usize) -> PathBuf {
+    chapter.with_extension(format!("{}.ts", index))
+}
+
+/// where a whole transcoded chapter goes
+struct Cache {
+    path: PathBuf,
+    /// written first, then renamed to `path`
+    part: PathBuf,
+    dir: PathBuf,
+    size: u64,
+}
+
+/// copy the output of ffmpeg to the client and to the cache, the cache is kept when ffmpeg
+/// succeeded even if the client left. the permit of the process is released on return.
+async fn pump(
+    mut child: Child,
+    _permit: OwnedSemaphorePermit,
+    mut stdout: impl AsyncRead + Unpin,
+    mut sender: hyper::body::Sender,
+    cache: Option<Cache>,
+) {
+    let stderr = child.stderr.take().map(|mut stderr| {
+        tokio::spawn(async move {
+            let mut text = String::new();
+            let _ = stderr.read_to_string(&mut text).await;
+            text
+        })
+    });
+    let part = cache.as_ref().map(|cache| cache.part.clone());
+    let mut file = match (&cache, &part) {
+        (Some(cache), Some(part)) => {
+            let created = async {
+                tokio::fs::create_dir_all(&cache.dir).await?;
+                tokio::fs::File::create(part).await
+            };
+            created
+                .await
+                .map_err(|e| warn!("fail to cache {:?}: {}", part, e))
+                .ok()
+        }
+        _ => None,
+    };
+    let mut client = true;
+    let mut complete = true;
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = match stdout.read(&mut buffer).await {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) => {
+                warn!("fail to read from ffmpeg: {}", e);
+                complete = false;
+                break;
+            }
+        };
+        let chunk = Bytes::copy_from_slice(&buffer[..read]);
+        if let Some(writer) = file.as_mut() {
+            if let Err(e) = writer.write_all(&chunk).await {
+                warn!("fail to write the transcode cache: {}", e);
+                file = None;
+            }
+        }
+        if client && sender.send_data(chunk).await.is_err() {
+            client = false;
+        }
+        if !client && file.is_none() {
+            // nobody wants the rest
+            complete = false;
+            break;
+        }
+    }
+    if !complete {
+        let _ = child.start_kill();
+    }
+    let success = match child.wait().await {
+        Ok(status) => status.success(),
+        Err(e) => {
+            warn!("fail to wait for ffmpeg: {}", e);
+            false
+        }
+    };
+    // a killed ffmpeg fails as well, only an ffmpeg that gave up on its own has something to say
+    if let Some(stderr) = stderr.filter(|_| complete && !success) {
+        warn!("ffmpeg failed: {}", stderr.await.unwrap_or_default());
+    }
+    if !success || !complete {
+        // the client should not take a truncated chapter for the whole one
+        sender.abort();
+    }
+    let (Some(cache), Some(part)) = (cache, part) else {
+        return;
+    };
+    let finished = match file {
+        Some(mut writer) if success && complete => writer.flush().await.is_ok(),
+        _ => false,
+    };
+    if !finished {
+        let _ = tokio::fs::remove_file(&part).await;
+        return;
+    }
+    if let Err(e) = tokio::fs::rename(&part, &cache.path).await {
+        warn!("fail to cache {:?}: {}", cache.path, e);
+        return;
+    }
+    match evict(&cache.dir, cache.size, &[cache.path]) {
+        Ok(0) => {}
+        Ok(freed) => debug!("evicted {} bytes of transcoded chapters", freed),
+        Err(e) => warn!("fail to trim the transcode cache: {}", e),
+    }
+}
+
+/// what identifies the content of a file, a changed file gets a new key
+fn source_key(source: &Path) -> io::Result<String> {
+    let metadata = std::fs::metadata(source)?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    Ok(format!(
+        "{}|{}|{}",
+        source.display(),
+        metadata.len(),
+        modified
+    ))
+}
+
+/// mark a cache file as just used
+fn touch(path: &Path) -> io::Result<()> {
+    std::fs::File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(SystemTime::now())
+}
+
+/// remove the cached files used least recently until the cache fits in `cap` bytes, returns
+/// the bytes freed. the files being written and the `kept` ones are left alone, even when they
+/// alone are over `cap`.
+pub(crate) fn evict(cache_dir: &Path, cap: u64, kept: &[PathBuf]) -> io::Result<u64> {
+    let mut files = vec![];
+    for entry in std::fs::read_dir(cache_dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() || path.extension().is_some_and(|ext| ext == "part") {
+            continue;
+        }
+        files.push((metadata.modified()?, metadata.len(), path));
+    }
+    files.sort();
+    let mut total = files.iter().map(|(_, len, _)| len).sum::<u64>();
+    let mut freed = 0;
+    for (_, len, path) in files {
+        if total <= cap {
+            break;
+        }
+        if kept.contains(&path) {
+            continue;
+        }
+        std::fs::remove_file(&path)?;
+        total -= len;
+        freed += len;
+    }
+    Ok(freed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::Path,
+        time::{Duration, SystemTime},
+    };
+
+    use super::{evict, ffmpeg_args, Codec, Target, Transcoder};
+
+    #[test]
+    fn test_ffmpeg_args() {
+        let target = Target::new(Codec::Mp3, Some(1000), Some(90.5));
+        assert_eq!(target.bitrate, 320);
+        let args = ffmpeg_args(Path::new("/books/a/0001.flac"), &target)
+            .into_iter()
+            .map(|arg| arg.into_string().unwrap())
+            .collect::<Vec<_>>()
+            .join(" ");
+        assert_eq!(
+            args,
+            "-nostdin -hide_banner -loglevel error -ss 90.500 -i /books/a/0001.flac -vn \
+             -map_metadata -1 -c:a libmp3lame -b:a 320k -f mp3 pipe:1"
+        );
+        let target = Target::new(Codec::Opus, None, Some(-3.));
+        let args = ffmpeg_args(Path::new("a.m4a"), &target);
+        assert!(!args.iter().any(|arg| arg == "-ss"));
+        assert!(args.iter().any(|arg| arg == "64k"));
+    }
+
+    #[test]
+    fn test_evict() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = SystemTime::now();
+        for (name, age) in [("old.opus", 30), ("used.opus", 10), ("new.opus", 20)] {
+            let path = dir.path().join(name);
+            std::fs::write(&path, [0; 100]).unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(now - Duration::from_secs(age))
+                .unwrap();
+        }
+        std::fs::write(dir.path().join("writing.part"), [0; 1000]).unwrap();
+        assert_eq!(evict(dir.path(), 250, &[]).unwrap(), 100);
+        assert!(!dir.path().join("old.opus").exists());
+        assert_eq!(evict(dir.path(), 100, &[]).unwrap(), 100);
+        assert!(dir.path().join("used.opus").exists());
+        assert!(dir.path().join("writing.part").exists());
+
+        // a file just made is kept even when it alone is over the cap
+        let made = dir.path().join("book.m4b");
+        std::fs::write(&made, [0; 500]).unwrap();
+        assert_eq!(
+            evict(dir.path(), 200, std::slice::from_ref(&made)).unwrap(),
+            100
+        );
+        assert!(made.exists());
+        assert!(!dir.path().join("used.opus").exists());
+    }
+
+    /// write a second of a mono 16 bit wav tone
+    fn write_wav(path: &Path) {
+        let rate = 8000u32;
+        let samples = (0..rate)
+            .map(|i| ((i as f32 * 0.3).sin() * 8000.) as i16)
+            .collect::<Vec<_>>();
+        let data_len = samples.len() as u32 * 2;
+        let mut wav = vec![];
+        wav.extend(b"RIFF");
+        wav.extend((36 + data_len).to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.extend(16u32.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(1u16.to_le_bytes());
+        wav.extend(rate.to_le_bytes());
+        wav.extend((rate * 2).to_le_bytes());
+        wav.extend(2u16.to_le_bytes());
+        wav.extend(16u16.to_le_bytes());
+        wav.extend(b"data");
+        wav.extend(data_len.to_le_bytes());
+        for sample in samples {
+            wav.extend(sample.to_le_bytes());
+        }
+        std::fs::write(path, wav).unwrap();
+    }
+
+    fn has_ffmpeg() -> bool {
+        let found = std::process::Command::new("ffmpeg")
+            .arg("-version")
+            .output()
+            .is_ok();
+        if !found {
+            eprintln!("ffmpeg is not installed, skip the test");
+        }
+        found
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_max_processes() {
+        let dir = tempfile::tempdir().unwrap();
+        let transcoder = Transcoder::new("true".into(), dir.path().to_path_buf(), 1 << 20, 1);
+        let (mut child, permit) = transcoder
+            .spawn(vec![], std::process::Stdio::null())
+            .await
+            .unwrap();
+        child.wait().await.unwrap();
+        // the process exited but its output may still be copied, the permit is what counts
+        let waiting = transcoder.spawn(vec![], std::process::Stdio::null());
+        assert!(tokio::time::timeout(Duration::from_millis(50), waiting)
+            .await
+            .is_err());
+        drop(permit);
+        let (mut child, _permit) = transcoder
+            .spawn(vec![], std::process::Stdio::null())
+            .await
+            .unwrap();
+        child.wait().await.unwrap();
+
+        // concurrent writers of the same cache file don't share a part file
+        let cached = dir.path().join("chapter.mp3");
+        assert_ne!(super::part_path(&cached, ""), super::part_path(&cached, ""));
+    }
+
+    #[tokio::test]
+    async fn test_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let transcoder = Transcoder::new("ffmpeg".into(), dir.path().to_path_buf(), 1 << 20, 1);
+        let runs = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let job = || {
+            let runs = runs.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                runs.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Ok(())
+            }
+        };
+        let cached = dir.path().join("book.m4b");
+        // a request gone before the job ends doesn't stop it
+        let gone = transcoder.once(&cached, job());
+        assert!(tokio::time::timeout(Duration::from_millis(10), gone)
+            .await
+            .is_err());
+        let (first, second) = tokio::join!(
+            transcoder.once(&cached, job()),
+            transcoder.once(&cached, job())
+        );
+        assert!(first.is_ok() && second.is_ok());
+        assert_eq!(runs.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert!(transcoder.jobs.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_segment() {
+        if !has_ffmpeg() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("0001.wav");
+        write_wav(&source);
+        let transcoder = Transcoder::new(
+            "ffmpeg".into(),
+            dir.path().join(super::CACHE_DIR),
+            1 << 20,
+            2,
+        );
+        let segment = super::hls::find_segment(&[(1, 1.)], "1-0.ts", None).unwrap();
+        let path = transcoder.segment(&source, &segment).await.unwrap();
+        let ts = std::fs::read(&path).unwrap();
+        // mpeg-ts packets of 188 bytes, starting with a sync byte
+        assert!(!ts.is_empty() && ts.len() % 188 == 0 && ts[0] == 0x47);
+        assert_eq!(transcoder.segment(&source, &segment).await.unwrap(), path);
+    }
+
+    #[tokio::test]
+    async fn test_transcode() {
+        if !has_ffmpeg() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("0001.wav");
+        write_wav(&source);
+        let cache_dir = dir.path().join(super::CACHE_DIR);
+        let transcoder = Transcoder::new("ffmpeg".into(), cache_dir.clone(), 1 << 20, 2);
+        let target = Target::new(Codec::Mp3, Some(32), None);
+        // two first requests at once each write their own part of the cache
+        let (first, second) = tokio::join!(
+            transcoder.respond(source.clone(), target, Default::default()),
+            transcoder.respond(source.clone(), target, Default::default())
+        );
+        let response = first.unwrap();
+        assert_eq!(response.headers()["content-type"], "audio/mpeg");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(!body.is_empty());
+        let other = hyper::body::to_bytes(second.unwrap().into_body())
+            .await
+            .unwrap();
+        assert_eq!(body, other);
+        // the cache is renamed in place once ffmpeg exited
+        let cached = transcoder.cache_path(&source, "Mp3|32", "mp3").unwrap();
+        for _ in 0..50 {
+            if cached.exists() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(std::fs::read(&cached).unwrap(), body);
+    }
+}
@@ -1,21 +1,22 @@
-use std::env;
-
 use crate::{
-    entities::{prelude::*, *},
-    progress::get_or_create_progress,
+    entities::{prelude::*, sea_orm_active_enums::ProgressState, *},
+    music::filter::{self, BookFilter, Facets, ValueCount},
+    progress::{chapter_durations, find_progress, stats, BookProgress},
+    tools::trash::{self, TrashKind},
 };
 use axum::{
-    extract::{Query, State},
+    extract::State,
     response::{Html, IntoResponse, Response},
     routing::{get, post},
-    Form, Router,
+    Router,
 };
 use hyper::StatusCode;
-use sea_orm::{ActiveModelTrait, ActiveValue, ColumnTrait, EntityTrait, QueryFilter};
+use sea_orm::{ActiveModelTrait, ActiveValue, ColumnTrait, EntityTrait, QueryFilter, QueryOrder};
 use tera::Tera;
 use tracing::{error, info};
 
 use crate::{
+    error::{AppError, AppForm, AppQuery, AppResult},
     middleware::{LoginInfo, PasskeyCheckResult},
     AppStat,
 };
@@ -27,13 +28,16 @@ pub(crate) fn route(state: AppStat) -> Router<AppStat> {
         .route("/index", get(index_page))
         .route("/authors", get(authors_page))
         .route("/books", get(books_page))
+        .route("/stats", get(stats_page))
         .route("/book_detail", get(book_detail_page))
         .route("/author_detail", get(author_detail_page))
         .route("/player", get(player_page))
         .route("/newplayer", get(newplayer_page))
         .route("/manager", get(manager_page))
         .route("/book_manager", get(book_manager_page))
+        .route("/author_manager", get(author_manager_page))
         .route("/account_manager", get(account_manager_page))
+        .route("/trash", get(trash_page))
         .route("/user_op", get(user_op_page))
         .route("/update_user_page", get(update_user_page))
         .route("/create_user_action", post(create_user_action_page))
@@ -59,30 +63,59 @@ struct RecentData {
     chapter_id: i32,
     progress: f64,
     progress_id: i32,
-}
-async fn index_html(state: &AppStat, data: &LoginInfo) -> Response {
+    /// the progress in the whole book, once the chapter durations are known
+    book_progress: Option<BookProgress>,
+    /// like `43% done, 5h12m left`
+    summary: Option<String>,
+    state: ProgressState,
+    finished_at: Option<chrono::NaiveDateTime>,
+    relisten_count: i32,
+}
+async fn index_html(state: &AppStat, data: &LoginInfo) -> AppResult<Response> {
     let tera = &state.tera;
     let mut context = tera::Context::new();
     context.insert("user_name", &data.user_name);
     context.insert("title", "sjq audiobook_server");
+    // the latest played first, the progress never played last
     let recent_played = Progress::find()
         .filter(progress::Column::AccountId.eq(data.user_id))
+        .order_by_desc(progress::Column::LastPlayedAt)
+        .order_by_desc(progress::Column::Id)
         .all(&state.connections.db)
-        .await
-        .unwrap();
-    let mut recent_data = Vec::new();
+        .await?;
+    let mut continue_listening = Vec::new();
+    let mut want_to_listen = Vec::new();
+    let mut finished = Vec::new();
     for m in recent_played {
-        let book = Music::find_by_id(m.music_id)
+        let list = match m.state {
+            ProgressState::InProgress => &mut continue_listening,
+            ProgressState::WantToListen => &mut want_to_listen,
+            ProgressState::Finished => &mut finished,
+            // abandoned books stay out of the way until played again
+            ProgressState::Abandoned => continue,
+        };
+        // books in the trash are hidden until restored
+        let Some(book) = Music::find_by_id(m.music_id)
+            .filter(music::Column::DeletedAt.is_null())
             .one(&state.connections.db)
-            .await
-            .unwrap()
-            .unwrap();
-        let author = Author::find_by_id(book.author_id)
+            .await?
+        else {
+            continue;
+        };
+        let Some(author) = Author::find_by_id(book.author_id)
             .one(&state.connections.db)
-            .await
-            .unwrap()
-            .unwrap();
-        recent_data.push(RecentData {
+            .await?
+        else {
+            continue;
+        };
+        let durations = chapter_durations(&state.connections.db, &[book.id]).await?;
+        let book_progress = BookProgress::new(
+            book.chapters,
+            durations.get(&book.id).map_or(&[], Vec::as_slice),
+            m.chapter_no,
+            m.progress,
+        );
+        list.push(RecentData {
             book_id: book.id,
             book_name: book.name,
             author: author.name,
@@ -90,17 +123,18 @@ async fn index_html(state: &AppStat, data: &LoginInfo) -> Response {
             progress: m.progress,
             author_id: author.id,
             progress_id: m.id,
+            summary: book_progress.as_ref().map(BookProgress::summary),
+            book_progress,
+            state: m.state,
+            finished_at: m.finished_at,
+            relisten_count: m.relisten_count,
         });
     }
-    context.insert("recent_played", &recent_data);
-    let html = tera.render("index.tera", &context).map_err(|e| {
-        error!("render error: {}", e);
-        (StatusCode::INTERNAL_SERVER_ERROR, "render error")
-    });
-    match html {
-        Ok(html) => (StatusCode::OK, Html(html)).into_response(),
-        Err(err) => err.into_response(),
-    }
+    context.insert("continue_listening", &continue_listening);
+    context.insert("want_to_listen", &want_to_listen);
+    context.insert("finished", &finished);
+    let html = tera.render("index.tera", &context)?;
+    Ok((StatusCode::OK, Html(html)).into_response())
 }
 
 fn login_html(state: &AppStat) -> Response {
@@ -127,10 +161,13 @@ fn logout_html(state: &AppStat) -> Response {
         .into_response()
 }
 
-async fn login_page(State(state): State<AppStat>, login_status: PasskeyCheckResult) -> Response {
+async fn login_page(
+    State(state): State<AppStat>,
+    login_status: PasskeyCheckResult,
+) -> AppResult<Response> {
     match login_status {
         PasskeyCheckResult::LogInSucceed((_, data)) => index_html(&state, &data).await,
-        _ => login_html(&state),
+        _ => Ok(login_html(&state)),
     }
 }
 async fn logout_page(state: State<AppStat>) -> Response {
@@ -140,10 +177,10 @@ async fn logout_page(state: State<AppStat>) -> Response {
 pub(crate) async fn index_page(
     State(state): State<AppStat>,
     login_status: PasskeyCheckResult,
-) -> Response {
+) -> AppResult<Response> {
     match login_status {
         PasskeyCheckResult::LogInSucceed((_, data)) => index_html(&state, &data).await,
-        _ => login_html(&state),
+        _ => Ok(login_html(&state)),
     }
 }
 #[derive(Debug, serde::Deserialize)]
@@ -151,15 +188,21 @@ struct Para {
     id: i32,
 }
 
-async fn authors_page(State(state): State<AppStat>, login_status: PasskeyCheckResult) -> Response {
+async fn authors_page(
+    State(state): State<AppStat>,
+    login_status: PasskeyCheckResult,
+) -> AppResult<Response> {
     match login_status {
         PasskeyCheckResult::LogInSucceed((_, data)) => {
-            let authors = Author::find().all(&state.connections.db).await.unwrap();
+            let authors = Author::find()
+                .filter(author::Column::DeletedAt.is_null())
+                .all(&state.connections.db)
+                .await?;
             let mut context = tera::Context::new();
             context.insert("title", "sjq audiobook_server");
             context.insert("user_name", &data.user_name);
             context.insert("authors", &authors);
-            state
+            Ok(state
                 .tera
                 .render("authors.tera", &context)
                 .map(|html| (StatusCode::OK, Html(html)))
@@ -167,20 +210,110 @@ async fn authors_page(State(state): State<AppStat>, login_status: PasskeyCheckRe
                     error!("render error: {}", e);
                     (StatusCode::INTERNAL_SERVER_ERROR, "render error")
                 })
-                .into_response()
+                .into_response())
         }
-        _ => login_html(&state),
+        _ => Ok(login_html(&state)),
     }
 }
-async fn books_page(State(state): State<AppStat>, login_status: PasskeyCheckResult) -> Response {
+#[derive(Debug, serde::Serialize)]
+struct Chip {
+    value: String,
+    label: String,
+    count: i64,
+    selected: bool,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct ChipGroup {
+    key: &'static str,
+    label: &'static str,
+    chips: Vec<Chip>,
+}
+
+/// the facets as groups of filter chips, the selected value of each group is marked
+fn filter_chips(filter: &BookFilter, facets: Facets) -> Vec<ChipGroup> {
+    let selected = filter.query_pairs();
+    let is_selected = |key: &str, value: &str| {
+        selected
+            .iter()
+            .any(|(selected_key, selected_value)| *selected_key == key && selected_value == value)
+    };
+    let values = |key: &'static str, label: &'static str, counts: Vec<ValueCount>| ChipGroup {
+        key,
+        label,
+        chips: counts
+            .into_iter()
+            .map(|count| Chip {
+                selected: is_selected(key, &count.value),
+                label: count.value.clone(),
+                value: count.value,
+                count: count.count,
+            })
+            .collect(),
+    };
+    let authors = ChipGroup {
+        key: "author_id",
+        label: "Author",
+        chips: facets
+            .authors
+            .into_iter()
+            .map(|author| Chip {
+                value: author.id.to_string(),
+                selected: is_selected("author_id", &author.id.to_string()),
+                label: author.name,
+                count: author.count,
+            })
+            .collect(),
+    };
+    let states = ChipGroup {
+        key: "state",
+        label: "State",
+        chips: facets
+            .states
+            .into_iter()
+            .map(|state| Chip {
+                value: state.state.as_str().to_string(),
+                label: state.state.as_str().replace('_', " "),
+                selected: is_selected("state", state.state.as_str()),
+                count: state.count as i64,
+            })
+            .collect(),
+    };
+    vec![
+        authors,
+        values("narrator", "Narrator", facets.narrators),
+        values("series", "Series", facets.series),
+        values("tag", "Tag", facets.tags),
+        values("language", "Language", facets.languages),
+        states,
+    ]
+}
+
+async fn books_page(
+    State(state): State<AppStat>,
+    AppQuery(filter): AppQuery<BookFilter>,
+    login_status: PasskeyCheckResult,
+) -> AppResult<Response> {
     match login_status {
         PasskeyCheckResult::LogInSucceed((_, data)) => {
-            let books = Music::find().all(&state.connections.db).await.unwrap();
+            let base = Music::find().filter(music::Column::DeletedAt.is_null());
+            let books = filter
+                .apply(base.clone(), data.user_id)
+                .all(&state.connections.db)
+                .await?;
+            let facets = filter::facets(&state.connections.db, base, &filter, data.user_id).await?;
+            let active = filter
+                .query_pairs()
+                .into_iter()
+                .map(|(key, value)| serde_json::json!({ "key": key, "value": value }))
+                .collect::<Vec<_>>();
             let mut context = tera::Context::new();
             context.insert("title", "sjq audiobook_server");
             context.insert("user_name", &data.user_name);
             context.insert("books", &books);
-            state
+            context.insert("filter_groups", &filter_chips(&filter, facets));
+            context.insert("active_filters", &active);
+            Ok(state
                 .tera
                 .render("books.tera", &context)
                 .map(|html| (StatusCode::OK, Html(html)))
@@ -188,32 +321,57 @@ async fn books_page(State(state): State<AppStat>, login_status: PasskeyCheckResu
                     error!("render error: {}", e);
                     (StatusCode::INTERNAL_SERVER_ERROR, "render error")
                 })
-                .into_response()
+                .into_response())
         }
-        _ => login_html(&state),
+        _ => Ok(login_html(&state)),
+    }
+}
+/// the listening stats of the user, and of the whole server for the admins
+async fn stats_page(
+    State(state): State<AppStat>,
+    login_status: PasskeyCheckResult,
+) -> AppResult<Response> {
+    let PasskeyCheckResult::LogInSucceed((_, data)) = login_status else {
+        return Ok(login_html(&state));
+    };
+    let db = &state.connections.db;
+    let mut context = tera::Context::new();
+    context.insert("title", "sjq audiobook_server");
+    context.insert("user_name", &data.user_name);
+    context.insert("stats", &stats::user_stats(db, data.user_id).await?);
+    // role 0 is the admin
+    if data.role_level == 0 {
+        context.insert("server", &stats::server_stats(db).await?);
     }
+    let html = state.tera.render("stats.tera", &context)?;
+    Ok((StatusCode::OK, Html(html)).into_response())
 }
+
 async fn book_detail_page(
     State(state): State<AppStat>,
-    Query(para): Query<Para>,
+    AppQuery(para): AppQuery<Para>,
     login_status: PasskeyCheckResult,
-) -> impl IntoResponse {
+) -> AppResult<Response> {
     match login_status {
         PasskeyCheckResult::LogInSucceed((_, data)) => {
             let book_id = para.id;
 
             let book = Music::find_by_id(book_id)
+                .filter(music::Column::DeletedAt.is_null())
                 .one(&state.connections.db)
-                .await
-                .unwrap()
-                .unwrap();
+                .await?
+                .ok_or_else(|| AppError::not_found(format!("book {} not found", book_id)))?;
             let author = Author::find_by_id(book.author_id)
                 .one(&state.connections.db)
-                .await
-                .unwrap()
-                .unwrap();
-            let progress =
-                get_or_create_progress(&state.connections.db, data.user_id, book_id).await;
+                .await?
+                .ok_or_else(|| {
+                    AppError::not_found(format!("author {} not found", book.author_id))
+                })?;
+            let progress = find_progress(&state.connections.db, data.user_id, book_id).await?;
+            let can_download = Account::find_by_id(data.user_id)
+                .one(&state.connections.db)
+                .await?
+                .is_some_and(|user| user.can_download);
             let mut context = tera::Context::new();
             // data for base
             context.insert("title", "sjq audiobook_server");
@@ -222,7 +380,13 @@ async fn book_detail_page(
             context.insert("book", &book);
             context.insert("author", &author);
             context.insert("progress", &progress);
-            state
+            if can_download {
+                let feed_token =
+                    crate::feed::feed_token(&state.connections.db, data.user_id).await?;
+                context.insert("feed_url", &crate::feed::feed_path(&feed_token, book.id));
+            }
+            context.insert("can_download", &can_download);
+            Ok(state
                 .tera
                 .render("book_detail.tera", &context)
                 .map(|html| (StatusCode::OK, Html(html)))
@@ -230,30 +394,30 @@ async fn book_detail_page(
                     error!("render error: {}", e);
                     (StatusCode::INTERNAL_SERVER_ERROR, "render error")
                 })
-                .into_response()
+                .into_response())
         }
-        _ => login_html(&state),
+        _ => Ok(login_html(&state)),
     }
 }
 async fn author_detail_page(
     State(state): State<AppStat>,
-    Query(para): Query<Para>,
+    AppQuery(para): AppQuery<Para>,
     login_status: PasskeyCheckResult,
-) -> Response {
+) -> AppResult<Response> {
     match login_status {
         PasskeyCheckResult::LogInSucceed((_, data)) => {
             let author_id = para.id;
             let author = Author::find_by_id(author_id)
+                .filter(author::Column::DeletedAt.is_null())
                 .one(&state.connections.db)
-                .await
-                .unwrap()
-                .unwrap();
+                .await?
+                .ok_or_else(|| AppError::not_found(format!("author {} not found", author_id)))?;
 
             let books = Music::find()
                 .filter(music::Column::AuthorId.eq(author.id))
+                .filter(music::Column::DeletedAt.is_null())
                 .all(&state.connections.db)
-                .await
-                .unwrap();
+                .await?;
             let mut context = tera::Context::new();
             // data for base
             context.insert("title", "sjq audiobook_server");
@@ -261,7 +425,7 @@ async fn author_detail_page(
 
             context.insert("author_name", &author.name);
             context.insert("books", &books);
-            state
+            Ok(state
                 .tera
                 .render("books.tera", &context)
                 .map(|html| (StatusCode::OK, Html(html)))
@@ -269,9 +433,9 @@ async fn author_detail_page(
                     error!("render error: {}", e);
                     (StatusCode::INTERNAL_SERVER_ERROR, "render error")
                 })
-                .into_response()
+                .into_response())
         }
-        _ => login_html(&state),
+        _ => Ok(login_html(&state)),
     }
 }
 
@@ -283,21 +447,20 @@ struct PlayerPara {
 
 async fn player_page(
     State(state): State<AppStat>,
-    Query(para): Query<PlayerPara>,
+    AppQuery(para): AppQuery<PlayerPara>,
     login_status: PasskeyCheckResult,
-) -> impl IntoResponse {
+) -> AppResult<Response> {
     match login_status {
         PasskeyCheckResult::LogInSucceed((_, data)) => {
             let book_id = para.book_id;
             let chapter_id = para.chapter_id;
 
             let book = Music::find_by_id(book_id)
+                .filter(music::Column::DeletedAt.is_null())
                 .one(&state.connections.db)
-                .await
-                .unwrap()
-                .unwrap();
-            let progress =
-                get_or_create_progress(&state.connections.db, data.user_id, book_id).await;
+                .await?
+                .ok_or_else(|| AppError::not_found(format!("book {} not found", book_id)))?;
+            let progress = find_progress(&state.connections.db, data.user_id, book_id).await?;
             let chapter_id = chapter_id.unwrap_or(progress.chapter_no);
             let mut context = tera::Context::new();
             // data for base
@@ -315,7 +478,7 @@ async fn player_page(
                 context.insert("this_progress", &0.);
             }
 
-            state
+            Ok(state
                 .tera
                 .render("player.tera", &context)
                 .map(|html| (StatusCode::OK, Html(html)))
@@ -323,29 +486,28 @@ async fn player_page(
                     error!("render error: {}", e);
                     (StatusCode::INTERNAL_SERVER_ERROR, "render error")
                 })
-                .into_response()
+                .into_response())
         }
-        _ => login_html(&state),
+        _ => Ok(login_html(&state)),
     }
 }
 
 async fn newplayer_page(
     State(state): State<AppStat>,
-    Query(para): Query<PlayerPara>,
+    AppQuery(para): AppQuery<PlayerPara>,
     login_status: PasskeyCheckResult,
-) -> impl IntoResponse {
+) -> AppResult<Response> {
     match login_status {
         PasskeyCheckResult::LogInSucceed((_, data)) => {
             let book_id = para.book_id;
             let chapter_id = para.chapter_id;
 
             let book = Music::find_by_id(book_id)
+                .filter(music::Column::DeletedAt.is_null())
                 .one(&state.connections.db)
-                .await
-                .unwrap()
-                .unwrap();
-            let progress =
-                get_or_create_progress(&state.connections.db, data.user_id, book_id).await;
+                .await?
+                .ok_or_else(|| AppError::not_found(format!("book {} not found", book_id)))?;
+            let progress = find_progress(&state.connections.db, data.user_id, book_id).await?;
             let chapter_id = chapter_id.unwrap_or(progress.chapter_no);
             let mut context = tera::Context::new();
             // data for base
@@ -363,7 +525,7 @@ async fn newplayer_page(
                 context.insert("this_progress", &0.);
             }
 
-            state
+            Ok(state
                 .tera
                 .render("newplayer.tera", &context)
                 .map(|html| (StatusCode::OK, Html(html)))
@@ -371,9 +533,9 @@ async fn newplayer_page(
                     error!("render error: {}", e);
                     (StatusCode::INTERNAL_SERVER_ERROR, "render error")
                 })
-                .into_response()
+                .into_response())
         }
-        _ => login_html(&state),
+        _ => Ok(login_html(&state)),
     }
 }
 async fn generate_manager_page_with_data(
@@ -384,17 +546,8 @@ async fn generate_manager_page_with_data(
 ) -> Response {
     let mut context = tera::Context::new();
 
-    match data.role_level {
-        0 => {
-            context.insert("admin", &true);
-        }
-        1 => {
-            context.insert("admin", &false);
-        }
-        _ => {
-            panic!("no such role")
-        }
-    }
+    // role 0 is the admin, anything else is a plain user
+    context.insert("admin", &(data.role_level == 0));
     // data for base
     context.insert("title", "manager");
     context.insert("user_name", &data.user_name);
@@ -440,8 +593,8 @@ async fn book_manager_page(
                 &state.tera,
                 "book_manager.tera",
                 [(
-                    "current_dir".to_string(),
-                    tera::to_value(env::current_dir().unwrap()).unwrap(),
+                    "import_roots".to_string(),
+                    tera::to_value(&state.import_roots).unwrap(),
                 )],
             )
             .await
@@ -450,6 +603,27 @@ async fn book_manager_page(
     }
 }
 
+async fn author_manager_page(
+    State(state): State<AppStat>,
+    login_status: PasskeyCheckResult,
+) -> impl IntoResponse {
+    match login_status {
+        PasskeyCheckResult::LogInSucceed((_, data)) => {
+            generate_manager_page(&data, &state.tera, "author_manager.tera").await
+        }
+        _ => login_html(&state),
+    }
+}
+
+async fn trash_page(State(state): State<AppStat>, login_status: PasskeyCheckResult) -> Response {
+    match login_status {
+        PasskeyCheckResult::LogInSucceed((_, data)) => {
+            generate_manager_page(&data, &state.tera, "trash.tera").await
+        }
+        _ => login_html(&state),
+    }
+}
+
 async fn account_manager_page(
     State(state): State<AppStat>,
     login_status: PasskeyCheckResult,
@@ -461,6 +635,7 @@ async fn account_manager_page(
         _ => login_html(&state),
     }
 }
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 enum UserOpData {
     AddUser,
@@ -476,29 +651,32 @@ struct InputPara {
 async fn user_op_page(
     State(state): State<AppStat>,
     login_status: PasskeyCheckResult,
-    form: Form<InputPara>,
-) -> Response {
+    AppForm(form): AppForm<InputPara>,
+) -> AppResult<Response> {
     match login_status {
         PasskeyCheckResult::LogInSucceed((_, data)) => {
-            let user_list = match &form.0.op {
+            let user_list = match &form.op {
                 UserOpData::DeleteUser | UserOpData::UpdateUser => {
-                    let users = Account::find().all(&state.connections.db).await.unwrap();
+                    let users = Account::find()
+                        .filter(account::Column::DeletedAt.is_null())
+                        .all(&state.connections.db)
+                        .await?;
                     Some(users)
                 }
                 _ => None,
             };
-            generate_manager_page_with_data(
+            Ok(generate_manager_page_with_data(
                 &data,
                 &state.tera,
                 "user_op.tera",
                 [
-                    ("data".to_string(), tera::to_value(form.0.op).unwrap()),
+                    ("data".to_string(), tera::to_value(form.op).unwrap()),
                     ("user_list".to_string(), tera::to_value(user_list).unwrap()),
                 ],
             )
-            .await
+            .await)
         }
-        _ => login_html(&state),
+        _ => Ok(login_html(&state)),
     }
 }
 #[derive(Debug, serde::Deserialize)]
@@ -508,24 +686,19 @@ struct UpdateUserForm {
 async fn update_user_page(
     State(state): State<AppStat>,
     login_status: PasskeyCheckResult,
-    form: Form<UpdateUserForm>,
-) -> Response {
+    AppForm(form): AppForm<UpdateUserForm>,
+) -> AppResult<Response> {
     match login_status {
         PasskeyCheckResult::LogInSucceed((_, data)) => {
-            let user = Account::find_by_id(form.0.id)
+            let user = Account::find_by_id(form.id)
                 .one(&state.connections.db)
-                .await
-                .unwrap()
-                .unwrap();
-            let admin_selected_str = match user.role_level {
-                0 => "selected='selected'",
-                1 => "",
-                _ => panic!("no such role"),
-            };
-            let user_selected_str = match user.role_level {
-                0 => "",
-                1 => "selected='selected'",
-                _ => panic!("no such role"),
+                .await?
+                .ok_or_else(|| AppError::not_found(format!("account {} not found", form.id)))?;
+            let is_admin = user.role_level == 0;
+            let (admin_selected_str, user_selected_str) = if is_admin {
+                ("selected='selected'", "")
+            } else {
+                ("", "selected='selected'")
             };
             let html = format!(
                 "<h1>update user</h1>
@@ -544,24 +717,20 @@ async fn update_user_page(
             </form>
             ",
                 user.name,
-                match user.role_level {
-                    0 => "Admin",
-                    1 => "User",
-                    _ => panic!("no such role"),
-                },
+                if is_admin { "Admin" } else { "User" },
                 admin_selected_str,
                 user_selected_str,
                 user.id
             );
-            generate_manager_page_with_data(
+            Ok(generate_manager_page_with_data(
                 &data,
                 &state.tera,
                 "simple.tera",
                 [("data".to_string(), tera::to_value(html).unwrap())],
             )
-            .await
+            .await)
         }
-        _ => login_html(&state),
+        _ => Ok(login_html(&state)),
     }
 }
 #[derive(Debug, serde::Deserialize)]
@@ -578,11 +747,10 @@ struct CreateUserForm {
 async fn create_user_action_page(
     State(state): State<AppStat>,
     login_status: PasskeyCheckResult,
-    form: Form<CreateUserForm>,
+    AppForm(form): AppForm<CreateUserForm>,
 ) -> Response {
     match login_status {
         PasskeyCheckResult::LogInSucceed((_, data)) => {
-            let form = form.0;
             let password = md5::compute(form.password);
             let password = format!("{:x}", password);
             let active = account::ActiveModel {
@@ -649,15 +817,19 @@ struct DeleteForm {
 async fn delete_user_action_page(
     State(state): State<AppStat>,
     login_status: PasskeyCheckResult,
-    form: Form<DeleteForm>,
+    AppForm(form): AppForm<DeleteForm>,
 ) -> Response {
     match login_status {
         PasskeyCheckResult::LogInSucceed((_, data)) => {
-            let form = form.0;
-
-            let result = Account::delete_by_id(form.id)
-                .exec(&state.connections.db)
-                .await;
+            // accounts go to the trash, they are purged from the trash manager
+            let mut result = trash::trash(&state.connections.db, TrashKind::Account, form.id).await;
+            if result.is_ok() {
+                let mut redis = state.connections.redis.lock().await;
+                result = trash::revoke_sessions(&mut redis, form.id)
+                    .await
+                    .map(|_| ())
+                    .map_err(Into::into);
+            }
             match result {
                 Ok(_) => {
                     generate_manager_page_with_data(
@@ -715,11 +887,10 @@ struct UpdateActionForm {
 async fn update_user_action_page(
     State(state): State<AppStat>,
     login_status: PasskeyCheckResult,
-    form: Form<UpdateActionForm>,
+    AppForm(form): AppForm<UpdateActionForm>,
 ) -> Response {
     match login_status {
         PasskeyCheckResult::LogInSucceed((_, data)) => {
-            let form = form.0;
             let password = md5::compute(form.password);
             let password = format!("{:x}", password);
             let active = account::ActiveModel {
@@ -730,6 +901,7 @@ async fn update_user_action_page(
                     Role::Admin => 0,
                     Role::User => 1,
                 }),
+                ..Default::default()
             };
             let result = active.save(&state.connections.db).await;
 